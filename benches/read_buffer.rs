@@ -0,0 +1,61 @@
+//! Compares how quickly requests can be read from a socket with different read buffer sizes.
+//! Run with `cargo bench --bench read_buffer`
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Instant;
+
+use http_server::request::http1_1::{self, read_head};
+
+const REQUESTS: usize = 2_000;
+const BODY_SIZE: usize = 16 * 1024;
+
+fn requests() -> Vec<u8> {
+    let mut raw = Vec::with_capacity(REQUESTS * (BODY_SIZE + 128));
+    for _ in 0..REQUESTS {
+        write!(
+            raw,
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: {BODY_SIZE}\r\n\r\n"
+        )
+        .unwrap();
+        raw.extend(std::iter::repeat_n(b'a', BODY_SIZE));
+    }
+    raw
+}
+
+/// Read every request from a loopback connection. Returns the elapsed time in milliseconds
+fn measure(capacity: usize, raw: &'static [u8]) -> u128 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+    let addr = listener.local_addr().unwrap();
+    let writer = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).expect("Connecting should succeed");
+        stream.write_all(raw).expect("Writing should succeed");
+    });
+
+    let (stream, _) = listener.accept().expect("Accepting should succeed");
+    let start = Instant::now();
+    let mut reader = BufReader::with_capacity(capacity, stream);
+    for _ in 0..REQUESTS {
+        let (head, _) = read_head(&mut reader).expect("Reading the head should succeed");
+        let head = http1_1::parse_req_head(&mut head.lines()).expect("The head should be valid");
+        let length: u64 = head.headers["content-length"].parse().unwrap();
+        io::copy(&mut (&mut reader).take(length), &mut io::sink()).unwrap();
+    }
+    let elapsed = start.elapsed().as_millis();
+
+    writer.join().unwrap();
+    elapsed
+}
+
+fn main() {
+    let raw: &'static [u8] = Box::leak(requests().into_boxed_slice());
+    for capacity in [1024, 8 * 1024, 64 * 1024, 256 * 1024] {
+        // Warm up the connection path before measuring
+        measure(capacity, raw);
+        println!(
+            "{0:>7} byte buffer: {1:>5} ms for {REQUESTS} requests",
+            capacity,
+            measure(capacity, raw)
+        );
+    }
+}
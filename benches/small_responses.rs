@@ -0,0 +1,70 @@
+//! Compares the latency of small responses with and without Nagle's algorithm disabled.
+//! Run with `cargo bench --bench small_responses`
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http_server::_crud_example as rest_api;
+use http_server::server::handlers::HandlerRegistry;
+use http_server::server::listener::{HTTPListener, ListenerConfig};
+
+static IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const ITERATIONS: usize = 500;
+
+fn run_listener(port: u16, nodelay_small_responses: bool) {
+    let store = Arc::new(Mutex::new(rest_api::DogStore::default()));
+    let registry = HandlerRegistry::new(vec![Arc::new(rest_api::DogStoreGetHandler::new(store))]);
+    let config = ListenerConfig::new(Some(Duration::new(10, 0)), true)
+        .nodelay_small_responses(nodelay_small_responses);
+
+    thread::spawn(move || HTTPListener::new(IP, port, registry, config).listen());
+    thread::sleep(Duration::from_millis(50));
+}
+
+/// Returns the latency of each request in microseconds, sorted
+fn measure(port: u16) -> Vec<u128> {
+    let mut latencies = Vec::with_capacity(ITERATIONS);
+    let mut response = Vec::new();
+
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        let mut stream = TcpStream::connect((IP, port)).expect("The server should be running");
+        stream
+            .write_all(b"GET /dogs HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .expect("Sending the request should succeed");
+        response.clear();
+        stream
+            .read_to_end(&mut response)
+            .expect("Reading the response should succeed");
+        latencies.push(start.elapsed().as_micros());
+    }
+
+    latencies.sort_unstable();
+    latencies
+}
+
+fn report(label: &str, latencies: &[u128]) {
+    let mean = latencies.iter().sum::<u128>() / latencies.len() as u128;
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{label:<16} mean {mean:>6} us | p50 {0:>6} us | p99 {1:>6} us",
+        percentile(50),
+        percentile(99)
+    );
+}
+
+fn main() {
+    let base_port = 9100 + (std::process::id() % 400) as u16 * 2;
+    run_listener(base_port, false);
+    run_listener(base_port + 1, true);
+
+    // Warm up both servers before measuring
+    measure(base_port);
+    measure(base_port + 1);
+
+    report("Nagle enabled", &measure(base_port));
+    report("Small nodelay", &measure(base_port + 1));
+    std::process::exit(0);
+}
@@ -1,26 +1,50 @@
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    request::{HTTPMethod, Request},
+    mime::{MimeType, SubMimeType},
+    request::{
+        conditional,
+        prefer::{self, ReturnPreference},
+        HTTPMethod, Request,
+    },
     server::{
-        self,
         handlers::{Handler, HandlerPath, HandlerResult},
-        response::{Response, ResponseBuilder, ResponseStatus},
+        response::{ResponseBuilder, ResponseStatus},
     },
 };
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DogStore {
     pub names: Vec<String>,
+    /// Incremented on every change so that updates can be made conditional
+    #[serde(skip)]
+    version: u64,
 }
 
 impl DogStore {
     pub fn add(&mut self, name: &str) {
-        self.names.push(name.to_string())
+        self.names.push(name.to_string());
+        self.version += 1;
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.names.retain(|existing| existing != name);
+        self.version += 1;
+    }
+
+    pub fn replace(&mut self, names: Vec<String>) {
+        self.names = names;
+        self.version += 1;
+    }
+
+    /// Identifies the current list of names, E.G for If-Match
+    pub fn etag(&self) -> String {
+        format!("\"{0}\"", self.version)
     }
 }
 
@@ -50,17 +74,36 @@ impl Handler for DogStoreGetHandler {
     }
 
     fn on_request(&self, req: Request) -> HandlerResult {
+        let available = [
+            MimeType::from_str("application/json").expect("JSON should be a supported MIME type"),
+            MimeType::from_str("text/plain").expect("Plain text should be a supported MIME type"),
+        ];
+        let Some(media_type) = req.head.accepts().best_match(&available) else {
+            return HandlerResult::Done(
+                ResponseBuilder::from(req)
+                    .status(ResponseStatus::NotAcceptable)
+                    .header("Vary", "Accept")
+                    .text("Dogs are available as application/json or text/plain")
+                    .build()
+                    .expect("A valid 406 response should be created"),
+            );
+        };
+
         let store = self.store.lock().unwrap();
-        let jsonified = serde_json::to_string(&*store).expect("DogStore should be serialisable");
+        let body = match media_type.sub_type {
+            SubMimeType::TXT => store.names.join("\n"),
+            _ => serde_json::to_string(&*store).expect("DogStore should be serialisable"),
+        };
 
         HandlerResult::Done(
             ResponseBuilder::from(req)
                 .ok()
-                .headers(HashMap::from([(
-                    "Content-Type".to_string(),
-                    "application/json".to_string(),
-                )]))
-                .body(jsonified)
+                .headers(HashMap::from([
+                    ("Content-Type".to_string(), media_type.to_string()),
+                    ("ETag".to_string(), store.etag()),
+                    ("Vary".to_string(), "Accept".to_string()),
+                ]))
+                .body(body)
                 .build()
                 .expect("A valid response should be created"),
         )
@@ -99,13 +142,14 @@ impl Handler for DogStorePostHandler {
             Ok(body) => {
                 let dog_name = match body["name"].as_str() {
                     Some(name) => name.to_string(),
+                    // Well-formed JSON without a name
                     None => {
                         return HandlerResult::Done(
                             ResponseBuilder::from(req)
-                                .bad_request()
+                                .status(ResponseStatus::UnprocessableContent)
                                 .body("Invalid field name".to_string())
                                 .build()
-                                .expect("A valid 400 response should be produced"),
+                                .expect("A valid 422 response should be produced"),
                         )
                     }
                 };
@@ -120,10 +164,21 @@ impl Handler for DogStorePostHandler {
                     )
                 } else {
                     store.add(&dog_name);
+                    let preference = prefer::return_preference(&req.head.headers);
+                    let builder =
+                        ResponseBuilder::from(req).created_at(&format!("/dogs/{dog_name}"));
+                    let builder = match preference {
+                        Some(ReturnPreference::Minimal) => builder
+                            .status(ResponseStatus::NoContent)
+                            .preference_applied(ReturnPreference::Minimal.as_applied()),
+                        Some(ReturnPreference::Representation) => builder
+                            .header("Content-Type", "application/json")
+                            .body(serde_json::json!({ "name": dog_name }).to_string())
+                            .preference_applied(ReturnPreference::Representation.as_applied()),
+                        None => builder.body("Added".to_string()),
+                    };
                     HandlerResult::Done(
-                        ResponseBuilder::from(req)
-                            .status(ResponseStatus::Created)
-                            .body("Added".to_string())
+                        builder
                             .build()
                             .expect("A valid 201 response should be produced"),
                     )
@@ -133,12 +188,135 @@ impl Handler for DogStorePostHandler {
                 log::error!("{e}");
                 HandlerResult::Done(
                     ResponseBuilder::from(req)
-                        .bad_request()
+                        .status(e.as_status_code())
                         .body(e.to_string())
                         .build()
-                        .expect("A valid 400 response should be produced"),
+                        .expect("A valid error response should be produced"),
                 )
             }
         }
     }
 }
+
+pub struct DogStoreDeleteHandler {
+    store: Arc<Mutex<DogStore>>,
+    path: HandlerPath,
+    method: HTTPMethod,
+}
+
+impl DogStoreDeleteHandler {
+    pub fn new(store: Arc<Mutex<DogStore>>) -> Self {
+        Self {
+            store,
+            path: HandlerPath::new("/dogs"),
+            method: HTTPMethod::Delete,
+        }
+    }
+}
+
+impl Handler for DogStoreDeleteHandler {
+    fn get_path(&self) -> &HandlerPath {
+        &self.path
+    }
+
+    fn get_method(&self) -> &HTTPMethod {
+        &self.method
+    }
+
+    fn on_request(&self, mut req: Request) -> HandlerResult {
+        let mut store = self.store.lock().unwrap();
+
+        let dog_name = match req.read_body_json() {
+            Ok(body) => body["name"].as_str().map(str::to_string),
+            Err(e) => {
+                log::error!("{e}");
+                None
+            }
+        };
+
+        let response = match dog_name {
+            Some(name) if store.names.contains(&name) => {
+                store.remove(&name);
+                ResponseBuilder::from(req).status(ResponseStatus::NoContent)
+            }
+            Some(_) => ResponseBuilder::from(req)
+                .not_found()
+                .body("No such dog".to_string()),
+            None => ResponseBuilder::from(req)
+                .bad_request()
+                .body("Invalid field name".to_string()),
+        };
+
+        HandlerResult::Done(
+            response
+                .build()
+                .expect("A valid DELETE response should be produced"),
+        )
+    }
+}
+
+/// Replaces the whole list of names. Clients must send If-Match with the ETag from
+/// `GET /dogs` so that they can't overwrite changes they haven't seen
+pub struct DogStorePutHandler {
+    store: Arc<Mutex<DogStore>>,
+    path: HandlerPath,
+    method: HTTPMethod,
+}
+
+impl DogStorePutHandler {
+    pub fn new(store: Arc<Mutex<DogStore>>) -> Self {
+        Self {
+            store,
+            path: HandlerPath::new("/dogs"),
+            method: HTTPMethod::Put,
+        }
+    }
+}
+
+impl Handler for DogStorePutHandler {
+    fn get_path(&self) -> &HandlerPath {
+        &self.path
+    }
+
+    fn get_method(&self) -> &HTTPMethod {
+        &self.method
+    }
+
+    fn on_request(&self, mut req: Request) -> HandlerResult {
+        let mut store = self.store.lock().unwrap();
+
+        // The store has no modification date, so If-Unmodified-Since is ignored
+        // (see RFC 9110 section 13.1.4) and only If-Match can fail
+        let response = if !conditional::is_conditional(&req.head.headers) {
+            ResponseBuilder::from(req).precondition_required()
+        } else if !conditional::if_match(&req.head.headers, Some(&store.etag())) {
+            ResponseBuilder::from(req).precondition_failed()
+        } else {
+            match req.read_body_json() {
+                Ok(body) => match serde_json::from_value::<DogStore>(body) {
+                    Ok(DogStore { names, .. }) => {
+                        store.replace(names);
+                        ResponseBuilder::from(req)
+                            .status(ResponseStatus::NoContent)
+                            .header("ETag", &store.etag())
+                    }
+                    Err(_) => ResponseBuilder::from(req)
+                        .status(ResponseStatus::UnprocessableContent)
+                        .body("Invalid field names".to_string()),
+                },
+                Err(e) => {
+                    log::error!("{e}");
+                    ResponseBuilder::from(req)
+                        .status(e.as_status_code())
+                        .body(e.to_string())
+                }
+            }
+        };
+
+        HandlerResult::Done(
+            response
+                .build()
+                .expect("A valid PUT response should be produced"),
+        )
+    }
+}
@@ -2,3 +2,5 @@ pub mod _crud_example;
 pub mod mime;
 pub mod request;
 pub mod server;
+#[cfg(test)]
+mod testing;
@@ -1,14 +1,12 @@
+use http_server::_crud_example::{
+    self as dogstore, DogStoreDeleteHandler, DogStoreGetHandler, DogStorePostHandler,
+    DogStorePutHandler,
+};
+use http_server::server::handlers::{HandlerGroup, HandlerRegistry};
+use http_server::server::listener::{self, ListenerConfig};
 use log::info;
-use server::handlers::HandlerRegistry;
-use server::listener::{self, ListenerConfig};
 use std::net::{IpAddr, Ipv4Addr};
-use std::sync::{Arc, Mutex};
-
-mod _crud_example;
-use _crud_example::{self as dogstore, DogStoreGetHandler, DogStorePostHandler};
-mod mime;
-mod request;
-mod server;
+use std::sync::Mutex;
 
 static IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 // TODO: increment if port is unavailable. Will require this to not be static
@@ -18,11 +16,14 @@ fn main() -> std::io::Result<()> {
     env_logger::init();
 
     info!(target: "listener", "Initialising handlers");
-    let dog_store = Arc::new(Mutex::new(dogstore::DogStore::default()));
-    let registry = HandlerRegistry::new(vec![
-        Arc::new(DogStoreGetHandler::new(dog_store.clone())),
-        Arc::new(DogStorePostHandler::new(dog_store.clone())),
-    ]);
+    let registry = HandlerRegistry::new(
+        HandlerGroup::new(Mutex::new(dogstore::DogStore::default()))
+            .handler(DogStoreGetHandler::new)
+            .handler(DogStorePostHandler::new)
+            .handler(DogStoreDeleteHandler::new)
+            .handler(DogStorePutHandler::new)
+            .build(),
+    );
 
     info!(target: "listener", "Starting server on {IP}:{PORT}");
     listener::HTTPListener::new(IP, PORT, registry, ListenerConfig::default()).listen()
@@ -19,6 +19,7 @@ pub enum MainMimeType {
     Multipart,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub enum SubMimeType {
     AAC,
@@ -50,6 +51,7 @@ pub enum SubMimeType {
     JS,
     JSON,
     JSONLD,
+    NDJSON,
     MID,
     MJS,
     MP3,
@@ -95,6 +97,7 @@ pub enum SubMimeType {
     _3G2,
     _7Z,
     FormData,
+    FormUrlEncoded,
 }
 
 impl FromStr for MimeType {
@@ -138,6 +141,9 @@ impl FromStr for MimeType {
             "text/javascript" => (MainMimeType::Text, SubMimeType::JS),
             "application/json" => (MainMimeType::Application, SubMimeType::JSON),
             "application/ld+json" => (MainMimeType::Application, SubMimeType::JSONLD),
+            "application/x-ndjson" | "application/jsonl" => {
+                (MainMimeType::Application, SubMimeType::NDJSON)
+            }
             "audio/midi" | "audio/x-midi" => (MainMimeType::Audio, SubMimeType::MID),
             "audio/mpeg" => (MainMimeType::Audio, SubMimeType::MP3),
             "video/mp4" => (MainMimeType::Video, SubMimeType::MP4),
@@ -195,6 +201,9 @@ impl FromStr for MimeType {
             "video/3gpp2" | "audio/3gpp2" => (MainMimeType::Video, SubMimeType::_3G2),
             "application/x-7z-compressed" => (MainMimeType::Application, SubMimeType::_7Z),
             "multipart/form-data" => (MainMimeType::Multipart, SubMimeType::FormData),
+            "application/x-www-form-urlencoded" => {
+                (MainMimeType::Application, SubMimeType::FormUrlEncoded)
+            }
             _ => return Err("Not a valid MIME type"),
         };
 
@@ -211,3 +220,156 @@ impl Display for MimeType {
         write!(f, "{0}", self.original)
     }
 }
+
+/// The number of leading bytes `MimeType::sniff` needs to recognise every supported type
+pub const SNIFF_LENGTH: usize = 8;
+
+impl MimeType {
+    /// Detect the type of some content from its leading magic bytes.
+    /// Only a few common binary formats are recognised; use this as a fallback when the type
+    /// can't be determined from the file extension
+    pub fn sniff(bytes: &[u8]) -> Option<MimeType> {
+        let mime = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            "image/png"
+        } else if bytes.starts_with(b"\xff\xd8\xff") {
+            "image/jpeg"
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            "image/gif"
+        } else if bytes.starts_with(b"%PDF-") {
+            "application/pdf"
+        } else if bytes.starts_with(b"\x1f\x8b") {
+            "application/gzip"
+        } else {
+            return None;
+        };
+
+        Some(MimeType::from_str(mime).expect("Sniffed MIME types should be valid"))
+    }
+
+    /// The type of a file from its extension, without the leading `.` (E.G `html`).
+    /// Extensions are matched case-insensitively
+    pub fn from_extension(extension: &str) -> Option<MimeType> {
+        let mime = match extension.to_ascii_lowercase().as_str() {
+            "aac" => "audio/aac",
+            "abw" => "application/x-abiword",
+            "apng" => "image/apng",
+            "arc" => "application/x-freearc",
+            "avif" => "image/avif",
+            "avi" => "video/x-msvideo",
+            "azw" => "application/vnd.amazon.ebook",
+            "bin" => "application/octet-stream",
+            "bmp" => "image/bmp",
+            "bz" => "application/x-bzip",
+            "bz2" => "application/x-bzip2",
+            "cda" => "application/x-cdf",
+            "csh" => "application/x-csh",
+            "css" => "text/css",
+            "csv" => "text/csv",
+            "doc" => "application/msword",
+            "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            "eot" => "application/vnd.ms-fontobject",
+            "epub" => "application/epub+zip",
+            "gz" => "application/gzip",
+            "gif" => "image/gif",
+            "htm" | "html" => "text/html",
+            "ico" => "image/vnd.microsoft.icon",
+            "ics" => "text/calendar",
+            "jar" => "application/java-archive",
+            "jpeg" | "jpg" => "image/jpeg",
+            "js" => "text/javascript",
+            "json" => "application/json",
+            "jsonld" => "application/ld+json",
+            "ndjson" | "jsonl" => "application/x-ndjson",
+            "mid" | "midi" => "audio/midi",
+            "mjs" => "text/javascript",
+            "mp3" => "audio/mpeg",
+            "mp4" => "video/mp4",
+            "mpeg" => "video/mpeg",
+            "mpkg" => "application/vnd.apple.installer+xml",
+            "odp" => "application/vnd.oasis.opendocument.presentation",
+            "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+            "odt" => "application/vnd.oasis.opendocument.text",
+            "oga" => "audio/ogg",
+            "ogv" => "video/ogg",
+            "ogx" => "application/ogg",
+            "otf" => "font/otf",
+            "png" => "image/png",
+            "pdf" => "application/pdf",
+            "php" => "application/x-httpd-php",
+            "ppt" => "application/vnd.ms-powerpoint",
+            "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+            "rar" => "application/vnd.rar",
+            "rtf" => "application/rtf",
+            "sh" => "application/x-sh",
+            "svg" => "image/svg+xml",
+            "tar" => "application/x-tar",
+            "tif" | "tiff" => "image/tiff",
+            "ts" => "video/mp2t",
+            "ttf" => "font/ttf",
+            "txt" => "text/plain",
+            "vsd" => "application/vnd.visio",
+            "wav" => "audio/wav",
+            "weba" => "audio/webm",
+            "webm" => "video/webm",
+            "webp" => "image/webp",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            "xhtml" => "application/xhtml+xml",
+            "xls" => "application/vnd.ms-excel",
+            "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "xml" => "application/xml",
+            "xul" => "application/vnd.mozilla.xul+xml",
+            "zip" => "application/zip",
+            "3gp" => "video/3gpp",
+            "3g2" => "video/3gpp2",
+            "7z" => "application/x-7z-compressed",
+            _ => return None,
+        };
+
+        Some(MimeType::from_str(mime).expect("Extensions should map to valid MIME types"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_png() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let mime = MimeType::sniff(png).expect("A PNG should be recognised");
+        assert_eq!(mime.sub_type, SubMimeType::PNG);
+        assert_eq!(mime.to_string(), "image/png");
+    }
+
+    #[test]
+    fn sniff_pdf() {
+        let pdf = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3";
+        let mime = MimeType::sniff(pdf).expect("A PDF should be recognised");
+        assert_eq!(mime.sub_type, SubMimeType::PDF);
+        assert_eq!(mime.to_string(), "application/pdf");
+    }
+
+    #[test]
+    fn from_extension() {
+        let mime = MimeType::from_extension("HTML").expect("HTML should be recognised");
+        assert_eq!(mime.sub_type, SubMimeType::HTM);
+        assert_eq!(mime.to_string(), "text/html");
+        assert_eq!(
+            MimeType::from_extension("jpg").map(|mime| mime.sub_type),
+            Some(SubMimeType::JPEG)
+        );
+        assert_eq!(MimeType::from_extension("unknown"), None);
+    }
+
+    #[test]
+    fn sniff_unknown() {
+        assert_eq!(MimeType::sniff(b"plain old text"), None);
+        assert_eq!(
+            MimeType::sniff(b"\x89PN"),
+            None,
+            "Truncated magic bytes shouldn't match"
+        );
+        assert_eq!(MimeType::sniff(b""), None);
+    }
+}
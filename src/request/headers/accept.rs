@@ -0,0 +1,206 @@
+use crate::mime::MimeType;
+
+use super::parse_weighted_list;
+
+/// A media range from an Accept header, E.G `text/*`. Either part can be the `*` wildcard
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaRange {
+    pub main_type: String,
+    pub sub_type: String,
+}
+
+impl MediaRange {
+    /// How closely this range matches a media type.
+    /// More specific ranges take precedence, so `text/html;q=0` overrides `text/*`
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let (main, sub) = media_type.split_once('/')?;
+        match (self.main_type.as_str(), self.sub_type.as_str()) {
+            ("*", "*") => Some(0),
+            (range_main, "*") if range_main.eq_ignore_ascii_case(main) => Some(1),
+            (range_main, range_sub)
+                if range_main.eq_ignore_ascii_case(main) && range_sub.eq_ignore_ascii_case(sub) =>
+            {
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The media ranges of an Accept header and their weights, from most to least preferred.
+/// Ranges with the same weight keep the order that the client sent them in
+#[derive(Debug, PartialEq, Clone)]
+pub struct AcceptList(Vec<(MediaRange, f32)>);
+
+impl AcceptList {
+    /// Parse an Accept header value, E.G `text/html, application/json;q=0.9, */*;q=0.1`.
+    /// Entries that aren't media ranges are skipped rather than rejecting the request
+    pub fn parse(accept: &str) -> Self {
+        let ranges = parse_weighted_list(accept)
+            .into_iter()
+            .filter_map(|(range, weight)| {
+                let (main_type, sub_type) = range.split_once('/')?;
+                let range = MediaRange {
+                    main_type: main_type.to_string(),
+                    sub_type: sub_type.to_string(),
+                };
+                Some((range, weight))
+            })
+            .collect();
+        Self(ranges)
+    }
+
+    pub fn ranges(&self) -> &[(MediaRange, f32)] {
+        &self.0
+    }
+
+    /// The weight of `media_type` according to the most specific matching range.
+    /// `None` means that no range matches
+    pub fn weight(&self, media_type: &str) -> Option<f32> {
+        self.0
+            .iter()
+            .filter_map(|(range, weight)| Some((range.specificity(media_type)?, *weight)))
+            // The first of the most specific ranges wins, which is the one with the highest weight
+            .rev()
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, weight)| weight)
+    }
+
+    /// Pick the media type from `available` (in order of the server's preference) that the
+    /// client prefers. Returns `None` if none of them are acceptable, which should result in a
+    /// 406 Not Acceptable
+    pub fn best_match<'a>(&self, available: &'a [MimeType]) -> Option<&'a MimeType> {
+        self.negotiate(available, |mime| &mime.original)
+    }
+
+    fn negotiate<'a, T>(
+        &self,
+        available: &'a [T],
+        media_type: impl Fn(&T) -> &str,
+    ) -> Option<&'a T> {
+        available
+            .iter()
+            .filter_map(|candidate| {
+                let media_type = media_type(candidate);
+                let essence = media_type.split(';').next().unwrap_or(media_type).trim();
+                Some((candidate, self.weight(essence)?))
+            })
+            .filter(|(_, weight)| *weight > 0.0)
+            // Ties are broken by the server's preference, so only replace strictly greater weights
+            .fold(None, |best: Option<(&T, f32)>, candidate| match best {
+                Some((_, best_weight)) if best_weight >= candidate.1 => best,
+                _ => Some(candidate),
+            })
+            .map(|(candidate, _)| candidate)
+    }
+}
+
+/// Pick the media type from `available` (in order of the server's preference) that the client
+/// prefers according to its Accept header. Parameters of the available types are ignored.
+/// Returns `None` if none of them are acceptable, which should result in a 406 Not Acceptable.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Accept
+pub fn negotiate_media_type<'a>(accept: &str, available: &[&'a str]) -> Option<&'a str> {
+    AcceptList::parse(accept)
+        .negotiate(available, |media_type| media_type)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const AVAILABLE: [&str; 2] = ["text/html", "application/json"];
+
+    #[test]
+    fn negotiate_exact() {
+        assert_eq!(
+            negotiate_media_type("application/json", &AVAILABLE),
+            Some("application/json")
+        );
+        assert_eq!(
+            negotiate_media_type("text/html;q=0.5, application/json", &AVAILABLE),
+            Some("application/json")
+        );
+    }
+
+    #[test]
+    fn negotiate_wildcards() {
+        assert_eq!(
+            negotiate_media_type("*/*", &AVAILABLE),
+            Some("text/html"),
+            "Ties should be broken by the server's preference"
+        );
+        assert_eq!(
+            negotiate_media_type("text/*;q=0.5, */*;q=0.1", &AVAILABLE),
+            Some("text/html")
+        );
+        assert_eq!(
+            negotiate_media_type("text/html;q=0, */*", &AVAILABLE),
+            Some("application/json"),
+            "More specific ranges should take precedence"
+        );
+    }
+
+    fn mime(media_type: &str) -> MimeType {
+        MimeType::from_str(media_type).expect("The media type should be supported")
+    }
+
+    #[test]
+    fn parse_ordered_by_weight() {
+        let accept =
+            AcceptList::parse("text/plain;q=0.5, text/html, application/json;q=0.9, */*;q=0.1");
+        let ranges: Vec<(String, f32)> = accept
+            .ranges()
+            .iter()
+            .map(|(range, weight)| (format!("{0}/{1}", range.main_type, range.sub_type), *weight))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                ("text/html".to_string(), 1.0),
+                ("application/json".to_string(), 0.9),
+                ("text/plain".to_string(), 0.5),
+                ("*/*".to_string(), 0.1),
+            ]
+        );
+
+        let available = [mime("text/plain"), mime("application/json")];
+        assert_eq!(accept.best_match(&available), Some(&available[1]));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let accept = AcceptList::parse("text/html;q=high, application/json;q=2, nonsense, ,");
+        assert_eq!(
+            accept
+                .ranges()
+                .iter()
+                .map(|(_, weight)| *weight)
+                .collect::<Vec<f32>>(),
+            [1.0, 1.0],
+            "Malformed weights should default to 1.0 and entries without a / should be skipped"
+        );
+    }
+
+    #[test]
+    fn best_match_wildcards() {
+        let available = [mime("application/json"), mime("text/plain")];
+        assert_eq!(
+            AcceptList::parse("*/*").best_match(&available),
+            Some(&available[0])
+        );
+        assert_eq!(
+            AcceptList::parse("text/*, */*;q=0.1").best_match(&available),
+            Some(&available[1])
+        );
+        assert_eq!(AcceptList::parse("image/*").best_match(&available), None);
+    }
+
+    #[test]
+    fn negotiate_not_acceptable() {
+        assert_eq!(negotiate_media_type("image/png", &AVAILABLE), None);
+        assert_eq!(negotiate_media_type("*/*;q=0", &AVAILABLE), None);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::request::content_type::ContentEncoding;
+
+use super::parse_weighted_list;
+
+/// The weight of an encoding in a parsed Accept-Encoding header.
+/// `None` means that the header doesn't mention the encoding at all
+fn explicit_weight(codings: &[(String, f32)], coding: &str) -> Option<f32> {
+    codings
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(coding))
+        .map(|(_, weight)| *weight)
+}
+
+/// Pick the encoding from `supported` (in order of the server's preference) that the client
+/// prefers according to its Accept-Encoding header.
+/// `identity` is acceptable unless it is explicitly refused (E.G `identity;q=0` or `*;q=0`).
+/// Returns `None` if none of the supported encodings are acceptable, which should result in a
+/// 406 Not Acceptable.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Accept-Encoding
+pub fn negotiate_encoding(
+    accept_encoding: &str,
+    supported: &[ContentEncoding],
+) -> Option<ContentEncoding> {
+    let codings = parse_weighted_list(accept_encoding);
+    let wildcard = explicit_weight(&codings, "*");
+
+    supported
+        .iter()
+        .map(|encoding| {
+            let weight = explicit_weight(&codings, &encoding.to_string())
+                .or(wildcard)
+                .unwrap_or(match encoding {
+                    ContentEncoding::Identity => 1.0,
+                    _ => 0.0,
+                });
+            (*encoding, weight)
+        })
+        .filter(|(_, weight)| *weight > 0.0)
+        // Ties are broken by the server's preference, so only replace strictly greater weights
+        .fold(
+            None,
+            |best: Option<(ContentEncoding, f32)>, candidate| match best {
+                Some((_, best_weight)) if best_weight >= candidate.1 => best,
+                _ => Some(candidate),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_prefers_weight() {
+        assert_eq!(
+            negotiate_encoding(
+                "deflate;q=0.5, gzip",
+                &[ContentEncoding::Deflate, ContentEncoding::Gzip]
+            ),
+            Some(ContentEncoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn negotiate_identity() {
+        assert_eq!(
+            negotiate_encoding(
+                "identity",
+                &[ContentEncoding::Gzip, ContentEncoding::Identity]
+            ),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            negotiate_encoding("br", &[ContentEncoding::Gzip, ContentEncoding::Identity]),
+            Some(ContentEncoding::Identity),
+            "identity should be acceptable when it isn't mentioned"
+        );
+        assert_eq!(
+            negotiate_encoding(
+                "gzip;q=0.5, identity;q=0",
+                &[ContentEncoding::Identity, ContentEncoding::Gzip]
+            ),
+            Some(ContentEncoding::Gzip),
+            "identity should not be selected when it is refused"
+        );
+    }
+
+    #[test]
+    fn negotiate_not_acceptable() {
+        assert_eq!(
+            negotiate_encoding("*;q=0", &[ContentEncoding::Gzip, ContentEncoding::Identity]),
+            None
+        );
+    }
+}
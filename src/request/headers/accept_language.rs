@@ -0,0 +1,88 @@
+use super::parse_weighted_list;
+
+/// How closely a language range from an Accept-Language header matches a language tag, using
+/// basic filtering: `fr` matches `fr` and `fr-CA` but `fr-CA` doesn't match `fr`.
+/// Longer ranges are more specific, so `en-GB;q=0` overrides `en`.
+/// See https://www.rfc-editor.org/rfc/rfc4647#section-3.3.1
+fn specificity(range: &str, tag: &str) -> Option<usize> {
+    if range == "*" {
+        return Some(0);
+    }
+    let matches = tag.len() >= range.len()
+        && tag.is_char_boundary(range.len())
+        && tag[..range.len()].eq_ignore_ascii_case(range)
+        && matches!(tag.as_bytes().get(range.len()), None | Some(b'-'));
+    matches.then_some(range.len())
+}
+
+/// The weight of `tag` according to the most specific matching range of a parsed
+/// Accept-Language header. `None` means that no range matches
+fn weight(ranges: &[(String, f32)], tag: &str) -> Option<f32> {
+    ranges
+        .iter()
+        .filter_map(|(range, weight)| Some((specificity(range, tag)?, *weight)))
+        // The first of the most specific ranges wins, which is the one with the highest weight
+        .rev()
+        .max_by_key(|(specificity, _)| *specificity)
+        .map(|(_, weight)| weight)
+}
+
+/// Pick the language tag from `available` (in order of the server's preference) that the
+/// client prefers according to its Accept-Language header.
+/// Returns `None` if none of them are acceptable. Unlike other negotiation, this is usually
+/// handled by falling back to a default language rather than with 406 Not Acceptable.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Accept-Language
+pub fn negotiate_language<'a>(accept_language: &str, available: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_weighted_list(accept_language);
+    available
+        .iter()
+        .filter_map(|tag| Some((*tag, weight(&ranges, tag)?)))
+        .filter(|(_, weight)| *weight > 0.0)
+        // Ties are broken by the server's preference, so only replace strictly greater weights
+        .fold(None, |best: Option<(&str, f32)>, candidate| match best {
+            Some((_, best_weight)) if best_weight >= candidate.1 => best,
+            _ => Some(candidate),
+        })
+        .map(|(tag, _)| tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AVAILABLE: [&str; 3] = ["en", "fr", "de-CH"];
+
+    #[test]
+    fn negotiate_prefixes() {
+        assert_eq!(
+            negotiate_language("fr-CA, fr;q=0.9, en;q=0.5", &AVAILABLE),
+            Some("fr")
+        );
+        assert_eq!(
+            negotiate_language("DE", &AVAILABLE),
+            Some("de-CH"),
+            "A range should match tags that extend it, ignoring case"
+        );
+        assert_eq!(
+            negotiate_language("*", &AVAILABLE),
+            Some("en"),
+            "Ties should be broken by the server's preference"
+        );
+        assert_eq!(
+            negotiate_language("en;q=0, *", &AVAILABLE),
+            Some("fr"),
+            "More specific ranges should take precedence"
+        );
+    }
+
+    #[test]
+    fn negotiate_not_acceptable() {
+        assert_eq!(negotiate_language("ja", &AVAILABLE), None);
+        assert_eq!(
+            negotiate_language("en-GB", &AVAILABLE),
+            None,
+            "A range shouldn't match tags that are shorter than it"
+        );
+        assert_eq!(negotiate_language("*;q=0", &AVAILABLE), None);
+    }
+}
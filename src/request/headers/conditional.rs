@@ -0,0 +1,75 @@
+use crate::request::types::HTTPHeaders;
+
+/// Whether a request makes its change conditional on the state of the resource, so that it
+/// can't silently overwrite someone else's update (the "lost update" problem).
+///
+/// Resources that require this should check it before modifying anything:
+/// 1. Respond to unconditional writes with `ResponseBuilder::precondition_required`
+///    (428 Precondition Required)
+/// 2. Respond to writes whose precondition doesn't hold, E.G `if_match` is false, with
+///    `ResponseBuilder::precondition_failed` (412 Precondition Failed)
+/// 3. Otherwise apply the change and send the new ETag so that the client can update again
+///
+/// See https://www.rfc-editor.org/rfc/rfc6585#section-3
+pub fn is_conditional(headers: &HTTPHeaders) -> bool {
+    headers.contains_key("if-match") || headers.contains_key("if-unmodified-since")
+}
+
+/// Whether the If-Match header allows the request given the resource's current `etag`.
+/// `None` means that the resource doesn't exist. Requests without If-Match are allowed.
+/// Entity tags use the strong comparison, so weak tags never match.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/If-Match
+pub fn if_match(headers: &HTTPHeaders, etag: Option<&str>) -> bool {
+    let Some(if_match) = headers.get("if-match") else {
+        return true;
+    };
+    let Some(etag) = etag.filter(|etag| !etag.starts_with("W/")) else {
+        return false;
+    };
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HTTPHeaders {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn conditional_requests() {
+        assert!(is_conditional(&headers(&[("if-match", "\"1\"")])));
+        assert!(is_conditional(&headers(&[(
+            "if-unmodified-since",
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        )])));
+        assert!(
+            !is_conditional(&headers(&[("if-none-match", "*")])),
+            "If-None-Match only protects against creating a resource twice"
+        );
+    }
+
+    #[test]
+    fn evaluate_if_match() {
+        let current = Some("\"2\"");
+        assert!(if_match(&headers(&[]), current));
+        assert!(if_match(&headers(&[("if-match", "\"1\", \"2\"")]), current));
+        assert!(if_match(&headers(&[("if-match", "*")]), current));
+        assert!(!if_match(&headers(&[("if-match", "\"1\"")]), current));
+        assert!(
+            !if_match(&headers(&[("if-match", "W/\"2\"")]), current),
+            "Weak tags shouldn't match"
+        );
+        assert!(
+            !if_match(&headers(&[("if-match", "*")]), None),
+            "* shouldn't match a resource that doesn't exist"
+        );
+    }
+}
@@ -2,8 +2,11 @@ use crate::mime::{MainMimeType, MimeType};
 use crate::request::types::{HTTPHeaders, RequestParseError};
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ContentEncoding {
+    /// No transformation. Only valid in Accept-Encoding, but some clients send it in
+    /// Content-Encoding too
+    Identity,
     Gzip,
     Compress,
     Deflate,
@@ -16,8 +19,10 @@ pub struct MimeParseInfo {
     pub length: u64,
     pub boundary: Option<String>,
     pub content_type: MimeType,
-    pub charset: Option<String>, // TODO: Handle decoding downstream with encoding_rs
+    pub charset: Option<String>,
     pub encoding: Vec<ContentEncoding>,
+    /// The body uses chunked transfer-encoding, so `length` is unknown and set to 0
+    pub chunked: bool,
 }
 
 struct ContentTypeInfo {
@@ -26,6 +31,20 @@ struct ContentTypeInfo {
     boundary: Option<String>,
 }
 
+impl std::fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Compress => "compress",
+            Self::Deflate => "deflate",
+            Self::Br => "br",
+            Self::Zstd => "zstd",
+        };
+        write!(f, "{s}")
+    }
+}
+
 /// Use `parse_content_encoding` instead of calling this directly
 /// because Content-Encoding headers can have multiple encodings
 impl FromStr for ContentEncoding {
@@ -38,6 +57,7 @@ impl FromStr for ContentEncoding {
             "deflate" => Ok(Self::Deflate),
             "br" => Ok(Self::Br),
             "zstd" => Ok(Self::Zstd),
+            "identity" => Ok(Self::Identity),
             other => Err(Self::Err::BodyParseError(format!(
                 "Invalid content encoding '{other}'"
             ))),
@@ -47,10 +67,13 @@ impl FromStr for ContentEncoding {
 
 /// The Content-Encoding header may have a series of encodings,
 /// representing a the order that encodings were applied.
+/// Empty elements (E.G from a trailing comma) are skipped and `identity` is dropped
+/// because it means that no encoding was applied.
 /// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Content-Encoding
 pub fn parse_content_encoding(s: &str) -> Result<Vec<ContentEncoding>, RequestParseError> {
     s.split(",")
         .map(str::trim)
+        .filter(|enc| !enc.is_empty() && !enc.eq_ignore_ascii_case("identity"))
         .map(ContentEncoding::from_str)
         .collect()
 }
@@ -99,17 +122,35 @@ fn parse_content_type(content_type: &str) -> Result<ContentTypeInfo, RequestPars
     })
 }
 
+/// Chunked must be the final transfer coding if it is present.
+/// See https://www.rfc-editor.org/rfc/rfc9112#section-6.1
+pub(crate) fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .map(str::trim)
+        .find(|coding| !coding.is_empty())
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
 pub fn parse_mime_info(headers: &HTTPHeaders) -> Result<MimeParseInfo, RequestParseError> {
-    let content_length = headers
-        .get("content-length")
-        .ok_or(RequestParseError::BodyParseError(
-            "Missing content-length".to_string(),
-        ))
-        .map(|len| {
-            u64::from_str(len).or(Err(RequestParseError::InvalidHeader(format!(
-                "{len} is not a valid integer"
-            ))))
-        })??;
+    // Transfer-Encoding overrides Content-Length
+    let chunked = headers
+        .get("transfer-encoding")
+        .is_some_and(|te| is_chunked(te));
+    let content_length = if chunked {
+        0
+    } else {
+        headers
+            .get("content-length")
+            .ok_or(RequestParseError::BodyParseError(
+                "Missing content-length".to_string(),
+            ))
+            .map(|len| {
+                u64::from_str(len).or(Err(RequestParseError::InvalidHeader(format!(
+                    "{len} is not a valid integer"
+                ))))
+            })??
+    };
 
     let encoding = headers
         .get("content-encoding")
@@ -132,6 +173,7 @@ pub fn parse_mime_info(headers: &HTTPHeaders) -> Result<MimeParseInfo, RequestPa
         boundary,
         charset,
         encoding,
+        chunked,
     })
 }
 
@@ -464,4 +506,28 @@ mod tests {
         ]))
         .expect_err("Parsing a Content-Type with an empty parameter should fail");
     }
+
+    #[test]
+    fn content_encoding_empty_elements() {
+        assert_eq!(
+            parse_content_encoding("gzip,")
+                .expect("Parsing a Content-Encoding with a trailing comma should succeed"),
+            vec![ContentEncoding::Gzip]
+        );
+        assert_eq!(
+            parse_content_encoding("gzip,,deflate")
+                .expect("Parsing a Content-Encoding with a double comma should succeed"),
+            vec![ContentEncoding::Gzip, ContentEncoding::Deflate]
+        );
+    }
+
+    #[test]
+    fn content_encoding_identity() {
+        assert_eq!(
+            parse_content_encoding("identity")
+                .expect("Parsing Content-Encoding = identity should succeed"),
+            vec![],
+            "identity means no encoding was applied"
+        );
+    }
 }
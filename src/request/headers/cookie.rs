@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+/// Parse the name-value pairs of a Cookie header, E.G `session=abc; theme=dark`.
+/// Names are case-sensitive. Cookies without a `=` have an empty value. When a name is repeated,
+/// the first value is kept because clients send the cookie with the most specific path first.
+/// See https://www.rfc-editor.org/rfc/rfc6265#section-5.4
+pub fn parse_cookies(cookie: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in cookie.split(';').map(str::trim) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        cookies
+            .entry(name.to_string())
+            .or_insert_with(|| value.trim().to_string());
+    }
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_cookies() {
+        let cookies = parse_cookies("session=abc123; Theme=dark; flag; token=a=b");
+        assert_eq!(cookies.len(), 4);
+        assert_eq!(cookies.get("session"), Some(&"abc123".to_string()));
+        assert_eq!(cookies.get("Theme"), Some(&"dark".to_string()));
+        assert_eq!(cookies.get("theme"), None, "Names should be case-sensitive");
+        assert_eq!(cookies.get("flag"), Some(&String::new()));
+        assert_eq!(
+            cookies.get("token"),
+            Some(&"a=b".to_string()),
+            "Pairs should be split on the first ="
+        );
+    }
+
+    #[test]
+    fn whitespace_tolerated() {
+        let cookies = parse_cookies("  a = 1 ;b=2;   ; c=3  ");
+        assert_eq!(
+            cookies,
+            HashMap::from([
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), "3".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_header() {
+        assert!(parse_cookies("").is_empty());
+        assert!(parse_cookies(" ; ;").is_empty());
+    }
+}
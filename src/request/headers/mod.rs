@@ -1 +1,141 @@
+pub mod accept;
+pub mod accept_encoding;
+pub mod accept_language;
+pub mod conditional;
 pub mod content_type;
+pub mod cookie;
+pub mod origin;
+pub mod prefer;
+pub mod range;
+pub mod via;
+
+use std::str::FromStr;
+
+/// Whether a comma-separated header value (E.G Connection) contains `token`, ignoring case
+pub fn contains_token(value: &str, token: &str) -> bool {
+    split_list(value).any(|element| element.eq_ignore_ascii_case(token))
+}
+
+/// The trimmed elements of a comma-separated header value, E.G `gzip, br;q=0.5`.
+/// Commas inside quoted strings don't separate elements and empty elements are skipped.
+/// See https://www.rfc-editor.org/rfc/rfc9110#section-5.6.1
+pub fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(value);
+    std::iter::from_fn(move || loop {
+        let remaining = rest?;
+        let element = match next_separator(remaining) {
+            Some(i) => {
+                rest = Some(&remaining[i + 1..]);
+                &remaining[..i]
+            }
+            None => {
+                rest = None;
+                remaining
+            }
+        };
+        let element = element.trim();
+        if !element.is_empty() {
+            return Some(element);
+        }
+    })
+}
+
+/// The items of a header that uses q-values, E.G Accept, Accept-Encoding, Accept-Language or
+/// TE, with their weights from most to least preferred. Items with the same weight keep the
+/// order they were sent in. Parameters other than `q` are dropped from the items.
+/// Malformed weights are treated as the default of 1.0 and weights outside 0.0-1.0 are clamped
+/// rather than rejecting the request.
+/// See https://www.rfc-editor.org/rfc/rfc9110#section-12.4.2
+pub fn parse_weighted_list(header: &str) -> Vec<(String, f32)> {
+    let mut items: Vec<(String, f32)> = split_list(header)
+        .filter_map(|element| {
+            let mut parts = element.split(';').map(str::trim);
+            let item = parts.next().filter(|item| !item.is_empty())?;
+            let weight = parts
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, q)| f32::from_str(q.trim()).ok())
+                .filter(|q| !q.is_nan())
+                .map_or(1.0, |q| q.clamp(0.0, 1.0));
+            Some((item.to_string(), weight))
+        })
+        .collect();
+    // Stable, so ties stay in the client's order
+    items.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    items
+}
+
+/// The index of the first comma that isn't inside a quoted string
+fn next_separator(value: &str) -> Option<usize> {
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, byte) in value.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            b',' if !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_simple_list() {
+        assert_eq!(
+            split_list(" gzip,br;q=0.5 , ,deflate,").collect::<Vec<&str>>(),
+            ["gzip", "br;q=0.5", "deflate"]
+        );
+        assert_eq!(split_list("").count(), 0);
+    }
+
+    #[test]
+    fn weighted_list_order() {
+        assert_eq!(
+            parse_weighted_list("deflate;q=0.5, gzip, br;q=0.8, identity;q=0"),
+            [
+                ("gzip".to_string(), 1.0),
+                ("br".to_string(), 0.8),
+                ("deflate".to_string(), 0.5),
+                ("identity".to_string(), 0.0),
+            ]
+        );
+        assert_eq!(
+            parse_weighted_list("text/html;level=1, fr;Q=0.3"),
+            [("text/html".to_string(), 1.0), ("fr".to_string(), 0.3)],
+            "Other parameters should be dropped and q should be case-insensitive"
+        );
+    }
+
+    #[test]
+    fn weighted_list_defaults() {
+        assert_eq!(
+            parse_weighted_list("a, b;q=high, c;q=7, d;q=-1, e;q=NaN"),
+            [
+                ("a".to_string(), 1.0),
+                ("b".to_string(), 1.0),
+                ("c".to_string(), 1.0),
+                ("e".to_string(), 1.0),
+                ("d".to_string(), 0.0),
+            ]
+        );
+        assert!(parse_weighted_list("").is_empty());
+    }
+
+    #[test]
+    fn split_quoted_commas() {
+        assert_eq!(
+            split_list(r#"private="set-cookie, authorization", max-age=60"#).collect::<Vec<&str>>(),
+            [r#"private="set-cookie, authorization""#, "max-age=60"]
+        );
+        assert_eq!(
+            split_list(r#"W/"a\",b", "c""#).collect::<Vec<&str>>(),
+            [r#"W/"a\",b""#, r#""c""#],
+            "Escaped quotes shouldn't end a quoted string"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::str::FromStr;
+
+use crate::request::types::{HTTPHeaders, Request, RequestParseError};
+
+/// The origin (scheme, host and port) that a request was sent from.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Origin
+#[derive(Debug, PartialEq, Clone)]
+pub struct Origin {
+    /// Lowercase, E.G "https"
+    pub scheme: String,
+    /// Lowercase. IPv6 literals keep their brackets
+    pub host: String,
+    /// `None` if the port wasn't specified
+    pub port: Option<u16>,
+}
+
+impl Origin {
+    /// The explicit port, or the scheme's default port
+    pub fn effective_port(&self) -> Option<u16> {
+        self.port.or(match self.scheme.as_str() {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        })
+    }
+
+    /// Whether both origins refer to the same scheme, host and port
+    pub fn same_as(&self, other: &Origin) -> bool {
+        self.scheme == other.scheme
+            && self.host == other.host
+            && self.effective_port() == other.effective_port()
+    }
+
+    /// The origin of a URL, E.G from the Referer header. The path, query and fragment are ignored
+    pub fn from_url(url: &str) -> Result<Self, RequestParseError> {
+        let end = url
+            .find("://")
+            .map(|scheme_end| scheme_end + 3)
+            .and_then(|authority_start| {
+                url[authority_start..]
+                    .find(['/', '?', '#'])
+                    .map(|offset| authority_start + offset)
+            })
+            .unwrap_or(url.len());
+        Origin::from_str(&url[..end])
+    }
+}
+
+impl FromStr for Origin {
+    type Err = RequestParseError;
+
+    /// Parse a serialised origin. The opaque `null` origin is rejected because it can't be
+    /// compared with anything
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RequestParseError::InvalidHeader(format!("Malformed origin '{s}'"));
+
+        let (scheme, authority) = s.trim().split_once("://").ok_or_else(invalid)?;
+        if scheme.is_empty()
+            || authority.is_empty()
+            || authority.contains(['/', '?', '#', '@'])
+            || !scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        {
+            return Err(invalid());
+        }
+
+        let (host, port) = match authority.rsplit_once(':') {
+            // The colons in an IPv6 literal aren't port separators
+            Some((host, port)) if !port.ends_with(']') => {
+                (host, Some(u16::from_str(port).map_err(|_| invalid())?))
+            }
+            _ => (authority, None),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Origin {
+            scheme: scheme.to_lowercase(),
+            host: host.to_lowercase(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.host)?;
+        match self.port {
+            Some(port) => write!(f, ":{port}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The origin the request was sent from, taken from the Origin header or, failing that,
+/// the Referer header. Returns `None` if neither is present and valid
+pub fn request_origin(headers: &HTTPHeaders) -> Option<Origin> {
+    match headers.get("origin") {
+        Some(origin) => Origin::from_str(origin)
+            .inspect_err(|err| log::debug!("Ignoring Origin header: {err}"))
+            .ok(),
+        None => Origin::from_url(headers.get("referer")?)
+            .inspect_err(|err| log::debug!("Ignoring Referer header: {err}"))
+            .ok(),
+    }
+}
+
+/// Whether the request was sent from one of the `allowed` origins (E.G "https://example.com").
+/// Requests that don't say where they came from are not same-origin
+pub fn is_same_origin(req: &Request, allowed: &[&str]) -> bool {
+    let Some(origin) = request_origin(&req.head.headers) else {
+        return false;
+    };
+    allowed
+        .iter()
+        .filter_map(|allowed| {
+            Origin::from_str(allowed)
+                .inspect_err(|err| log::warn!("Invalid allowed origin: {err}"))
+                .ok()
+        })
+        .any(|allowed| allowed.same_as(&origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    #[test]
+    fn parse_origin() {
+        assert_eq!(
+            Origin::from_str("HTTPS://Example.com:8443").expect("The origin should be valid"),
+            Origin {
+                scheme: "https".to_string(),
+                host: "example.com".to_string(),
+                port: Some(8443)
+            }
+        );
+        assert_eq!(
+            Origin::from_str("http://[::1]").expect("IPv6 origins should be valid"),
+            Origin {
+                scheme: "http".to_string(),
+                host: "[::1]".to_string(),
+                port: None
+            }
+        );
+        for origin in [
+            "null",
+            "example.com",
+            "https://",
+            "https://a.com/path",
+            "https://a.com:x",
+        ] {
+            assert!(
+                Origin::from_str(origin).is_err(),
+                "{origin} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn origin_from_referer() {
+        assert_eq!(
+            Origin::from_url("https://example.com/form?next=/home")
+                .expect("The referer should be valid")
+                .to_string(),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn same_origin() {
+        let allowed = ["https://example.com"];
+        for origin in [
+            "Origin: https://example.com",
+            "Origin: https://example.com:443",
+            "Referer: https://example.com/form",
+        ] {
+            let (req, _) = mock_request(
+                &format!("POST / HTTP/1.1\r\nHost: a.com\r\n{origin}\r\n"),
+                b"",
+            );
+            assert!(is_same_origin(&req, &allowed), "{origin} should be allowed");
+        }
+
+        for origin in [
+            "Origin: http://example.com",
+            "Origin: https://evil.com",
+            "Origin: null",
+            "Referer: https://example.com.evil.com/",
+        ] {
+            let (req, _) = mock_request(
+                &format!("POST / HTTP/1.1\r\nHost: a.com\r\n{origin}\r\n"),
+                b"",
+            );
+            assert!(
+                !is_same_origin(&req, &allowed),
+                "{origin} should be rejected"
+            );
+        }
+
+        let (req, _) = mock_request("POST / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        assert!(!is_same_origin(&req, &allowed));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::request::types::HTTPHeaders;
+
+/// A single preference from the Prefer header, E.G `return=minimal` or `wait=10`.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Prefer
+#[derive(Debug, PartialEq, Clone)]
+pub struct Preference {
+    pub name: String,
+    pub value: Option<String>,
+    pub params: Vec<(String, Option<String>)>,
+}
+
+/// How much of the resource the client wants returned after it has been modified
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReturnPreference {
+    /// Only the status and headers
+    Minimal,
+    /// The full representation of the resource
+    Representation,
+}
+
+impl ReturnPreference {
+    /// The value to echo in the Preference-Applied header when this preference is honoured
+    pub fn as_applied(&self) -> &'static str {
+        match self {
+            Self::Minimal => "return=minimal",
+            Self::Representation => "return=representation",
+        }
+    }
+}
+
+fn parse_name_value(s: &str) -> (String, Option<String>) {
+    match s.split_once('=') {
+        Some((name, value)) => (
+            name.trim().to_lowercase(),
+            Some(value.trim().trim_matches('"').to_string()),
+        ),
+        None => (s.trim().to_lowercase(), None),
+    }
+}
+
+/// The Prefer header is a comma-separated list of preferences, each with optional
+/// semicolon-separated parameters. Empty elements are ignored
+pub fn parse_prefer(s: &str) -> Vec<Preference> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|preference| !preference.is_empty())
+        .map(|preference| {
+            let mut parts = preference.split(';');
+            let (name, value) = parse_name_value(parts.next().unwrap_or_default());
+            let params = parts
+                .map(str::trim)
+                .filter(|param| !param.is_empty())
+                .map(parse_name_value)
+                .collect();
+            Preference {
+                name,
+                value,
+                params,
+            }
+        })
+        .collect()
+}
+
+/// The client's `return` preference, if it stated a recognised one.
+/// The first `return` preference wins if there are several
+pub fn return_preference(headers: &HTTPHeaders) -> Option<ReturnPreference> {
+    let preferences = parse_prefer(headers.get("prefer")?);
+    let preference = preferences
+        .iter()
+        .find(|preference| preference.name == "return")?;
+
+    match preference.value.as_deref() {
+        Some(value) if value.eq_ignore_ascii_case("minimal") => Some(ReturnPreference::Minimal),
+        Some(value) if value.eq_ignore_ascii_case("representation") => {
+            Some(ReturnPreference::Representation)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefer_headers(prefer: &str) -> HTTPHeaders {
+        HTTPHeaders::from([("prefer".to_string(), prefer.to_string())])
+    }
+
+    #[test]
+    fn parse_preferences() {
+        assert_eq!(
+            parse_prefer("respond-async, wait=10, handling=lenient; foo=\"bar\", , "),
+            vec![
+                Preference {
+                    name: "respond-async".to_string(),
+                    value: None,
+                    params: vec![],
+                },
+                Preference {
+                    name: "wait".to_string(),
+                    value: Some("10".to_string()),
+                    params: vec![],
+                },
+                Preference {
+                    name: "handling".to_string(),
+                    value: Some("lenient".to_string()),
+                    params: vec![("foo".to_string(), Some("bar".to_string()))],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn return_minimal() {
+        assert_eq!(
+            return_preference(&prefer_headers("return=minimal")),
+            Some(ReturnPreference::Minimal)
+        );
+        assert_eq!(ReturnPreference::Minimal.as_applied(), "return=minimal");
+    }
+
+    #[test]
+    fn return_representation() {
+        assert_eq!(
+            return_preference(&prefer_headers("wait=5, return=representation")),
+            Some(ReturnPreference::Representation)
+        );
+        assert_eq!(
+            ReturnPreference::Representation.as_applied(),
+            "return=representation"
+        );
+    }
+
+    #[test]
+    fn return_unknown() {
+        assert_eq!(
+            return_preference(&prefer_headers("return=everything")),
+            None
+        );
+        assert_eq!(return_preference(&prefer_headers("wait=5")), None);
+        assert_eq!(return_preference(&HTTPHeaders::new()), None);
+    }
+}
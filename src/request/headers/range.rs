@@ -0,0 +1,319 @@
+use std::str::FromStr;
+
+use crate::request::types::HTTPHeaders;
+
+/// A single range from a `Range: bytes=...` header.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Range
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ByteRange {
+    /// `start-end`, inclusive
+    Bounded(u64, u64),
+    /// `start-`: from `start` to the end of the representation
+    From(u64),
+    /// `-length`: the last `length` bytes of the representation
+    Suffix(u64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeParseError {
+    /// Only `bytes` is supported. Servers should ignore ranges in other units and send the
+    /// full representation
+    UnsupportedUnit(String),
+    Malformed(String),
+}
+
+impl ByteRange {
+    /// The inclusive start and end offsets of this range in a representation of `length` bytes.
+    /// Returns `None` if the range can't be satisfied, which should result in a
+    /// 416 Range Not Satisfiable
+    pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+        let last = length.checked_sub(1)?;
+        match *self {
+            ByteRange::Bounded(start, end) if start <= last => Some((start, end.min(last))),
+            ByteRange::From(start) if start <= last => Some((start, last)),
+            ByteRange::Suffix(suffix_length) if suffix_length > 0 => {
+                Some((length.saturating_sub(suffix_length), last))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = RangeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || RangeParseError::Malformed(format!("Invalid byte range '{s}'"));
+        let parse_offset = |offset: &str| u64::from_str(offset).map_err(|_| malformed());
+
+        let (start, end) = s.trim().split_once('-').ok_or_else(malformed)?;
+        match (start, end) {
+            ("", "") => Err(malformed()),
+            ("", suffix_length) => Ok(ByteRange::Suffix(parse_offset(suffix_length)?)),
+            (start, "") => Ok(ByteRange::From(parse_offset(start)?)),
+            (start, end) => {
+                let (start, end) = (parse_offset(start)?, parse_offset(end)?);
+                if start > end {
+                    Err(malformed())
+                } else {
+                    Ok(ByteRange::Bounded(start, end))
+                }
+            }
+        }
+    }
+}
+
+/// Parse the value of a Range header. Only the `bytes` unit is supported
+pub fn parse_range(s: &str) -> Result<Vec<ByteRange>, RangeParseError> {
+    let (unit, ranges) = s
+        .split_once('=')
+        .ok_or_else(|| RangeParseError::Malformed(format!("Missing range unit in '{s}'")))?;
+
+    let unit = unit.trim();
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return Err(RangeParseError::UnsupportedUnit(unit.to_string()));
+    }
+
+    ranges
+        .split(',')
+        .filter(|range| !range.trim().is_empty())
+        .map(ByteRange::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|ranges| {
+            if ranges.is_empty() {
+                Err(RangeParseError::Malformed(format!("No ranges in '{s}'")))
+            } else {
+                Ok(ranges)
+            }
+        })
+}
+
+/// The byte ranges requested by the client.
+/// Returns `None` if there is no Range header or it should be ignored (E.G unsupported units),
+/// in which case the full representation should be sent
+pub fn requested_ranges(headers: &HTTPHeaders) -> Option<Vec<ByteRange>> {
+    let range = headers.get("range")?;
+    parse_range(range)
+        .inspect_err(|err| log::debug!("Ignoring Range header '{range}': {err:?}"))
+        .ok()
+}
+
+/// The part of a representation to send in response to a (possibly) ranged request
+#[derive(Debug, PartialEq)]
+pub enum RangeSelection<'a> {
+    /// Send the whole representation as 200 OK
+    Full(&'a [u8]),
+    /// Send `part` as 206 Partial Content with the given Content-Range
+    Partial {
+        part: &'a [u8],
+        content_range: String,
+    },
+    /// Send 416 Range Not Satisfiable with the given Content-Range
+    Unsatisfiable { content_range: String },
+}
+
+/// Select the part of `bytes` to send for `ranges`, as returned by `conditional_ranges`.
+/// Shared by everything that serves ranges so they agree on the semantics: only a single range
+/// is honoured and multiple ranges get the full representation
+pub fn select_range<'a>(ranges: Option<&[ByteRange]>, bytes: &'a [u8]) -> RangeSelection<'a> {
+    let length = bytes.len() as u64;
+    let Some([range]) = ranges else {
+        return RangeSelection::Full(bytes);
+    };
+
+    match range.resolve(length) {
+        Some((start, end)) => RangeSelection::Partial {
+            part: &bytes[start as usize..=end as usize],
+            content_range: format!("bytes {start}-{end}/{length}"),
+        },
+        None => RangeSelection::Unsatisfiable {
+            content_range: format!("bytes */{length}"),
+        },
+    }
+}
+
+/// The validator from an If-Range header. The range is only sent if the validator matches the
+/// current representation; otherwise the full representation is sent.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/If-Range
+#[derive(Debug, PartialEq, Clone)]
+pub enum IfRange {
+    /// An entity tag, including its quotes and weakness prefix
+    ETag(String),
+    /// An HTTP date, compared against Last-Modified
+    Date(String),
+}
+
+impl IfRange {
+    /// Whether the validator matches the current representation. Entity tags use the strong
+    /// comparison, so weak tags never match. Dates must be identical to Last-Modified
+    pub fn matches(&self, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+        match self {
+            IfRange::ETag(tag) => {
+                !tag.starts_with("W/")
+                    && etag.is_some_and(|etag| !etag.starts_with("W/") && etag == tag)
+            }
+            IfRange::Date(date) => last_modified.is_some_and(|last_modified| last_modified == date),
+        }
+    }
+}
+
+/// Parse the value of an If-Range header
+pub fn parse_if_range(s: &str) -> IfRange {
+    let s = s.trim();
+    if s.starts_with('"') || s.starts_with("W/") {
+        IfRange::ETag(s.to_string())
+    } else {
+        IfRange::Date(s.to_string())
+    }
+}
+
+/// The byte ranges to send for a representation with the given validators.
+/// The same as `requested_ranges`, but returns `None` if the request has an If-Range header
+/// that doesn't match, in which case the full representation should be sent
+pub fn conditional_ranges(
+    headers: &HTTPHeaders,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> Option<Vec<ByteRange>> {
+    let ranges = requested_ranges(headers)?;
+    match headers
+        .get("if-range")
+        .map(|if_range| parse_if_range(if_range))
+    {
+        Some(if_range) if !if_range.matches(etag, last_modified) => {
+            log::debug!("Ignoring Range header because If-Range {if_range:?} is stale");
+            None
+        }
+        _ => Some(ranges),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-499, 500-, -200").expect("Parsing byte ranges should succeed"),
+            vec![
+                ByteRange::Bounded(0, 499),
+                ByteRange::From(500),
+                ByteRange::Suffix(200)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unsupported_unit() {
+        assert_eq!(
+            parse_range("items=0-5"),
+            Err(RangeParseError::UnsupportedUnit("items".to_string()))
+        );
+        assert_eq!(
+            requested_ranges(&HTTPHeaders::from([(
+                "range".to_string(),
+                "items=0-5".to_string()
+            )])),
+            None,
+            "Ranges with unsupported units should be ignored"
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        for range in ["bytes=", "bytes=-", "bytes=5-1", "bytes=a-b", "0-5"] {
+            assert!(
+                matches!(parse_range(range), Err(RangeParseError::Malformed(_))),
+                "{range} should be malformed"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        assert_eq!(ByteRange::Bounded(0, 499).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::From(50).resolve(100), Some((50, 99)));
+        assert_eq!(ByteRange::Suffix(200).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::From(100).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+        assert_eq!(ByteRange::From(0).resolve(0), None);
+    }
+
+    #[test]
+    fn select_sub_range() {
+        // E.G an image embedded in the binary
+        let asset: Vec<u8> = (0..=255).collect();
+
+        assert_eq!(
+            select_range(Some(&[ByteRange::Bounded(16, 19)]), &asset),
+            RangeSelection::Partial {
+                part: &[16, 17, 18, 19],
+                content_range: "bytes 16-19/256".to_string()
+            }
+        );
+        assert_eq!(
+            select_range(Some(&[ByteRange::Suffix(2)]), &asset),
+            RangeSelection::Partial {
+                part: &[254, 255],
+                content_range: "bytes 254-255/256".to_string()
+            }
+        );
+        assert_eq!(
+            select_range(Some(&[ByteRange::From(256)]), &asset),
+            RangeSelection::Unsatisfiable {
+                content_range: "bytes */256".to_string()
+            }
+        );
+        assert_eq!(
+            select_range(Some(&[ByteRange::From(0), ByteRange::From(1)]), &asset),
+            RangeSelection::Full(&asset),
+            "Multiple ranges should get the full representation"
+        );
+        assert_eq!(select_range(None, &asset), RangeSelection::Full(&asset));
+    }
+
+    fn range_headers(if_range: &str) -> HTTPHeaders {
+        HTTPHeaders::from([
+            ("range".to_string(), "bytes=0-4".to_string()),
+            ("if-range".to_string(), if_range.to_string()),
+        ])
+    }
+
+    #[test]
+    fn if_range_matching() {
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        for if_range in ["\"v1\"", last_modified] {
+            assert_eq!(
+                conditional_ranges(
+                    &range_headers(if_range),
+                    Some("\"v1\""),
+                    Some(last_modified)
+                ),
+                Some(vec![ByteRange::Bounded(0, 4)]),
+                "If-Range {if_range} should match"
+            );
+        }
+    }
+
+    #[test]
+    fn if_range_stale() {
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        for if_range in ["\"v0\"", "W/\"v1\"", "Tue, 20 Oct 2015 07:28:00 GMT"] {
+            assert_eq!(
+                conditional_ranges(
+                    &range_headers(if_range),
+                    Some("\"v1\""),
+                    Some(last_modified)
+                ),
+                None,
+                "If-Range {if_range} should be stale"
+            );
+        }
+        assert_eq!(
+            conditional_ranges(&range_headers("\"v1\""), None, None),
+            None,
+            "If-Range can't match a representation without validators"
+        );
+    }
+}
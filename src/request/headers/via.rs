@@ -0,0 +1,97 @@
+use crate::request::types::{HTTPHeaders, RequestParseError};
+
+/// A single hop from the Via header.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Via
+#[derive(Debug, PartialEq, Clone)]
+pub struct ViaEntry {
+    /// E.G "1.1" or "HTTP/1.1"
+    pub protocol: String,
+    /// The host (and optional port) or pseudonym of the intermediary
+    pub received_by: String,
+    pub comment: Option<String>,
+}
+
+fn parse_via_entry(entry: &str) -> Result<ViaEntry, RequestParseError> {
+    let invalid = || RequestParseError::InvalidHeader(format!("Malformed Via entry '{entry}'"));
+
+    let (hop, comment) = match entry.split_once('(') {
+        Some((hop, comment)) => (
+            hop,
+            Some(
+                comment
+                    .strip_suffix(')')
+                    .ok_or_else(invalid)?
+                    .trim()
+                    .to_string(),
+            ),
+        ),
+        None => (entry, None),
+    };
+
+    let mut parts = hop.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(protocol), Some(received_by), None) => Ok(ViaEntry {
+            protocol: protocol.to_string(),
+            received_by: received_by.to_string(),
+            comment,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// The Via header is a comma-separated list of the intermediaries a request passed through,
+/// in the order that they were visited
+pub fn parse_via(s: &str) -> Result<Vec<ViaEntry>, RequestParseError> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_via_entry)
+        .collect()
+}
+
+/// Whether `pseudonym` has already handled the request. Used for loop detection
+pub fn has_visited(headers: &HTTPHeaders, pseudonym: &str) -> Result<bool, RequestParseError> {
+    Ok(headers
+        .get("via")
+        .map(|via| parse_via(via))
+        .transpose()?
+        .is_some_and(|entries| {
+            entries
+                .iter()
+                .any(|entry| entry.received_by.eq_ignore_ascii_case(pseudonym))
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_single_hop() {
+        assert_eq!(
+            parse_via("1.1 vegur").expect("Parsing a single hop should succeed"),
+            vec![ViaEntry {
+                protocol: "1.1".to_string(),
+                received_by: "vegur".to_string(),
+                comment: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_multiple_hops() {
+        let entries = parse_via("HTTP/1.1 GWA, 1.0 fred (Apache/1.1), 1.1 p.example.net:8080")
+            .expect("Parsing multiple hops should succeed");
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].protocol, "HTTP/1.1");
+        assert_eq!(entries[1].comment, Some("Apache/1.1".to_string()));
+        assert_eq!(entries[2].received_by, "p.example.net:8080");
+    }
+
+    #[test]
+    fn parse_malformed() {
+        parse_via("1.1").expect_err("A hop without a received-by should fail");
+        parse_via("1.1 a b").expect_err("A hop with too many tokens should fail");
+        parse_via("1.1 a (unterminated").expect_err("An unterminated comment should fail");
+    }
+}
@@ -1,47 +1,371 @@
-use std::{
-    io::{BufReader, Cursor, Read},
-    sync::{Arc, Mutex},
-};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::time::Instant;
+
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::{GzDecoder, ZlibDecoder};
 
 use crate::request::content_type::{ContentEncoding, MimeParseInfo};
-use crate::request::types::{BodyReader, Json};
+use crate::request::types::{BodyError, BodyLines, BodyReadOptions, BodyReader, Json};
 use crate::{
     mime::{MainMimeType, MimeType, SubMimeType},
     request::SyncableStream,
+    server::streams::PrefixedStream,
 };
 
-pub fn decode_body(encoding: &[ContentEncoding], body: Vec<u8>) -> Result<String, &'static str> {
-    // TODO: Use flate2 and rust-brotli to decode the body
-    String::from_utf8(body).or(Err("Failed to decode bytes as UTF-8"))
+/// Undo each content encoding, last applied first, then decode the body as UTF-8.
+/// `ContentEncoding::Identity` is a no-op
+pub fn decode_body(encoding: &[ContentEncoding], body: Vec<u8>) -> Result<String, BodyError> {
+    decode_charset(None, decompress(encoding, body, None)?)
+}
+
+/// Undo each content encoding in reverse order, E.G `gzip, deflate` is inflated and then
+/// gunzipped. The decoded body is limited to `max_size` bytes because a small compressed
+/// body can expand to gigabytes (a decompression bomb)
+pub fn decompress(
+    encoding: &[ContentEncoding],
+    body: Vec<u8>,
+    max_size: Option<u64>,
+) -> Result<Vec<u8>, BodyError> {
+    if encoding
+        .iter()
+        .all(|encoding| *encoding == ContentEncoding::Identity)
+    {
+        return Ok(body);
+    }
+
+    // One byte over the limit is read so that reaching it exactly isn't mistaken for exceeding it
+    let limit = max_size.map_or(u64::MAX, |max_size| max_size.saturating_add(1));
+    let mut decoded = Vec::new();
+    decoding_reader(encoding, Box::new(body.as_slice()))?
+        .take(limit)
+        .read_to_end(&mut decoded)
+        .or(Err(BodyError::Decode(
+            "Failed to decompress the body".to_string(),
+        )))?;
+    match max_size {
+        Some(max_size) if decoded.len() as u64 > max_size => Err(BodyError::TooLarge(max_size)),
+        _ => Ok(decoded),
+    }
 }
 
+/// Transcode a body in the given charset to UTF-8. A missing charset is assumed to be UTF-8
+pub fn decode_charset(charset: Option<&str>, body: Vec<u8>) -> Result<String, BodyError> {
+    let encoding = match charset {
+        None => UTF_8,
+        Some(label) => Encoding::for_label(label.trim().trim_matches('"').as_bytes())
+            .ok_or_else(|| BodyError::UnsupportedCharset(label.to_string()))?,
+    };
+    if encoding == UTF_8 {
+        return String::from_utf8(body).or(Err(BodyError::Decode(
+            "Failed to decode bytes as UTF-8".to_string(),
+        )));
+    }
+
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&body)
+        .map(|text| text.into_owned())
+        .ok_or_else(|| BodyError::Decode(format!("Failed to decode bytes as {}", encoding.name())))
+}
+
+/// Bodies are read in increments of this many bytes so that progress can be reported and the
+/// maximum size enforced without trusting Content-Length
+const READ_INCREMENT: usize = 8 * 1024;
+
 pub struct HTTP1_1BodyReader<R: SyncableStream> {
     stream: BufReader<R>,
+    options: BodyReadOptions,
+}
+
+/// Arms the stream's read timeout before every read so that reading stops at the deadline,
+/// however slowly the client trickles in bytes
+struct DeadlineReader<'a, R: SyncableStream> {
+    inner: &'a mut BufReader<R>,
+    deadline: Option<Instant>,
+}
+
+impl<R: SyncableStream> DeadlineReader<'_, R> {
+    fn arm(&self) -> Result<(), IoError> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(IoError::new(ErrorKind::TimedOut, "The deadline has passed"));
+        }
+        self.inner.get_ref().set_read_timeout(Some(remaining))
+    }
 }
 
-fn read_body<Stream: Read>(length: u64, reader: &mut BufReader<Stream>) -> Result<Vec<u8>, String> {
-    let expected_length = length.try_into().expect("The server should be 64-bit");
-    let mut bytes: Vec<u8> = vec![0; expected_length];
+impl<R: SyncableStream> Read for DeadlineReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.arm()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<R: SyncableStream> BufRead for DeadlineReader<'_, R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.arm()?;
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount)
+    }
+}
 
-    reader
-        .read_exact(&mut bytes)
-        .or(Err("Could not read from stream"))?;
+/// Read `body` to the end in increments, enforcing the maximum size and reporting progress
+fn read_increments(
+    body: &mut impl Read,
+    options: &mut BodyReadOptions,
+) -> Result<Vec<u8>, BodyError> {
+    // Content-Length is not used for the capacity because a malicious client could use it to
+    // exhaust the server's memory
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut increment = [0; READ_INCREMENT];
 
-    let actual_length = bytes.len();
-    if actual_length != expected_length {
-        Err(format!("Content-Length ({expected_length}) is greater than the actual length ({actual_length})"))
+    loop {
+        let n_read = body.read(&mut increment)?;
+        if n_read == 0 {
+            return Ok(bytes);
+        }
+        bytes.extend_from_slice(&increment[..n_read]);
+
+        let total = bytes.len() as u64;
+        if let Some(max_size) = options.max_size.filter(|max_size| total > *max_size) {
+            return Err(BodyError::TooLarge(max_size));
+        }
+        if let Some(on_progress) = options.on_progress.as_mut() {
+            if !on_progress(total) {
+                return Err(BodyError::Aborted(total));
+            }
+        }
+    }
+}
+
+fn read_body<Reader: BufRead>(
+    length: u64,
+    reader: &mut Reader,
+    options: &mut BodyReadOptions,
+) -> Result<Vec<u8>, BodyError> {
+    if let Some(max_size) = options.max_size.filter(|max_size| length > *max_size) {
+        return Err(BodyError::TooLarge(max_size));
+    }
+
+    let bytes = read_increments(&mut reader.take(length), options)?;
+    let actual_length = bytes.len() as u64;
+    if actual_length != length {
+        Err(BodyError::LengthMismatch {
+            expected: length,
+            actual: actual_length,
+        })
     } else {
         Ok(bytes)
     }
 }
 
+/// Carries a `BodyError` through `Read`, E.G out of a decoder wrapping a `ChunkedReader`.
+/// Converting the `IoError` back into a `BodyError` recovers it
+fn body_io_error(err: BodyError) -> IoError {
+    IoError::other(err)
+}
+
+/// The longest chunk size or trailer line accepted, so that a client cannot send an endless line
+const MAX_FRAMING_LINE: u64 = 8 * 1024;
+
+/// Read a line of chunked framing, without its CRLF
+fn read_framing_line<Reader: BufRead>(reader: &mut Reader) -> Result<String, BodyError> {
+    let mut line = String::new();
+    let n_read = reader.take(MAX_FRAMING_LINE).read_line(&mut line)?;
+    if n_read == 0 {
+        return Err(BodyError::Framing(
+            "The chunked body ended before the terminating chunk".to_string(),
+        ));
+    }
+    if !line.ends_with('\n') {
+        return Err(BodyError::Framing(
+            "Chunk framing line is too long".to_string(),
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Decodes a body with chunked transfer-encoding as it is read. Trailers are checked for
+/// validity and then discarded. See https://www.rfc-editor.org/rfc/rfc9112#section-7.1
+struct ChunkedReader<Reader: BufRead> {
+    inner: Reader,
+    /// The size of the current chunk and how much of it is left to read
+    chunk: Option<(u64, u64)>,
+    /// The total of the chunk sizes so far, checked against `max_size` before each chunk
+    /// is read
+    declared: u64,
+    max_size: Option<u64>,
+    finished: bool,
+}
+
+impl<Reader: BufRead> ChunkedReader<Reader> {
+    fn new(inner: Reader, max_size: Option<u64>) -> Self {
+        Self {
+            inner,
+            chunk: None,
+            declared: 0,
+            max_size,
+            finished: false,
+        }
+    }
+
+    /// Read the next chunk's size. Returns 0 for the terminating chunk
+    fn next_chunk(&mut self) -> Result<u64, BodyError> {
+        let size_line = read_framing_line(&mut self.inner)?;
+        // Chunk extensions are ignored
+        let size_str = size_line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size_str, 16)
+            .map_err(|_| BodyError::Framing(format!("Invalid chunk size '{size_str}'")))?;
+
+        self.declared = self.declared.saturating_add(size);
+        match self.max_size {
+            Some(max_size) if self.declared > max_size => Err(BodyError::TooLarge(max_size)),
+            _ => Ok(size),
+        }
+    }
+
+    fn read_trailers(&mut self) -> Result<(), BodyError> {
+        loop {
+            let trailer = read_framing_line(&mut self.inner)?;
+            if trailer.is_empty() {
+                return Ok(());
+            }
+            if !trailer.contains(':') {
+                return Err(BodyError::Framing(format!("Malformed trailer '{trailer}'")));
+            }
+        }
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> Result<usize, BodyError> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        let (size, remaining) = match self.chunk {
+            Some(chunk) => chunk,
+            None => match self.next_chunk()? {
+                0 => {
+                    self.read_trailers()?;
+                    self.finished = true;
+                    return Ok(0);
+                }
+                size => (size, size),
+            },
+        };
+
+        let to_read = remaining.min(buf.len() as u64);
+        let n_read = self.inner.by_ref().take(to_read).read(buf)?;
+        if n_read == 0 {
+            return Err(BodyError::LengthMismatch {
+                expected: size,
+                actual: size - remaining,
+            });
+        }
+
+        let remaining = remaining - n_read as u64;
+        self.chunk = Some((size, remaining));
+        if remaining == 0 {
+            self.chunk = None;
+            if !read_framing_line(&mut self.inner)?.is_empty() {
+                return Err(BodyError::Framing(format!(
+                    "Chunk is longer than its size ({size})"
+                )));
+            }
+        }
+        Ok(n_read)
+    }
+}
+
+impl<Reader: BufRead> Read for ChunkedReader<Reader> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.read_chunked(buf).map_err(body_io_error)
+    }
+}
+
+fn read_chunked_body<Reader: BufRead>(
+    reader: &mut Reader,
+    options: &mut BodyReadOptions,
+) -> Result<Vec<u8>, BodyError> {
+    read_increments(&mut ChunkedReader::new(reader, options.max_size), options)
+}
+
+/// Fails with `BodyError::TooLarge` once more than `max_size` bytes have been read, E.G from a
+/// decoder whose output is much larger than its input
+struct SizeLimit<Reader: Read> {
+    inner: Reader,
+    n_read: u64,
+    max_size: Option<u64>,
+}
+
+impl<Reader: Read> Read for SizeLimit<Reader> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.n_read += n_read as u64;
+        match self.max_size {
+            Some(max_size) if self.n_read > max_size => {
+                Err(body_io_error(BodyError::TooLarge(max_size)))
+            }
+            _ => Ok(n_read),
+        }
+    }
+}
+
+/// Undo each content encoding in reverse order as `body` is read
+fn decoding_reader<'a>(
+    encoding: &[ContentEncoding],
+    body: Box<dyn Read + 'a>,
+) -> Result<Box<dyn Read + 'a>, BodyError> {
+    encoding.iter().rev().try_fold(body, |body, encoding| {
+        Ok(match encoding {
+            ContentEncoding::Identity => body,
+            ContentEncoding::Gzip => Box::new(GzDecoder::new(body)),
+            // Deflate in HTTP means the zlib format. See https://www.rfc-editor.org/rfc/rfc9110#section-8.4.1.2
+            ContentEncoding::Deflate => Box::new(ZlibDecoder::new(body)),
+            ContentEncoding::Br => Box::new(brotli::Decompressor::new(body, READ_INCREMENT)),
+            ContentEncoding::Zstd => Box::new(zstd::stream::read::Decoder::new(body)?),
+            // LZW is obsolete, so no decoder is provided
+            ContentEncoding::Compress => {
+                return Err(BodyError::UnsupportedEncoding("compress".to_string()))
+            }
+        })
+    })
+}
+
 impl<R: SyncableStream> HTTP1_1BodyReader<R> {
     pub fn new(reader: BufReader<R>) -> Self {
-        Self { stream: reader }
+        Self {
+            stream: reader,
+            options: BodyReadOptions::default(),
+        }
+    }
+
+    fn read_all(&mut self, parse_info: &MimeParseInfo) -> Result<Vec<u8>, BodyError> {
+        let mut reader = DeadlineReader {
+            inner: &mut self.stream,
+            deadline: self.options.deadline,
+        };
+        if parse_info.chunked {
+            read_chunked_body(&mut reader, &mut self.options)
+        } else {
+            read_body(parse_info.length, &mut reader, &mut self.options)
+        }
     }
 }
 impl<R: SyncableStream> BodyReader for HTTP1_1BodyReader<R> {
-    fn text(&mut self, parse_info: &MimeParseInfo) -> Result<String, String> {
+    fn set_options(&mut self, options: BodyReadOptions) {
+        self.options = options;
+    }
+
+    fn bytes(&mut self, parse_info: &MimeParseInfo) -> Result<Vec<u8>, BodyError> {
+        let bytes = self.read_all(parse_info)?;
+        decompress(&parse_info.encoding, bytes, self.options.max_size)
+    }
+
+    fn text(&mut self, parse_info: &MimeParseInfo) -> Result<String, BodyError> {
         if !matches!(
             parse_info.content_type,
             MimeType {
@@ -49,14 +373,15 @@ impl<R: SyncableStream> BodyReader for HTTP1_1BodyReader<R> {
                 ..
             },
         ) {
-            return Err("Not a text document".to_string());
+            return Err(BodyError::UnsupportedMediaType(
+                "Not a text document".to_string(),
+            ));
         }
 
-        let bytes = read_body(parse_info.length, &mut self.stream)?;
-        decode_body(&parse_info.encoding, bytes).map_err(|e| e.to_string())
+        decode_charset(parse_info.charset.as_deref(), self.bytes(parse_info)?)
     }
 
-    fn json(&mut self, parse_info: &MimeParseInfo) -> Result<Json, String> {
+    fn json(&mut self, parse_info: &MimeParseInfo) -> Result<Json, BodyError> {
         if !matches!(
             parse_info.content_type,
             MimeType {
@@ -65,30 +390,127 @@ impl<R: SyncableStream> BodyReader for HTTP1_1BodyReader<R> {
                 ..
             },
         ) {
-            return Err("Not JSON".to_string());
+            return Err(BodyError::UnsupportedMediaType("Not JSON".to_string()));
         }
 
-        // FIXME: this assumes that the charset is UTF-8. Use encoding_rs to decode first
-        let content_bytes = read_body(parse_info.length, &mut self.stream)?;
-        let content: String = decode_body(&parse_info.encoding, content_bytes)?;
+        let content = decode_charset(parse_info.charset.as_deref(), self.bytes(parse_info)?)?;
 
+        // Any JSON is a valid `Json`, so every error here is malformed JSON
         serde_json::from_str::<Json>(content.as_str())
-            .map_err(|reason| format!("Failed to decode JSON because: '{reason}'"))
+            .map_err(|reason| BodyError::MalformedJson(reason.to_string()))
+    }
+
+    fn lines<'a>(&'a mut self, parse_info: &MimeParseInfo) -> Result<BodyLines<'a>, BodyError> {
+        let length = parse_info.length;
+        let max_size = self.options.max_size;
+        if let Some(max_size) =
+            max_size.filter(|max_size| !parse_info.chunked && length > *max_size)
+        {
+            return Err(BodyError::TooLarge(max_size));
+        }
+        // Line-based formats such as NDJSON are always UTF-8
+        if let Some(charset) = parse_info.charset.as_deref().filter(|charset| {
+            Encoding::for_label(charset.trim().trim_matches('"').as_bytes()) != Some(UTF_8)
+        }) {
+            return Err(BodyError::UnsupportedCharset(charset.to_string()));
+        }
+
+        let reader = DeadlineReader {
+            inner: &mut self.stream,
+            deadline: self.options.deadline,
+        };
+        let framed: Box<dyn Read + 'a> = if parse_info.chunked {
+            Box::new(ChunkedReader::new(reader, max_size))
+        } else {
+            Box::new(reader.take(length))
+        };
+        let decoded = SizeLimit {
+            inner: decoding_reader(&parse_info.encoding, framed)?,
+            n_read: 0,
+            max_size,
+        };
+        let lines = BufReader::new(decoded)
+            .lines()
+            .map(|line| line.map_err(BodyError::from));
+        Ok(Box::new(lines))
     }
 
+    /// Bytes that were read ahead, E.G a pipelined request, are kept
     fn into_stream(self: Box<Self>) -> Box<dyn crate::request::SyncableStream> {
-        Box::new(self.stream.into_inner())
+        let buffered = self.stream.buffer().to_vec();
+        let inner: Box<dyn SyncableStream> = Box::new(self.stream.into_inner());
+        if buffered.is_empty() {
+            inner
+        } else {
+            Box::new(PrefixedStream::new(buffered, inner))
+        }
+    }
+
+    fn pipe(&mut self, length: Option<u64>, out: &mut dyn Write) -> Result<u64, IoError> {
+        let Some(length) = length else {
+            let mut body = ChunkedReader::new(&mut self.stream, self.options.max_size);
+            return std::io::copy(&mut body, out);
+        };
+        let copied = std::io::copy(&mut self.stream.by_ref().take(length), out)?;
+        if copied != length {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "The body ended {0} bytes before Content-Length",
+                    length - copied
+                ),
+            ));
+        }
+        Ok(copied)
+    }
+
+    fn echo(&mut self, head: &[u8], length: u64) -> Result<u64, IoError> {
+        self.stream.get_mut().write_all(head)?;
+
+        // The stream is both the source and destination, so it can't be passed to io::copy
+        let mut increment = [0; READ_INCREMENT];
+        let mut remaining = length;
+        while remaining > 0 {
+            let to_read = increment
+                .len()
+                .min(remaining.try_into().unwrap_or(usize::MAX));
+            let n_read = self.stream.read(&mut increment[..to_read])?;
+            if n_read == 0 {
+                return Err(IoError::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("The body ended {remaining} bytes before Content-Length"),
+                ));
+            }
+            self.stream.get_mut().write_all(&increment[..n_read])?;
+            remaining -= n_read as u64;
+        }
+
+        self.stream.get_mut().flush()?;
+        Ok(length)
+    }
+
+    fn discard(&mut self, length: u64) -> Result<u64, IoError> {
+        std::io::copy(&mut self.stream.by_ref().take(length), &mut std::io::sink())
+    }
+
+    fn write_interim(&mut self, interim: &[u8]) -> Result<(), IoError> {
+        // The client is blocked until it receives this, so it mustn't be buffered
+        let stream = self.stream.get_mut();
+        stream.write_all(interim)?;
+        stream.flush()
     }
 }
 
 // TODO: multipart parser
-fn mock_stream(content: &'static str) -> Box<BufReader<Cursor<Vec<u8>>>> {
-    Box::new(BufReader::new(Cursor::new(content.into())))
+#[cfg(test)]
+fn mock_stream(content: &'static str) -> Box<BufReader<std::io::Cursor<Vec<u8>>>> {
+    Box::new(BufReader::new(std::io::Cursor::new(content.into())))
 }
 
 #[cfg(test)]
 mod json_tests {
     use super::*;
+    use std::io::Cursor;
 
     impl SyncableStream for Cursor<Vec<u8>> {
         fn get_type(&self) -> crate::request::SyncableStreamType {
@@ -108,13 +530,12 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(r#"{"foo":"bar"}"#),
-        }
-        .json(&mime_info)
-        .expect("Parsing the body should succeed");
+        HTTP1_1BodyReader::new(*mock_stream(r#"{"foo":"bar"}"#))
+            .json(&mime_info)
+            .expect("Parsing the body should succeed");
     }
 
     #[test]
@@ -129,16 +550,15 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(
-                r#"{
+        HTTP1_1BodyReader::new(*mock_stream(
+            r#"{
   "foo": "bar",
   "baz": "qux"
 }"#,
-            ),
-        }
+        ))
         .json(&mime_info)
         .expect("Parsing a multiline JSON body should succeed");
     }
@@ -155,13 +575,12 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(r#"{"foo":"bar"}"#),
-        }
-        .json(&mime_info)
-        .expect_err("An error should be thrown when the Content-Length is wrong");
+        HTTP1_1BodyReader::new(*mock_stream(r#"{"foo":"bar"}"#))
+            .json(&mime_info)
+            .expect_err("An error should be thrown when the Content-Length is wrong");
     }
 
     #[test]
@@ -176,13 +595,12 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream("lol"),
-        }
-        .json(&incorrect_mime_info)
-        .expect_err("Calling parse_body_json when the MIME type is not JSON should fail");
+        HTTP1_1BodyReader::new(*mock_stream("lol"))
+            .json(&incorrect_mime_info)
+            .expect_err("Calling parse_body_json when the MIME type is not JSON should fail");
 
         let correct_mime_info = MimeParseInfo {
             content_type: MimeType {
@@ -194,13 +612,12 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(r#"not a json"#),
-        }
-        .json(&correct_mime_info)
-        .expect_err("Parsing a body that is not JSON as JSON should fail");
+        HTTP1_1BodyReader::new(*mock_stream(r#"not a json"#))
+            .json(&correct_mime_info)
+            .expect_err("Parsing a body that is not JSON as JSON should fail");
     }
 
     #[test]
@@ -215,13 +632,12 @@ mod json_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(r#""#),
-        }
-        .json(&mime_info)
-        .expect_err("Parsing an empty body as JSON should fail");
+        HTTP1_1BodyReader::new(*mock_stream(r#""#))
+            .json(&mime_info)
+            .expect_err("Parsing an empty body as JSON should fail");
     }
 }
 
@@ -241,12 +657,11 @@ mod text_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
-        let result = HTTP1_1BodyReader {
-            stream: *mock_stream(r#"<!doctype html><title>a</title>"#),
-        }
-        .text(&mime_info)
-        .expect("Parsing a basic HTML document should succeed");
+        let result = HTTP1_1BodyReader::new(*mock_stream(r#"<!doctype html><title>a</title>"#))
+            .text(&mime_info)
+            .expect("Parsing a basic HTML document should succeed");
         assert_eq!(result, "<!doctype html><title>a</title>".to_string());
     }
 
@@ -262,16 +677,39 @@ mod text_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
         };
 
-        let result = HTTP1_1BodyReader {
-            stream: *mock_stream(r#""#),
-        }
-        .text(&mime_info)
-        .expect("Parsing an empty HTML document should succeed");
+        let result = HTTP1_1BodyReader::new(*mock_stream(r#""#))
+            .text(&mime_info)
+            .expect("Parsing an empty HTML document should succeed");
         assert_eq!(result, "".to_string());
     }
 
+    #[test]
+    fn parse_identity_encoded() {
+        let mime_info = MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Text,
+                sub_type: SubMimeType::TXT,
+                original: "text/plain".to_string(),
+            },
+            length: 11u64,
+            boundary: None,
+            charset: None,
+            encoding: vec![ContentEncoding::Identity],
+            chunked: false,
+        };
+
+        let result = HTTP1_1BodyReader::new(*mock_stream("hello world"))
+            .text(&mime_info)
+            .expect("Parsing an identity-encoded body should succeed");
+        assert_eq!(
+            result, "hello world",
+            "identity should not transform the body"
+        );
+    }
+
     #[test]
     fn parse_nontext() {
         let mime_info = MimeParseInfo {
@@ -284,13 +722,486 @@ mod text_tests {
             boundary: None,
             charset: None,
             encoding: vec![],
+            chunked: false,
+        };
+
+        HTTP1_1BodyReader::new(*mock_stream(r#"IDK what an .mp3 file looks like"#))
+            .text(&mime_info)
+            .expect_err("Parsing a non-text document should fail");
+    }
+
+    #[test]
+    fn parse_shift_jis() {
+        // "こんにちは" in Shift_JIS
+        let body: &'static [u8] = &[0x82, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd];
+        let mime_info = MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Text,
+                sub_type: SubMimeType::TXT,
+                original: "text/plain".to_string(),
+            },
+            length: body.len() as u64,
+            boundary: None,
+            charset: Some("Shift_JIS".to_string()),
+            encoding: vec![],
+            chunked: false,
+        };
+
+        let result = HTTP1_1BodyReader::new(BufReader::new(std::io::Cursor::new(body.to_vec())))
+            .text(&mime_info)
+            .expect("Parsing a Shift_JIS body should succeed");
+        assert_eq!(result, "こんにちは");
+    }
+
+    #[test]
+    fn parse_unknown_charset() {
+        let mime_info = MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Text,
+                sub_type: SubMimeType::TXT,
+                original: "text/plain".to_string(),
+            },
+            length: 5u64,
+            boundary: None,
+            charset: Some("not-a-charset".to_string()),
+            encoding: vec![],
+            chunked: false,
+        };
+
+        HTTP1_1BodyReader::new(*mock_stream("hello"))
+            .text(&mime_info)
+            .expect_err("Parsing a body in an unknown charset should fail");
+    }
+    // TODO: add tests for encodings and boundaries
+}
+
+#[cfg(test)]
+mod limit_tests {
+    use super::*;
+    use crate::request::{http1_1, Request, RequestParseError};
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    fn text_mime_info(length: u64) -> MimeParseInfo {
+        MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Text,
+                sub_type: SubMimeType::TXT,
+                original: "text/plain".to_string(),
+            },
+            length,
+            boundary: None,
+            charset: None,
+            encoding: vec![],
+            chunked: false,
+        }
+    }
+
+    fn large_reader(length: usize) -> HTTP1_1BodyReader<Cursor<Vec<u8>>> {
+        HTTP1_1BodyReader::new(BufReader::new(Cursor::new(vec![b'a'; length])))
+    }
+
+    #[test]
+    fn progress_callback_increasing() {
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let progress_ref = Arc::clone(&progress);
+
+        let mut reader = large_reader(20_000);
+        reader.set_options(BodyReadOptions {
+            max_size: None,
+            on_progress: Some(Box::new(move |total| {
+                progress_ref.lock().unwrap().push(total);
+                true
+            })),
+            ..Default::default()
+        });
+        reader
+            .text(&text_mime_info(20_000))
+            .expect("Reading a body within the limit should succeed");
+
+        let progress = progress.lock().unwrap();
+        assert!(
+            progress.len() > 1,
+            "The callback should fire once per increment. Calls: {progress:?}"
+        );
+        assert!(
+            progress.windows(2).all(|pair| pair[0] < pair[1]),
+            "The byte counts should increase. Calls: {progress:?}"
+        );
+        assert_eq!(progress.last(), Some(&20_000));
+    }
+
+    #[test]
+    fn progress_callback_abort() {
+        let mut reader = large_reader(20_000);
+        reader.set_options(BodyReadOptions {
+            max_size: None,
+            on_progress: Some(Box::new(|total| total < READ_INCREMENT as u64)),
+            ..Default::default()
+        });
+        reader
+            .text(&text_mime_info(20_000))
+            .expect_err("Returning false from the progress callback should abort the read");
+    }
+
+    #[test]
+    fn exceeds_max_size() {
+        let mut reader = large_reader(20_000);
+        reader.set_options(BodyReadOptions {
+            max_size: Some(10_000),
+            on_progress: None,
+            ..Default::default()
+        });
+        reader
+            .text(&text_mime_info(20_000))
+            .expect_err("Reading a body larger than the maximum size should fail");
+
+        let head = http1_1::parse_req_head(
+            &mut "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: text/plain\r\nContent-Length: 20000\r\n"
+                .lines(),
+        )
+        .expect("The request head should be valid");
+        let mut request = Request::new(head, BufReader::new(Cursor::new(vec![b'a'; 20_000])));
+        request.set_body_options(BodyReadOptions {
+            max_size: Some(10_000),
+            on_progress: None,
+            ..Default::default()
+        });
+
+        let err = request
+            .read_body_text()
+            .expect_err("Reading a body larger than the maximum size should fail");
+        assert_eq!(err, RequestParseError::ContentTooLarge(10_000));
+        assert_eq!(err.as_status_code().to_code(), 413);
+    }
+}
+
+#[cfg(test)]
+mod chunked_tests {
+    use super::*;
+    use crate::request::{http1_1, Request};
+    use std::io::Cursor;
+
+    fn chunked_mime_info(main_type: MainMimeType, sub_type: SubMimeType) -> MimeParseInfo {
+        MimeParseInfo {
+            content_type: MimeType {
+                main_type,
+                sub_type,
+                original: String::new(),
+            },
+            length: 0,
+            boundary: None,
+            charset: None,
+            encoding: vec![],
+            chunked: true,
+        }
+    }
+
+    #[test]
+    fn multiple_chunks() {
+        let result = HTTP1_1BodyReader::new(*mock_stream(
+            "5\r\nhello\r\n1;ext=1\r\n \r\nB\r\nchunked wor\r\n2\r\nld\r\n0\r\n\r\n",
+        ))
+        .text(&chunked_mime_info(MainMimeType::Text, SubMimeType::TXT))
+        .expect("Parsing a multi-chunk body should succeed");
+        assert_eq!(result, "hello chunked world");
+    }
+
+    #[test]
+    fn json_with_trailers() {
+        let result = HTTP1_1BodyReader::new(*mock_stream(
+            "7\r\n{\"foo\":\r\n6\r\n\"bar\"}\r\n0\r\nChecksum: abc\r\nExpires: never\r\n\r\n",
+        ))
+        .json(&chunked_mime_info(
+            MainMimeType::Application,
+            SubMimeType::JSON,
+        ))
+        .expect("Parsing a chunked JSON body with trailers should succeed");
+        assert_eq!(result, serde_json::json!({"foo": "bar"}));
+    }
+
+    #[test]
+    fn only_terminating_chunk() {
+        let result = HTTP1_1BodyReader::new(*mock_stream("0\r\n\r\n"))
+            .text(&chunked_mime_info(MainMimeType::Text, SubMimeType::TXT))
+            .expect("Parsing an empty chunked body should succeed");
+        assert_eq!(result, "");
+    }
+
+    #[test]
+    fn malformed_chunk_size() {
+        let err = HTTP1_1BodyReader::new(*mock_stream("zz\r\nhello\r\n0\r\n\r\n"))
+            .text(&chunked_mime_info(MainMimeType::Text, SubMimeType::TXT))
+            .expect_err("A non-hex chunk size should be rejected");
+        assert_eq!(
+            err,
+            BodyError::Framing("Invalid chunk size 'zz'".to_string())
+        );
+    }
+
+    #[test]
+    fn chunk_longer_than_size() {
+        HTTP1_1BodyReader::new(*mock_stream("3\r\nhello\r\n0\r\n\r\n"))
+            .text(&chunked_mime_info(MainMimeType::Text, SubMimeType::TXT))
+            .expect_err("A chunk longer than its size should be rejected");
+    }
+
+    #[test]
+    fn missing_terminating_chunk() {
+        HTTP1_1BodyReader::new(*mock_stream("5\r\nhello\r\n"))
+            .text(&chunked_mime_info(MainMimeType::Text, SubMimeType::TXT))
+            .expect_err("A chunked body without a terminating chunk should be rejected");
+    }
+
+    #[test]
+    fn request_with_transfer_encoding() {
+        let head = http1_1::parse_req_head(
+            &mut "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n"
+                .lines(),
+        )
+        .expect("The request head should be valid");
+        let body = b"3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n".to_vec();
+        let mut request = Request::new(head, BufReader::new(Cursor::new(body)));
+
+        let text = request
+            .read_body_text()
+            .expect("Reading a chunked body without Content-Length should succeed");
+        assert_eq!(text, "abcdef");
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(body: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_then_deflate() {
+        let body = deflate(&gzip(b"hello world"));
+        let mime_info = MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Text,
+                sub_type: SubMimeType::TXT,
+                original: "text/plain".to_string(),
+            },
+            length: body.len() as u64,
+            boundary: None,
+            charset: None,
+            encoding: vec![ContentEncoding::Gzip, ContentEncoding::Deflate],
+            chunked: false,
+        };
+
+        let result = HTTP1_1BodyReader::new(BufReader::new(std::io::Cursor::new(body)))
+            .text(&mime_info)
+            .expect("Decoding a gzipped then deflated body should succeed");
+        assert_eq!(result, "hello world");
+
+        decode_body(
+            &[ContentEncoding::Deflate, ContentEncoding::Gzip],
+            deflate(&gzip(b"hello world")),
+        )
+        .expect_err("Encodings should be undone in reverse order");
+    }
+
+    #[test]
+    fn decompression_bomb() {
+        let bomb = gzip(&vec![b'a'; 1_000_000]);
+        assert!(bomb.len() < 10_000, "The bomb should be small on the wire");
+        let (mut request, _) = crate::testing::mock_request(
+            &format!(
+                "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\nContent-Length: {0}\r\n",
+                bomb.len()
+            ),
+            &bomb,
+        );
+        request.set_body_options(BodyReadOptions {
+            max_size: Some(10_000),
+            on_progress: None,
+            ..Default::default()
+        });
+
+        let err = request
+            .read_body_text()
+            .expect_err("A body that inflates past the maximum size should be rejected");
+        assert_eq!(
+            err,
+            crate::request::RequestParseError::InvalidBody(BodyError::TooLarge(10_000))
+        );
+        assert_eq!(err.as_status_code().to_code(), 413);
+    }
+
+    #[test]
+    fn gzipped_lines() {
+        let body = gzip(b"first\nsecond\n");
+        let mime_info = MimeParseInfo {
+            content_type: MimeType {
+                main_type: MainMimeType::Application,
+                sub_type: SubMimeType::NDJSON,
+                original: "application/x-ndjson".to_string(),
+            },
+            length: body.len() as u64,
+            boundary: None,
+            charset: None,
+            encoding: vec![ContentEncoding::Gzip],
+            chunked: false,
         };
 
-        HTTP1_1BodyReader {
-            stream: *mock_stream(r#"IDK what an .mp3 file looks like"#),
+        let mut reader = HTTP1_1BodyReader::new(BufReader::new(std::io::Cursor::new(body)));
+        let lines: Vec<String> = reader
+            .lines(&mime_info)
+            .expect("The encoding is supported")
+            .collect::<Result<_, _>>()
+            .expect("Decoding the lines should succeed");
+        assert_eq!(lines, ["first", "second"]);
+    }
+
+    #[test]
+    fn brotli_and_zstd() {
+        let mut brotli = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        brotli.write_all(b"brotli body").unwrap();
+        assert_eq!(
+            decode_body(&[ContentEncoding::Br], brotli.into_inner()),
+            Ok("brotli body".to_string())
+        );
+
+        let zstd = zstd::bulk::compress(b"zstd body", 3).unwrap();
+        assert_eq!(
+            decode_body(&[ContentEncoding::Zstd], zstd),
+            Ok("zstd body".to_string())
+        );
+    }
+
+    #[test]
+    fn unsupported_and_corrupt() {
+        assert_eq!(
+            decode_body(&[ContentEncoding::Compress], b"abc".to_vec()),
+            Err(BodyError::UnsupportedEncoding("compress".to_string()))
+        );
+        decode_body(&[ContentEncoding::Gzip], b"not gzip".to_vec())
+            .expect_err("A corrupt gzip body should fail to decode");
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+    use std::io::{Cursor, ErrorKind};
+
+    fn mime_info(main_type: MainMimeType, sub_type: SubMimeType, length: u64) -> MimeParseInfo {
+        MimeParseInfo {
+            content_type: MimeType {
+                main_type,
+                sub_type,
+                original: String::new(),
+            },
+            length,
+            boundary: None,
+            charset: None,
+            encoding: vec![],
+            chunked: false,
         }
-        .text(&mime_info)
-        .expect_err("Parsing a non-text document should fail");
     }
-    // TODO: add tests for encodings, charsets, and boundaries
+
+    /// A connection that drops as soon as it is read from
+    struct ResetStream;
+
+    impl Read for ResetStream {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(IoError::from(ErrorKind::ConnectionReset))
+        }
+    }
+
+    impl Write for ResetStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SyncableStream for ResetStream {
+        fn get_type(&self) -> crate::request::SyncableStreamType {
+            crate::request::SyncableStreamType::Tcp
+        }
+    }
+
+    #[test]
+    fn wrong_content_type() {
+        let err = HTTP1_1BodyReader::new(*mock_stream("hello"))
+            .json(&mime_info(MainMimeType::Text, SubMimeType::TXT, 5))
+            .expect_err("Reading text/plain as JSON should fail");
+        assert!(matches!(err, BodyError::UnsupportedMediaType(_)), "{err:?}");
+    }
+
+    #[test]
+    fn decode_failed() {
+        let body = vec![b'h', b'i', 0xff];
+        let err = HTTP1_1BodyReader::new(BufReader::new(Cursor::new(body)))
+            .text(&mime_info(MainMimeType::Text, SubMimeType::TXT, 3))
+            .expect_err("Invalid UTF-8 should fail to decode");
+        assert!(matches!(err, BodyError::Decode(_)), "{err:?}");
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let err = HTTP1_1BodyReader::new(*mock_stream("abc"))
+            .text(&mime_info(MainMimeType::Text, SubMimeType::TXT, 10))
+            .expect_err("A body shorter than Content-Length should be rejected");
+        assert_eq!(
+            err,
+            BodyError::LengthMismatch {
+                expected: 10,
+                actual: 3
+            }
+        );
+
+        let mut info = mime_info(MainMimeType::Text, SubMimeType::TXT, 0);
+        info.chunked = true;
+        let err = HTTP1_1BodyReader::new(*mock_stream("a\r\nabc"))
+            .text(&info)
+            .expect_err("A chunk shorter than its size should be rejected");
+        assert_eq!(
+            err,
+            BodyError::LengthMismatch {
+                expected: 10,
+                actual: 3
+            }
+        );
+    }
+
+    #[test]
+    fn io_error() {
+        let err = HTTP1_1BodyReader::new(BufReader::new(ResetStream))
+            .text(&mime_info(MainMimeType::Text, SubMimeType::TXT, 5))
+            .expect_err("A dropped connection should fail the read");
+        assert!(matches!(err, BodyError::Io(_)), "{err:?}");
+    }
+
+    #[test]
+    fn json_error() {
+        let err = HTTP1_1BodyReader::new(*mock_stream("{\"id\":"))
+            .json(&mime_info(MainMimeType::Application, SubMimeType::JSON, 6))
+            .expect_err("Truncated JSON should fail to parse");
+        assert!(matches!(err, BodyError::MalformedJson(_)), "{err:?}");
+        assert_eq!(err.as_status_code().to_code(), 400);
+    }
 }
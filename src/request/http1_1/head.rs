@@ -1,5 +1,9 @@
 use crate::request::types::*;
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{BufRead, Error as IoError, Read},
+    str::FromStr,
+};
 
 struct StartLine {
     method: HTTPMethod,
@@ -7,8 +11,27 @@ struct StartLine {
     version: HTTPVersion,
 }
 
-fn parse_start_line(line: &str) -> Result<StartLine, RequestParseError> {
+fn parse_start_line(
+    line: &str,
+    options: &HeadParseOptions,
+) -> Result<StartLine, RequestParseError> {
+    // Checked before splitting so that padding any segment, E.G the version, is bounded
+    if line.len() > options.max_start_line_length {
+        return Err(RequestParseError::StartLineTooLong(
+            options.max_start_line_length,
+        ));
+    }
+    if !line.is_ascii() {
+        return Err(RequestParseError::InvalidStartLine("Non-ASCII characters"));
+    }
+
     let segments: Vec<&str> = line.split(' ').take(3).collect();
+    if segments
+        .get(1)
+        .is_some_and(|uri| uri.len() > options.max_uri_length)
+    {
+        return Err(RequestParseError::URITooLong(options.max_uri_length));
+    }
     let parse_method = |m| {
         HTTPMethod::from_str(m).map_err(|_| RequestParseError::InvalidStartLine("Invalid method"))
     };
@@ -39,6 +62,13 @@ fn parse_start_line(line: &str) -> Result<StartLine, RequestParseError> {
     }
 }
 
+/// No whitespace is allowed between a header name and its colon. Proxies may disagree about
+/// the name of `Foo : bar`, which can be used to smuggle requests.
+/// See https://www.rfc-editor.org/rfc/rfc9112#section-5.1
+pub fn has_whitespace_before_colon(name: &str) -> bool {
+    name.ends_with(|c: char| c.is_ascii_whitespace())
+}
+
 fn parse_headers<'a, I: Iterator<Item = &'a str>>(
     lines: &mut I,
 ) -> Result<HTTPHeaders, RequestParseError> {
@@ -48,27 +78,204 @@ fn parse_headers<'a, I: Iterator<Item = &'a str>>(
         if parts.len() != 2 {
             return Err(RequestParseError::InvalidHeader(line_no.to_string()));
         }
+        // Only header values may contain non-ASCII (opaque) bytes
+        if !parts[0].is_ascii() {
+            return Err(RequestParseError::InvalidHeader(format!(
+                "Non-ASCII header name on line {line_no}"
+            )));
+        }
+        if has_whitespace_before_colon(parts[0]) {
+            return Err(RequestParseError::InvalidHeader(format!(
+                "Whitespace between the header name and colon on line {line_no}"
+            )));
+        }
 
         // Headers must be case-insensitive
-        headers.insert(
-            parts[0].to_lowercase().trim().to_string(),
-            parts[1].trim().to_string(),
-        );
+        let (name, value) = (parts[0].to_lowercase().trim().to_string(), parts[1].trim());
+        match headers.entry(name) {
+            Entry::Vacant(e) => {
+                e.insert(value.to_string());
+            }
+            // Ambiguous hosts could be used to route a request to the wrong virtual host
+            Entry::Occupied(e) if e.key() == "host" => {
+                return Err(RequestParseError::InvalidHeader(format!(
+                    "Duplicate Host header on line {line_no}"
+                )));
+            }
+            // Set-Cookie can't be combined because cookie values may contain commas
+            Entry::Occupied(mut e) if e.key() == "set-cookie" => {
+                e.insert(value.to_string());
+            }
+            // Cookie pairs are separated by semicolons, E.G when a proxy splits them into
+            // several headers. See https://www.rfc-editor.org/rfc/rfc9113#section-8.2.3
+            Entry::Occupied(mut e) if e.key() == "cookie" => {
+                let combined = e.get_mut();
+                combined.push_str("; ");
+                combined.push_str(value);
+            }
+            // Repeated headers are equivalent to a single comma-separated header.
+            // See https://www.rfc-editor.org/rfc/rfc7230#section-3.2.2
+            Entry::Occupied(mut e) => {
+                let combined = e.get_mut();
+                combined.push_str(", ");
+                combined.push_str(value);
+            }
+        }
     }
 
     Ok(headers)
 }
 
+/// Bounds the work done splitting huge query strings
+pub const DEFAULT_MAX_QUERY_PARAMS: usize = 1000;
+/// RFC 9112 recommends supporting request lines of at least 8000 bytes.
+/// See https://www.rfc-editor.org/rfc/rfc9112#section-3
+pub const DEFAULT_MAX_START_LINE_LENGTH: usize = 8192;
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8000;
+pub const DEFAULT_MAX_HEAD_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct HeadParseOptions {
+    /// Reject requests whose Host header isn't a well-formed authority or disagrees with an
+    /// absolute-form request target
+    pub strict_host: bool,
+    /// Reject requests with more query parameters than this with 414 URI Too Long
+    pub max_query_params: usize,
+    /// Resolve `.` and `..` segments in origin-form paths before routing
+    pub normalize_paths: bool,
+    /// Reject start lines longer than this in bytes with 414 URI Too Long
+    pub max_start_line_length: usize,
+    /// Reject request targets longer than this in bytes with 414 URI Too Long
+    pub max_uri_length: usize,
+    /// Reject header sections (every line after the start line, including line endings) longer
+    /// than this in bytes with 431 Request Header Fields Too Large
+    pub max_head_length: usize,
+}
+
+impl Default for HeadParseOptions {
+    fn default() -> Self {
+        Self {
+            strict_host: false,
+            max_query_params: DEFAULT_MAX_QUERY_PARAMS,
+            normalize_paths: false,
+            max_start_line_length: DEFAULT_MAX_START_LINE_LENGTH,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            max_head_length: DEFAULT_MAX_HEAD_LENGTH,
+        }
+    }
+}
+
+/// Whether `host` is a valid `uri-host [ ":" port ]`.
+/// See https://www.rfc-editor.org/rfc/rfc9110#section-7.2
+fn is_valid_authority(authority: &str) -> bool {
+    let (host, port) = if authority.starts_with('[') {
+        // IP-literal E.G [::1]:8080
+        match authority.split_once(']') {
+            Some((literal, rest)) => (
+                &literal[1..],
+                match rest {
+                    "" => None,
+                    rest => match rest.strip_prefix(':') {
+                        Some(port) => Some(port),
+                        None => return false,
+                    },
+                },
+            ),
+            None => return false,
+        }
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    let valid_host = !host.is_empty()
+        && host.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(
+                    c,
+                    // unreserved, pct-encoded, and sub-delims. `:` is allowed for IPv6 literals
+                    '-' | '.'
+                        | '_'
+                        | '~'
+                        | '%'
+                        | '!'
+                        | '$'
+                        | '&'
+                        | '\''
+                        | '('
+                        | ')'
+                        | '*'
+                        | '+'
+                        | ','
+                        | ';'
+                        | '='
+                        | ':'
+                )
+        })
+        && (authority.starts_with('[') || !host.contains(':'));
+    let valid_port = port.is_none_or(|port| port.is_empty() || u16::from_str(port).is_ok());
+
+    valid_host && valid_port
+}
+
+/// The authority of an absolute-form request target, E.G example.com:8080 in
+/// http://example.com:8080/about
+fn absolute_form_authority(path: &str) -> &str {
+    let without_scheme = path.split_once("://").map_or(path, |(_, rest)| rest);
+    without_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or(without_scheme)
+}
+
+/// Compare two authorities, ignoring case and the default HTTP port
+fn same_authority(a: &str, b: &str) -> bool {
+    let normalise = |authority: &str| {
+        authority
+            .strip_suffix(":80")
+            .unwrap_or(authority)
+            .to_lowercase()
+    };
+    normalise(a) == normalise(b)
+}
+
+fn validate_host(host: &str, path: &Path) -> Result<(), RequestParseError> {
+    if !is_valid_authority(host) {
+        return Err(RequestParseError::InvalidHost(format!(
+            "'{host}' is not a valid authority"
+        )));
+    }
+
+    if let Path::AbsoluteForm(uri) = path {
+        let uri_authority = absolute_form_authority(uri);
+        if !same_authority(uri_authority, host) {
+            return Err(RequestParseError::InvalidHost(format!(
+                "Host '{host}' does not match the request target's authority '{uri_authority}'"
+            )));
+        }
+    }
+    Ok(())
+}
+
 pub fn parse_req_head<'a>(
     req: &mut impl Iterator<Item = &'a str>,
+) -> Result<RequestHead, RequestParseError> {
+    parse_req_head_with(req, &HeadParseOptions::default())
+}
+
+pub fn parse_req_head_with<'a>(
+    req: &mut impl Iterator<Item = &'a str>,
+    options: &HeadParseOptions,
 ) -> Result<RequestHead, RequestParseError> {
     let StartLine {
         method,
-        path,
+        mut path,
         version,
     } = req
         .next()
-        .map(parse_start_line)
+        .map(|line| parse_start_line(line, options))
         .ok_or(RequestParseError::InvalidStartLine("Missing start line"))??;
 
     let mut header_req = req.by_ref().take_while(|line| !line.is_empty());
@@ -76,20 +283,168 @@ pub fn parse_req_head<'a>(
 
     // HTTP/1.1 requires a Host header
     if version == HTTPVersion::V1_1 {
-        headers
+        let host = headers
             .get("host")
             .ok_or(RequestParseError::MissingHostHeader)?;
+
+        if options.strict_host {
+            validate_host(host, &path)?;
+        }
+    }
+
+    if options.normalize_paths {
+        path = path.normalized();
+    }
+
+    let query = path.raw_query().map(str::to_string);
+    if let Some(query) = &query {
+        let max_params = options.max_query_params;
+        let n_params = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .take(max_params + 1)
+            .count();
+        if n_params > max_params {
+            return Err(RequestParseError::TooManyQueryParams(max_params));
+        }
     }
-    // TODO: validate host
 
     Ok(RequestHead {
         method,
         path,
         version,
         headers,
+        query,
+        connection: ConnectionInfo::default(),
     })
 }
 
+/// Read until the end of the request head (empty line), leaving `reader` positioned at the
+/// first byte of the body.
+/// Returns the head with each line terminated by a CRLF and the number of bytes consumed from
+/// `reader`. These can differ because clients may use bare LFs as line endings.
+/// The head is decoded as ISO-8859-1 because header values may contain arbitrary bytes.
+/// The parser is responsible for rejecting non-ASCII bytes where they aren't allowed.
+/// Nothing is bounded, so use `read_head_with` when reading from a client
+pub fn read_head<R: BufRead>(reader: &mut R) -> Result<(String, usize), IoError> {
+    let unbounded = HeadParseOptions {
+        max_start_line_length: usize::MAX,
+        max_head_length: usize::MAX,
+        ..Default::default()
+    };
+    read_head_with(reader, &unbounded)
+}
+
+/// `read_head`, but stops reading as soon as the start line or header section is longer than
+/// allowed by `options`, so a client can't make the server buffer an endless line.
+/// The error wraps a `RequestParseError` that can be responded with
+pub fn read_head_with<R: BufRead>(
+    reader: &mut R,
+    options: &HeadParseOptions,
+) -> Result<(String, usize), IoError> {
+    let mut request_content = String::new();
+    let mut consumed = 0;
+    let mut line = Vec::new();
+    let mut is_start_line = true;
+    let mut start_line_length = 0;
+
+    // This ultimately does 2 passes through the connection :( Would it be possible to cut out
+    // the first pass? The main reason for it is to unwrap each line
+    loop {
+        // Leave room for the line ending, which isn't counted towards the start line
+        let (limit, err) = if is_start_line {
+            (
+                options.max_start_line_length.saturating_add(2),
+                RequestParseError::StartLineTooLong(options.max_start_line_length),
+            )
+        } else {
+            (
+                options
+                    .max_head_length
+                    .saturating_sub(consumed - start_line_length),
+                RequestParseError::HeadTooLarge(options.max_head_length),
+            )
+        };
+        line.clear();
+        let n_read = Read::take(&mut *reader, limit as u64).read_until(b'\n', &mut line)?;
+        consumed += n_read;
+        if n_read == limit && !line.ends_with(b"\n") {
+            return Err(IoError::other(err));
+        }
+
+        // Only strip a single line ending so that the body offset is unaffected
+        let unwrapped = line.strip_suffix(b"\n").unwrap_or(&line);
+        let unwrapped = unwrapped.strip_suffix(b"\r").unwrap_or(unwrapped);
+        if n_read == 0 || unwrapped.is_empty() {
+            break;
+        }
+        request_content.extend(unwrapped.iter().map(|&byte| char::from(byte)));
+        request_content += "\r\n";
+        if is_start_line {
+            start_line_length = consumed;
+            is_start_line = false;
+        }
+    }
+
+    Ok((request_content, consumed))
+}
+
+/// The result of parsing the bytes of a head received so far
+#[derive(Debug)]
+// Boxing the head isn't worth it: a status is returned once per read and then unpacked
+#[allow(clippy::large_enum_variant)]
+pub enum HeadParseStatus {
+    /// The parsed head and the number of bytes it took up. The body starts after them
+    Complete(RequestHead, usize),
+    /// The end of the head hasn't been received yet. Parse again once more bytes arrive
+    Incomplete,
+}
+
+/// Parse a head that may not have been fully received. Unlike `parse_req_head`, a partial head
+/// isn't an error.
+/// The listener blocks on `read_head_with` instead, so this is for callers that receive bytes
+/// incrementally themselves, E.G from a non-blocking socket
+pub fn parse_partial_req_head(
+    buffer: &[u8],
+    options: &HeadParseOptions,
+) -> Result<HeadParseStatus, RequestParseError> {
+    let mut end = 0;
+    let mut start_line_end = 0;
+    let mut is_start_line = true;
+    while let Some(line_length) = buffer[end..].iter().position(|&byte| byte == b'\n') {
+        let line = &buffer[end..end + line_length];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        end += line_length + 1;
+
+        // HTTP/0.9 heads are only a start line, E.G `GET /`
+        let is_http0_9 = is_start_line && line.split(|&byte| byte == b' ').count() == 2;
+        if line.is_empty() || is_http0_9 {
+            let (head, _) = read_head_with(&mut &buffer[..end], options).map_err(|err| {
+                *err.into_inner()
+                    .and_then(|inner| inner.downcast::<RequestParseError>().ok())
+                    .expect("Reading from an in-memory buffer should only fail on a length limit")
+            })?;
+            return parse_req_head_with(&mut head.lines(), options)
+                .map(|head| HeadParseStatus::Complete(head, end));
+        }
+        if is_start_line {
+            start_line_end = end;
+            is_start_line = false;
+        }
+    }
+
+    // Don't wait for the rest of a head that will be rejected anyway
+    if is_start_line && buffer.len() > options.max_start_line_length {
+        return Err(RequestParseError::StartLineTooLong(
+            options.max_start_line_length,
+        ));
+    }
+    if !is_start_line && buffer.len() - start_line_end > options.max_head_length {
+        return Err(RequestParseError::HeadTooLarge(options.max_head_length));
+    }
+    Ok(HeadParseStatus::Incomplete)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,16 +454,155 @@ mod tests {
         let request = parse_req_head(&mut "GET /\r\n".lines())
             .expect("Parsing an HTTP/0.9 request should succeed");
         assert_eq!(HTTPMethod::Get, request.method);
-        assert_eq!(Path::OriginForm("/".to_string()), request.path,);
+        assert_eq!(Path::OriginForm("/".into()), request.path,);
         assert_eq!(HTTPVersion::V0_9, request.version);
     }
 
+    #[test]
+    fn http_request_query() {
+        let request = parse_req_head(&mut "GET /search?q=rust HTTP/1.1\r\nHost: x\r\n".lines())
+            .expect("Parsing an origin-form request with a query should succeed");
+        assert_eq!(request.query, Some("q=rust".to_string()));
+
+        let request =
+            parse_req_head(&mut "GET http://x/search?q=rust HTTP/1.1\r\nHost: x\r\n".lines())
+                .expect("Parsing an absolute-form request with a query should succeed");
+        assert_eq!(
+            request.query,
+            Some("q=rust".to_string()),
+            "Absolute-form queries should be extracted like origin-form queries"
+        );
+
+        let request = parse_req_head(&mut "GET http://x/search HTTP/1.1\r\nHost: x\r\n".lines())
+            .expect("Parsing an absolute-form request should succeed");
+        assert_eq!(request.query, None);
+    }
+
+    #[test]
+    fn partial_head() {
+        let options = HeadParseOptions::default();
+        let received = b"POST /upload HTTP/1.1\r\nHost: a.c";
+        assert!(matches!(
+            parse_partial_req_head(received, &options),
+            Ok(HeadParseStatus::Incomplete)
+        ));
+        assert!(matches!(
+            parse_partial_req_head(b"", &options),
+            Ok(HeadParseStatus::Incomplete)
+        ));
+
+        let received = [&received[..], b"om\r\nContent-Length: 4\r\n\r\nbody"].concat();
+        let Ok(HeadParseStatus::Complete(head, consumed)) =
+            parse_partial_req_head(&received, &options)
+        else {
+            panic!("The rest of the head should complete it");
+        };
+        assert_eq!(head.headers.get("host"), Some(&"a.com".to_string()));
+        assert_eq!(&received[consumed..], b"body");
+
+        let Ok(HeadParseStatus::Complete(head, consumed)) =
+            parse_partial_req_head(b"GET /\r\n", &options)
+        else {
+            panic!("An HTTP/0.9 head should be complete after its start line");
+        };
+        assert_eq!(head.version, HTTPVersion::V0_9);
+        assert_eq!(consumed, 7);
+
+        parse_partial_req_head(b"NOT A REQUEST LINE\r\n\r\n", &options)
+            .expect_err("A malformed head should still be an error once complete");
+    }
+
+    #[test]
+    fn normalize_paths() {
+        let options = HeadParseOptions {
+            normalize_paths: true,
+            ..Default::default()
+        };
+        let request = parse_req_head_with(
+            &mut "GET /a/./b/../c HTTP/1.1\r\nHost: x\r\n".lines(),
+            &options,
+        )
+        .expect("Parsing a path with dot segments should succeed");
+        assert_eq!(request.path, Path::OriginForm("/a/c".into()));
+
+        let request = parse_req_head_with(
+            &mut "GET /../etc?x=1 HTTP/1.1\r\nHost: x\r\n".lines(),
+            &options,
+        )
+        .expect("Parsing a path that escapes the root should succeed");
+        assert_eq!(request.path, Path::OriginForm("/etc?x=1".into()));
+
+        let request = parse_req_head(&mut "GET /a/../b HTTP/1.1\r\nHost: x\r\n".lines())
+            .expect("Parsing a path with dot segments should succeed");
+        assert_eq!(
+            request.path,
+            Path::OriginForm("/a/../b".into()),
+            "Paths should only be normalised when enabled"
+        );
+    }
+
+    #[test]
+    fn start_line_too_long() {
+        let options = HeadParseOptions {
+            max_start_line_length: 64,
+            max_uri_length: 32,
+            ..Default::default()
+        };
+        let padded_version = format!("GET / HTTP/1.1{0}\r\nHost: x\r\n", "0".repeat(64));
+        let err = parse_req_head_with(&mut padded_version.lines(), &options)
+            .expect_err("A start line padded with a huge version should be rejected");
+        assert_eq!(err, RequestParseError::StartLineTooLong(64));
+        assert_eq!(err.as_status_code().to_code(), 414);
+
+        let long_uri = format!("GET /{0} HTTP/1.1\r\nHost: x\r\n", "a".repeat(32));
+        let err = parse_req_head_with(&mut long_uri.lines(), &options)
+            .expect_err("A request target over its own limit should be rejected");
+        assert_eq!(err, RequestParseError::URITooLong(32));
+        assert_eq!(err.as_status_code().to_code(), 414);
+
+        let status = parse_partial_req_head(&padded_version.as_bytes()[..70], &options);
+        assert_eq!(
+            status.expect_err("An overlong start line should be rejected before its end arrives"),
+            RequestParseError::StartLineTooLong(64)
+        );
+
+        let fits = format!("GET /{0} HTTP/1.1\r\nHost: x\r\n", "a".repeat(31));
+        parse_req_head_with(&mut fits.lines(), &options)
+            .expect("A start line within both limits should be accepted");
+    }
+
+    #[test]
+    fn too_many_query_params() {
+        let options = HeadParseOptions {
+            max_query_params: 3,
+            ..Default::default()
+        };
+        parse_req_head_with(
+            &mut "GET /search?a=1&b=2&&c=3 HTTP/1.1\r\nHost: x\r\n".lines(),
+            &options,
+        )
+        .expect("A query with the maximum number of parameters should be accepted");
+
+        let err = parse_req_head_with(
+            &mut "GET /search?a=1&b=2&c=3&d=4 HTTP/1.1\r\nHost: x\r\n".lines(),
+            &options,
+        )
+        .expect_err("A query with too many parameters should be rejected");
+        assert_eq!(err, RequestParseError::TooManyQueryParams(3));
+        assert_eq!(err.as_status_code().to_code(), 414);
+
+        let query = vec!["a=1"; DEFAULT_MAX_QUERY_PARAMS + 1].join("&");
+        let head = format!("GET /?{query} HTTP/1.1\r\nHost: x\r\n");
+        parse_req_head(&mut head.lines())
+            .expect_err("The default limit on query parameters should be enforced");
+    }
+
     #[test]
     fn http_request_v1_0() {
         let request = parse_req_head(&mut "GET / HTTP/1.0\r\n".lines())
             .expect("Parsing an HTTP/1.0 request should succeed");
         assert_eq!(HTTPMethod::Get, request.method);
-        assert_eq!(Path::OriginForm("/".to_string()), request.path,);
+        assert_eq!(Path::OriginForm("/".into()), request.path,);
         assert_eq!(HTTPVersion::V1_0, request.version);
     }
 
@@ -118,7 +612,7 @@ mod tests {
             .expect("Parsing a request with an origin-form path should succeed");
         assert_eq!(HTTPMethod::Get, request.method);
         assert_eq!(
-            Path::OriginForm("/".to_string()),
+            Path::OriginForm("/".into()),
             request.path,
             "Should be origin form, got {0:?}",
             request.path
@@ -167,7 +661,7 @@ mod tests {
         let request = parse_req_head(&mut "GET / HTTP/1.1\nHost: cheese.com\n".lines())
             .expect("Parsing a request containing LFs should succeed");
         assert_eq!(HTTPMethod::Get, request.method);
-        assert_eq!(Path::OriginForm("/".to_string()), request.path);
+        assert_eq!(Path::OriginForm("/".into()), request.path);
         assert_eq!(HTTPVersion::V1_1, request.version);
     }
 
@@ -177,7 +671,209 @@ mod tests {
         let request = parse_req_head(&mut "GET / HTTP/1.1\r\nHost: cheese.com\r\n".lines())
             .expect("Parsing a request containing carriage returns should succeed");
         assert_eq!(HTTPMethod::Get, request.method);
-        assert_eq!(Path::OriginForm("/".to_string()), request.path);
+        assert_eq!(Path::OriginForm("/".into()), request.path);
         assert_eq!(HTTPVersion::V1_1, request.version);
     }
+
+    #[test]
+    fn http_request_duplicate_headers_combined() {
+        let request = parse_req_head(
+            &mut "GET / HTTP/1.1\r\nHost: cheese.com\r\nAccept: text/html\r\nAccept: application/json\r\n"
+                .lines(),
+        )
+        .expect("Parsing a request with repeated headers should succeed");
+        assert_eq!(
+            request.headers.get("accept"),
+            Some(&"text/html, application/json".to_string()),
+            "Repeated headers should be combined in the order they were received"
+        );
+    }
+
+    #[test]
+    fn http_request_duplicate_host() {
+        let err =
+            parse_req_head(&mut "GET / HTTP/1.1\r\nHost: cheese.com\r\nHost: evil.com\r\n".lines())
+                .expect_err("Parsing a request with multiple Host headers should fail");
+        assert!(matches!(err, RequestParseError::InvalidHeader(_)));
+    }
+
+    #[test]
+    fn http_request_strict_host() {
+        let strict = HeadParseOptions {
+            strict_host: true,
+            ..Default::default()
+        };
+
+        for host in ["example.com", "example.com:8080", "127.0.0.1", "[::1]:80"] {
+            parse_req_head_with(
+                &mut format!("GET / HTTP/1.1\r\nHost: {host}\r\n").lines(),
+                &strict,
+            )
+            .unwrap_or_else(|err| panic!("Host {host} should be accepted: {err}"));
+        }
+
+        for host in ["exa mple.com", "example.com:port", "", "example.com:99999"] {
+            let err = parse_req_head_with(
+                &mut format!("GET / HTTP/1.1\r\nHost: {host}\r\n").lines(),
+                &strict,
+            )
+            .expect_err("A malformed Host should be rejected in strict mode");
+            assert!(
+                matches!(err, RequestParseError::InvalidHost(_)),
+                "Host {host} should be invalid, got {err:?}"
+            );
+            assert_eq!(err.as_status_code().to_code(), 400);
+        }
+
+        parse_req_head(&mut "GET / HTTP/1.1\r\nHost: exa mple.com\r\n".lines())
+            .expect("Malformed hosts should be tolerated outside of strict mode");
+    }
+
+    #[test]
+    fn http_request_strict_host_absolute_form() {
+        let strict = HeadParseOptions {
+            strict_host: true,
+            ..Default::default()
+        };
+
+        parse_req_head_with(
+            &mut "GET http://example.com/about HTTP/1.1\r\nHost: example.com\r\n".lines(),
+            &strict,
+        )
+        .expect("A Host matching the absolute-form target should be accepted");
+
+        let err = parse_req_head_with(
+            &mut "GET http://example.com/about HTTP/1.1\r\nHost: evil.com\r\n".lines(),
+            &strict,
+        )
+        .expect_err("A Host that disagrees with the absolute-form target should be rejected");
+        assert!(matches!(err, RequestParseError::InvalidHost(_)));
+    }
+
+    #[test]
+    fn http_request_non_ascii() {
+        for request in [
+            "GÉT / HTTP/1.1\r\nHost: example.com\r\n",
+            "GET /café HTTP/1.1\r\nHost: example.com\r\n",
+        ] {
+            let err = parse_req_head(&mut request.lines())
+                .expect_err("Non-ASCII characters in the start line should be rejected");
+            assert!(matches!(err, RequestParseError::InvalidStartLine(_)));
+            assert_eq!(err.as_status_code().to_code(), 400);
+        }
+
+        parse_req_head(&mut "GET / HTTP/1.1\r\nHost: example.com\r\nNäme: a\r\n".lines())
+            .expect_err("Non-ASCII characters in header names should be rejected");
+
+        let request =
+            parse_req_head(&mut "GET / HTTP/1.1\r\nHost: example.com\r\nX-Name: café\r\n".lines())
+                .expect("Non-ASCII characters in header values should be allowed");
+        assert_eq!(request.headers.get("x-name"), Some(&"café".to_string()));
+    }
+
+    #[test]
+    fn http_request_whitespace_before_colon() {
+        for header in ["Foo : bar", "Foo\t: bar"] {
+            let err = parse_req_head(
+                &mut format!("GET / HTTP/1.1\r\nHost: example.com\r\n{header}\r\n").lines(),
+            )
+            .expect_err("Whitespace before the colon should be rejected");
+            assert!(matches!(err, RequestParseError::InvalidHeader(_)));
+            assert_eq!(err.as_status_code().to_code(), 400);
+        }
+
+        let request =
+            parse_req_head(&mut "GET / HTTP/1.1\r\nHost: example.com\r\nFoo: bar\r\n".lines())
+                .expect("A header without whitespace before the colon should be accepted");
+        assert_eq!(request.headers.get("foo"), Some(&"bar".to_string()));
+    }
+
+    #[test]
+    fn read_head_bare_lf_body_offset() {
+        let raw = "POST / HTTP/1.1\nHost: example.com\r\nContent-Type: text/plain\nContent-Length: 5\n\nhello";
+        let mut reader = std::io::BufReader::new(std::io::Cursor::new(raw.as_bytes().to_vec()));
+
+        let (head, consumed) = read_head(&mut reader).expect("Reading the head should succeed");
+        assert_eq!(
+            consumed,
+            raw.len() - "hello".len(),
+            "Every byte of the head, including mixed line endings, should be consumed"
+        );
+        assert!(head.ends_with("Content-Length: 5\r\n"));
+
+        let request_head =
+            parse_req_head(&mut head.lines()).expect("A head with mixed line endings should parse");
+        let mut request = crate::request::Request::new(request_head, reader);
+        assert_eq!(
+            request
+                .read_body_text()
+                .expect("Reading the body should succeed"),
+            "hello"
+        );
+    }
+
+    #[test]
+    fn read_head_opaque_header_value() {
+        let raw = b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Name: caf\xe9\r\n\r\n";
+        let mut reader = std::io::BufReader::new(std::io::Cursor::new(raw.to_vec()));
+
+        let (head, _) = read_head(&mut reader)
+            .expect("Reading a head containing non-UTF-8 bytes should succeed");
+        let request_head = parse_req_head(&mut head.lines())
+            .expect("Non-ASCII bytes should be allowed in header values");
+        assert_eq!(
+            request_head.headers.get("x-name"),
+            Some(&"café".to_string()),
+            "Header values should be decoded as ISO-8859-1"
+        );
+    }
+
+    #[test]
+    fn read_head_limits() {
+        let options = HeadParseOptions {
+            max_start_line_length: 64,
+            max_head_length: 128,
+            ..Default::default()
+        };
+        let limit_hit = |err: IoError| {
+            *err.into_inner()
+                .and_then(|inner| inner.downcast::<RequestParseError>().ok())
+                .expect("Hitting a limit should be reported as a RequestParseError")
+        };
+
+        // Neither would ever end if the limits weren't enforced while reading
+        let mut endless_start_line = std::io::BufReader::new(std::io::repeat(b'a'));
+        let err = read_head_with(&mut endless_start_line, &options)
+            .expect_err("An endless start line should be rejected");
+        assert_eq!(limit_hit(err), RequestParseError::StartLineTooLong(64));
+
+        let mut endless_header = std::io::BufReader::new(
+            b"GET / HTTP/1.1\r\nHost: x\r\nX-Padding: ".chain(std::io::repeat(b'a')),
+        );
+        let err = read_head_with(&mut endless_header, &options)
+            .expect_err("An endless header should be rejected");
+        let err = limit_hit(err);
+        assert_eq!(err, RequestParseError::HeadTooLarge(128));
+        assert_eq!(err.as_status_code().to_code(), 431);
+
+        let many_headers = format!("GET / HTTP/1.1\r\n{0}\r\n", "X: y\r\n".repeat(32));
+        let err = read_head_with(&mut many_headers.as_bytes(), &options)
+            .expect_err("Many short headers should count towards the same limit");
+        assert_eq!(limit_hit(err), RequestParseError::HeadTooLarge(128));
+        assert_eq!(
+            parse_partial_req_head(&many_headers.as_bytes()[..160], &options)
+                .expect_err("An overlong header section should be rejected before it ends"),
+            RequestParseError::HeadTooLarge(128)
+        );
+
+        // The start line is exactly at its limit, which excludes the line ending
+        let fits = format!(
+            "GET /{0} HTTP/1.1\r\nHost: x\r\n{1}\r\n",
+            "a".repeat(64 - "GET / HTTP/1.1".len()),
+            "X: y\r\n".repeat((128 - "Host: x\r\n\r\n".len()) / "X: y\r\n".len())
+        );
+        let (_, consumed) = read_head_with(&mut fits.as_bytes(), &options)
+            .expect("A head within both limits should be read");
+        assert_eq!(consumed, fits.len());
+    }
 }
@@ -1,17 +1,33 @@
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 
 // Re-exports
 mod headers;
 pub use headers::*;
 pub mod http1_1;
+mod query;
+pub use query::*;
 mod types;
 pub use types::*;
 
 impl FromStr for RequestHead {
     type Err = RequestParseError;
 
+    /// Parse a request head of any supported version. The version is taken from the start line:
+    /// HTTP/0.9 requests have no version segment. HTTP/2 and HTTP/3 have no textual head, so
+    /// they are rejected with `UnsupportedVersion`
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        todo!("Implement a parser that can handle any HTTP version using the version-specific modules");
+        let start_line = s
+            .lines()
+            .next()
+            .ok_or(RequestParseError::InvalidStartLine("Missing start line"))?;
+
+        match start_line.split(' ').nth(2) {
+            Some(version @ ("HTTP/2" | "HTTP/2.0" | "HTTP/3" | "HTTP/3.0")) => {
+                Err(RequestParseError::UnsupportedVersion(version.to_string()))
+            }
+            // HTTP/0.9, 1.0 and 1.1 share a syntax. The version is validated by the parser
+            _ => http1_1::parse_req_head(&mut s.lines()),
+        }
     }
 }
 
@@ -22,4 +38,201 @@ impl RequestHead {
             HTTPMethod::Put | HTTPMethod::Post | HTTPMethod::Patch
         )
     }
+
+    /// The scheme the client used, E.G for building redirects or deciding whether to send HSTS.
+    /// X-Forwarded-Proto is only believed when the peer is a trusted proxy because any client
+    /// can send it. Otherwise this is the scheme of the connection itself
+    pub fn scheme(&self) -> Scheme {
+        if !self.connection.trusted_proxy {
+            return self.connection.scheme;
+        }
+        // Each proxy appends to the list, so the last value is from the trusted peer
+        let forwarded = self
+            .headers
+            .get("x-forwarded-proto")
+            .and_then(|protos| protos.rsplit(',').next())
+            .map(str::trim);
+        match forwarded {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => Scheme::Https,
+            Some(proto) if proto.eq_ignore_ascii_case("http") => Scheme::Http,
+            _ => self.connection.scheme,
+        }
+    }
+
+    /// The media types that the client will accept, from its Accept header.
+    /// A missing header means that any media type is acceptable
+    pub fn accepts(&self) -> accept::AcceptList {
+        accept::AcceptList::parse(self.headers.get("accept").map_or("*/*", String::as_str))
+    }
+
+    /// The elements of a comma-separated header, E.G `Cache-Control` or `Accept-Encoding`.
+    /// Empty if the header wasn't sent. See `split_list`
+    pub fn header_values(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.headers
+            .get(&name.to_lowercase())
+            .into_iter()
+            .flat_map(|value| split_list(value))
+    }
+
+    /// The cookies sent in the Cookie header, by name. Empty if there is no Cookie header
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.headers
+            .get("cookie")
+            .map(|cookie| cookie::parse_cookies(cookie))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str_v0_9() {
+        let request =
+            RequestHead::from_str("GET /\r\n").expect("Parsing an HTTP/0.9 request should succeed");
+        assert_eq!(HTTPMethod::Get, request.method);
+        assert_eq!(Path::OriginForm("/".into()), request.path);
+        assert_eq!(HTTPVersion::V0_9, request.version);
+    }
+
+    #[test]
+    fn from_str_v1_0() {
+        let request = RequestHead::from_str("GET / HTTP/1.0\r\n")
+            .expect("Parsing an HTTP/1.0 request should succeed");
+        assert_eq!(HTTPMethod::Get, request.method);
+        assert_eq!(Path::OriginForm("/".into()), request.path);
+        assert_eq!(HTTPVersion::V1_0, request.version);
+    }
+
+    #[test]
+    fn from_str_v1_1() {
+        let request = RequestHead::from_str("GET /search?q=rust HTTP/1.1\r\nHost: example.com\r\n")
+            .expect("Parsing an HTTP/1.1 request should succeed");
+        assert_eq!(HTTPMethod::Get, request.method);
+        assert_eq!(Path::OriginForm("/search?q=rust".into()), request.path);
+        assert_eq!(HTTPVersion::V1_1, request.version);
+        assert_eq!(
+            request.headers.get("host"),
+            Some(&"example.com".to_string())
+        );
+        assert_eq!(request.query, Some("q=rust".to_string()));
+
+        let request = RequestHead::from_str("GET / HTTP/1.1\nHost: cheese.com\n")
+            .expect("Parsing a request containing LFs should succeed");
+        assert_eq!(HTTPVersion::V1_1, request.version);
+    }
+
+    fn forwarded_request(trusted_proxy: bool) -> RequestHead {
+        let mut head = RequestHead::from_str(
+            "GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-Proto: https\r\n",
+        )
+        .expect("The forwarded request should be parsed");
+        head.connection = ConnectionInfo {
+            peer_addr: Some(([10, 0, 0, 1], 443).into()),
+            scheme: Scheme::Http,
+            trusted_proxy,
+        };
+        head
+    }
+
+    #[test]
+    fn trusted_forwarded_proto() {
+        let head = forwarded_request(true);
+        assert_eq!(head.scheme(), Scheme::Https);
+        assert_eq!(head.scheme().to_string(), "https");
+    }
+
+    #[test]
+    fn untrusted_forwarded_proto_ignored() {
+        let head = forwarded_request(false);
+        assert_eq!(
+            head.scheme(),
+            Scheme::Http,
+            "Clients that aren't trusted proxies shouldn't be able to claim HTTPS"
+        );
+    }
+
+    #[test]
+    fn accepts() {
+        let head = RequestHead::from_str(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nAccept: text/plain;q=0.5, application/json\r\n",
+        )
+        .expect("The request should be parsed");
+        let available = [
+            crate::mime::MimeType::from_str("text/plain").expect("text/plain should be supported"),
+            crate::mime::MimeType::from_str("application/json")
+                .expect("application/json should be supported"),
+        ];
+        assert_eq!(head.accepts().best_match(&available), Some(&available[1]));
+
+        let head = RequestHead::from_str("GET / HTTP/1.1\r\nHost: a.com\r\n")
+            .expect("The request should be parsed");
+        assert_eq!(
+            head.accepts().best_match(&available),
+            Some(&available[0]),
+            "Anything should be acceptable without an Accept header"
+        );
+    }
+
+    #[test]
+    fn header_values() {
+        let head = RequestHead::from_str(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nCache-Control: no-cache\r\nCache-Control: no-store, no-transform\r\n",
+        )
+        .expect("The request should be parsed");
+        assert_eq!(
+            head.header_values("Cache-Control").collect::<Vec<&str>>(),
+            ["no-cache", "no-store", "no-transform"],
+            "Repeated headers should be treated as one list"
+        );
+        assert_eq!(head.header_values("Connection").count(), 0);
+    }
+
+    #[test]
+    fn cookies() {
+        let head = RequestHead::from_str("GET / HTTP/1.1\r\nHost: a.com\r\nCookie: a=1; b=2\r\n")
+            .expect("The request should be parsed");
+        assert_eq!(head.cookies().get("b"), Some(&"2".to_string()));
+
+        let head = RequestHead::from_str(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nCookie: a=1\r\nCookie: b=2\r\n",
+        )
+        .expect("The request should be parsed");
+        assert_eq!(
+            head.cookies(),
+            HashMap::from([
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2".to_string()),
+            ]),
+            "Repeated Cookie headers should be joined like one list"
+        );
+
+        let head = RequestHead::from_str("GET / HTTP/1.1\r\nHost: a.com\r\n")
+            .expect("The request should be parsed");
+        assert!(head.cookies().is_empty());
+    }
+
+    #[test]
+    fn from_str_errors() {
+        assert_eq!(
+            RequestHead::from_str("GET / HTTP/1.1\r\n").map(|_| ()),
+            Err(RequestParseError::MissingHostHeader),
+            "HTTP/1.1 requests should be validated like they are by the listener"
+        );
+        assert!(matches!(
+            RequestHead::from_str("GET / HTTP/1.1\r\nHost: a.com\r\nFoo : bar\r\n"),
+            Err(RequestParseError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            RequestHead::from_str(""),
+            Err(RequestParseError::InvalidStartLine(_))
+        ));
+        for version in ["HTTP/2", "HTTP/3"] {
+            assert_eq!(
+                RequestHead::from_str(&format!("GET / {version}\r\n")).map(|_| ()),
+                Err(RequestParseError::UnsupportedVersion(version.to_string()))
+            );
+        }
+    }
 }
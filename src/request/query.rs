@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Decoded query parameters. Repeated keys (E.G `?tag=a&tag=b`) keep every value in order
+pub type QueryParams = HashMap<String, Vec<String>>;
+
+/// An origin-form request target (E.G `/search?q=rust`).
+/// The original encoded form is kept so that it can be displayed exactly as it was received.
+/// The query is only parsed when it is first used, so that oversized queries can be rejected
+/// without decoding them
+#[derive(Debug, Clone)]
+pub struct OriginForm {
+    raw: String,
+    query: OnceLock<Option<QueryParams>>,
+}
+
+impl PartialEq for OriginForm {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl OriginForm {
+    pub fn new(raw: impl Into<String>) -> Self {
+        Self {
+            raw: raw.into(),
+            query: OnceLock::new(),
+        }
+    }
+
+    /// The target as received, including the query
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// The path without the query. It is left percent-encoded because decoding `%2F` would
+    /// change which segments the path has
+    pub fn path(&self) -> &str {
+        self.raw.split_once('?').map_or(&self.raw, |(path, _)| path)
+    }
+
+    /// The undecoded query, without the leading `?`
+    pub fn raw_query(&self) -> Option<&str> {
+        self.raw.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn query(&self) -> Option<&QueryParams> {
+        self.query
+            .get_or_init(|| self.raw_query().map(parse_query))
+            .as_ref()
+    }
+
+    /// Resolve `.` and `..` segments in the path. The query is kept as it is
+    pub fn normalized(&self) -> Self {
+        let path = remove_dot_segments(self.path());
+        match self.raw_query() {
+            Some(query) => Self::new(format!("{path}?{query}")),
+            None => Self::new(path),
+        }
+    }
+}
+
+impl From<&str> for OriginForm {
+    fn from(raw: &str) -> Self {
+        Self::new(raw)
+    }
+}
+
+impl From<String> for OriginForm {
+    fn from(raw: String) -> Self {
+        Self::new(raw)
+    }
+}
+
+impl std::fmt::Display for OriginForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// Resolve `.` and `..` segments in an absolute path as described in
+/// https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4. `..` at the root is dropped so the
+/// path can't escape it, and percent-encoded dots (`%2e`) are treated as dots
+pub fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    // Resolving the last segment leaves a directory, E.G `/a/b/..` is `/a/`
+    let mut trailing_slash = false;
+    for segment in path.split('/').skip(1) {
+        let decoded = segment.to_ascii_lowercase().replace("%2e", ".");
+        trailing_slash = matches!(decoded.as_str(), "." | "..");
+        match decoded.as_str() {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !normalized.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Parse an `application/x-www-form-urlencoded` query, without the leading `?`.
+/// A parameter without `=` (E.G `?flag`) has an empty value
+pub fn parse_query(query: &str) -> QueryParams {
+    let mut params = QueryParams::new();
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        params
+            .entry(percent_decode(key))
+            .or_default()
+            .push(percent_decode(value));
+    }
+    params
+}
+
+/// Decode `%XX` escapes and `+` as a space. Malformed escapes are kept as they are and invalid
+/// UTF-8 is replaced with U+FFFD
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let escape = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = escape {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+                decoded.push(b'%');
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_parsed_lazily() {
+        let target = OriginForm::new("/search?q=rust");
+        assert!(
+            target.query.get().is_none(),
+            "Creating a target shouldn't parse its query"
+        );
+        assert_eq!(target.clone(), OriginForm::new("/search?q=rust"));
+
+        assert_eq!(
+            target.query().and_then(|query| query.get("q")),
+            Some(&vec!["rust".to_string()])
+        );
+        assert_eq!(
+            target,
+            OriginForm::new("/search?q=rust"),
+            "Equality shouldn't depend on whether the query has been parsed"
+        );
+    }
+
+    #[test]
+    fn empty_query() {
+        let target = OriginForm::new("/search?");
+        assert_eq!(target.query(), Some(&QueryParams::new()));
+        assert_eq!(target.path(), "/search");
+
+        let target = OriginForm::new("/search");
+        assert_eq!(
+            target.query(),
+            None,
+            "No `?` should mean that there's no query"
+        );
+    }
+
+    #[test]
+    fn encoded_spaces() {
+        let target = OriginForm::new("/search?q=hello%20world&name=a+b&tag%21=%E2%9C%93");
+        let query = target.query().expect("The query should be parsed");
+        assert_eq!(query["q"], vec!["hello world"]);
+        assert_eq!(
+            query["name"],
+            vec!["a b"],
+            "`+` should be decoded as a space"
+        );
+        assert_eq!(query["tag!"], vec!["✓"], "Keys should be decoded too");
+        assert_eq!(
+            target.to_string(),
+            "/search?q=hello%20world&name=a+b&tag%21=%E2%9C%93",
+            "Display should round-trip the encoded form"
+        );
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let query = parse_query("tag=a&tag=b&flag&tag=c");
+        assert_eq!(query["tag"], vec!["a", "b", "c"]);
+        assert_eq!(
+            query["flag"],
+            vec![""],
+            "A bare key should have an empty value"
+        );
+    }
+
+    #[test]
+    fn dot_segments() {
+        assert_eq!(remove_dot_segments("/a/./b/../c"), "/a/c");
+        assert_eq!(
+            remove_dot_segments("/../etc"),
+            "/etc",
+            "`..` should be clamped at the root"
+        );
+        assert_eq!(remove_dot_segments("/a/../../../etc/passwd"), "/etc/passwd");
+        assert_eq!(remove_dot_segments("/a/%2E%2e/b"), "/b");
+        assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+        assert_eq!(remove_dot_segments("/a/b/"), "/a/b/");
+        assert_eq!(remove_dot_segments("/"), "/");
+        assert_eq!(
+            OriginForm::new("/a/../b?next=/../c").normalized().as_str(),
+            "/b?next=/../c",
+            "The query should not be normalised"
+        );
+    }
+
+    #[test]
+    fn malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+}
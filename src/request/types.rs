@@ -1,25 +1,147 @@
-use super::{headers, http1_1::HTTP1_1BodyReader};
-use crate::{request::content_type::MimeParseInfo, server::response::Response};
+use super::{
+    contains_token, headers,
+    http1_1::{self, HTTP1_1BodyReader},
+    parse_query, OriginForm, QueryParams,
+};
+use crate::{
+    mime::SubMimeType,
+    request::content_type::MimeParseInfo,
+    server::response::{format_head, format_interim, ResponseStatus},
+};
+use serde::de::DeserializeOwned;
 use std::{
     collections::HashMap,
     fmt::Display,
-    io::{BufReader, Read, Write},
+    io::{BufReader, Error as IoError, ErrorKind, Read, Write},
+    net::SocketAddr,
     str::FromStr,
-    sync::Arc,
+    time::{Duration, Instant},
 };
 
 /// An arbitrary JSON
 pub type Json = serde_json::Value;
 
+/// The decoded lines of a request body
+pub type BodyLines<'a> = Box<dyn Iterator<Item = Result<String, BodyError>> + 'a>;
+
+/// Why a body couldn't be read or decoded. Use `as_status_code` to pick the response
+#[derive(Debug, PartialEq, Clone)]
+pub enum BodyError {
+    /// The body's framing was invalid, E.G a malformed chunk size
+    Framing(String),
+    /// The body, or one of its chunks, ended before its declared length in bytes
+    LengthMismatch { expected: u64, actual: u64 },
+    /// The connection failed while the body was being read
+    Io(String),
+    /// The body grew larger than the maximum size, in bytes, while it was being read
+    TooLarge(u64),
+    /// The progress callback stopped the read after this many bytes
+    Aborted(u64),
+    /// The client didn't send the body before the deadline or the connection's read timeout
+    Timeout,
+    /// The reader can't parse the body's Content-Type, E.G reading text/plain as JSON
+    UnsupportedMediaType(String),
+    /// The body has a content coding that can't be undone
+    UnsupportedEncoding(String),
+    /// The body is in a charset that can't be decoded
+    UnsupportedCharset(String),
+    /// The bytes don't match the body's encoding or charset, E.G invalid UTF-8
+    Decode(String),
+    /// The body isn't well-formed JSON
+    MalformedJson(String),
+    /// The body is well-formed JSON but doesn't have the expected shape, E.G a field is
+    /// missing or has the wrong type
+    UnexpectedJson(String),
+}
+
+impl BodyError {
+    pub fn as_status_code(&self) -> ResponseStatus {
+        match self {
+            Self::Framing(_)
+            | Self::LengthMismatch { .. }
+            | Self::Io(_)
+            | Self::Aborted(_)
+            | Self::Decode(_)
+            | Self::MalformedJson(_) => ResponseStatus::BadRequest,
+            Self::TooLarge(_) => ResponseStatus::ContentTooLarge,
+            Self::Timeout => ResponseStatus::RequestTimeout,
+            Self::UnsupportedMediaType(_)
+            | Self::UnsupportedEncoding(_)
+            | Self::UnsupportedCharset(_) => ResponseStatus::UnsupportedMediaType,
+            Self::UnexpectedJson(_) => ResponseStatus::UnprocessableContent,
+        }
+    }
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Framing(reason) | Self::UnsupportedMediaType(reason) | Self::Decode(reason) => {
+                write!(f, "{reason}")
+            }
+            Self::LengthMismatch { expected, actual } => write!(
+                f,
+                "The declared length ({expected}) is greater than the actual length ({actual})"
+            ),
+            Self::Io(reason) => write!(f, "Could not read from stream: {reason}"),
+            Self::TooLarge(max_size) => {
+                write!(f, "The body is greater than the maximum size ({max_size})")
+            }
+            Self::Aborted(total) => {
+                write!(f, "Reading the body was aborted after {total} bytes")
+            }
+            Self::Timeout => write!(f, "The body was not received in time"),
+            Self::UnsupportedEncoding(encoding) => {
+                write!(f, "The {encoding} encoding is not supported")
+            }
+            Self::UnsupportedCharset(charset) => write!(f, "Unsupported charset '{charset}'"),
+            Self::MalformedJson(reason) => write!(f, "Failed to decode JSON because: '{reason}'"),
+            Self::UnexpectedJson(reason) => {
+                write!(f, "The JSON does not have the expected shape: '{reason}'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+impl From<IoError> for BodyError {
+    fn from(err: IoError) -> Self {
+        // Errors from reading the body itself, E.G invalid chunk framing, are passed through
+        if let Some(err) = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<BodyError>())
+        {
+            return err.clone();
+        }
+        match err.kind() {
+            // Sockets report read timeouts as either kind depending on the platform
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BodyError {
+    /// Type errors, such as a missing field, are from well-formed JSON. Everything else means
+    /// that the JSON itself is invalid
+    fn from(err: serde_json::Error) -> Self {
+        match err.classify() {
+            serde_json::error::Category::Data => Self::UnexpectedJson(err.to_string()),
+            _ => Self::MalformedJson(err.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Path {
-    OriginForm(String),
+    OriginForm(OriginForm),
     AbsoluteForm(String),
     AuthorityForm(String, u16), // Used by the CONNECT method
     Asterisk,                   // Used by the OPTIONS method
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum HTTPMethod {
     Get,
     Post,
@@ -31,9 +153,14 @@ pub enum HTTPMethod {
     Options,
     Trace,
     Head,
+    /// Syntactically valid methods that the server doesn't know about (E.G WebDAV's PROPFIND).
+    /// These are preserved so that a 501 Not Implemented can be returned instead of a 400
+    Other(String),
 }
 
 pub type HTTPHeaders = HashMap<String, String>;
+/// Values captured from the request path by a route's parameters, E.G `id` in `/dogs/:id`
+pub type PathParams = HashMap<String, String>;
 
 #[derive(Debug)]
 pub struct RequestHead {
@@ -41,6 +168,37 @@ pub struct RequestHead {
     pub path: Path,
     pub version: HTTPVersion,
     pub headers: HTTPHeaders,
+    /// The raw query string of the request target, without the leading `?`
+    pub query: Option<String>,
+    /// Where the request came from. Filled in by the listener
+    pub connection: ConnectionInfo,
+}
+
+/// The scheme a request was originally sent with
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http => write!(f, "http"),
+            Self::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// The connection a request was received on
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    /// The scheme of the connection itself, which is `Http` behind a TLS-terminating proxy
+    pub scheme: Scheme,
+    /// The peer is a proxy whose forwarding headers (E.G X-Forwarded-Proto) can be believed
+    pub trusted_proxy: bool,
 }
 
 pub type RequestBody = Option<String>;
@@ -53,6 +211,20 @@ pub struct Request {
     // TODO: test what happens if multiple handlers read the body
     // FIXME: create a wrapper that stores the body once read
     body: Box<dyn BodyReader + Send + Sync + 'static>,
+    max_body_size: Option<u64>,
+    on_limit_exceeded: BodyLimitExceededAction,
+    max_drain: u64,
+    /// The connection must be closed after responding because the body was left unread
+    close_connection: bool,
+    /// When the response must be sent by
+    deadline: Option<Instant>,
+    /// The client sent `Expect: 100-continue` and is waiting for a 100 Continue before sending
+    /// the body
+    awaiting_continue: bool,
+    /// The body has been read, rejected or discarded, so the next request on the connection
+    /// comes after it
+    body_read: bool,
+    path_params: PathParams,
 }
 
 #[derive(Debug, PartialEq)]
@@ -60,8 +232,21 @@ pub enum RequestParseError {
     InvalidStartLine(&'static str),
     InvalidHeader(String),
     MissingHostHeader, // HTTP 1.1 requires the Host header to be set
+    InvalidHost(String),
     BodyParseError(String),
+    /// The body couldn't be read or decoded. The status code depends on why
+    InvalidBody(BodyError),
     UnsupportedVersion(String),
+    /// The body is larger than the configured maximum size in bytes
+    ContentTooLarge(u64),
+    /// The query string has more parameters than the configured maximum
+    TooManyQueryParams(usize),
+    /// The start line is longer than the configured maximum in bytes
+    StartLineTooLong(usize),
+    /// The request target is longer than the configured maximum in bytes
+    URITooLong(usize),
+    /// The header section is longer than the configured maximum in bytes
+    HeadTooLarge(usize),
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
@@ -81,12 +266,110 @@ pub enum SyncableStreamType {
 
 pub trait SyncableStream: Read + Write + Send + Sync + 'static {
     fn get_type(&self) -> SyncableStreamType;
+    /// Enable or disable Nagle's algorithm. A no-op for streams that aren't TCP sockets
+    fn set_nodelay(&self, _nodelay: bool) -> Result<(), IoError> {
+        Ok(())
+    }
+    /// Close the connection in both directions. A no-op for streams that aren't sockets
+    fn shutdown(&self) -> Result<(), IoError> {
+        Ok(())
+    }
+    /// Fail reads that block for longer than `timeout`. A no-op for streams that aren't sockets
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl SyncableStream for Box<dyn SyncableStream> {
+    fn get_type(&self) -> SyncableStreamType {
+        self.as_ref().get_type()
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), IoError> {
+        self.as_ref().set_nodelay(nodelay)
+    }
+
+    fn shutdown(&self) -> Result<(), IoError> {
+        self.as_ref().shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        self.as_ref().set_read_timeout(timeout)
+    }
+}
+
+/// Called with the total number of body bytes read so far. Return `false` to abort the read
+pub type BodyProgressCallback = Box<dyn FnMut(u64) -> bool + Send + Sync>;
+
+/// What to do with a body that is larger than `BodyReadOptions::max_size`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BodyLimitExceededAction {
+    /// Read and discard the body so that the client receives the 413 response cleanly.
+    /// Bodies larger than `BodyReadOptions::max_drain` close the connection instead
+    #[default]
+    Reject413,
+    /// Leave the body unread and close the connection after responding
+    CloseConnection,
+}
+
+/// How to respond to requests with `Expect: 100-continue`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExpectPolicy {
+    /// Send 100 Continue when the body is first read
+    #[default]
+    Honor,
+    /// Never send 100 Continue. Clients send the body anyway after waiting for a while
+    Ignore,
+    /// Reject the request with 417 Expectation Failed
+    Reject417,
+}
+
+/// The default maximum number of bytes discarded when rejecting an oversized body
+const DEFAULT_MAX_DRAIN: u64 = 1024 * 1024;
+
+pub struct BodyReadOptions {
+    /// Bodies larger than this many bytes will be rejected
+    pub max_size: Option<u64>,
+    pub on_progress: Option<BodyProgressCallback>,
+    pub on_limit_exceeded: BodyLimitExceededAction,
+    /// The most bytes that will be read and discarded by `BodyLimitExceededAction::Reject413`
+    pub max_drain: u64,
+    /// Fail with `BodyError::Timeout` if the body hasn't been received by this time.
+    /// The connection's read timeout is shortened to enforce it
+    pub deadline: Option<Instant>,
+}
+
+impl Default for BodyReadOptions {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            on_progress: None,
+            on_limit_exceeded: BodyLimitExceededAction::default(),
+            max_drain: DEFAULT_MAX_DRAIN,
+            deadline: None,
+        }
+    }
 }
 
 pub trait BodyReader {
-    fn text(&mut self, mime_info: &MimeParseInfo) -> Result<String, String>;
-    fn json(&mut self, mime_info: &MimeParseInfo) -> Result<Json, String>;
+    fn set_options(&mut self, options: BodyReadOptions);
+    /// The body with any content encodings undone, whatever its MIME type
+    fn bytes(&mut self, mime_info: &MimeParseInfo) -> Result<Vec<u8>, BodyError>;
+    fn text(&mut self, mime_info: &MimeParseInfo) -> Result<String, BodyError>;
+    fn json(&mut self, mime_info: &MimeParseInfo) -> Result<Json, BodyError>;
+    /// Read the body one line at a time without buffering all of it.
+    /// Line endings are stripped
+    fn lines<'a>(&'a mut self, mime_info: &MimeParseInfo) -> Result<BodyLines<'a>, BodyError>;
     fn into_stream(self: Box<Self>) -> Box<dyn SyncableStream>;
+    /// Copy `length` bytes of the body, or the whole chunked body if `length` is `None`, to
+    /// `out` without buffering it. Chunked bodies are copied without their framing
+    fn pipe(&mut self, length: Option<u64>, out: &mut dyn Write) -> Result<u64, IoError>;
+    /// Write `head` to the client followed by `length` bytes of the body
+    fn echo(&mut self, head: &[u8], length: u64) -> Result<u64, IoError>;
+    /// Read and throw away `length` bytes of the body
+    fn discard(&mut self, length: u64) -> Result<u64, IoError>;
+    /// Write an informational (1xx) response and flush it immediately
+    fn write_interim(&mut self, interim: &[u8]) -> Result<(), IoError>;
     // TODO: add multipart parsing. Will require a breaking change
 }
 
@@ -94,7 +377,7 @@ impl FromStr for Path {
     type Err = ();
     fn from_str(path: &str) -> Result<Path, Self::Err> {
         if path.starts_with('/') {
-            Ok(Path::OriginForm(path.to_string()))
+            Ok(Path::OriginForm(path.into()))
         }
         // TODO: support HTTPS
         else if path.starts_with("http://") {
@@ -116,10 +399,38 @@ impl FromStr for Path {
     }
 }
 
+impl Path {
+    /// The query component of an origin-form or absolute-form path, without the leading `?`
+    pub fn raw_query(&self) -> Option<&str> {
+        match self {
+            Path::OriginForm(path) => path.raw_query(),
+            Path::AbsoluteForm(path) => path.split_once('?').map(|(_, query)| query),
+            Path::AuthorityForm(..) | Path::Asterisk => None,
+        }
+    }
+
+    /// Resolve `.` and `..` segments in an origin-form path. Other forms are returned unchanged
+    pub fn normalized(self) -> Self {
+        match self {
+            Path::OriginForm(path) => Path::OriginForm(path.normalized()),
+            other => other,
+        }
+    }
+
+    /// The decoded query parameters of an origin-form path
+    pub fn query(&self) -> Option<&QueryParams> {
+        match self {
+            Path::OriginForm(path) => path.query(),
+            Path::AbsoluteForm(_) | Path::AuthorityForm(..) | Path::Asterisk => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Path {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let content = match self {
-            Path::OriginForm(path) | Path::AbsoluteForm(path) => path,
+            Path::OriginForm(path) => path.as_str(),
+            Path::AbsoluteForm(path) => path,
             Path::AuthorityForm(path, port) => &format!("{path}:{port}"),
             Path::Asterisk => "*",
         };
@@ -128,6 +439,31 @@ impl std::fmt::Display for Path {
     }
 }
 
+/// Whether `s` is a token as defined by https://www.rfc-editor.org/rfc/rfc9110#section-5.6.2
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(
+                    c,
+                    '!' | '#'
+                        | '$'
+                        | '%'
+                        | '&'
+                        | '\''
+                        | '*'
+                        | '+'
+                        | '-'
+                        | '.'
+                        | '^'
+                        | '_'
+                        | '`'
+                        | '|'
+                        | '~'
+                )
+        })
+}
+
 impl FromStr for HTTPMethod {
     type Err = ();
     fn from_str(s: &str) -> Result<HTTPMethod, Self::Err> {
@@ -141,6 +477,7 @@ impl FromStr for HTTPMethod {
             "OPTIONS" => Ok(HTTPMethod::Options),
             "TRACE" => Ok(HTTPMethod::Trace),
             "HEAD" => Ok(HTTPMethod::Head),
+            other if is_token(other) => Ok(HTTPMethod::Other(other.to_string())),
             _ => Err(()),
         }
     }
@@ -148,8 +485,14 @@ impl FromStr for HTTPMethod {
 
 impl Display for HTTPMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let upper_cased = format!("{self:?}").to_uppercase();
-        write!(f, "{upper_cased}")
+        match self {
+            // Methods are case-sensitive, so unknown methods are displayed as received
+            HTTPMethod::Other(method) => write!(f, "{method}"),
+            known => {
+                let upper_cased = format!("{known:?}").to_uppercase();
+                write!(f, "{upper_cased}")
+            }
+        }
     }
 }
 
@@ -188,19 +531,58 @@ impl std::fmt::Display for RequestParseError {
         let prelude = "Failed to parse request.";
         let content = match self {
             Self::BodyParseError(reason) => format!("Could not parse body: {reason}"),
+            Self::InvalidBody(err) => format!("Could not parse body: {err}"),
             Self::InvalidStartLine(reason) => format!("Start line is invalid: {reason}"),
             Self::MissingHostHeader => {
                 "The Host header must be passed in HTTP/1.1 requests".to_string()
             }
+            Self::InvalidHost(reason) => format!("The Host header is invalid: {reason}"),
             Self::InvalidHeader(header_line) => {
                 format!("The following header was invalid: \"{header_line}\"")
             }
             Self::UnsupportedVersion(version) => format!("Unsupported version \"{version}\""),
+            Self::ContentTooLarge(max_size) => {
+                format!("The body is larger than the maximum size of {max_size} bytes")
+            }
+            Self::TooManyQueryParams(max_params) => {
+                format!("The query string has more than {max_params} parameters")
+            }
+            Self::StartLineTooLong(max_length) => {
+                format!("The start line is longer than the maximum of {max_length} bytes")
+            }
+            Self::URITooLong(max_length) => {
+                format!("The request target is longer than the maximum of {max_length} bytes")
+            }
+            Self::HeadTooLarge(max_length) => {
+                format!("The headers are longer than the maximum of {max_length} bytes")
+            }
         };
         write!(f, "{prelude}\n=>{content}")
     }
 }
 
+impl RequestParseError {
+    pub fn as_status_code(&self) -> ResponseStatus {
+        match self {
+            Self::InvalidStartLine(_)
+            | Self::InvalidHeader(_)
+            | Self::MissingHostHeader
+            | Self::InvalidHost(_)
+            | Self::BodyParseError(_) => ResponseStatus::BadRequest,
+            Self::InvalidBody(err) => err.as_status_code(),
+            Self::UnsupportedVersion(_) => ResponseStatus::HTTPVersionNotSupported,
+            Self::ContentTooLarge(_) => ResponseStatus::ContentTooLarge,
+            // Usually overlong because of the target, which is what 414 is for
+            Self::TooManyQueryParams(_) | Self::URITooLong(_) | Self::StartLineTooLong(_) => {
+                ResponseStatus::URITooLong
+            }
+            Self::HeadTooLarge(_) => ResponseStatus::RequestHeaderFieldsTooLarge,
+        }
+    }
+}
+
+impl std::error::Error for RequestParseError {}
+
 impl Request {
     pub fn new<R: SyncableStream>(head: RequestHead, reader: BufReader<R>) -> Self {
         let reader_wrapper = match head.version {
@@ -215,27 +597,382 @@ impl Request {
             }
         };
 
+        // 100 Continue was introduced in HTTP/1.1
+        let awaiting_continue = head.version == HTTPVersion::V1_1
+            && head
+                .headers
+                .get("expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+
         Self {
             head,
             body: Box::new(reader_wrapper),
+            max_body_size: None,
+            on_limit_exceeded: BodyLimitExceededAction::default(),
+            max_drain: DEFAULT_MAX_DRAIN,
+            close_connection: false,
+            deadline: None,
+            awaiting_continue,
+            body_read: false,
+            path_params: PathParams::new(),
         }
     }
 
-    pub fn read_body_text(&mut self) -> Result<String, RequestParseError> {
-        let mime_info = headers::content_type::parse_mime_info(&self.head.headers)?;
-        self.body.text(&mime_info).map_err(|e| {
-            RequestParseError::BodyParseError(format!("Failed to parse body due to '{e}'"))
+    /// The parameters captured by the route that matched this request
+    pub fn path_params(&self) -> &PathParams {
+        &self.path_params
+    }
+
+    /// A parameter captured by the route that matched this request, E.G `id` for `/dogs/:id`
+    pub fn path_param(&self, name: &str) -> Option<&str> {
+        self.path_params.get(name).map(String::as_str)
+    }
+
+    pub(crate) fn set_path_params(&mut self, params: PathParams) {
+        self.path_params = params;
+    }
+
+    /// Parse a complete recorded request (head and body) without a socket, E.G to replay
+    /// captured traffic. The body is truncated to Content-Length if it is set
+    pub fn from_bytes(raw: &[u8]) -> Result<(RequestHead, Vec<u8>), RequestParseError> {
+        let mut reader = std::io::Cursor::new(raw);
+        let (raw_head, consumed) =
+            http1_1::read_head(&mut reader).expect("Reading from memory should not fail");
+        let head = http1_1::parse_req_head(&mut raw_head.lines())?;
+
+        let mut body = raw[consumed..].to_vec();
+        if let Some(length) = head.headers.get("content-length") {
+            let length = usize::from_str(length).or(Err(RequestParseError::InvalidHeader(
+                format!("{length} is not a valid integer"),
+            )))?;
+            if body.len() < length {
+                return Err(RequestParseError::BodyParseError(format!(
+                    "Content-Length ({length}) is greater than the actual length ({0})",
+                    body.len()
+                )));
+            }
+            body.truncate(length);
+        }
+
+        Ok((head, body))
+    }
+
+    /// Apply the server's `ExpectPolicy` to this request.
+    /// Returns `false` if the request should be rejected with 417 Expectation Failed
+    pub fn apply_expect_policy(&mut self, policy: ExpectPolicy) -> bool {
+        match policy {
+            ExpectPolicy::Honor => true,
+            ExpectPolicy::Ignore => {
+                self.awaiting_continue = false;
+                true
+            }
+            ExpectPolicy::Reject417 => !self.awaiting_continue,
+        }
+    }
+
+    /// Tell the client to send the body if it is waiting for permission.
+    /// If the body is never read, the 100 Continue is skipped and the final response
+    /// is sent on its own
+    fn send_continue(&mut self) -> Result<(), RequestParseError> {
+        if !self.awaiting_continue {
+            return Ok(());
+        }
+        self.awaiting_continue = false;
+
+        let interim = format_interim(self.head.version, &ResponseStatus::Continue);
+        self.body.write_interim(interim.as_bytes()).map_err(|e| {
+            RequestParseError::BodyParseError(format!("Failed to send 100 Continue due to '{e}'"))
         })
     }
 
-    pub fn read_body_json(&mut self) -> Result<Json, RequestParseError> {
+    /// Configure how the body will be read. Must be called before reading the body
+    pub fn set_body_options(&mut self, options: BodyReadOptions) {
+        self.max_body_size = options.max_size;
+        self.on_limit_exceeded = options.on_limit_exceeded;
+        self.max_drain = options.max_drain;
+        self.body.set_options(options);
+    }
+
+    /// Set the time by which the response must be sent. Requests that miss their deadline
+    /// are responded to with 504 Gateway Timeout instead
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the connection must be closed after responding to this request
+    pub fn should_close(&self) -> bool {
+        self.close_connection
+    }
+
+    /// Whether the client expects to send another request on this connection after the
+    /// response. HTTP/1.1 connections are persistent unless either side sends
+    /// `Connection: close`, while older versions close them after every response
+    pub fn keep_alive(&self) -> bool {
+        self.head.version == HTTPVersion::V1_1
+            && !self.should_close()
+            && !self
+                .head
+                .headers
+                .get("connection")
+                .is_some_and(|connection| contains_token(connection, "close"))
+    }
+
+    /// Fail fast instead of reading a body that will be rejected anyway
+    fn check_body_size(&mut self, length: u64) -> Result<(), RequestParseError> {
+        let max_size = match self.max_body_size {
+            Some(max_size) if length > max_size => max_size,
+            _ => return Ok(()),
+        };
+
+        // A client waiting for 100 Continue hasn't sent the body, so there's nothing to drain
+        let can_drain = !self.awaiting_continue && length <= self.max_drain;
+        self.awaiting_continue = false;
+        match self.on_limit_exceeded {
+            BodyLimitExceededAction::Reject413 if can_drain => {
+                if self.body.discard(length).is_err() {
+                    self.close_connection = true;
+                }
+            }
+            _ => self.close_connection = true,
+        }
+
+        Err(RequestParseError::ContentTooLarge(max_size))
+    }
+
+    fn parse_mime_info(&mut self) -> Result<MimeParseInfo, RequestParseError> {
         let mime_info = headers::content_type::parse_mime_info(&self.head.headers)?;
-        self.body.json(&mime_info).map_err(|e| {
-            RequestParseError::BodyParseError(format!("Failed to parse body due to '{e}'"))
+        self.body_read = true;
+        self.check_body_size(mime_info.length)?;
+        Ok(mime_info)
+    }
+
+    pub fn read_body_bytes(&mut self) -> Result<Vec<u8>, RequestParseError> {
+        let mime_info = self.parse_mime_info()?;
+        self.send_continue()?;
+        self.body
+            .bytes(&mime_info)
+            .map_err(RequestParseError::InvalidBody)
+    }
+
+    /// Read the body, failing with 413 Content Too Large once it exceeds `max_bytes` or with
+    /// 408 Request Timeout if it hasn't been received by `deadline`, whichever comes first.
+    /// Use this for uploads from untrusted clients so that they can't hold a worker
+    /// indefinitely. Replaces any options set with `set_body_options`
+    pub fn read_body_bounded(
+        &mut self,
+        max_bytes: u64,
+        deadline: Instant,
+    ) -> Result<Vec<u8>, RequestParseError> {
+        self.set_body_options(BodyReadOptions {
+            max_size: Some(max_bytes),
+            on_limit_exceeded: self.on_limit_exceeded,
+            max_drain: self.max_drain,
+            deadline: Some(deadline),
+            ..Default::default()
+        });
+        let result = self.read_body_bytes();
+        if let Err(RequestParseError::InvalidBody(BodyError::Timeout | BodyError::TooLarge(_))) =
+            result
+        {
+            // The rest of the body is still on its way, so the next request can't be found
+            self.close_connection = true;
+        }
+        result
+    }
+
+    pub fn read_body_text(&mut self) -> Result<String, RequestParseError> {
+        let mime_info = self.parse_mime_info()?;
+        self.send_continue()?;
+        self.body
+            .text(&mime_info)
+            .map_err(RequestParseError::InvalidBody)
+    }
+
+    pub fn read_body_json(&mut self) -> Result<Json, RequestParseError> {
+        let mime_info = self.parse_mime_info()?;
+        self.send_continue()?;
+        self.body
+            .json(&mime_info)
+            .map_err(RequestParseError::InvalidBody)
+    }
+
+    /// Parse a JSON body into `T`. Well-formed JSON that doesn't match `T` fails with
+    /// `BodyError::UnexpectedJson` (422 Unprocessable Content) rather than
+    /// `BodyError::MalformedJson` (400 Bad Request)
+    pub fn read_body_json_as<T: DeserializeOwned>(&mut self) -> Result<T, RequestParseError> {
+        serde_json::from_value(self.read_body_json()?)
+            .map_err(|err| RequestParseError::InvalidBody(err.into()))
+    }
+
+    /// Parse an HTML form submitted as application/x-www-form-urlencoded.
+    /// Repeated keys keep every value in order
+    pub fn read_body_form(&mut self) -> Result<QueryParams, RequestParseError> {
+        let mime_info = self.parse_mime_info()?;
+        if mime_info.content_type.sub_type != SubMimeType::FormUrlEncoded {
+            return Err(RequestParseError::InvalidBody(
+                BodyError::UnsupportedMediaType("Not a URL-encoded form".to_string()),
+            ));
+        }
+
+        self.send_continue()?;
+        let bytes = self
+            .body
+            .bytes(&mime_info)
+            .map_err(RequestParseError::InvalidBody)?;
+        // Anything outside of ASCII should have been percent-encoded
+        let form = std::str::from_utf8(&bytes).map_err(|_| {
+            RequestParseError::InvalidBody(BodyError::Decode(
+                "The form is not valid UTF-8".to_string(),
+            ))
+        })?;
+        Ok(parse_query(form))
+    }
+
+    /// Iterate over the lines of the body as they are received
+    pub fn body_lines(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<String, RequestParseError>> + '_, RequestParseError>
+    {
+        let mime_info = self.parse_mime_info()?;
+        self.send_continue()?;
+        let lines = self
+            .body
+            .lines(&mime_info)
+            .map_err(RequestParseError::InvalidBody)?;
+
+        Ok(lines.map(|line| line.map_err(RequestParseError::InvalidBody)))
+    }
+
+    /// Parse a newline-delimited JSON (application/x-ndjson) body into one `T` per record.
+    /// Blank lines are skipped
+    pub fn read_body_ndjson<T: DeserializeOwned>(&mut self) -> Result<Vec<T>, RequestParseError> {
+        let mime_info = self.parse_mime_info()?;
+        if mime_info.content_type.sub_type != SubMimeType::NDJSON {
+            return Err(RequestParseError::InvalidBody(
+                BodyError::UnsupportedMediaType("Not newline-delimited JSON".to_string()),
+            ));
+        }
+
+        let mut records = Vec::new();
+        for (line_no, line) in self.body_lines()?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str::<T>(&line).map_err(|reason| {
+                RequestParseError::BodyParseError(format!(
+                    "Failed to decode JSON on line {0} because: '{reason}'",
+                    line_no + 1
+                ))
+            })?);
+        }
+        Ok(records)
+    }
+
+    /// The body's length, or `None` if it is chunked
+    fn body_length(&mut self) -> Result<Option<u64>, RequestParseError> {
+        let headers = &self.head.headers;
+        if headers
+            .get("transfer-encoding")
+            .is_some_and(|te| headers::content_type::is_chunked(te))
+        {
+            return Ok(None);
+        }
+        let length = headers.get("content-length").map_or(Ok(0), |len| {
+            u64::from_str(len).or(Err(RequestParseError::InvalidHeader(format!(
+                "{len} is not a valid integer"
+            ))))
+        })?;
+
+        self.check_body_size(length)?;
+        Ok(Some(length))
+    }
+
+    /// Once the body has been streamed it has either been consumed or the connection is left
+    /// somewhere in the middle of it, so the next request can't be found
+    fn finish_streamed_body<T>(&mut self, result: &Result<T, IoError>) {
+        self.body_read = true;
+        if result.is_err() {
+            self.close_connection = true;
+        }
+    }
+
+    /// Copy the body to `out` as it is received instead of buffering it, E.G to forward it
+    /// to an upstream server. Chunked bodies are copied without their framing.
+    /// Returns the number of bytes copied
+    pub fn pipe_body(&mut self, out: &mut dyn Write) -> Result<u64, RequestParseError> {
+        let length = self.body_length()?;
+        self.send_continue()?;
+        let result = self.body.pipe(length, out);
+        self.finish_streamed_body(&result);
+        result.map_err(|e| {
+            RequestParseError::BodyParseError(format!("Failed to pipe body due to '{e}'"))
+        })
+    }
+
+    /// Respond with the request's body as it is received instead of buffering it.
+    /// The response has the same Content-Length and Content-Type as the request
+    /// Chunked bodies are refused because the length of the response isn't known up front
+    pub fn echo_body(mut self, status: ResponseStatus) -> Result<u64, RequestParseError> {
+        let Some(length) = self.body_length()? else {
+            return Err(RequestParseError::BodyParseError(
+                "Chunked bodies can't be echoed because the response needs a Content-Length"
+                    .to_string(),
+            ));
+        };
+        self.send_continue()?;
+
+        let mut headers = HTTPHeaders::from([("content-length".to_string(), length.to_string())]);
+        if let Some(content_type) = self.head.headers.get("content-type") {
+            headers.insert("content-type".to_string(), content_type.clone());
+        }
+        let head = match self.head.version {
+            // HTTP/0.9 responses are just the body
+            HTTPVersion::V0_9 => String::new(),
+            version => format_head(version, &status, &headers),
+        };
+
+        let result = self.body.echo(head.as_bytes(), length);
+        self.finish_streamed_body(&result);
+        result.map_err(|e| {
+            RequestParseError::BodyParseError(format!("Failed to echo body due to '{e}'"))
         })
     }
 
-    pub fn into_stream(self) -> Box<dyn SyncableStream> {
+    /// Throw away a body that was never read so that the next request on the connection can be
+    /// read. The connection is marked to be closed instead if the body can't be drained cheaply
+    pub fn discard_unread_body(&mut self) {
+        if self.body_read {
+            return;
+        }
+        self.body_read = true;
+
+        let headers = &self.head.headers;
+        let length = match headers.get("content-length").map(|len| u64::from_str(len)) {
+            _ if headers.contains_key("transfer-encoding") => None,
+            Some(Ok(length)) => Some(length),
+            Some(Err(_)) => None,
+            None => Some(0),
+        };
+        match length {
+            Some(0) => {}
+            // A client waiting for 100 Continue may or may not send the body now
+            _ if self.awaiting_continue => self.close_connection = true,
+            Some(length) if length <= self.max_drain => {
+                if self.body.discard(length).is_err() {
+                    self.close_connection = true;
+                }
+            }
+            _ => self.close_connection = true,
+        }
+    }
+
+    pub fn into_stream(mut self) -> Box<dyn SyncableStream> {
+        self.discard_unread_body();
         self.body.into_stream()
     }
 }
@@ -293,12 +1030,12 @@ mod path_tests {
     #[test]
     fn path_parse_origin_form() {
         assert_eq!(
-            Path::OriginForm("/".to_string()),
+            Path::OriginForm("/".into()),
             Path::from_str("/").expect("Parsing / should succeed")
         );
 
         assert_eq!(
-            Path::OriginForm("/echo/falls/spring".to_string()),
+            Path::OriginForm("/echo/falls/spring".into()),
             Path::from_str("/echo/falls/spring")
                 .expect("Parsing a nested origin-form path should succeed")
         );
@@ -307,7 +1044,7 @@ mod path_tests {
     #[test]
     fn path_parse_origin_form_file_extension() {
         assert_eq!(
-            Path::OriginForm("/spring.html".to_string()),
+            Path::OriginForm("/spring.html".into()),
             Path::from_str("/spring.html")
                 .expect("Parsing an origin-form path with a file extension should succeed")
         );
@@ -431,4 +1168,624 @@ mod method_tests {
             HTTPMethod::from_str("HEAD").expect("Parsing HEAD should succeed")
         );
     }
+
+    #[test]
+    fn method_parse_other() {
+        assert_eq!(
+            HTTPMethod::Other("PROPFIND".to_string()),
+            HTTPMethod::from_str("PROPFIND").expect("Parsing a WebDAV method should succeed")
+        );
+        assert_eq!(
+            HTTPMethod::Other("PROPFIND".to_string()).to_string(),
+            "PROPFIND",
+            "Unknown methods should be displayed as received"
+        );
+    }
+
+    #[test]
+    fn method_parse_invalid() {
+        HTTPMethod::from_str("").expect_err("Parsing an empty method should fail");
+        HTTPMethod::from_str("GE(T").expect_err("Parsing a non-token method should fail");
+    }
+}
+
+#[cfg(test)]
+mod request_tests {
+    use std::io::{BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use serde::Deserialize;
+
+    use super::{
+        BodyError, BodyLimitExceededAction, BodyReadOptions, ExpectPolicy, Request,
+        RequestParseError,
+    };
+
+    use crate::{
+        server::response::{ResponseBuilder, ResponseStatus},
+        testing::mock_request,
+    };
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Event {
+        id: u32,
+        kind: String,
+    }
+
+    #[test]
+    fn request_from_bytes() {
+        let recorded = b"POST /dogs HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/json\r\nContent-Length: 17\r\n\r\n{\"name\":\"Alfred\"}";
+
+        let (head, body) =
+            Request::from_bytes(recorded).expect("Parsing a recorded request should succeed");
+        assert_eq!(head.method, crate::request::HTTPMethod::Post);
+        assert_eq!(head.path.to_string(), "/dogs");
+        assert_eq!(head.headers.get("host"), Some(&"a.com".to_string()));
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&body).expect("The recorded body should be valid JSON");
+        assert_eq!(json["name"], "Alfred");
+
+        Request::from_bytes(&recorded[..recorded.len() - 1])
+            .expect_err("A truncated body should be rejected");
+    }
+
+    #[test]
+    fn read_ndjson() {
+        let body = b"{\"id\":1,\"kind\":\"start\"}\n{\"id\":2,\"kind\":\"tick\"}\r\n\n{\"id\":3,\"kind\":\"stop\"}\n";
+        let (mut request, _) = mock_request(
+            &format!(
+                "POST /events HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/x-ndjson\r\nContent-Length: {0}\r\n",
+                body.len()
+            ),
+            body,
+        );
+
+        let events = request
+            .read_body_ndjson::<Event>()
+            .expect("Parsing the NDJSON body should succeed");
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    id: 1,
+                    kind: "start".to_string()
+                },
+                Event {
+                    id: 2,
+                    kind: "tick".to_string()
+                },
+                Event {
+                    id: 3,
+                    kind: "stop".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_ndjson_chunked() {
+        // The second record is split across chunks
+        let body = b"18\r\n{\"id\":1,\"kind\":\"start\"}\n\r\nA\r\n{\"id\":2,\"k\r\nD\r\nind\":\"tick\"}\n\r\n0\r\n\r\n";
+        let (mut request, _) = mock_request(
+            "POST /events HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n",
+            body,
+        );
+
+        let events = request
+            .read_body_ndjson::<Event>()
+            .expect("Parsing the chunked NDJSON body should succeed");
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    id: 1,
+                    kind: "start".to_string()
+                },
+                Event {
+                    id: 2,
+                    kind: "tick".to_string()
+                },
+            ]
+        );
+
+        let (mut request, _) = mock_request(
+            "POST /events HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n",
+            b"5\r\n{}\n\r\n0\r\n\r\n",
+        );
+        request
+            .read_body_ndjson::<serde_json::Value>()
+            .expect_err("Invalid chunk framing should be an error rather than no records");
+    }
+
+    #[test]
+    fn read_ndjson_wrong_type() {
+        let (mut request, _) = mock_request(
+            "POST /events HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/json\r\nContent-Length: 2\r\n",
+            b"{}",
+        );
+        request
+            .read_body_ndjson::<serde_json::Value>()
+            .expect_err("A JSON body should not be parsed as NDJSON");
+    }
+
+    fn oversized_request(options: BodyReadOptions) -> Request {
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: text/plain\r\nContent-Length: 20\r\n",
+            b"01234567890123456789",
+        );
+        request.set_body_options(BodyReadOptions {
+            max_size: Some(10),
+            ..options
+        });
+        request
+    }
+
+    fn unread(request: Request) -> String {
+        let mut rest = String::new();
+        request
+            .into_stream()
+            .read_to_string(&mut rest)
+            .expect("The rest of the stream should be readable");
+        rest
+    }
+
+    #[test]
+    fn body_limit_reject_drains() {
+        let mut request = oversized_request(BodyReadOptions::default());
+        let err = request
+            .read_body_text()
+            .expect_err("Reading a body larger than the maximum size should fail");
+        assert_eq!(err.as_status_code(), ResponseStatus::ContentTooLarge);
+        assert!(!request.should_close());
+        assert_eq!(
+            unread(request),
+            "",
+            "The oversized body should be discarded"
+        );
+    }
+
+    #[test]
+    fn body_limit_reject_drain_bounded() {
+        let mut request = oversized_request(BodyReadOptions {
+            max_drain: 15,
+            ..Default::default()
+        });
+        request
+            .read_body_text()
+            .expect_err("Reading a body larger than the maximum size should fail");
+        assert!(
+            request.should_close(),
+            "Bodies larger than the drain limit should close the connection"
+        );
+        assert_eq!(unread(request), "01234567890123456789");
+    }
+
+    #[test]
+    fn body_limit_close_connection() {
+        let mut request = oversized_request(BodyReadOptions {
+            on_limit_exceeded: BodyLimitExceededAction::CloseConnection,
+            ..Default::default()
+        });
+        request
+            .read_body_text()
+            .expect_err("Reading a body larger than the maximum size should fail");
+        assert!(request.should_close());
+        assert_eq!(
+            unread(request),
+            "01234567890123456789",
+            "The body should be left unread"
+        );
+    }
+
+    #[test]
+    fn bounded_body_too_large() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/octet-stream\r\nContent-Length: 20\r\n",
+            b"01234567890123456789",
+        );
+        let err = request
+            .read_body_bounded(10, deadline)
+            .expect_err("A declared length over the cap should be rejected");
+        assert_eq!(err.as_status_code(), ResponseStatus::ContentTooLarge);
+
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/octet-stream\r\nTransfer-Encoding: chunked\r\n",
+            b"a\r\n0123456789\r\na\r\n0123456789\r\n0\r\n\r\n",
+        );
+        let err = request
+            .read_body_bounded(10, deadline)
+            .expect_err("A chunked body over the cap should be rejected");
+        assert_eq!(err, RequestParseError::InvalidBody(BodyError::TooLarge(10)));
+        assert_eq!(err.as_status_code(), ResponseStatus::ContentTooLarge);
+        assert!(request.should_close());
+
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/octet-stream\r\nContent-Length: 10\r\n",
+            b"0123456789",
+        );
+        let body = request
+            .read_body_bounded(10, deadline)
+            .expect("A body within both limits should be read");
+        assert_eq!(body, b"0123456789");
+    }
+
+    #[test]
+    fn bounded_body_timeout() {
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let mut client =
+            TcpStream::connect(server.local_addr().unwrap()).expect("Connecting should succeed");
+        let (stream, _) = server.accept().expect("Accepting should succeed");
+        // The client stalls after sending part of the body
+        client.write_all(b"01234").unwrap();
+
+        let head = super::http1_1::parse_req_head(
+            &mut "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/octet-stream\r\nContent-Length: 20\r\n".lines(),
+        )
+        .expect("The request head should be valid");
+        let mut request = Request::new(head, BufReader::new(stream));
+        let started = Instant::now();
+        let err = request
+            .read_body_bounded(1024, started + Duration::from_millis(100))
+            .expect_err("A stalled body should time out");
+        assert_eq!(err, RequestParseError::InvalidBody(BodyError::Timeout));
+        assert_eq!(err.as_status_code(), ResponseStatus::RequestTimeout);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "The read should stop at the deadline"
+        );
+        assert!(request.should_close());
+    }
+
+    #[test]
+    fn unread_body_discarded() {
+        let (request, _) = mock_request(
+            "POST /dogs HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n",
+            b"helloGET /dogs HTTP/1.1\r\nHost: a.com\r\n\r\n",
+        );
+        assert!(request.keep_alive());
+        assert_eq!(
+            unread(request),
+            "GET /dogs HTTP/1.1\r\nHost: a.com\r\n\r\n",
+            "Only the next request should be left on the connection"
+        );
+
+        let (request, _) = mock_request(
+            "GET /dogs HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n",
+            b"",
+        );
+        assert!(!request.keep_alive());
+        let (request, _) = mock_request("GET /dogs HTTP/1.0\r\n", b"");
+        assert!(
+            !request.keep_alive(),
+            "HTTP/1.0 connections should be closed by default"
+        );
+    }
+
+    fn body_error(raw_head: &str, body: &[u8]) -> RequestParseError {
+        let (mut request, _) = mock_request(
+            &format!("{raw_head}Content-Length: {}\r\n", body.len()),
+            body,
+        );
+        request
+            .read_body_json_as::<Event>()
+            .expect_err("Reading the body should fail")
+    }
+
+    #[test]
+    fn body_error_status_codes() {
+        let json_head = "POST /dogs HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/json\r\n";
+
+        let err = body_error(json_head, b"{\"id\": 7");
+        assert!(
+            matches!(
+                err,
+                RequestParseError::InvalidBody(BodyError::MalformedJson(_))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.as_status_code().to_code(), 400);
+
+        let err = body_error(json_head, b"{\"id\": 7, \"kind\": \"\xff\"}");
+        assert!(
+            matches!(err, RequestParseError::InvalidBody(BodyError::Decode(_))),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.as_status_code().to_code(),
+            400,
+            "Invalid UTF-8 should be a bad request"
+        );
+
+        let err = body_error(
+            "POST /dogs HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/json; charset=klingon\r\n",
+            b"{}",
+        );
+        assert_eq!(
+            err,
+            RequestParseError::InvalidBody(BodyError::UnsupportedCharset("klingon".to_string()))
+        );
+        assert_eq!(err.as_status_code().to_code(), 415);
+
+        let err = body_error(
+            "POST /dogs HTTP/1.1\r\nHost: a.com\r\nContent-Type: text/plain\r\n",
+            b"Alfred",
+        );
+        assert!(
+            matches!(
+                err,
+                RequestParseError::InvalidBody(BodyError::UnsupportedMediaType(_))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.as_status_code().to_code(), 415);
+
+        let err = body_error(json_head, b"{\"id\": \"seven\", \"kind\": \"bark\"}");
+        assert!(
+            matches!(
+                err,
+                RequestParseError::InvalidBody(BodyError::UnexpectedJson(_))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.as_status_code().to_code(),
+            422,
+            "Well-formed JSON with the wrong shape should be unprocessable"
+        );
+    }
+
+    #[test]
+    fn form_body() {
+        let form = b"name=Ada+Lovelace&empty=&flag&q=fish%26chips&tag=a&tag=b";
+        let (mut request, _) = mock_request(
+            &format!(
+                "POST /form HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n",
+                form.len()
+            ),
+            form,
+        );
+        let form = request
+            .read_body_form()
+            .expect("Reading a URL-encoded form should succeed");
+        assert_eq!(form["name"], vec!["Ada Lovelace"]);
+        assert_eq!(form["empty"], vec![""]);
+        assert_eq!(form["flag"], vec![""]);
+        assert_eq!(
+            form["q"],
+            vec!["fish&chips"],
+            "Encoded ampersands should not split the value"
+        );
+        assert_eq!(form["tag"], vec!["a", "b"]);
+
+        let (mut request, _) = mock_request(
+            "POST /form HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/json\r\nContent-Length: 2\r\n",
+            b"{}",
+        );
+        request
+            .read_body_form()
+            .expect_err("A JSON body should not be parsed as a form");
+    }
+
+    #[test]
+    fn binary_body_bytes() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\xff\xfe";
+        let (mut request, _) = mock_request(
+            &format!(
+                "POST /upload HTTP/1.1\r\nHost: a.com\r\nContent-Type: image/png\r\nContent-Length: {}\r\n",
+                png.len()
+            ),
+            png,
+        );
+        let bytes = request
+            .read_body_bytes()
+            .expect("Reading a PNG body should succeed");
+        assert_eq!(bytes, png, "The body should be byte-identical");
+
+        let ttf = [0x00, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x80, 0xc3];
+        let (mut request, _) = mock_request(
+            "POST /fonts HTTP/1.1\r\nHost: a.com\r\nContent-Type: font/ttf\r\nContent-Length: 8\r\n",
+            &ttf,
+        );
+        assert_eq!(
+            request
+                .read_body_bytes()
+                .expect("Reading a TTF body should succeed"),
+            ttf
+        );
+    }
+
+    #[test]
+    fn expect_continue_in_order() {
+        let (mut request, output) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nExpect: 100-continue\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n",
+            b"hello",
+        );
+        assert_eq!(
+            request
+                .read_body_text()
+                .expect("Reading the body should succeed"),
+            "hello"
+        );
+
+        ResponseBuilder::from(request)
+            .ok()
+            .body("world".to_string())
+            .build()
+            .expect("A valid response should be constructed")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"),
+            "The 100 Continue should be sent before the final response. Written: {written:?}"
+        );
+        assert!(written.ends_with("world"));
+    }
+
+    #[test]
+    fn expect_continue_skipped_when_body_unread() {
+        let (request, output) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nExpect: 100-continue\r\nContent-Length: 5\r\n",
+            b"hello",
+        );
+
+        ResponseBuilder::from(request)
+            .status(crate::server::response::ResponseStatus::Forbidden)
+            .build()
+            .expect("A valid response should be constructed")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "No 100 Continue should be sent if the body isn't read. Written: {written:?}"
+        );
+    }
+
+    fn expect_continue_request() -> (Request, Arc<Mutex<Vec<u8>>>) {
+        mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nExpect: 100-continue\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n",
+            b"hello",
+        )
+    }
+
+    #[test]
+    fn expect_policy_honor() {
+        let (mut request, output) = expect_continue_request();
+        assert!(request.apply_expect_policy(ExpectPolicy::Honor));
+        request
+            .read_body_text()
+            .expect("Reading the body should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(written, "HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn expect_policy_ignore() {
+        let (mut request, output) = expect_continue_request();
+        assert!(request.apply_expect_policy(ExpectPolicy::Ignore));
+        assert_eq!(
+            request
+                .read_body_text()
+                .expect("Reading the body should succeed"),
+            "hello"
+        );
+
+        assert!(
+            output.lock().unwrap().is_empty(),
+            "No 100 Continue should be sent when Expect is ignored"
+        );
+    }
+
+    #[test]
+    fn expect_policy_reject() {
+        let (mut request, _) = expect_continue_request();
+        assert!(
+            !request.apply_expect_policy(ExpectPolicy::Reject417),
+            "Requests expecting 100 Continue should be rejected"
+        );
+
+        let (mut request, _) = mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        assert!(
+            request.apply_expect_policy(ExpectPolicy::Reject417),
+            "Requests without Expect should be unaffected"
+        );
+    }
+
+    #[test]
+    fn echo_large_body() {
+        let body: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let (request, output) = mock_request(
+            "POST /echo HTTP/1.1\r\nHost: a.com\r\nContent-Type: application/octet-stream\r\nContent-Length: 1000000\r\n",
+            &body,
+        );
+
+        let copied = request
+            .echo_body(ResponseStatus::OK)
+            .expect("Echoing the body should succeed");
+        assert_eq!(copied, 1_000_000);
+
+        let written = output.lock().unwrap();
+        let head_end = written
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("The response should have a head")
+            + 4;
+        let head = String::from_utf8_lossy(&written[..head_end]);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Length: 1000000\r\n"));
+        assert!(head.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(
+            written[head_end..] == body[..],
+            "The body should be echoed unchanged"
+        );
+    }
+
+    #[test]
+    fn pipe_body() {
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nContent-Length: 5\r\n",
+            b"helloEXTRA",
+        );
+
+        let mut upstream = Vec::new();
+        let copied = request
+            .pipe_body(&mut upstream)
+            .expect("Piping the body should succeed");
+        assert_eq!(copied, 5);
+        assert_eq!(
+            upstream, b"hello",
+            "Only Content-Length bytes should be piped"
+        );
+    }
+
+    #[test]
+    fn pipe_chunked_body() {
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n",
+            b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\nGET /next HTTP/1.1\r\n",
+        );
+
+        let mut upstream = Vec::new();
+        let copied = request
+            .pipe_body(&mut upstream)
+            .expect("Piping a chunked body should succeed");
+        assert_eq!(copied, 11);
+        assert_eq!(upstream, b"hello world", "The framing should be removed");
+        assert!(!request.should_close());
+
+        let (mut request, _) = mock_request(
+            "POST / HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n",
+            b"5\r\nhel",
+        );
+        request
+            .pipe_body(&mut Vec::new())
+            .expect_err("A truncated chunked body should fail to pipe");
+        assert!(
+            request.should_close(),
+            "The connection can't be reused after part of the body was read"
+        );
+    }
+
+    #[test]
+    fn echo_chunked_body_refused() {
+        let (request, output) = mock_request(
+            "POST /echo HTTP/1.1\r\nHost: a.com\r\nTransfer-Encoding: chunked\r\n",
+            b"5\r\nhello\r\n0\r\n\r\n",
+        );
+        request
+            .echo_body(ResponseStatus::OK)
+            .expect_err("Chunked bodies can't be echoed with a Content-Length");
+        assert!(
+            output.lock().unwrap().is_empty(),
+            "Nothing should be sent for a refused echo"
+        );
+    }
 }
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::request::{HTTPMethod, Path, Request};
+
+use super::handlers::{HandlerRegistryAddError, RequestDispatcher, SyncableHandler};
+use super::response::{Response, ResponseBuilder};
+
+/// The path prefix that ACME servers fetch HTTP-01 challenge responses from
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Maps challenge tokens to their key authorizations
+pub type AcmeTokens = Arc<Mutex<HashMap<String, String>>>;
+
+/// Answers ACME HTTP-01 challenges (E.G from Let's Encrypt) so the server can act as its own
+/// ACME responder. `GET /.well-known/acme-challenge/<token>` is answered with the token's key
+/// authorization, or 404 for unknown tokens. Every other request is passed on to `inner`.
+/// Tokens can be added and removed through the shared map while the server is running
+pub struct AcmeChallengeHandler<D> {
+    tokens: AcmeTokens,
+    inner: D,
+}
+
+impl<D: RequestDispatcher> AcmeChallengeHandler<D> {
+    pub fn new(tokens: AcmeTokens, inner: D) -> Self {
+        Self { tokens, inner }
+    }
+
+    /// The token from a challenge request's path, if it is one
+    fn challenge_token(req: &Request) -> Option<String> {
+        if req.head.method != HTTPMethod::Get {
+            return None;
+        }
+        let Path::OriginForm(path) = &req.head.path else {
+            return None;
+        };
+        path.path()
+            .strip_prefix(ACME_CHALLENGE_PREFIX)
+            .filter(|token| !token.is_empty() && !token.contains('/'))
+            .map(str::to_string)
+    }
+}
+
+impl<D: RequestDispatcher> RequestDispatcher for AcmeChallengeHandler<D> {
+    type Error = D::Error;
+
+    fn add(&mut self, handler: Arc<SyncableHandler>) -> Result<(), HandlerRegistryAddError> {
+        self.inner.add(handler)
+    }
+
+    fn dispatch(&self, req: Request) -> Result<Response, D::Error> {
+        let Some(token) = Self::challenge_token(&req) else {
+            return self.inner.dispatch(req);
+        };
+
+        let key_authorization = self
+            .tokens
+            .lock()
+            .expect("The ACME token lock should not be poisoned")
+            .get(&token)
+            .cloned();
+        let builder = ResponseBuilder::from(req);
+        let builder = match key_authorization {
+            Some(key_authorization) => builder.ok().text(&key_authorization),
+            None => {
+                log::debug!("Unknown ACME challenge token {token}");
+                builder.not_found().text("Unknown challenge token")
+            }
+        };
+        Ok(builder
+            .build()
+            .expect("A valid ACME challenge response should be produced"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::handlers::HandlerRegistry;
+    use crate::server::response::ResponseStatus;
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    fn acme_handler() -> AcmeChallengeHandler<HandlerRegistry> {
+        let tokens = AcmeTokens::default();
+        tokens
+            .lock()
+            .unwrap()
+            .insert("abc123".to_string(), "abc123.thumbprint".to_string());
+        AcmeChallengeHandler::new(tokens, HandlerRegistry::new(Vec::new()))
+    }
+
+    #[test]
+    fn serve_known_token() {
+        let (req, _) = mock_request(
+            "GET /.well-known/acme-challenge/abc123 HTTP/1.1\r\nHost: example.com\r\n",
+            b"",
+        );
+        let response = acme_handler()
+            .dispatch(req)
+            .expect("A known token should be served");
+
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(response.body(), b"abc123.thumbprint");
+        assert_eq!(
+            response.get_header("Content-Type".to_string()),
+            Some("text/plain; charset=UTF-8".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_token_not_found() {
+        let (req, _) = mock_request(
+            "GET /.well-known/acme-challenge/missing HTTP/1.1\r\nHost: example.com\r\n",
+            b"",
+        );
+        let response = acme_handler()
+            .dispatch(req)
+            .expect("Unknown tokens should be answered by the ACME handler");
+
+        assert_eq!(*response.status(), ResponseStatus::NotFound);
+    }
+
+    #[test]
+    fn other_paths_passed_on() {
+        let (req, _) = mock_request("GET /dogs HTTP/1.1\r\nHost: example.com\r\n", b"");
+        acme_handler()
+            .dispatch(req)
+            .expect_err("Other paths should be dispatched to the inner dispatcher");
+    }
+}
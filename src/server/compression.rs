@@ -0,0 +1,406 @@
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+
+use crate::request::accept_encoding::negotiate_encoding;
+use crate::request::content_type::ContentEncoding;
+
+use super::response::{Response, ResponseStatus};
+
+/// Trades compression speed for size. Suitable for dynamic responses
+pub const DEFAULT_BROTLI_QUALITY: u32 = 5;
+/// zstd's own default level
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// The base-2 logarithm of brotli's window size. 22 (4 MiB) is the encoder's default
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// Smaller bodies aren't worth compressing. They fit in a single packet anyway and gzip adds
+/// 18 bytes of framing
+pub const DEFAULT_MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Settings for compressing response bodies
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// MIME types (without parameters) that are worth compressing.
+    /// A trailing `/*` matches every subtype, E.G `text/*`
+    compressible_types: Vec<String>,
+    /// From 0 (fastest) to 11 (smallest)
+    brotli_quality: u32,
+    /// From 1 (fastest) to 22 (smallest)
+    zstd_level: i32,
+    /// Bodies smaller than this many bytes are sent uncompressed
+    min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    /// Text-based formats. Media types such as images are usually compressed already
+    fn default() -> Self {
+        Self {
+            compressible_types: [
+                "text/*",
+                "application/json",
+                "application/ld+json",
+                "application/xml",
+                "application/xhtml+xml",
+                "application/javascript",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+            brotli_quality: DEFAULT_BROTLI_QUALITY,
+            zstd_level: DEFAULT_ZSTD_LEVEL,
+            min_size: DEFAULT_MIN_COMPRESS_SIZE,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Start with an empty allowlist instead of the defaults
+    pub fn empty() -> Self {
+        Self {
+            compressible_types: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the brotli quality from 0 (fastest) to 11 (smallest).
+    /// E.G 4 for dynamic responses and 11 for static files that are compressed once
+    pub fn brotli_quality(mut self, quality: u32) -> Self {
+        assert!(quality <= 11, "{quality} is not a valid brotli quality");
+        self.brotli_quality = quality;
+        self
+    }
+
+    /// Set the zstd level from 1 (fastest) to 22 (smallest)
+    pub fn zstd_level(mut self, level: i32) -> Self {
+        assert!(
+            (1..=22).contains(&level),
+            "{level} is not a valid zstd level"
+        );
+        self.zstd_level = level;
+        self
+    }
+
+    /// Send bodies smaller than `min_size` bytes uncompressed
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Compress `body` with the configured quality for `encoding`.
+    /// Returns `None` if the encoding isn't supported
+    pub fn compress(&self, encoding: ContentEncoding, body: &[u8]) -> Option<Vec<u8>> {
+        match encoding {
+            ContentEncoding::Br => {
+                let mut writer = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    BROTLI_BUFFER_SIZE,
+                    self.brotli_quality,
+                    BROTLI_WINDOW,
+                );
+                writer
+                    .write_all(body)
+                    .expect("Compressing into memory should not fail");
+                Some(writer.into_inner())
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(body)
+                    .expect("Compressing into memory should not fail");
+                Some(
+                    encoder
+                        .finish()
+                        .expect("Compressing into memory should not fail"),
+                )
+            }
+            ContentEncoding::Zstd => Some(
+                zstd::bulk::compress(body, self.zstd_level)
+                    .expect("Compressing into memory should not fail"),
+            ),
+            ContentEncoding::Identity => Some(body.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Add a MIME type, E.G `application/wasm`, to the allowlist
+    pub fn compressible(mut self, mime_type: &str) -> Self {
+        self.compressible_types.push(mime_type.to_lowercase());
+        self
+    }
+
+    /// Whether a body with the given Content-Type should be compressed.
+    /// Parameters such as charset are ignored
+    pub fn is_compressible(&self, content_type: &str) -> bool {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or(content_type)
+            .trim()
+            .to_lowercase();
+
+        self.compressible_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(main_type) => essence
+                    .split_once('/')
+                    .is_some_and(|(essence_main, _)| essence_main == main_type),
+                None => *allowed == essence,
+            })
+    }
+
+    /// Compress the response's body with gzip if the client's Accept-Encoding allows it and
+    /// the body is compressible and at least `min_size` bytes. Content-Length is updated to
+    /// match, and a strong ETag is weakened because the bytes sent no longer match the
+    /// identity representation it was computed for.
+    /// Streamed bodies, ranges and bodies that are already encoded are left alone
+    pub fn compress_response(&self, response: &mut Response, accept_encoding: Option<&str>) {
+        let is_candidate = response.body.len() >= self.min_size
+            && *response.status() != ResponseStatus::PartialContent
+            && response
+                .get_header("Content-Encoding".to_string())
+                .is_none()
+            && response
+                .get_header("Content-Type".to_string())
+                .is_some_and(|content_type| self.is_compressible(&content_type));
+        if !is_candidate {
+            return;
+        }
+        // Caches mustn't send the compressed body to clients that can't decode it, even when
+        // this client can't either
+        response.add_vary("Accept-Encoding");
+
+        let negotiated = accept_encoding.and_then(|accept_encoding| {
+            negotiate_encoding(
+                accept_encoding,
+                &[ContentEncoding::Gzip, ContentEncoding::Identity],
+            )
+        });
+        if negotiated != Some(ContentEncoding::Gzip) {
+            return;
+        }
+        let compressed = self
+            .compress(ContentEncoding::Gzip, &response.body)
+            .expect("gzip is supported");
+        if !response.is_chunked() {
+            response.set_header("Content-Length".to_string(), compressed.len().to_string());
+        }
+        response.set_header("Content-Encoding".to_string(), "gzip".to_string());
+        // Otherwise a Range request validated with the ETag could be answered with bytes of
+        // the uncompressed body. See https://www.rfc-editor.org/rfc/rfc9110#section-8.8.3.3
+        if let Some(etag) = response
+            .get_header("ETag".to_string())
+            .filter(|etag| !etag.starts_with("W/"))
+        {
+            response.set_header("ETag".to_string(), format!("W/{etag}"));
+        }
+        response.body = compressed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::server::response::ResponseBuilder;
+
+    use super::*;
+
+    /// Repetitive enough to compress well, varied enough for the quality to matter
+    fn compressible_body() -> Vec<u8> {
+        (0..2000)
+            .map(|i| {
+                format!(
+                    "{{\"id\":{i},\"name\":\"dog-{0}\",\"good\":true}}\n",
+                    i % 37
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn higher_quality_is_smaller() {
+        let body = compressible_body();
+
+        let fast = CompressionConfig::default().brotli_quality(1).zstd_level(1);
+        let small = CompressionConfig::default()
+            .brotli_quality(11)
+            .zstd_level(19);
+        for encoding in [ContentEncoding::Br, ContentEncoding::Zstd] {
+            let fast = fast
+                .compress(encoding, &body)
+                .expect("The encoding is supported");
+            let small = small
+                .compress(encoding, &body)
+                .expect("The encoding is supported");
+            assert!(
+                small.len() <= fast.len(),
+                "A higher {encoding} quality should not produce a larger output. Got {0} > {1}",
+                small.len(),
+                fast.len()
+            );
+            assert!(fast.len() < body.len());
+        }
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let body = compressible_body();
+        let config = CompressionConfig::default();
+
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(
+            config
+                .compress(ContentEncoding::Br, &body)
+                .expect("brotli is supported")
+                .as_slice(),
+            BROTLI_BUFFER_SIZE,
+        )
+        .read_to_end(&mut decompressed)
+        .expect("The brotli output should be valid");
+        assert_eq!(decompressed, body);
+
+        let decompressed = zstd::decode_all(
+            config
+                .compress(ContentEncoding::Zstd, &body)
+                .expect("zstd is supported")
+                .as_slice(),
+        )
+        .expect("The zstd output should be valid");
+        assert_eq!(decompressed, body);
+
+        assert_eq!(config.compress(ContentEncoding::Compress, &body), None);
+    }
+
+    #[test]
+    fn default_compressible_types() {
+        let config = CompressionConfig::default();
+        assert!(config.is_compressible("text/html; charset=UTF-8"));
+        assert!(config.is_compressible("application/json"));
+        assert!(config.is_compressible("image/svg+xml"));
+        assert!(!config.is_compressible("image/png"));
+        assert!(!config.is_compressible("application/wasm"));
+    }
+
+    #[test]
+    fn custom_compressible_type() {
+        let config = CompressionConfig::default().compressible("application/wasm");
+        assert!(
+            config.is_compressible("application/wasm"),
+            "Types added to the allowlist should be compressed"
+        );
+        assert!(
+            config.is_compressible("text/css"),
+            "The defaults should be kept"
+        );
+
+        let config = CompressionConfig::empty().compressible("application/wasm");
+        assert!(!config.is_compressible("text/css"));
+    }
+
+    fn respond(accept_encoding: &str, content_type: &str, body: Vec<u8>) -> Response {
+        let (request, _) = crate::testing::mock_request(
+            &format!("GET / HTTP/1.1\r\nHost: a.com\r\nAccept-Encoding: {accept_encoding}\r\n"),
+            b"",
+        );
+        ResponseBuilder::from(request)
+            .ok()
+            .header("Content-Type", content_type)
+            .body_bytes(body)
+            .auto_compress(CompressionConfig::default())
+            .build()
+            .expect("A valid 200 response should be produced")
+    }
+
+    #[test]
+    fn auto_compress_gzip() {
+        let body = compressible_body();
+        let response = respond("br;q=0.5, gzip", "application/json", body.clone());
+
+        assert_eq!(
+            response.get_header("Content-Encoding".to_string()),
+            Some("gzip".to_string())
+        );
+        assert_eq!(
+            response.get_header("Content-Length".to_string()),
+            Some(response.body().len().to_string()),
+            "Content-Length should be the compressed length"
+        );
+        assert_eq!(
+            response.get_header("Vary".to_string()),
+            Some("Accept-Encoding".to_string())
+        );
+        assert!(response.body().len() < body.len());
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(response.body())
+            .read_to_end(&mut decompressed)
+            .expect("The gzip output should be valid");
+        assert_eq!(decompressed, body);
+    }
+
+    #[test]
+    fn compressed_etag_weakened() {
+        let with_etag = |etag: &str| {
+            let (request, _) =
+                crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+            ResponseBuilder::from(request)
+                .ok()
+                .header("Content-Type", "application/json")
+                .header("ETag", etag)
+                .body_bytes(compressible_body())
+                .build()
+                .expect("A valid 200 response should be produced")
+        };
+        let config = CompressionConfig::default();
+        for (etag, expected) in [("\"v1\"", "W/\"v1\""), ("W/\"v1\"", "W/\"v1\"")] {
+            let mut response = with_etag(etag);
+            config.compress_response(&mut response, Some("gzip"));
+            assert_eq!(
+                response.get_header("ETag".to_string()),
+                Some(expected.to_string())
+            );
+        }
+
+        let mut response = with_etag("\"v1\"");
+        config.compress_response(&mut response, Some("identity"));
+        assert_eq!(
+            response.get_header("ETag".to_string()),
+            Some("\"v1\"".to_string()),
+            "Uncompressed responses should keep their strong ETag"
+        );
+    }
+
+    #[test]
+    fn auto_compress_skipped() {
+        let body = compressible_body();
+
+        let response = respond("br", "application/json", body.clone());
+        assert_eq!(response.body(), body, "The client doesn't accept gzip");
+        assert_eq!(
+            response.get_header("Vary".to_string()),
+            Some("Accept-Encoding".to_string()),
+            "Caches should know that other clients may get a compressed body"
+        );
+
+        let response = respond("gzip", "image/png", body.clone());
+        assert_eq!(
+            response.body(),
+            body,
+            "Images are usually compressed already"
+        );
+        assert_eq!(response.get_header("Content-Encoding".to_string()), None);
+
+        let response = respond("gzip", "application/json", b"{}".to_vec());
+        assert_eq!(
+            response.body(),
+            b"{}",
+            "Small bodies aren't worth compressing"
+        );
+        assert_eq!(response.get_header("Vary".to_string()), None);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::request::SyncableStream;
+
+/// Where an HTTP/1.x connection is in the cycle of serving a request.
+/// The connection loop in `HTTPListener` moves through these with `ConnectionState::next`:
+///
+/// ReadingHead -> ReadingBody -> Dispatching -> Writing -> KeepAlive -> ReadingBody -> ...
+///
+/// Writing ends in `Closed` instead if either side closes the connection, or `Upgraded` if a
+/// handler takes it over, E.G for a WebSocket. Any state can move to `Closed` when the client
+/// disconnects or the connection times out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// Waiting for the first request's head
+    ReadingHead,
+    /// The head has been parsed. The body is left for the handler to read
+    ReadingBody,
+    /// Waiting for a worker to produce a response
+    Dispatching,
+    Writing,
+    /// The response has been sent and the connection is idle until the next request's head
+    KeepAlive,
+    /// The connection belongs to a handler that speaks another protocol over it
+    Upgraded,
+    Closed,
+}
+
+/// Something that happened on a connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionEvent {
+    HeadReceived,
+    /// The request was queued for a worker
+    Dispatched,
+    /// A response was produced, either by a handler or by the listener rejecting the request
+    ResponseReady,
+    /// The response has been sent
+    ResponseSent {
+        keep_alive: bool,
+    },
+    /// A handler took over the connection after the response
+    Upgraded,
+    /// The client disconnected, the connection timed out or an error occurred
+    Closed,
+}
+
+/// A transition that the state machine doesn't allow, which means that the connection loop has
+/// a bug
+#[derive(Debug, PartialEq)]
+pub struct InvalidTransition {
+    pub from: ConnectionState,
+    pub event: ConnectionEvent,
+}
+
+impl std::fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Can't handle {0:?} in state {1:?}",
+            self.event, self.from
+        )
+    }
+}
+
+impl ConnectionState {
+    pub fn next(self, event: ConnectionEvent) -> Result<Self, InvalidTransition> {
+        use ConnectionEvent as Event;
+
+        match (self, event) {
+            (Self::Upgraded, _) => Err(InvalidTransition { from: self, event }),
+            (_, Event::Closed) => Ok(Self::Closed),
+            (Self::ReadingHead | Self::KeepAlive, Event::HeadReceived) => Ok(Self::ReadingBody),
+            (Self::ReadingBody, Event::Dispatched) => Ok(Self::Dispatching),
+            // The listener can respond without dispatching, E.G with 503 Service Unavailable
+            (Self::ReadingBody | Self::Dispatching, Event::ResponseReady) => Ok(Self::Writing),
+            (Self::Writing, Event::ResponseSent { keep_alive: true }) => Ok(Self::KeepAlive),
+            (Self::Writing, Event::ResponseSent { keep_alive: false }) => Ok(Self::Closed),
+            (Self::Writing, Event::Upgraded) => Ok(Self::Upgraded),
+            _ => Err(InvalidTransition { from: self, event }),
+        }
+    }
+
+    /// Whether the connection is waiting between requests. Timing out here is expected
+    /// because clients keep connections open in case they need them again
+    pub fn is_idle(&self) -> bool {
+        *self == Self::KeepAlive
+    }
+
+    /// Whether the listener has finished with the connection
+    pub fn is_finished(&self) -> bool {
+        matches!(self, Self::Closed | Self::Upgraded)
+    }
+}
+
+/// What happened to a connection after a worker sent the response to one of its requests
+pub enum ResponseOutcome {
+    /// The connection is handed back to read the next request from
+    KeepAlive(Box<dyn SyncableStream>),
+    Closed,
+    /// A handler has taken over the connection
+    Upgraded,
+}
+
+impl ResponseOutcome {
+    /// The events that bring a connection from `Dispatching` to the end of the response
+    pub fn events(&self) -> [ConnectionEvent; 2] {
+        let sent = match self {
+            Self::KeepAlive(_) => ConnectionEvent::ResponseSent { keep_alive: true },
+            Self::Closed => ConnectionEvent::ResponseSent { keep_alive: false },
+            Self::Upgraded => ConnectionEvent::Upgraded,
+        };
+        [ConnectionEvent::ResponseReady, sent]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drive(
+        state: ConnectionState,
+        events: &[ConnectionEvent],
+    ) -> Result<ConnectionState, InvalidTransition> {
+        events
+            .iter()
+            .try_fold(state, |state, event| state.next(*event))
+    }
+
+    #[test]
+    fn keep_alive_sequence() {
+        use ConnectionEvent::*;
+
+        let request = [
+            HeadReceived,
+            Dispatched,
+            ResponseReady,
+            ResponseSent { keep_alive: true },
+        ];
+        let state = drive(ConnectionState::ReadingHead, &request)
+            .expect("Serving a request should be valid");
+        assert_eq!(state, ConnectionState::KeepAlive);
+        assert!(state.is_idle());
+
+        let state = drive(state, &request).expect("Serving a second request should be valid");
+        assert_eq!(state, ConnectionState::KeepAlive);
+
+        let state = drive(
+            state,
+            &[
+                HeadReceived,
+                Dispatched,
+                ResponseReady,
+                ResponseSent { keep_alive: false },
+            ],
+        )
+        .expect("Serving the last request should be valid");
+        assert_eq!(state, ConnectionState::Closed);
+        assert!(state.is_finished());
+
+        assert_eq!(
+            ConnectionState::KeepAlive.next(Closed),
+            Ok(ConnectionState::Closed),
+            "An idle connection should be able to time out"
+        );
+    }
+
+    #[test]
+    fn upgrade() {
+        use ConnectionEvent::*;
+
+        let state = drive(
+            ConnectionState::ReadingHead,
+            &[HeadReceived, Dispatched, ResponseReady, Upgraded],
+        )
+        .expect("Upgrading a connection should be valid");
+        assert_eq!(state, ConnectionState::Upgraded);
+        assert!(state.is_finished());
+        assert_eq!(
+            state.next(HeadReceived),
+            Err(InvalidTransition {
+                from: ConnectionState::Upgraded,
+                event: HeadReceived
+            }),
+            "No more requests should be read from an upgraded connection"
+        );
+    }
+
+    #[test]
+    fn invalid_transitions() {
+        use ConnectionEvent::*;
+
+        ConnectionState::ReadingHead
+            .next(Dispatched)
+            .expect_err("A request can't be dispatched before its head is read");
+        ConnectionState::Dispatching
+            .next(HeadReceived)
+            .expect_err("Pipelined requests aren't read while one is being dispatched");
+        ConnectionState::Closed
+            .next(HeadReceived)
+            .expect_err("A closed connection can't receive requests");
+    }
+}
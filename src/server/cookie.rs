@@ -0,0 +1,185 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::request::is_token;
+
+/// Whether a cookie is sent with cross-site requests.
+/// See https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Set-Cookie#samesitesamesite-value
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Strict => write!(f, "Strict"),
+            Self::Lax => write!(f, "Lax"),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
+
+/// A cookie to set on the client with `ResponseBuilder::set_cookie`, E.G
+/// `Cookie::new("session", id).path("/").http_only(true).same_site(SameSite::Lax)`.
+/// Formats as the value of a Set-Cookie header.
+/// See https://www.rfc-editor.org/rfc/rfc6265#section-4.1
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Only send the cookie for requests under `path`
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Send the cookie to `domain` and its subdomains rather than only the host that set it
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    /// Expire the cookie after `max_age`. A zero duration removes the cookie.
+    /// Cookies without a Max-Age last until the browser is closed
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Hide the cookie from JavaScript, E.G for session IDs
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Browsers reject `SameSite=None` without Secure, so it also makes the cookie secure
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self.secure |= same_site == SameSite::None;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Whether the name is a token and the value only contains characters allowed in a cookie.
+    /// Other characters, E.G `;`, would let the value inject attributes
+    pub fn is_valid(&self) -> bool {
+        let is_cookie_octet =
+            |byte: u8| matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E);
+        let is_attribute_value = |value: &Option<String>| {
+            value.as_ref().is_none_or(|value| {
+                !value
+                    .bytes()
+                    .any(|byte| byte == b';' || byte.is_ascii_control())
+            })
+        };
+        is_token(&self.name)
+            && self.value.bytes().all(is_cookie_octet)
+            && is_attribute_value(&self.path)
+            && is_attribute_value(&self.domain)
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{0}={1}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={0}", max_age.as_secs())?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_attributes() {
+        assert_eq!(Cookie::new("theme", "dark").to_string(), "theme=dark");
+        assert_eq!(
+            Cookie::new("session", "abc123")
+                .path("/")
+                .same_site(SameSite::Lax)
+                .http_only(true)
+                .to_string(),
+            "session=abc123; Path=/; SameSite=Lax; HttpOnly"
+        );
+        assert_eq!(
+            Cookie::new("id", "1")
+                .domain("example.com")
+                .max_age(Duration::from_secs(3600))
+                .secure(true)
+                .to_string(),
+            "id=1; Domain=example.com; Max-Age=3600; Secure"
+        );
+        assert_eq!(
+            Cookie::new("tracker", "x")
+                .same_site(SameSite::None)
+                .to_string(),
+            "tracker=x; SameSite=None; Secure",
+            "SameSite=None should imply Secure"
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Cookie::new("session", "abc-123_%3D").is_valid());
+        assert!(Cookie::new("empty", "").is_valid());
+        assert!(!Cookie::new("session", "a; HttpOnly").is_valid());
+        assert!(!Cookie::new("bad name", "1").is_valid());
+        assert!(!Cookie::new("a", "1").path("/; Domain=evil.com").is_valid());
+    }
+}
@@ -0,0 +1,134 @@
+use serde_json::{json, Map, Value};
+
+use crate::{
+    mime::{MainMimeType, SubMimeType},
+    request::{content_type, HTTPMethod, Request},
+};
+
+use super::{
+    handlers::{Handler, HandlerPath, HandlerResult},
+    response::ResponseBuilder,
+};
+
+/// Headers that carry credentials and are redacted by default
+const DEFAULT_REDACTED: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// Responds with the parsed request as pretty-printed JSON, for debugging clients.
+/// Credentials are redacted unless `redact` is changed
+pub struct DebugEchoHandler {
+    path: HandlerPath,
+    method: HTTPMethod,
+    redacted: Vec<String>,
+}
+
+impl DebugEchoHandler {
+    pub fn new(path: &str, method: HTTPMethod) -> Self {
+        Self {
+            path: HandlerPath::new(path),
+            method,
+            redacted: DEFAULT_REDACTED.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+
+    /// Replace the list of headers whose values are hidden in the dump
+    pub fn redact(mut self, headers: &[&str]) -> Self {
+        self.redacted = headers.iter().map(|h| h.to_lowercase()).collect();
+        self
+    }
+
+    /// The body as JSON or text depending on its content type.
+    /// Bodies that can't be read are dumped as null
+    fn read_body(req: &mut Request) -> Value {
+        let Ok(mime_info) = content_type::parse_mime_info(&req.head.headers) else {
+            return Value::Null;
+        };
+
+        match (
+            mime_info.content_type.main_type,
+            mime_info.content_type.sub_type,
+        ) {
+            (MainMimeType::Application, SubMimeType::JSON) => {
+                req.read_body_json().unwrap_or(Value::Null)
+            }
+            (MainMimeType::Text, _) => req.read_body_text().map_or(Value::Null, Value::String),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl Handler for DebugEchoHandler {
+    fn get_path(&self) -> &HandlerPath {
+        &self.path
+    }
+
+    fn get_method(&self) -> &HTTPMethod {
+        &self.method
+    }
+
+    fn on_request(&self, mut req: Request) -> HandlerResult {
+        let body = DebugEchoHandler::read_body(&mut req);
+        let headers: Map<String, Value> = req
+            .head
+            .headers
+            .iter()
+            .map(|(key, value)| {
+                let value = if self.redacted.contains(key) {
+                    "[redacted]".to_string()
+                } else {
+                    value.clone()
+                };
+                (key.clone(), Value::String(value))
+            })
+            .collect();
+        let dump = json!({
+            "method": req.head.method.to_string(),
+            "path": req.head.path.to_string(),
+            "version": req.head.version.to_string(),
+            "headers": headers,
+            "query": req.head.query,
+            "body": body,
+        });
+
+        HandlerResult::Done(
+            ResponseBuilder::from(req)
+                .ok()
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string_pretty(&dump).expect("The dump should be serialisable"))
+                .build()
+                .expect("A valid debug response should be produced"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    #[test]
+    fn dump_request() {
+        let handler = DebugEchoHandler::new("/debug", HTTPMethod::Post);
+        let (req, _) = mock_request(
+            "POST /debug?verbose=1 HTTP/1.1\r\nHost: a.com\r\nAuthorization: Bearer secret\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n",
+            b"hello",
+        );
+
+        let HandlerResult::Done(response) = handler.on_request(req) else {
+            panic!("The debug handler should produce a response");
+        };
+        let dump: Value =
+            serde_json::from_slice(response.body()).expect("The dump should be valid JSON");
+
+        assert_eq!(dump["method"], "POST");
+        assert_eq!(dump["path"], "/debug?verbose=1");
+        assert_eq!(dump["query"], "verbose=1");
+        assert_eq!(dump["body"], "hello");
+        assert_eq!(dump["headers"]["host"], "a.com");
+        assert_eq!(
+            dump["headers"]["authorization"], "[redacted]",
+            "Credentials should be redacted by default"
+        );
+        assert!(!String::from_utf8_lossy(response.body()).contains("secret"));
+    }
+}
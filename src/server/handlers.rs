@@ -1,27 +1,113 @@
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use crate::request::{HTTPMethod, HTTPVersion, Path, Request, RequestHead, SyncableStream};
-use crate::server::response::Response;
+use crate::request::{
+    via, HTTPMethod, HTTPVersion, Path, PathParams, Request, RequestHead, SyncableStream,
+};
+use crate::server::response::{Hijacker, Response};
 
 use super::response::{ResponseBuilder, ResponseStatus};
 
-static KEY_DELIMITER: &str = "[##]";
-
 pub type HandlerCallback = Box<dyn FnMut(Request) -> Response>;
 
 #[derive(PartialEq, Debug)]
-pub struct HandlerPath(String);
+pub struct HandlerPath(
+    String,
+    /// The segments of a path with parameters. `None` for static paths, which are matched
+    /// exactly
+    Option<Vec<PathSegment>>,
+);
+
+#[derive(PartialEq, Debug)]
+enum PathSegment {
+    Static(String),
+    /// Matches any single non-empty segment and captures it under this name
+    Param(String),
+}
 
 /// A relative path to match against
 impl HandlerPath {
+    /// Segments starting with `:` are parameters, E.G `/dogs/:id` matches `/dogs/42` and
+    /// captures `id=42`. Use `Request::path_param` to get the captured values
     pub fn new(path: &str) -> Self {
         if !path.starts_with('/') {
             panic!("Invalid path {path}. Must be a relative path")
         }
-        Self(path.strip_suffix('/').unwrap_or(path).to_string())
+        if path == "/" {
+            // Stripping the trailing slash from the index would leave an empty path
+            return Self::exact(path);
+        }
+        let path = path.strip_suffix('/').unwrap_or(path);
+
+        let segments: Vec<PathSegment> = path
+            .split('/')
+            .skip(1)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) if !name.is_empty() => PathSegment::Param(name.to_string()),
+                _ => PathSegment::Static(segment.to_string()),
+            })
+            .collect();
+        let has_params = segments
+            .iter()
+            .any(|segment| matches!(segment, PathSegment::Param(_)));
+        Self(path.to_string(), has_params.then_some(segments))
+    }
+
+    /// A path that is matched exactly, E.G a request's path
+    fn exact(path: &str) -> Self {
+        Self(path.to_string(), None)
+    }
+
+    /// The parameters captured from `path`, if it matches this path's pattern.
+    /// Always `None` for static paths
+    fn capture(&self, path: &str) -> Option<PathParams> {
+        let pattern = self.1.as_ref()?;
+        let path = path
+            .strip_suffix('/')
+            .filter(|path| !path.is_empty())
+            .unwrap_or(path);
+        let segments: Vec<&str> = path.split('/').skip(1).collect();
+        if segments.len() != pattern.len() {
+            return None;
+        }
+
+        let mut params = PathParams::new();
+        for (expected, actual) in pattern.iter().zip(segments) {
+            match expected {
+                PathSegment::Static(expected) if expected == actual => {}
+                PathSegment::Param(name) if !actual.is_empty() => {
+                    params.insert(name.clone(), actual.to_string());
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+
+    /// How many segments must match exactly. The most specific pattern wins when several match
+    fn static_segments(&self) -> usize {
+        self.1.as_ref().map_or(0, |pattern| {
+            pattern
+                .iter()
+                .filter(|segment| matches!(segment, PathSegment::Static(_)))
+                .count()
+        })
+    }
+
+    /// Routes are only matched against the path, not the query
+    fn without_query(&self) -> &str {
+        self.0.split_once('?').map_or(&self.0, |(path, _)| path)
+    }
+
+    /// Whether this path is `prefix` or is nested beneath it
+    fn is_under(&self, prefix: &HandlerPath) -> bool {
+        // Matched like routes, otherwise a query would let requests skip scoped middleware
+        prefix.0 == "/"
+            || self
+                .without_query()
+                .strip_prefix(&prefix.0)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
     }
 }
 
@@ -34,7 +120,7 @@ impl TryFrom<Path> for HandlerPath {
             Path::AuthorityForm(..) => {
                 Err("Can't convert from authority form: it's only used for CONNECT")
             }
-            Path::OriginForm(path) => Ok(HandlerPath(path)),
+            Path::OriginForm(path) => Ok(HandlerPath::exact(path.as_str())),
             Path::AbsoluteForm(path) => {
                 if path
                     .splitn(2, '/')
@@ -45,9 +131,9 @@ impl TryFrom<Path> for HandlerPath {
                     .is_empty()
                 {
                     // Index page (E.G example.com/). Corrects example.com to example.com/
-                    Ok(HandlerPath("/".to_string()))
+                    Ok(HandlerPath::exact("/"))
                 } else {
-                    Ok(HandlerPath(path.to_string()))
+                    Ok(HandlerPath::exact(&path))
                 }
             }
         }
@@ -57,10 +143,57 @@ impl TryFrom<Path> for HandlerPath {
 /// Handlers will return a `Done` if finished (I.E a response has been generated)
 /// or a `Continue` containing the potentially-modified `Request`
 /// if the next handler should continue processing the request.
-/// All endpoints must return a `Done` while middleware may return either
+/// All endpoints must return a `Done` while middleware may return either.
+/// A `Hijack` sends the response and then gives the connection to the hijacker, E.G for
+/// WebSockets. No further response is sent
 pub enum HandlerResult {
     Done(Response),
     Continue(Request),
+    Hijack(Response, Hijacker),
+}
+
+/// A job passed to the executor given to `HandlerResult::defer_with`
+pub type DeferredJob = Box<dyn FnOnce() + Send + 'static>;
+
+impl HandlerResult {
+    /// Run `job` on a new background thread and respond with 202 Accepted without waiting for
+    /// it, E.G for long-running exports. `status_url` should be somewhere the client can poll
+    /// to see whether the job has finished. See `ResponseBuilder::accepted`.
+    /// Every call spawns a thread and nothing limits how many run at once, so a burst of
+    /// requests can exhaust the process's threads. Use `defer_with` to run jobs on a bounded
+    /// pool instead
+    pub fn defer(
+        req: Request,
+        status_url: Option<&str>,
+        job: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        Self::defer_with(
+            req,
+            status_url,
+            |job| {
+                // Detached: the job's result is only observable through `status_url`
+                drop(std::thread::spawn(job));
+            },
+            job,
+        )
+    }
+
+    /// Like `defer`, but `job` is handed to `spawn` to run, E.G by sending it to a bounded
+    /// pool of workers
+    pub fn defer_with(
+        req: Request,
+        status_url: Option<&str>,
+        spawn: impl FnOnce(DeferredJob),
+        job: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        spawn(Box::new(job));
+        HandlerResult::Done(
+            ResponseBuilder::from(req)
+                .accepted(status_url)
+                .build()
+                .expect("A valid 202 response should be produced"),
+        )
+    }
 }
 
 pub trait Handler {
@@ -69,51 +202,115 @@ pub trait Handler {
     fn on_request(&self, req: Request) -> HandlerResult;
 }
 
-type SyncableHandler = dyn Handler + Send + Sync;
+pub type SyncableHandler = dyn Handler + Send + Sync;
+
+/// Runs before the handler of every route under the path prefix it is registered with.
+/// Return `Continue` to pass the request on or `Done` to respond early, E.G to reject
+/// unauthenticated requests
+pub trait Middleware {
+    fn on_request(&self, req: Request) -> HandlerResult;
+}
+
+pub type SyncableMiddleware = dyn Middleware + Send + Sync;
+
+/// Builds a set of handlers that share one state object, E.G the GET and POST handlers for
+/// a resource. Pass the result to `HandlerRegistry::new`
+pub struct HandlerGroup<S> {
+    state: Arc<S>,
+    handlers: Vec<Arc<SyncableHandler>>,
+}
+
+impl<S> HandlerGroup<S> {
+    pub fn new(state: S) -> Self {
+        Self::from_shared(Arc::new(state))
+    }
+
+    /// Use state that is already shared with something outside of the group
+    pub fn from_shared(state: Arc<S>) -> Self {
+        Self {
+            state,
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> Arc<S> {
+        Arc::clone(&self.state)
+    }
+
+    /// Add a handler constructed from the shared state.
+    /// Handler constructors that take an `Arc<S>` can be passed directly
+    pub fn handler<H, F>(mut self, make_handler: F) -> Self
+    where
+        H: Handler + Send + Sync + 'static,
+        F: FnOnce(Arc<S>) -> H,
+    {
+        self.handlers
+            .push(Arc::new(make_handler(Arc::clone(&self.state))));
+        self
+    }
+
+    pub fn build(self) -> Vec<Arc<SyncableHandler>> {
+        self.handlers
+    }
+}
 
 /**
    A composite key from a handler. This is necessary because paths can be reused for
    different HTTP verbs
 */
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
-struct HandlerRegistryKey(String);
+pub struct HandlerRegistryKey(HTTPMethod, String);
 
 impl From<&SyncableHandler> for HandlerRegistryKey {
     fn from(handler: &SyncableHandler) -> Self {
-        Self(format!(
-            "{0}{KEY_DELIMITER}{1}",
-            handler.get_method(),
-            handler.get_path().0
-        ))
+        Self(handler.get_method().clone(), handler.get_path().0.clone())
     }
 }
 
 impl From<&dyn Handler> for HandlerRegistryKey {
     fn from(handler: &dyn Handler) -> Self {
-        Self(format!(
-            "{0}{KEY_DELIMITER}{1}",
-            handler.get_method(),
-            handler.get_path().0
-        ))
+        Self(handler.get_method().clone(), handler.get_path().0.clone())
     }
 }
 
 impl From<(HTTPMethod, String)> for HandlerRegistryKey {
     fn from((method, path): (HTTPMethod, String)) -> Self {
-        Self(format!("{0}{KEY_DELIMITER}{1}", method, path))
+        Self(method, path)
     }
 }
 
+/// What to do when a handler is registered for a method and path that already has one
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DuplicateHandlerPolicy {
+    /// Fail with `HandlerRegistryAddError::DuplicateKey`
+    #[default]
+    Reject,
+    /// Replace the existing handler
+    LastWins,
+    /// Keep the existing handler and ignore the new one, logging a warning
+    FirstWins,
+}
+
 #[derive(Default)]
 pub struct HandlerRegistry {
     // TODO: figure out how to efficiently discriminate between HTTP methods
     handlers: HashMap<HandlerRegistryKey, Arc<SyncableHandler>>,
+    duplicate_policy: DuplicateHandlerPolicy,
+    /// The name this server identifies itself with in the Via header.
+    /// Requests that have already passed through this server are rejected
+    via_pseudonym: Option<String>,
+    /// Run in the order they were added
+    middleware: Vec<(HandlerPath, Arc<SyncableMiddleware>)>,
+    /// Handle every request under their prefix that no route matches
+    mounts: Vec<(HandlerPath, Arc<SyncableHandler>)>,
 }
 
 #[derive(Debug)]
 pub enum HandlerRegistryAddError {
     DuplicateKey(HandlerRegistryKey),
     UnhandlableMethod(HTTPMethod),
+    /// Handlers can only be added directly to a set of virtual hosts if it has a default host
+    NoDefaultHost,
 }
 
 #[derive(Debug)]
@@ -122,6 +319,14 @@ pub enum HandlerCallErrorReason {
     /// The server needs to know where to route to
     UnhandlablePath(Path),
     NoCompatibleHandler(HTTPMethod, Path),
+    /// The path has routes, but not for the request's method. Holds the methods it does have
+    MethodNotAllowed(Path, Vec<HTTPMethod>),
+    /// The method isn't known to the server and no handler has been registered for it
+    UnsupportedMethod(HTTPMethod),
+    /// The request's Via header shows that it has already passed through this server
+    LoopDetected(String),
+    /// The request's Host doesn't match any virtual host served by this server
+    MisdirectedRequest(Option<String>),
 }
 
 pub struct HandlerCallError {
@@ -129,6 +334,8 @@ pub struct HandlerCallError {
     stream: Box<dyn SyncableStream>,
     pub http_version: HTTPVersion,
     pub path: Path,
+    /// The request's body couldn't be discarded, so the connection can't be reused
+    close_connection: bool,
 }
 
 impl std::fmt::Debug for HandlerCallError {
@@ -147,6 +354,8 @@ pub trait DispatcherError {
     fn into_response(self) -> ResponseBuilder;
 }
 
+/// Routes requests to the code that responds to them. `HandlerRegistry` is the default, but
+/// `HTTPListener` accepts any implementation, E.G a trie-based router
 pub trait RequestDispatcher {
     type Error: DispatcherError;
 
@@ -159,50 +368,311 @@ impl DispatcherError for HandlerCallError {
         match self.reason {
             HandlerCallErrorReason::UnhandlablePath(_)
             | HandlerCallErrorReason::NoCompatibleHandler(_, _) => ResponseStatus::NotFound,
+            HandlerCallErrorReason::MethodNotAllowed(..) => ResponseStatus::MethodNotAllowed,
+            HandlerCallErrorReason::UnsupportedMethod(_) => ResponseStatus::NotImplemented,
+            HandlerCallErrorReason::LoopDetected(_) => ResponseStatus::LoopDetected,
+            HandlerCallErrorReason::MisdirectedRequest(_) => ResponseStatus::MisdirectedRequest,
         }
     }
 
     fn into_response(self) -> ResponseBuilder {
+        let status = match self.reason {
+            // Malformed paths are reported as 400 rather than 404
+            HandlerCallErrorReason::UnhandlablePath(_) => ResponseStatus::BadRequest,
+            _ => self.as_status_code(),
+        };
+        // 405 responses must list the methods that are allowed.
+        // See https://www.rfc-editor.org/rfc/rfc9110#section-15.5.6
+        let allow = match &self.reason {
+            HandlerCallErrorReason::MethodNotAllowed(_, methods) => Some(format_allow(methods)),
+            _ => None,
+        };
+        let message = match self.reason {
+            HandlerCallErrorReason::UnhandlablePath(path) => format!("Malformed URL path {path}"),
+            HandlerCallErrorReason::NoCompatibleHandler(httpmethod, ref path) => {
+                format!("No matching handler found for {httpmethod} {path}")
+            }
+            HandlerCallErrorReason::MethodNotAllowed(ref path, _) => {
+                format!("{path} does not support this method")
+            }
+            HandlerCallErrorReason::UnsupportedMethod(httpmethod) => {
+                format!("The {httpmethod} method is not supported by this server")
+            }
+            HandlerCallErrorReason::LoopDetected(pseudonym) => {
+                format!("Request has already been handled by {pseudonym}")
+            }
+            HandlerCallErrorReason::MisdirectedRequest(host) => match host {
+                Some(host) => format!("This server is not configured to serve {host}"),
+                None => "No host was specified".to_string(),
+            },
+        };
+
+        // The message can contain attacker-controlled input such as the path, so it must
+        // never be rendered as HTML
         let builder = ResponseBuilder::default()
             .version(self.http_version)
-            .stream(self.stream);
-
-        match self.reason {
-            HandlerCallErrorReason::UnhandlablePath(path) => builder
-                .bad_request()
-                .body(format!("Malformed URL path {path}")),
-            HandlerCallErrorReason::NoCompatibleHandler(httpmethod, ref path) => builder
-                .not_found()
-                .body(format!("No matching handler found for {httpmethod} {path}")),
+            .stream(self.stream)
+            .status(status)
+            .text(&message)
+            .header("X-Content-Type-Options", "nosniff");
+        let builder = match allow {
+            Some(allow) => builder.header("Allow", &allow),
+            None => builder,
+        };
+        if self.close_connection {
+            builder.header("Connection", "close")
+        } else {
+            builder
         }
     }
 }
 
+/// The value of an Allow header listing `methods`
+fn format_allow(methods: &[HTTPMethod]) -> String {
+    methods
+        .iter()
+        .map(HTTPMethod::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl HandlerCallError {
-    pub fn new(reason: HandlerCallErrorReason, req: Request) -> Self {
+    pub fn new(reason: HandlerCallErrorReason, mut req: Request) -> Self {
+        req.discard_unread_body();
         Self {
             reason,
             http_version: req.head.version,
             path: req.head.path.clone(),
+            close_connection: req.should_close(),
             stream: req.into_stream(),
         }
     }
 }
 
 impl HandlerRegistry {
+    /// If two of `handlers` share a method and path, the first is kept and a warning is logged.
+    /// Handlers added later with `add` are rejected if they duplicate an existing one.
+    /// Use `try_new` to reject duplicates here too or `with_duplicate_policy` to choose how
+    /// they are handled
     pub fn new(handlers: Vec<Arc<SyncableHandler>>) -> Self {
-        let mut registry = HashMap::new();
-        handlers.into_iter().for_each(|h| {
-            let key = { HandlerRegistryKey::from(h.as_ref()) };
-            registry.entry(key).or_insert(h);
-        });
-        HandlerRegistry { handlers: registry }
+        let mut registry = Self::default();
+        for handler in handlers {
+            registry
+                .insert(handler, DuplicateHandlerPolicy::FirstWins)
+                .expect("Duplicate handlers should be ignored rather than rejected");
+        }
+        registry
+    }
+
+    /// Fails if two handlers share a method and path
+    pub fn try_new(handlers: Vec<Arc<SyncableHandler>>) -> Result<Self, HandlerRegistryAddError> {
+        Self::with_duplicate_policy(handlers, DuplicateHandlerPolicy::Reject)
+    }
+
+    /// Register `handlers` in order, resolving duplicates with `policy`.
+    /// The policy also applies to handlers registered later with `add`
+    pub fn with_duplicate_policy(
+        handlers: Vec<Arc<SyncableHandler>>,
+        policy: DuplicateHandlerPolicy,
+    ) -> Result<Self, HandlerRegistryAddError> {
+        let mut registry = HandlerRegistry {
+            handlers: HashMap::new(),
+            duplicate_policy: policy,
+            via_pseudonym: None,
+            middleware: Vec::new(),
+            mounts: Vec::new(),
+        };
+        for handler in handlers {
+            registry.insert(handler, policy)?;
+        }
+        Ok(registry)
+    }
+
+    /// Register `handler`, resolving duplicates with `policy`
+    fn insert(
+        &mut self,
+        handler: Arc<SyncableHandler>,
+        policy: DuplicateHandlerPolicy,
+    ) -> Result<(), HandlerRegistryAddError> {
+        let key = HandlerRegistryKey::from(handler.as_ref());
+        match (self.handlers.entry(key), policy) {
+            (Entry::Vacant(e), _) => {
+                e.insert(handler);
+            }
+            (Entry::Occupied(mut e), DuplicateHandlerPolicy::LastWins) => {
+                e.insert(handler);
+            }
+            (Entry::Occupied(e), DuplicateHandlerPolicy::FirstWins) => {
+                log::warn!(
+                    "Ignoring a duplicate handler for {0:?}. The first one registered is used",
+                    e.key()
+                );
+            }
+            (Entry::Occupied(e), DuplicateHandlerPolicy::Reject) => {
+                return Err(HandlerRegistryAddError::DuplicateKey(e.key().clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send every request under `prefix` that doesn't match a route to `handler`, whatever its
+    /// method, E.G to plug in a sub-app that does its own routing. The handler receives the
+    /// path with `prefix` stripped, so `/api/dogs` is seen as `/dogs` when mounted at `/api`.
+    /// The most specific mount is used when prefixes overlap
+    pub fn mount(mut self, prefix: &str, handler: Arc<SyncableHandler>) -> Self {
+        let prefix = prefix.strip_suffix("/*").unwrap_or(prefix);
+        let prefix = HandlerPath::new(if prefix.is_empty() { "/" } else { prefix });
+        self.mounts.push((prefix, handler));
+        self
+    }
+
+    /// The mounted handler for `path` and the path with the mount's prefix stripped
+    fn mount_for(&self, path: &Path) -> Option<(&Arc<SyncableHandler>, String)> {
+        let Path::OriginForm(path) = path else {
+            return None;
+        };
+        let (path, query) = (path.path(), path.raw_query());
+
+        self.mounts
+            .iter()
+            .filter_map(|(prefix, handler)| {
+                let rest = if prefix.0 == "/" {
+                    path
+                } else {
+                    path.strip_prefix(&prefix.0)?
+                };
+                (rest.is_empty() || rest.starts_with('/')).then_some((prefix, handler, rest))
+            })
+            .max_by_key(|(prefix, ..)| prefix.0.len())
+            .map(|(_, handler, rest)| {
+                let rest = if rest.is_empty() { "/" } else { rest };
+                let stripped = match query {
+                    Some(query) => format!("{rest}?{query}"),
+                    None => rest.to_string(),
+                };
+                (handler, stripped)
+            })
+    }
+
+    /// Run `middleware` before the handlers of every route under `prefix`.
+    /// `/admin` and `/admin/*` both match `/admin` and `/admin/users` but not `/administrator`
+    pub fn with_middleware(mut self, prefix: &str, middleware: Arc<SyncableMiddleware>) -> Self {
+        let prefix = prefix.strip_suffix("/*").unwrap_or(prefix);
+        let prefix = HandlerPath::new(if prefix.is_empty() { "/" } else { prefix });
+        self.middleware.push((prefix, middleware));
+        self
+    }
+
+    /// Enable loop detection. Requests whose Via header already contains `pseudonym`
+    /// will be rejected with 508 Loop Detected
+    pub fn with_via_pseudonym(mut self, pseudonym: &str) -> Self {
+        self.via_pseudonym = Some(pseudonym.to_string());
+        self
     }
 
     pub fn get(&self, method: HTTPMethod, path: HandlerPath) -> Option<&Arc<SyncableHandler>> {
         self.handlers
             .get(&HandlerRegistryKey::from((method, path.0)))
     }
+
+    /// The handler for a request path and the parameters it captured. Static routes take
+    /// priority over routes with parameters, so `/dogs/new` wins over `/dogs/:id`.
+    /// HEAD requests fall back to the GET handler when there isn't a HEAD handler
+    pub fn find(
+        &self,
+        method: HTTPMethod,
+        path: &HandlerPath,
+    ) -> Option<(&Arc<SyncableHandler>, PathParams)> {
+        self.find_exact_method(method.clone(), path)
+            .or_else(|| match method {
+                HTTPMethod::Head => self.find_exact_method(HTTPMethod::Get, path),
+                _ => None,
+            })
+    }
+
+    fn find_exact_method(
+        &self,
+        method: HTTPMethod,
+        path: &HandlerPath,
+    ) -> Option<(&Arc<SyncableHandler>, PathParams)> {
+        let request_path = path.without_query();
+        if let Some(handler) = self.get(method.clone(), HandlerPath::exact(request_path)) {
+            return Some((handler, PathParams::new()));
+        }
+
+        self.handlers
+            .iter()
+            .filter(|(HandlerRegistryKey(handler_method, _), _)| *handler_method == method)
+            .filter_map(|(_, handler)| {
+                let params = handler.get_path().capture(request_path)?;
+                Some((handler, params))
+            })
+            // Ties are broken by path so that the choice doesn't depend on the hash map's order
+            .max_by(|(a, _), (b, _)| {
+                let (a, b) = (a.get_path(), b.get_path());
+                a.static_segments()
+                    .cmp(&b.static_segments())
+                    .then_with(|| b.0.cmp(&a.0))
+            })
+    }
+
+    /// The methods that have a route matching `path`, sorted so that the order is stable
+    pub fn allowed_methods(&self, path: &HandlerPath) -> Vec<HTTPMethod> {
+        let request_path = path.without_query();
+        Self::served_methods(
+            self.handlers
+                .iter()
+                .filter(|(HandlerRegistryKey(_, route), handler)| {
+                    route == request_path || handler.get_path().capture(request_path).is_some()
+                })
+                .map(|(HandlerRegistryKey(method, _), _)| method.clone()),
+        )
+    }
+
+    /// Every method that has a route, sorted like `allowed_methods`
+    fn all_methods(&self) -> Vec<HTTPMethod> {
+        Self::served_methods(
+            self.handlers
+                .keys()
+                .map(|HandlerRegistryKey(method, _)| method.clone()),
+        )
+    }
+
+    /// The sorted, deduplicated methods served by handlers for `methods`.
+    /// GET handlers also serve HEAD, so it is included whenever GET is
+    fn served_methods(methods: impl Iterator<Item = HTTPMethod>) -> Vec<HTTPMethod> {
+        let mut methods: Vec<HTTPMethod> = methods.collect();
+        if methods.contains(&HTTPMethod::Get) {
+            methods.push(HTTPMethod::Head);
+        }
+        methods.sort_by_key(HTTPMethod::to_string);
+        methods.dedup();
+        methods
+    }
+
+    /// Answer an OPTIONS request with the methods available for its target
+    fn options_response(req: Request, allowed: &[HTTPMethod]) -> Response {
+        ResponseBuilder::from(req)
+            .status(ResponseStatus::NoContent)
+            .header("Allow", &format_allow(allowed))
+            .build()
+            .expect("A valid OPTIONS response should be produced")
+    }
+
+    /// Every registered route as a (method, path) pair, sorted by path then method
+    pub fn routes(&self) -> Vec<(HTTPMethod, String)> {
+        let mut routes: Vec<(HTTPMethod, String)> = self
+            .handlers
+            .keys()
+            .map(|HandlerRegistryKey(method, path)| (method.clone(), path.clone()))
+            .collect();
+        routes.sort_by(|(method_a, path_a), (method_b, path_b)| {
+            path_a
+                .cmp(path_b)
+                .then_with(|| method_a.to_string().cmp(&method_b.to_string()))
+        });
+        routes
+    }
 }
 
 impl RequestDispatcher for HandlerRegistry {
@@ -218,38 +688,92 @@ impl RequestDispatcher for HandlerRegistry {
             ));
         }
 
-        let key = HandlerRegistryKey::from(handler.as_ref());
-
-        if let Entry::Vacant(e) = self.handlers.entry(key.clone()) {
-            e.insert(handler);
-            Ok(())
-        } else {
-            Err(HandlerRegistryAddError::DuplicateKey(key))
-        }
+        self.insert(handler, self.duplicate_policy)
     }
 
     fn dispatch(&self, req: Request) -> Result<Response, HandlerCallError> {
+        if let Some(pseudonym) = &self.via_pseudonym {
+            // Malformed Via headers can't be used to detect loops, so they are ignored
+            if via::has_visited(&req.head.headers, pseudonym).unwrap_or(false) {
+                return Err(HandlerCallError::new(
+                    HandlerCallErrorReason::LoopDetected(pseudonym.clone()),
+                    req,
+                ));
+            }
+        }
+
+        // `OPTIONS *` asks about the server as a whole rather than a resource
+        if req.head.method == HTTPMethod::Options && req.head.path == Path::Asterisk {
+            let allowed = self.all_methods();
+            return Ok(Self::options_response(req, &allowed));
+        }
+
         let RequestHead {
-            method, ref path, ..
+            ref method,
+            ref path,
+            ..
         } = req.head;
-        let owned_path = path.clone();
+        let (method, owned_path) = (method.clone(), path.clone());
         let mut lazy_req = Some(req);
 
-        let handler_path = owned_path.clone().try_into().or_else(|_| {
-            Err(HandlerCallError::new(
-                HandlerCallErrorReason::UnhandlablePath(owned_path.clone()),
-                lazy_req.take().unwrap(),
-            ))
-        })?;
-        let handler = self.get(method, handler_path).ok_or_else(|| {
+        let handler_path: HandlerPath = owned_path.clone().try_into().map_err(|_| {
             HandlerCallError::new(
-                HandlerCallErrorReason::NoCompatibleHandler(method, owned_path),
+                HandlerCallErrorReason::UnhandlablePath(owned_path.clone()),
                 lazy_req.take().unwrap(),
             )
         })?;
+        let middleware: Vec<&Arc<SyncableMiddleware>> = self
+            .middleware
+            .iter()
+            .filter(|(prefix, _)| handler_path.is_under(prefix))
+            .map(|(_, middleware)| middleware)
+            .collect();
+        // OPTIONS can't be registered, so it is answered for every path that has routes
+        if method == HTTPMethod::Options && self.find(method.clone(), &handler_path).is_none() {
+            let allowed = self.allowed_methods(&handler_path);
+            if !allowed.is_empty() {
+                return Ok(Self::options_response(lazy_req.take().unwrap(), &allowed));
+            }
+        }
+        let (handler, mounted_path) = match self.find(method.clone(), &handler_path) {
+            Some((handler, params)) => {
+                if let Some(req) = lazy_req.as_mut() {
+                    req.set_path_params(params);
+                }
+                (handler, None)
+            }
+            None => match self.mount_for(&owned_path) {
+                Some((handler, stripped)) => (handler, Some(stripped)),
+                None => {
+                    let allowed = self.allowed_methods(&handler_path);
+                    let reason = match method {
+                        HTTPMethod::Other(_) => HandlerCallErrorReason::UnsupportedMethod(method),
+                        _ if !allowed.is_empty() => {
+                            HandlerCallErrorReason::MethodNotAllowed(owned_path, allowed)
+                        }
+                        _ => HandlerCallErrorReason::NoCompatibleHandler(method, owned_path),
+                    };
+                    return Err(HandlerCallError::new(reason, lazy_req.take().unwrap()));
+                }
+            },
+        };
+
+        let mut req = lazy_req.take().unwrap();
+        for m in middleware {
+            match m.on_request(req) {
+                HandlerResult::Done(res) => return Ok(res),
+                HandlerResult::Hijack(res, hijacker) => return Ok(res.hijack(hijacker)),
+                HandlerResult::Continue(next) => req = next,
+            }
+        }
 
-        match handler.on_request(lazy_req.take().unwrap()) {
-            HandlerResult::Done(res) => Ok(res),
+        if let Some(stripped) = mounted_path {
+            req.head.path = Path::OriginForm(stripped.into());
+        }
+        // The handler may be the GET handler, so its body is dropped for HEAD requests
+        match handler.on_request(req) {
+            HandlerResult::Done(res) => Ok(res.for_method(&method)),
+            HandlerResult::Hijack(res, hijacker) => Ok(res.for_method(&method).hijack(hijacker)),
             HandlerResult::Continue(_) => {
                 todo!("Pass the request onto the next Handler")
             }
@@ -259,10 +783,21 @@ impl RequestDispatcher for HandlerRegistry {
 
 #[cfg(test)]
 mod tests {
-    use crate::{request::HTTPVersion, server::response::ResponseBuilder};
+    use std::io::{BufReader, Cursor, Write};
+
+    use crate::{
+        request::{http1_1, HTTPVersion},
+        server::response::ResponseBuilder,
+    };
 
     use super::*;
 
+    fn make_request(raw_head: &str) -> Request {
+        let head = http1_1::parse_req_head(&mut raw_head.lines())
+            .expect("The test request head should be valid");
+        Request::new(head, BufReader::new(Cursor::new(Vec::new())))
+    }
+
     struct HelloWorldHandler {
         path: HandlerPath,
         method: HTTPMethod,
@@ -378,4 +913,764 @@ mod tests {
             .add(Arc::new(OptionsHandler {}))
             .expect_err("Adding a handler for OPTIONS should fail");
     }
+
+    #[test]
+    fn dispatch_loop_detected() {
+        let registry = HandlerRegistry::new(vec![Arc::new(HelloWorldHandler::new())])
+            .with_via_pseudonym("http-server");
+
+        let err = registry
+            .dispatch(make_request(
+                "GET / HTTP/1.1\r\nHost: example.com\r\nVia: 1.1 proxy.example.com, 1.1 http-server\r\n",
+            ))
+            .expect_err("A request that has already visited this server should be rejected");
+        assert_eq!(err.as_status_code(), ResponseStatus::LoopDetected);
+
+        registry
+            .dispatch(make_request(
+                "GET / HTTP/1.1\r\nHost: example.com\r\nVia: 1.1 proxy.example.com\r\n",
+            ))
+            .expect("A request that hasn't visited this server should be dispatched");
+    }
+
+    #[test]
+    fn error_response_not_html() {
+        let registry = HandlerRegistry::new(vec![Arc::new(HelloWorldHandler::new())]);
+
+        let err = registry
+            .dispatch(make_request(
+                "GET /<script>alert(1)</script> HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect_err("There is no handler for the path");
+        let response = err
+            .into_response()
+            .build()
+            .expect("A valid error response should be produced");
+
+        assert_eq!(
+            response.get_header("Content-Type".to_string()),
+            Some("text/plain; charset=UTF-8".to_string()),
+            "Error bodies that echo the path must not be served as HTML"
+        );
+        assert_eq!(
+            response.get_header("X-Content-Type-Options".to_string()),
+            Some("nosniff".to_string()),
+            "Browsers should not sniff the error body as HTML"
+        );
+    }
+
+    #[test]
+    fn scoped_middleware() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct PathHandler {
+            path: HandlerPath,
+        }
+
+        impl Handler for PathHandler {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Get
+            }
+
+            fn on_request(&self, req: Request) -> HandlerResult {
+                HandlerResult::Done(
+                    ResponseBuilder::from(req)
+                        .ok()
+                        .build()
+                        .expect("A valid 200 response should be produced"),
+                )
+            }
+        }
+
+        #[derive(Default)]
+        struct AuthMiddleware {
+            calls: AtomicUsize,
+        }
+
+        impl Middleware for AuthMiddleware {
+            fn on_request(&self, req: Request) -> HandlerResult {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                if req.head.headers.contains_key("authorization") {
+                    HandlerResult::Continue(req)
+                } else {
+                    HandlerResult::Done(
+                        ResponseBuilder::from(req)
+                            .unauthorised()
+                            .build()
+                            .expect("A valid 401 response should be produced"),
+                    )
+                }
+            }
+        }
+
+        let auth = Arc::new(AuthMiddleware::default());
+        let registry = HandlerRegistry::new(vec![
+            Arc::new(PathHandler {
+                path: HandlerPath::new("/admin"),
+            }),
+            Arc::new(PathHandler {
+                path: HandlerPath::new("/admin/x"),
+            }),
+            Arc::new(PathHandler {
+                path: HandlerPath::new("/public/x"),
+            }),
+        ])
+        .with_middleware("/admin/*", auth.clone());
+
+        let response = registry
+            .dispatch(make_request(
+                "GET /admin/x HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect("The admin route should be dispatched");
+        assert_eq!(*response.status(), ResponseStatus::Unauthorized);
+        assert_eq!(auth.calls.load(Ordering::SeqCst), 1);
+
+        let response = registry
+            .dispatch(make_request(
+                "GET /admin/x HTTP/1.1\r\nHost: example.com\r\nAuthorization: Basic YTpi\r\n",
+            ))
+            .expect("The admin route should be dispatched");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(auth.calls.load(Ordering::SeqCst), 2);
+
+        let response = registry
+            .dispatch(make_request(
+                "GET /public/x HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect("The public route should be dispatched");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(
+            auth.calls.load(Ordering::SeqCst),
+            2,
+            "The auth middleware should not run for public routes"
+        );
+
+        for path in ["/admin?x=1", "/admin/x?x=1"] {
+            let response = registry
+                .dispatch(make_request(&format!(
+                    "GET {path} HTTP/1.1\r\nHost: example.com\r\n"
+                )))
+                .expect("The admin route should be dispatched");
+            assert_eq!(
+                *response.status(),
+                ResponseStatus::Unauthorized,
+                "A query should not bypass the middleware for {path}"
+            );
+        }
+        assert_eq!(auth.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn handler_path_is_under() {
+        let admin = HandlerPath::new("/admin");
+        assert!(HandlerPath::new("/admin").is_under(&admin));
+        assert!(HandlerPath::new("/admin/users").is_under(&admin));
+        assert!(!HandlerPath::new("/administrator").is_under(&admin));
+        assert!(HandlerPath::new("/admin?x=1").is_under(&admin));
+        assert!(HandlerPath::new("/admin/users?x=1").is_under(&admin));
+        assert!(!HandlerPath::new("/administrator?x=/admin").is_under(&admin));
+        assert!(HandlerPath::new("/anything").is_under(&HandlerPath::new("/")));
+    }
+
+    #[test]
+    fn mounted_sub_app() {
+        use crate::_crud_example::{DogStore, DogStoreGetHandler};
+        use std::sync::Mutex;
+
+        /// A sub-app that routes requests using its own registry
+        struct SubApp {
+            path: HandlerPath,
+            routes: HandlerRegistry,
+        }
+
+        impl Handler for SubApp {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Get
+            }
+
+            fn on_request(&self, req: Request) -> HandlerResult {
+                HandlerResult::Done(self.routes.dispatch(req).unwrap_or_else(|err| {
+                    err.into_response()
+                        .build()
+                        .expect("A valid error response should be produced")
+                }))
+            }
+        }
+
+        let api = Arc::new(SubApp {
+            path: HandlerPath::new("/api"),
+            routes: HandlerRegistry::new(
+                HandlerGroup::new(Mutex::new(DogStore::default()))
+                    .handler(DogStoreGetHandler::new)
+                    .build(),
+            ),
+        });
+        let registry =
+            HandlerRegistry::new(vec![Arc::new(HelloWorldHandler::new())]).mount("/api/*", api);
+
+        let response = registry
+            .dispatch(make_request(
+                "GET /api/dogs HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect("Requests under the mount should be dispatched to the sub-app");
+        assert_eq!(
+            *response.status(),
+            ResponseStatus::OK,
+            "The sub-app should route /api/dogs to its /dogs handler"
+        );
+
+        let response = registry
+            .dispatch(make_request(
+                "GET /api/cats HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect("The sub-app should respond to unknown routes itself");
+        assert_eq!(*response.status(), ResponseStatus::NotFound);
+
+        registry
+            .dispatch(make_request(
+                "GET /apis/dogs HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect_err("Paths that only share a prefix with the mount should not match");
+    }
+
+    /// Responds with the name of the route and the parameters it captured
+    struct RouteHandler {
+        path: HandlerPath,
+        name: &'static str,
+    }
+
+    impl Handler for RouteHandler {
+        fn get_path(&self) -> &HandlerPath {
+            &self.path
+        }
+
+        fn get_method(&self) -> &HTTPMethod {
+            &HTTPMethod::Get
+        }
+
+        fn on_request(&self, req: Request) -> HandlerResult {
+            let mut params: Vec<String> = req
+                .path_params()
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect();
+            params.sort();
+            let body = format!("{0} {1}", self.name, params.join("&"));
+            HandlerResult::Done(
+                ResponseBuilder::from(req)
+                    .ok()
+                    .text(body.trim_end())
+                    .build()
+                    .expect("A valid 200 response should be produced"),
+            )
+        }
+    }
+
+    fn route(path: &str, name: &'static str) -> Arc<SyncableHandler> {
+        Arc::new(RouteHandler {
+            path: HandlerPath::new(path),
+            name,
+        })
+    }
+
+    fn routed_to(registry: &HandlerRegistry, path: &str) -> Option<String> {
+        registry
+            .dispatch(make_request(&format!(
+                "GET {path} HTTP/1.1\r\nHost: example.com\r\n"
+            )))
+            .ok()
+            .map(|response| String::from_utf8_lossy(response.body()).to_string())
+    }
+
+    #[test]
+    fn path_params_captured() {
+        let registry = HandlerRegistry::new(vec![
+            route("/dogs/:id", "dog"),
+            route("/dogs/:id/toys/:toy", "toy"),
+        ]);
+
+        assert_eq!(
+            routed_to(&registry, "/dogs/42").as_deref(),
+            Some("dog id=42")
+        );
+        assert_eq!(
+            routed_to(&registry, "/dogs/42/?sort=name").as_deref(),
+            Some("dog id=42"),
+            "The query and a trailing slash should be ignored"
+        );
+        assert_eq!(
+            routed_to(&registry, "/dogs/42/toys/ball").as_deref(),
+            Some("toy id=42&toy=ball")
+        );
+        assert_eq!(routed_to(&registry, "/dogs"), None);
+        assert_eq!(
+            routed_to(&registry, "/dogs//toys/ball"),
+            None,
+            "Parameters should not match empty segments"
+        );
+        assert_eq!(routed_to(&registry, "/dogs/42/bones"), None);
+    }
+
+    #[test]
+    fn static_route_wins_over_params() {
+        let registry = HandlerRegistry::new(vec![
+            route("/dogs/:id", "dog"),
+            route("/dogs/new", "new"),
+            route("/:kind/new", "kind"),
+        ]);
+
+        assert_eq!(routed_to(&registry, "/dogs/new").as_deref(), Some("new"));
+        assert_eq!(
+            routed_to(&registry, "/dogs/new?breed=pug").as_deref(),
+            Some("new")
+        );
+        assert_eq!(routed_to(&registry, "/dogs/7").as_deref(), Some("dog id=7"));
+        assert_eq!(
+            routed_to(&registry, "/cats/new").as_deref(),
+            Some("kind kind=cats")
+        );
+    }
+
+    #[test]
+    fn duplicate_policy_consistent() {
+        struct NamedHandler {
+            path: HandlerPath,
+            name: &'static str,
+        }
+
+        impl Handler for NamedHandler {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Get
+            }
+
+            fn on_request(&self, req: Request) -> HandlerResult {
+                HandlerResult::Done(
+                    ResponseBuilder::from(req)
+                        .ok()
+                        .text(self.name)
+                        .build()
+                        .expect("A valid 200 response should be produced"),
+                )
+            }
+        }
+
+        let named = |name| -> Arc<SyncableHandler> {
+            Arc::new(NamedHandler {
+                path: HandlerPath::new("/"),
+                name,
+            })
+        };
+        let winner = |registry: &HandlerRegistry| {
+            let response = registry
+                .dispatch(make_request("GET / HTTP/1.1\r\nHost: example.com\r\n"))
+                .expect("The request should be dispatched");
+            String::from_utf8_lossy(response.body()).to_string()
+        };
+
+        for (policy, expected) in [
+            (DuplicateHandlerPolicy::FirstWins, "first"),
+            (DuplicateHandlerPolicy::LastWins, "second"),
+        ] {
+            let constructed = HandlerRegistry::with_duplicate_policy(
+                vec![named("first"), named("second")],
+                policy,
+            )
+            .expect("Duplicates should be resolved");
+            let mut added = HandlerRegistry::with_duplicate_policy(vec![named("first")], policy)
+                .expect("A single handler should be registered");
+            added
+                .add(named("second"))
+                .expect("Duplicates should be resolved");
+
+            assert_eq!(winner(&constructed), expected, "{policy:?}");
+            assert_eq!(winner(&added), expected, "{policy:?}");
+        }
+
+        let policy = DuplicateHandlerPolicy::Reject;
+        assert!(matches!(
+            HandlerRegistry::with_duplicate_policy(vec![named("first"), named("second")], policy),
+            Err(HandlerRegistryAddError::DuplicateKey(_))
+        ));
+        let mut added = HandlerRegistry::with_duplicate_policy(vec![named("first")], policy)
+            .expect("A single handler should be registered");
+        assert!(matches!(
+            added.add(named("second")),
+            Err(HandlerRegistryAddError::DuplicateKey(_))
+        ));
+        assert_eq!(winner(&added), "first");
+
+        let mut constructed = HandlerRegistry::new(vec![named("first"), named("second")]);
+        assert_eq!(
+            winner(&constructed),
+            "first",
+            "new shouldn't panic on duplicates"
+        );
+        assert!(
+            matches!(
+                constructed.add(named("third")),
+                Err(HandlerRegistryAddError::DuplicateKey(_))
+            ),
+            "Duplicates should be rejected by default"
+        );
+        assert!(matches!(
+            HandlerRegistry::try_new(vec![named("first"), named("second")]),
+            Err(HandlerRegistryAddError::DuplicateKey(_))
+        ));
+    }
+
+    #[test]
+    fn dispatch_unknown_method() {
+        let registry = HandlerRegistry::new(vec![Arc::new(HelloWorldHandler::new())]);
+
+        let err = registry
+            .dispatch(make_request("PROPFIND / HTTP/1.1\r\nHost: example.com\r\n"))
+            .expect_err("Dispatching an unknown method should fail");
+        assert_eq!(err.as_status_code(), ResponseStatus::NotImplemented);
+    }
+
+    #[test]
+    fn handler_group_shared_state() {
+        use crate::_crud_example::{
+            DogStore, DogStoreDeleteHandler, DogStoreGetHandler, DogStorePostHandler,
+        };
+        use std::sync::Mutex;
+
+        let group = HandlerGroup::new(Mutex::new(DogStore::default()))
+            .handler(DogStoreGetHandler::new)
+            .handler(DogStorePostHandler::new)
+            .handler(DogStoreDeleteHandler::new);
+        let state = group.state();
+        let registry = HandlerRegistry::new(group.build());
+
+        for method in [HTTPMethod::Get, HTTPMethod::Post, HTTPMethod::Delete] {
+            assert!(
+                registry
+                    .get(method.clone(), HandlerPath::new("/dogs"))
+                    .is_some(),
+                "A {method} handler for /dogs should be registered"
+            );
+        }
+        assert_eq!(
+            Arc::strong_count(&state),
+            4,
+            "Every handler should hold a reference to the same store"
+        );
+    }
+
+    #[test]
+    fn registry_routes() {
+        use crate::_crud_example::{
+            DogStore, DogStoreDeleteHandler, DogStoreGetHandler, DogStorePostHandler,
+        };
+        use std::sync::Mutex;
+
+        let mut handlers = HandlerGroup::new(Mutex::new(DogStore::default()))
+            .handler(DogStoreGetHandler::new)
+            .handler(DogStorePostHandler::new)
+            .handler(DogStoreDeleteHandler::new)
+            .build();
+        handlers.push(Arc::new(HelloWorldHandler::new()));
+        let registry = HandlerRegistry::new(handlers);
+
+        assert_eq!(
+            registry.routes(),
+            vec![
+                (HTTPMethod::Get, "/".to_string()),
+                (HTTPMethod::Delete, "/dogs".to_string()),
+                (HTTPMethod::Get, "/dogs".to_string()),
+                (HTTPMethod::Post, "/dogs".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn registry_key_with_old_delimiter() {
+        struct DelimiterHandler {
+            path: HandlerPath,
+        }
+
+        impl Handler for DelimiterHandler {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Get
+            }
+
+            fn on_request(&self, req: Request) -> HandlerResult {
+                HandlerResult::Continue(req)
+            }
+        }
+
+        let registry = HandlerRegistry::new(vec![
+            Arc::new(DelimiterHandler {
+                path: HandlerPath::new("/a[##]b"),
+            }),
+            Arc::new(DelimiterHandler {
+                path: HandlerPath::new("/a"),
+            }),
+        ]);
+
+        let handler = registry
+            .get(HTTPMethod::Get, HandlerPath::new("/a[##]b"))
+            .expect("A path containing [##] should be routable");
+        assert_eq!(*handler.get_path(), HandlerPath::new("/a[##]b"));
+        assert_eq!(
+            registry.routes(),
+            vec![
+                (HTTPMethod::Get, "/a".to_string()),
+                (HTTPMethod::Get, "/a[##]b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn hijacked_connection() {
+        struct EchoUpgradeHandler {
+            path: HandlerPath,
+        }
+
+        impl Handler for EchoUpgradeHandler {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Get
+            }
+
+            fn on_request(&self, req: Request) -> HandlerResult {
+                let response = ResponseBuilder::from(req)
+                    .status(ResponseStatus::SwitchingProtocols)
+                    .header("Upgrade", "echo")
+                    .header("Connection", "Upgrade")
+                    .build()
+                    .expect("A valid 101 response should be produced");
+                HandlerResult::Hijack(
+                    response,
+                    Box::new(|mut stream| {
+                        stream
+                            .write_all(b"custom protocol bytes")
+                            .expect("Writing to the hijacked stream should succeed");
+                    }),
+                )
+            }
+        }
+
+        let registry = HandlerRegistry::new(vec![Arc::new(EchoUpgradeHandler {
+            path: HandlerPath::new("/echo"),
+        })]);
+        let (request, output) =
+            crate::testing::mock_request("GET /echo HTTP/1.1\r\nHost: a.com\r\n", b"");
+        registry
+            .dispatch(request)
+            .expect("The upgrade route should be dispatched")
+            .send()
+            .expect("Sending the 101 should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "The initial response should be sent first. Written: {written:?}"
+        );
+        assert!(
+            written.ends_with("\r\n\r\ncustom protocol bytes"),
+            "The hijacker should write after the head. Written: {written:?}"
+        );
+    }
+
+    #[test]
+    fn deferred_job() {
+        let (request, _) =
+            crate::testing::mock_request("POST /exports HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let HandlerResult::Done(response) =
+            HandlerResult::defer(request, Some("/exports/1"), move || {
+                sender.send("finished").unwrap();
+            })
+        else {
+            panic!("Deferring should respond straight away");
+        };
+
+        assert_eq!(*response.status(), ResponseStatus::Accepted);
+        assert_eq!(
+            response.get_header("Location".to_string()),
+            Some("/exports/1".to_string())
+        );
+        assert_eq!(
+            receiver.recv_timeout(std::time::Duration::from_secs(5)),
+            Ok("finished"),
+            "The job should run in the background"
+        );
+    }
+
+    #[test]
+    fn deferred_job_on_executor() {
+        let (request, _) =
+            crate::testing::mock_request("POST /exports HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let (jobs, queued) = std::sync::mpsc::channel::<DeferredJob>();
+        let ran = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let ran_ref = Arc::clone(&ran);
+        let HandlerResult::Done(response) = HandlerResult::defer_with(
+            request,
+            None,
+            |job| jobs.send(job).expect("The executor should accept the job"),
+            move || ran_ref.store(true, std::sync::atomic::Ordering::Release),
+        ) else {
+            panic!("Deferring should respond straight away");
+        };
+
+        assert_eq!(*response.status(), ResponseStatus::Accepted);
+        assert!(
+            !ran.load(std::sync::atomic::Ordering::Acquire),
+            "The job should only run when the executor runs it"
+        );
+        queued.recv().expect("The job should be queued")();
+        assert!(ran.load(std::sync::atomic::Ordering::Acquire));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        use crate::_crud_example::{DogStore, DogStoreGetHandler};
+        use std::sync::Mutex;
+
+        let registry = HandlerRegistry::new(
+            HandlerGroup::new(Mutex::new(DogStore::default()))
+                .handler(DogStoreGetHandler::new)
+                .build(),
+        );
+        let err = registry
+            .dispatch(make_request(
+                "DELETE /dogs HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect_err("/dogs has no DELETE handler");
+        assert!(matches!(
+            err.reason,
+            HandlerCallErrorReason::MethodNotAllowed(_, ref methods)
+                if *methods == [HTTPMethod::Get, HTTPMethod::Head]
+        ));
+
+        let response = err
+            .into_response()
+            .build()
+            .expect("A valid 405 response should be produced");
+        assert_eq!(*response.status(), ResponseStatus::MethodNotAllowed);
+        assert_eq!(
+            response.get_header("Allow".to_string()),
+            Some("GET, HEAD".to_string()),
+            "GET handlers also serve HEAD"
+        );
+
+        let err = registry
+            .dispatch(make_request(
+                "DELETE /cats HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect_err("/cats has no handlers");
+        assert_eq!(err.as_status_code(), ResponseStatus::NotFound);
+    }
+
+    #[test]
+    fn automatic_options() {
+        use crate::_crud_example::{DogStore, DogStoreGetHandler, DogStorePostHandler};
+        use std::sync::Mutex;
+
+        struct DeleteCatHandler {
+            path: HandlerPath,
+        }
+
+        impl Handler for DeleteCatHandler {
+            fn get_path(&self) -> &HandlerPath {
+                &self.path
+            }
+
+            fn get_method(&self) -> &HTTPMethod {
+                &HTTPMethod::Delete
+            }
+
+            fn on_request(&self, _req: Request) -> HandlerResult {
+                todo!("Not dispatched to")
+            }
+        }
+
+        let mut handlers = HandlerGroup::new(Mutex::new(DogStore::default()))
+            .handler(DogStoreGetHandler::new)
+            .handler(DogStorePostHandler::new)
+            .build();
+        handlers.push(Arc::new(DeleteCatHandler {
+            path: HandlerPath::new("/cats/:id"),
+        }));
+        let registry = HandlerRegistry::new(handlers);
+
+        let response = registry
+            .dispatch(make_request(
+                "OPTIONS /dogs HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect("OPTIONS should be answered for paths with routes");
+        assert_eq!(*response.status(), ResponseStatus::NoContent);
+        assert_eq!(
+            response.get_header("Allow".to_string()),
+            Some("GET, HEAD, POST".to_string()),
+            "GET handlers also serve HEAD"
+        );
+
+        let response = registry
+            .dispatch(make_request("OPTIONS * HTTP/1.1\r\nHost: example.com\r\n"))
+            .expect("OPTIONS * should be answered");
+        assert_eq!(*response.status(), ResponseStatus::NoContent);
+        assert_eq!(
+            response.get_header("Allow".to_string()),
+            Some("DELETE, GET, HEAD, POST".to_string()),
+            "Every registered method should be listed"
+        );
+
+        registry
+            .dispatch(make_request(
+                "OPTIONS /cats HTTP/1.1\r\nHost: example.com\r\n",
+            ))
+            .expect_err("Paths without routes should still be not found");
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        use crate::_crud_example::{DogStore, DogStoreGetHandler};
+        use std::sync::Mutex;
+
+        let mut store = DogStore::default();
+        store.add("Alfred");
+        let registry = HandlerRegistry::new(
+            HandlerGroup::new(Mutex::new(store))
+                .handler(DogStoreGetHandler::new)
+                .build(),
+        );
+        let get = registry
+            .dispatch(make_request("GET /dogs HTTP/1.1\r\nHost: example.com\r\n"))
+            .expect("GET /dogs should be dispatched");
+        let head = registry
+            .dispatch(make_request("HEAD /dogs HTTP/1.1\r\nHost: example.com\r\n"))
+            .expect("HEAD /dogs should be answered by the GET handler");
+
+        assert_eq!(head.status(), get.status());
+        assert!(!get.body().is_empty());
+        assert_eq!(head.body(), b"", "HEAD responses have no body");
+        for header in ["Content-Length", "Content-Type"] {
+            assert_eq!(
+                head.get_header(header.to_string()),
+                get.get_header(header.to_string()),
+                "{header} should be the same as for GET"
+            );
+        }
+    }
 }
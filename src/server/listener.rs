@@ -1,23 +1,53 @@
 use crate::request::{self, http1_1, SyncableStream};
 use std::{
-    io::{BufRead, BufReader, Error as IoError, ErrorKind, Read},
-    net::{IpAddr, TcpListener, TcpStream},
+    hash::{BuildHasher, RandomState},
+    io::{BufReader, Error as IoError, ErrorKind, Read},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::RangeInclusive,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use log::info;
 
-use crate::request::RequestParseError;
+use crate::request::{
+    http1_1::HeadParseOptions, ConnectionInfo, ExpectPolicy, HTTPMethod, HTTPVersion,
+    RequestParseError, Scheme,
+};
 
 use super::{
-    handlers::HandlerRegistry,
-    request_queue::{RequestQueue, RequestQueueOptions, ThreadPool},
+    connection::{ConnectionEvent, ConnectionState, ResponseOutcome},
+    handlers::{HandlerRegistry, RequestDispatcher},
+    request_queue::{
+        QueuedRequest, RequestQueue, RequestQueueHandle, RequestQueueOptions, ResponseSizeHook,
+        ThreadPool,
+    },
+    response::{ResponseBuilder, ResponseStatus, DEFAULT_CHUNK_SIZE},
+    streams::{TeeStream, TrafficLog},
 };
 
-static CARRIAGE_RETURN: &str = "\r\n";
+/// Reads from a connection, which may be wrapped for debugging (see `TeeStream`)
+type ConnectionReader = BufReader<Box<dyn SyncableStream>>;
+/// The same as `BufReader`'s default
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+/// The default maximum number of connections served at once. Each one has its own thread
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Pick a Retry-After value uniformly from `range`.
+/// A reversed range, E.G `10..=5`, always gives its start
+pub fn jittered_retry_after(range: &RangeInclusive<u64>) -> u64 {
+    // Each RandomState is randomly keyed, which is enough randomness to spread out retries
+    let random = RandomState::new().hash_one(Instant::now());
+    match range.end().saturating_sub(*range.start()).checked_add(1) {
+        Some(span) => range.start() + random % span,
+        // Only `0..=u64::MAX` is too wide to count, and any value is within it
+        None => random,
+    }
+}
 
 /// A low-level function for receiving and operating on TCP connections.
 /// Use `Listener` for a higher level interface
@@ -34,8 +64,9 @@ where
     let listener = TcpListener::bind((ip, port))?;
 
     while !shutdown.load(Ordering::Acquire) {
-        let _ = on_stream(listener.accept()?.0)
-            .inspect_err(|err| println!("Error occurred in on_stream: {0:?}", err));
+        let _ = on_stream(listener.accept()?.0).inspect_err(
+            |err| log::error!(target: "listener", "Error occurred in on_stream: {err:?}"),
+        );
     }
     Ok(())
 }
@@ -47,6 +78,29 @@ pub struct ListenerConfig {
     /// Disables the CTRL + C signal as the ctrlc crate doesn't
     /// allow multiple handlers to be registered at the same time
     is_test: bool,
+    head_options: HeadParseOptions,
+    /// The total time allowed from accepting a connection to sending the response
+    request_timeout: Option<Duration>,
+    /// Disable Nagle's algorithm for responses that fit in a single packet
+    nodelay_small_responses: bool,
+    /// Requests received while this many are waiting for a worker are rejected with
+    /// 503 Service Unavailable
+    max_queued_requests: Option<usize>,
+    /// The range of Retry-After values, in seconds, sent with 503 responses
+    retry_after: RangeInclusive<u64>,
+    /// The capacity of the buffer used to read each request's head and body
+    read_buffer_size: usize,
+    on_response_sent: Option<ResponseSizeHook>,
+    expect_continue: ExpectPolicy,
+    /// The largest chunk sent in chunked responses
+    chunk_size: usize,
+    /// Where to record the bytes received and sent on every connection
+    traffic_log: Option<TrafficLog>,
+    /// Peers whose forwarding headers are believed, E.G a TLS-terminating load balancer
+    trusted_proxies: Vec<IpAddr>,
+    /// Connections accepted while this many are open are rejected with
+    /// 503 Service Unavailable, so that idle keep-alive connections can't exhaust threads
+    max_connections: usize,
 }
 
 impl Default for ListenerConfig {
@@ -54,60 +108,236 @@ impl Default for ListenerConfig {
         Self {
             timeout: Some(std::time::Duration::new(10, 0)),
             is_test: false,
+            head_options: HeadParseOptions::default(),
+            request_timeout: None,
+            nodelay_small_responses: true,
+            max_queued_requests: None,
+            retry_after: 1..=10,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            on_response_sent: None,
+            expect_continue: ExpectPolicy::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            traffic_log: None,
+            trusted_proxies: Vec::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
         }
     }
 }
 
 impl ListenerConfig {
     pub fn new(timeout: Option<std::time::Duration>, is_test: bool) -> Self {
-        Self { timeout, is_test }
+        Self {
+            timeout,
+            is_test,
+            ..Default::default()
+        }
+    }
+
+    /// Reject requests with a malformed Host header or a Host that disagrees with the
+    /// request target with 400 Bad Request
+    pub fn strict_host(mut self, strict_host: bool) -> Self {
+        self.head_options.strict_host = strict_host;
+        self
+    }
+
+    /// Resolve `.` and `..` segments in request paths before routing, so `/a/./b/../c` is
+    /// handled as `/a/c`. `..` can't escape the root
+    pub fn normalize_paths(mut self, normalize_paths: bool) -> Self {
+        self.head_options.normalize_paths = normalize_paths;
+        self
+    }
+
+    /// Reject requests with more than `max_query_params` query parameters with
+    /// 414 URI Too Long
+    pub fn max_query_params(mut self, max_query_params: usize) -> Self {
+        self.head_options.max_query_params = max_query_params;
+        self
+    }
+
+    /// Reject requests whose start line (method, target and version together) is longer than
+    /// `max_start_line_length` bytes with 414 URI Too Long.
+    /// The limit is enforced while reading, so overlong start lines are never buffered in full
+    pub fn max_start_line_length(mut self, max_start_line_length: usize) -> Self {
+        self.head_options.max_start_line_length = max_start_line_length;
+        self
+    }
+
+    /// Reject requests whose target is longer than `max_uri_length` bytes with
+    /// 414 URI Too Long
+    pub fn max_uri_length(mut self, max_uri_length: usize) -> Self {
+        self.head_options.max_uri_length = max_uri_length;
+        self
+    }
+
+    /// Reject requests whose headers (including line endings) are longer than
+    /// `max_head_length` bytes with 431 Request Header Fields Too Large
+    pub fn max_head_length(mut self, max_head_length: usize) -> Self {
+        self.head_options.max_head_length = max_head_length;
+        self
+    }
+
+    /// Bound the total time spent reading, processing and responding to a request.
+    /// Requests that take longer are responded to with 504 Gateway Timeout where possible
+    pub fn request_timeout(mut self, request_timeout: Option<Duration>) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Send small responses immediately instead of letting Nagle's algorithm delay them.
+    /// Large responses keep Nagle's algorithm enabled for throughput
+    pub fn nodelay_small_responses(mut self, enabled: bool) -> Self {
+        self.nodelay_small_responses = enabled;
+        self
+    }
+
+    /// Choose whether to send 100 Continue, proceed silently or respond with
+    /// 417 Expectation Failed to requests with `Expect: 100-continue`
+    pub fn expect_continue(mut self, policy: ExpectPolicy) -> Self {
+        self.expect_continue = policy;
+        self
+    }
+
+    /// Split chunked response bodies into chunks of at most `chunk_size` bytes.
+    /// Small chunks waste bandwidth on size prefixes while large chunks delay the first bytes
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunks can't be empty");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Record every byte read from and written to connections in `log` for debugging.
+    /// Concurrent connections are interleaved, so this is best used with a single client.
+    /// Error responses sent before a request is parsed are not recorded
+    pub fn record_traffic(mut self, log: TrafficLog) -> Self {
+        self.traffic_log = Some(log);
+        self
+    }
+
+    /// Report the number of bytes sent for each response, E.G to enforce per-tenant
+    /// egress quotas
+    pub fn on_response_sent(mut self, hook: ResponseSizeHook) -> Self {
+        self.on_response_sent = Some(hook);
+        self
+    }
+
+    /// Reject requests with 503 Service Unavailable while `max_queued` requests are waiting for
+    /// a worker. Each rejection has a random Retry-After from `retry_after` (in seconds) so that
+    /// clients don't all retry at the same time
+    pub fn overload_limit(mut self, max_queued: usize, retry_after: RangeInclusive<u64>) -> Self {
+        assert!(
+            !retry_after.is_empty(),
+            "{retry_after:?} is an invalid Retry-After range"
+        );
+        self.max_queued_requests = Some(max_queued);
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Reject new connections with 503 Service Unavailable while `max` are open.
+    /// Every open connection has a thread, including idle keep-alive connections
+    pub fn max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "{max} is an invalid connection limit");
+        self.max_connections = max;
+        self
+    }
+
+    /// Set the capacity of the buffer used to read requests. Larger buffers need fewer reads
+    /// for large bodies at the cost of more memory per connection
+    pub fn read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        assert!(read_buffer_size > 0, "The read buffer can't be empty");
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// Believe X-Forwarded-Proto on requests from these addresses. See `RequestHead::scheme`.
+    /// Only list proxies that overwrite or append to the header, or clients could spoof it
+    pub fn trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Describe a connection from `peer_addr`. Connections are always plaintext
+    fn connection_info(&self, peer_addr: Option<SocketAddr>) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr,
+            scheme: Scheme::Http,
+            trusted_proxy: peer_addr.is_some_and(|addr| self.trusted_proxies.contains(&addr.ip())),
+        }
+    }
+
+    fn reader<R: Read>(&self, stream: R) -> BufReader<R> {
+        BufReader::with_capacity(self.read_buffer_size, stream)
     }
 }
 
 /// Parses incoming HTTP messages from TCP connections using
 /// the given parse function before dispatching the request to handlers.
+/// Each connection is read on its own thread so that idle keep-alive connections don't block
+/// new ones from being accepted.
+/// Requests are routed by `D`, which is usually a `HandlerRegistry` but can be any
+/// `RequestDispatcher`, E.G a router that scales better to many routes.
 /// Will support middleware in the future
-pub struct HTTPListener {
+pub struct HTTPListener<D: RequestDispatcher = HandlerRegistry> {
     ip: IpAddr,
     port: u16,
+    dispatcher: Arc<D>,
     request_queue: RequestQueue,
-    config: ListenerConfig,
+    config: Arc<ListenerConfig>,
     // This will be written to at most once but read every time there is a new connection
     shutdown_signal: Arc<AtomicBool>,
+    open_connections: Arc<AtomicUsize>,
 }
 
 impl SyncableStream for TcpStream {
     fn get_type(&self) -> request::SyncableStreamType {
         request::SyncableStreamType::Tcp
     }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), IoError> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn shutdown(&self) -> Result<(), IoError> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
 }
 
-impl HTTPListener {
-    pub fn new(
-        ip: IpAddr,
-        port: u16,
-        handler_registry: HandlerRegistry,
-        config: ListenerConfig,
-    ) -> Self {
-        let request_queue =
-            RequestQueue::new(Arc::new(handler_registry), RequestQueueOptions::default())
-                .expect("The threadpool should spawn");
+impl<D: RequestDispatcher + Send + Sync + 'static> HTTPListener<D> {
+    pub fn new(ip: IpAddr, port: u16, dispatcher: D, config: ListenerConfig) -> Self {
+        let queue_options = RequestQueueOptions::default()
+            .nodelay_small_responses(config.nodelay_small_responses)
+            .on_response_sent(config.on_response_sent.clone())
+            .chunk_size(config.chunk_size);
+        let dispatcher = Arc::new(dispatcher);
+        let request_queue = RequestQueue::new(Arc::clone(&dispatcher), queue_options)
+            .expect("The threadpool should spawn");
 
         Self {
             ip,
             port,
-            config,
+            dispatcher,
+            config: Arc::new(config),
             request_queue,
             shutdown_signal: Arc::new(AtomicBool::new(false)),
+            open_connections: Arc::new(AtomicUsize::new(0)),
         }
     }
 
+    /// The dispatcher that the workers route requests with
+    pub fn dispatcher(&self) -> &D {
+        &self.dispatcher
+    }
+
     /// Send the signal to stop processing new TCP connections and already-accepted requests
     pub fn shutdown(&mut self) {
         log::info!("Shutting down listener. Source: shutdown() call");
 
         self.shutdown_signal.store(true, Ordering::Release);
-        HTTPListener::dummy_request(self.ip, self.port);
+        Self::dummy_request(self.ip, self.port);
         self.request_queue.shutdown();
     }
 
@@ -124,7 +354,7 @@ impl HTTPListener {
         ctrlc::set_handler(move || {
             log::info!("Shutting down listener. Source: interrupt handler");
             signal_ref.store(true, Ordering::Release);
-            HTTPListener::dummy_request(owned_ip, owned_port);
+            Self::dummy_request(owned_ip, owned_port);
         })
         .expect("The CTRL + C interrupt handler should spawn");
     }
@@ -134,11 +364,26 @@ impl HTTPListener {
             self.create_signal_handler();
         }
 
+        let context = self.connection_context();
         let result = listen(
             self.ip,
             self.port,
             Arc::clone(&self.shutdown_signal),
-            |mut conn| self.handle_connection(&mut conn),
+            |mut conn| {
+                let Some(slot) = context.open_connection() else {
+                    return context.reject_connection(&conn);
+                };
+                let context = context.clone();
+                thread::Builder::new()
+                    .name("http-connection".to_string())
+                    .spawn(move || {
+                        let _ = context.handle_connection(&mut conn).inspect_err(|err| {
+                            info!(target: "listener", "Error occurred while handling a connection: {err:?}")
+                        });
+                        drop(slot);
+                    })
+                    .map(|_| ())
+            },
         );
 
         // This will run after the shutdown signal has been received via CTRL + C
@@ -146,58 +391,310 @@ impl HTTPListener {
         result
     }
 
-    fn handle_connection(&mut self, stream: &mut TcpStream) -> Result<(), IoError> {
-        let client_ip: String = stream
-            .peer_addr()
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            config: Arc::clone(&self.config),
+            request_queue: self.request_queue.handle(),
+            open_connections: Arc::clone(&self.open_connections),
+        }
+    }
+}
+
+fn advance(state: ConnectionState, event: ConnectionEvent) -> ConnectionState {
+    state
+        .next(event)
+        .expect("The connection loop should only make valid transitions")
+}
+
+/// Everything needed to serve the requests sent on one connection
+#[derive(Clone)]
+struct ConnectionContext {
+    config: Arc<ListenerConfig>,
+    request_queue: RequestQueueHandle,
+    open_connections: Arc<AtomicUsize>,
+}
+
+/// Counts towards `ListenerConfig::max_connections` until dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl ConnectionContext {
+    /// Count a new connection, unless the maximum number of connections are already open
+    fn open_connection(&self) -> Option<ConnectionSlot> {
+        self.open_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.config.max_connections).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(&self.open_connections)))
+    }
+
+    /// Turn away a connection accepted while too many are open.
+    /// This runs on the accepting thread, so it can't wait long for a slow client
+    fn reject_connection(&self, stream: &TcpStream) -> Result<(), IoError> {
+        info!(target: "listener", "Rejecting connection: too many connections are open");
+        stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+        ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .status(ResponseStatus::ServiceUnavailable)
+            .header(
+                "Retry-After",
+                &jittered_retry_after(&self.config.retry_after).to_string(),
+            )
+            .text("Too many connections are open")
+            .header("Connection", "close")
+            .stream(Box::new(stream.try_clone()?))
+            .build()
+            .expect("A valid 503 response should be produced")
+            .send()
+            .map(|_| ())
+    }
+
+    /// Serve requests from `stream` until the connection is closed.
+    /// HTTP/1.1 connections are kept open after each response, and the next request is only
+    /// read once the previous response has been sent.
+    /// Returns the state the connection finished in: `Closed` or `Upgraded`
+    fn handle_connection(&self, stream: &mut TcpStream) -> Result<ConnectionState, IoError> {
+        let peer_addr = stream.peer_addr().ok();
+        let client_ip: String = peer_addr
             .map(|addr| addr.to_string())
             .unwrap_or("IP address unknown".to_string());
         info!(target: "listener", "Connection received from {client_ip}");
 
-        info!(target: "listener", "Configuring connection for {client_ip}");
-        self.configure_connection(stream)?;
+        let mut connection = self.wrap_stream(stream)?;
+        let mut state = ConnectionState::ReadingHead;
 
-        let (request_content, reader) = self.read_message(stream)?;
-        info!(target: "listener", "Parsing message from {client_ip} as HTTP request");
+        loop {
+            let deadline = self
+                .config
+                .request_timeout
+                .map(|request_timeout| Instant::now() + request_timeout);
 
-        let request_head = self.parse_message(request_content).map_err(|err| {
-        info!(target: "listener", "Failed to parse request from {client_ip} due to the following error: {err}");
-        IoError::new(
-            ErrorKind::InvalidData,
-            "Could not parse message as HTTP request",
-        )
-    })?;
-        info!(target: "listener", "Request received from {client_ip}: {request_head:?}");
+            info!(target: "listener", "Configuring connection for {client_ip}");
+            self.configure_connection(stream, deadline)?;
 
-        let request = request::Request::new(request_head, reader);
+            let (request_content, reader) = match self.read_message(connection) {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    // Not an error: clients (and `shutdown`) may connect without sending anything
+                    log::debug!(target: "listener", "Connection from {client_ip} closed before sending a request");
+                    return Ok(advance(state, ConnectionEvent::Closed));
+                }
+                Err(err)
+                    if state.is_idle()
+                        && matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    log::debug!(target: "listener", "Closing idle connection from {client_ip}");
+                    return Ok(advance(state, ConnectionEvent::Closed));
+                }
+                Err(err)
+                    if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                        && deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                {
+                    info!(target: "listener", "Request from {client_ip} exceeded the request timeout");
+                    // The client may have stopped reading too, so failing to respond is expected
+                    let _ = self.respond(
+                        stream,
+                        None,
+                        ResponseStatus::GatewayTimeout,
+                        "The request took too long to receive",
+                    );
+                    return Err(err);
+                }
+                Err(err) => {
+                    if let Some(parse_err) = err
+                        .get_ref()
+                        .and_then(|inner| inner.downcast_ref::<RequestParseError>())
+                    {
+                        info!(target: "listener", "Rejecting request from {client_ip} due to the following error: {parse_err}");
+                        self.reject(stream, None, parse_err)?;
+                    }
+                    return Err(err);
+                }
+            };
+            info!(target: "listener", "Parsing message from {client_ip} as HTTP request");
 
-        self.request_queue.enqueue(request);
-        Ok(())
+            // Known even if the rest of the head is invalid, so a HEAD request isn't sent a body
+            let method = request_content
+                .split(' ')
+                .next()
+                .and_then(|method| method.parse::<HTTPMethod>().ok());
+            let mut request_head = match self.parse_message(request_content) {
+                Ok(head) => head,
+                Err(err) => {
+                    info!(target: "listener", "Failed to parse request from {client_ip} due to the following error: {err}");
+                    self.reject(stream, method.as_ref(), &err)?;
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        "Could not parse message as HTTP request",
+                    ));
+                }
+            };
+            request_head.connection = self.config.connection_info(peer_addr);
+            info!(target: "listener", "Request received from {client_ip}: {request_head:?}");
+            // The body is left for the worker to read, so the connection is only in
+            // `ReadingBody` until the request is queued or rejected
+            state = advance(state, ConnectionEvent::HeadReceived);
+
+            let method = request_head.method.clone();
+            let mut request = request::Request::new(request_head, reader);
+            request.set_deadline(deadline);
+
+            if !request.apply_expect_policy(self.config.expect_continue) {
+                info!(target: "listener", "Rejecting request from {client_ip}: Expect is not supported");
+                ResponseBuilder::from(request)
+                    .status(ResponseStatus::ExpectationFailed)
+                    .text("Expect: 100-continue is not supported")
+                    .header("Connection", "close")
+                    .build()
+                    .expect("A valid 417 response should be produced")
+                    .for_method(&method)
+                    .send()?;
+                return Ok(ResponseOutcome::Closed
+                    .events()
+                    .into_iter()
+                    .fold(state, advance));
+            }
+
+            if self
+                .config
+                .max_queued_requests
+                .is_some_and(|max_queued| self.request_queue.pending() >= max_queued)
+            {
+                info!(target: "listener", "Rejecting request from {client_ip}: the server is overloaded");
+                ResponseBuilder::from(request)
+                    .status(ResponseStatus::ServiceUnavailable)
+                    .header(
+                        "Retry-After",
+                        &jittered_retry_after(&self.config.retry_after).to_string(),
+                    )
+                    .text("The server is overloaded")
+                    .header("Connection", "close")
+                    .build()
+                    .expect("A valid 503 response should be produced")
+                    .for_method(&method)
+                    .send()?;
+                return Ok(ResponseOutcome::Closed
+                    .events()
+                    .into_iter()
+                    .fold(state, advance));
+            }
+
+            let keep_alive = request.keep_alive();
+            // Requests aren't pipelined: the next one is only read once this one has been
+            // responded to, so each connection has at most one request in flight and can't
+            // monopolise the workers. No per-connection limit is needed (see the
+            // `pipelining_does_not_starve` test)
+            let (sender, receiver) = mpsc::channel();
+            self.request_queue
+                .enqueue(QueuedRequest::from(request).return_stream_to(sender));
+            state = advance(state, ConnectionEvent::Dispatched);
+
+            // The sender is dropped without reporting if the response couldn't be sent
+            let outcome = match receiver.recv() {
+                Ok(ResponseOutcome::KeepAlive(_)) if !keep_alive => ResponseOutcome::Closed,
+                Ok(outcome) => outcome,
+                Err(_) => ResponseOutcome::Closed,
+            };
+            state = outcome.events().into_iter().fold(state, advance);
+            log::debug!(target: "listener", "Connection from {client_ip} is now {state:?}");
+            match outcome {
+                ResponseOutcome::KeepAlive(returned) => connection = returned,
+                ResponseOutcome::Closed | ResponseOutcome::Upgraded => return Ok(state),
+            }
+        }
     }
 
-    fn configure_connection(&self, conn: &TcpStream) -> Result<(), IoError> {
-        conn.set_read_timeout(self.config.timeout)?;
-        conn.set_write_timeout(self.config.timeout)?;
+    /// Respond to a request that couldn't be parsed
+    fn reject(
+        &self,
+        stream: &TcpStream,
+        method: Option<&HTTPMethod>,
+        err: &RequestParseError,
+    ) -> Result<(), IoError> {
+        self.respond(stream, method, err.as_status_code(), &err.to_string())
+    }
+
+    /// Respond without a parsed request and close the connection.
+    /// The version is unknown, so the response uses the newest supported HTTP/1.x version.
+    /// The body is left out if `method` is known to be HEAD
+    fn respond(
+        &self,
+        stream: &TcpStream,
+        method: Option<&HTTPMethod>,
+        status: ResponseStatus,
+        message: &str,
+    ) -> Result<(), IoError> {
+        let response = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .status(status)
+            .text(message)
+            .header("Connection", "close")
+            .stream(Box::new(stream.try_clone()?))
+            .build()
+            .expect("A valid error response should be produced");
+        match method {
+            Some(method) => response.for_method(method),
+            None => response,
+        }
+        .send()
+        .map(|_| ())
+    }
+
+    fn configure_connection(
+        &self,
+        conn: &TcpStream,
+        deadline: Option<Instant>,
+    ) -> Result<(), IoError> {
+        // The socket can't block for longer than the time left until the deadline
+        let remaining = deadline.map(|deadline| {
+            deadline
+                .saturating_duration_since(Instant::now())
+                // A zero timeout is invalid
+                .max(Duration::from_millis(1))
+        });
+        let timeout = match (self.config.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+
+        conn.set_read_timeout(timeout)?;
+        conn.set_write_timeout(timeout)?;
         Ok(())
     }
 
-    fn read_message(&self, stream: &TcpStream) -> Result<(String, BufReader<TcpStream>), IoError> {
-        let mut request_content = String::new();
-        // Read until end of request head (empty line).
+    /// The stream that requests are read from and responses are written to
+    fn wrap_stream(&self, stream: &TcpStream) -> Result<Box<dyn SyncableStream>, IoError> {
+        Ok(match &self.config.traffic_log {
+            Some(log) => Box::new(TeeStream::new(
+                Box::new(stream.try_clone()?),
+                Arc::clone(log),
+            )),
+            None => Box::new(stream.try_clone()?),
+        })
+    }
+
+    /// Read a request head from the connection.
+    /// Returns `None` if the connection was closed before any bytes were sent
+    fn read_message(
+        &self,
+        stream: Box<dyn SyncableStream>,
+    ) -> Result<Option<(String, ConnectionReader)>, IoError> {
         // NOTE: further reading will be required to get the request body
-        let mut reader = stream.try_clone().map(BufReader::new)?;
-        // This ultimately does 2 passes through the connection :( Would it be possible to cut out
-        // the first pass? The main reason for it is to unwrap each line
-        for line in reader.by_ref().lines() {
-            let mut unwrapped = line?;
-            if unwrapped.is_empty() {
-                break;
-            } else {
-                unwrapped.push_str(CARRIAGE_RETURN);
-                request_content += &unwrapped;
-            }
+        let mut reader = self.config.reader(stream);
+        let (request_content, consumed) =
+            http1_1::read_head_with(&mut reader, &self.config.head_options)?;
+        if consumed == 0 {
+            return Ok(None);
         }
+        log::debug!(target: "listener", "Request head consumed {consumed} bytes");
 
-        Ok((request_content, reader))
+        Ok(Some((request_content, reader)))
     }
 
     fn parse_message(
@@ -206,6 +703,528 @@ impl HTTPListener {
     ) -> Result<crate::request::RequestHead, RequestParseError> {
         // This iterator will be adavanced to the request body
         let req_lines = &mut message.lines();
-        http1_1::parse_req_head(req_lines)
+        http1_1::parse_req_head_with(req_lines, &self.config.head_options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn read_buffer_size_used() {
+        let reader = ListenerConfig::default().reader(Cursor::new(Vec::new()));
+        assert_eq!(reader.capacity(), DEFAULT_READ_BUFFER_SIZE);
+
+        let reader = ListenerConfig::default()
+            .read_buffer_size(64 * 1024)
+            .reader(Cursor::new(Vec::new()));
+        assert_eq!(
+            reader.capacity(),
+            64 * 1024,
+            "The configured capacity should be used"
+        );
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let config = ListenerConfig::default().trusted_proxies(vec![[10, 0, 0, 1].into()]);
+        assert!(
+            config
+                .connection_info(Some(([10, 0, 0, 1], 50000).into()))
+                .trusted_proxy
+        );
+        assert!(
+            !config
+                .connection_info(Some(([10, 0, 0, 2], 50000).into()))
+                .trusted_proxy
+        );
+        assert!(!config.connection_info(None).trusted_proxy);
+    }
+
+    #[test]
+    fn immediately_closed_connection() {
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        drop(TcpStream::connect(addr).expect("Connecting should succeed"));
+        let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+        let mut listener = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            HandlerRegistry::new(Vec::new()),
+            ListenerConfig::default(),
+        );
+        let (result, records) = crate::testing::capture_logs(|| {
+            listener.connection_context().handle_connection(&mut stream)
+        });
+        listener.request_queue.shutdown();
+        result.expect("A connection closed before sending anything should not be an error");
+        assert!(
+            records
+                .iter()
+                .any(|(level, message)| *level == log::Level::Debug
+                    && message.contains("closed before sending a request")),
+            "The closed connection should be logged at debug level. Logged: {records:?}"
+        );
+        assert!(
+            records.iter().all(|(level, _)| *level > log::Level::Warn),
+            "A closed connection isn't a problem worth warning about. Logged: {records:?}"
+        );
+    }
+
+    #[test]
+    fn connection_limit() {
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        let mut listener = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            HandlerRegistry::new(Vec::new()),
+            ListenerConfig::default()
+                .max_connections(1)
+                .overload_limit(usize::MAX, 5..=5),
+        );
+        let context = listener.connection_context();
+
+        let slot = context
+            .open_connection()
+            .expect("The first connection should be allowed");
+        assert!(
+            context.open_connection().is_none(),
+            "Connections over the limit should be refused"
+        );
+
+        let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+        let (stream, _) = server.accept().expect("Accepting should succeed");
+        context
+            .reject_connection(&stream)
+            .expect("The rejection should be sent");
+        drop(stream);
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .expect("Reading the rejection should succeed");
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(response.contains("Retry-After: 5\r\n"), "{response}");
+
+        drop(slot);
+        assert!(
+            context.open_connection().is_some(),
+            "Closing a connection should free its slot"
+        );
+        listener.request_queue.shutdown();
+    }
+
+    #[test]
+    fn head_error_without_body() {
+        use std::io::Write;
+
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+        client
+            .write_all(b"HEAD / HTTP/1.1\r\nHost: a.com\r\nNot a header\r\n\r\n")
+            .unwrap();
+        let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+        let mut listener = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            HandlerRegistry::new(Vec::new()),
+            ListenerConfig::default(),
+        );
+        assert!(listener
+            .connection_context()
+            .handle_connection(&mut stream)
+            .is_err());
+        listener.request_queue.shutdown();
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"), "{response:?}");
+        assert!(
+            response.ends_with("\r\n\r\n"),
+            "Errors in reply to HEAD requests should not have a body. Response: {response:?}"
+        );
+    }
+
+    #[test]
+    fn overlong_head_rejected() {
+        use std::io::Write;
+
+        let endless_start_line = "a".repeat(1000);
+        let endless_header = format!("GET / HTTP/1.1\r\nHost: a.com\r\nX: {0}", "a".repeat(1000));
+        for (head, expected) in [
+            (endless_start_line, "HTTP/1.1 414"),
+            (endless_header, "HTTP/1.1 431"),
+        ] {
+            let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+            let addr = server.local_addr().unwrap();
+            let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+            // The head never ends, so it can only be rejected once the limit is reached
+            client.write_all(head.as_bytes()).unwrap();
+            let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+            let mut listener = HTTPListener::new(
+                addr.ip(),
+                addr.port(),
+                HandlerRegistry::new(Vec::new()),
+                ListenerConfig::default()
+                    .max_start_line_length(64)
+                    .max_head_length(128),
+            );
+            assert!(listener
+                .connection_context()
+                .handle_connection(&mut stream)
+                .is_err());
+            listener.request_queue.shutdown();
+            drop(stream);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with(expected), "{response:?}");
+        }
+    }
+
+    #[test]
+    fn connection_states() {
+        use std::io::Write;
+
+        /// Serve one connection that sends `raw` and then stops writing
+        fn serve(config: ListenerConfig, raw: &str) -> (ConnectionState, String) {
+            let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+            let addr = server.local_addr().unwrap();
+            let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+            client.write_all(raw.as_bytes()).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+            let mut listener = HTTPListener::new(
+                addr.ip(),
+                addr.port(),
+                HandlerRegistry::new(Vec::new()),
+                config,
+            );
+            let state = listener
+                .connection_context()
+                .handle_connection(&mut stream)
+                .expect("The connection should be served");
+            listener.request_queue.shutdown();
+            drop(stream);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            (state, response)
+        }
+
+        let (state, response) = serve(
+            ListenerConfig::default().expect_continue(ExpectPolicy::Reject417),
+            "POST / HTTP/1.1\r\nHost: a.com\r\nExpect: 100-continue\r\nContent-Length: 1\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 417"), "{response:?}");
+        assert_eq!(state, ConnectionState::Closed);
+
+        let (state, response) = serve(
+            ListenerConfig::default().overload_limit(0, 5..=5),
+            "GET / HTTP/1.1\r\nHost: a.com\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 503"), "{response:?}");
+        assert_eq!(state, ConnectionState::Closed);
+
+        let (state, response) = serve(
+            ListenerConfig::default(),
+            "GET / HTTP/1.1\r\nHost: a.com\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404"), "{response:?}");
+        assert_eq!(
+            state,
+            ConnectionState::Closed,
+            "A kept-alive connection should close when the client does"
+        );
+    }
+
+    #[test]
+    fn empty_body_keep_alive() {
+        use crate::request::Request;
+        use crate::server::handlers::{HandlerCallError, HandlerRegistryAddError, SyncableHandler};
+        use crate::server::response::Response;
+        use std::io::Write;
+
+        /// Responds to every request with an empty 200
+        struct EmptyDispatcher;
+
+        impl RequestDispatcher for EmptyDispatcher {
+            type Error = HandlerCallError;
+
+            fn add(
+                &mut self,
+                _handler: Arc<SyncableHandler>,
+            ) -> Result<(), HandlerRegistryAddError> {
+                unimplemented!("Handlers aren't needed")
+            }
+
+            fn dispatch(&self, request: Request) -> Result<Response, Self::Error> {
+                Ok(ResponseBuilder::from(request)
+                    .ok()
+                    .build()
+                    .expect("A valid 200 response should be produced"))
+            }
+        }
+
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+        let mut listener: HTTPListener<EmptyDispatcher> = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            EmptyDispatcher,
+            ListenerConfig::default(),
+        );
+        let context = listener.connection_context();
+        let connection = thread::spawn(move || context.handle_connection(&mut stream));
+
+        client
+            .write_all(b"GET /first HTTP/1.1\r\nHost: a.com\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let (first, _) =
+            http1_1::read_head(&mut reader).expect("The first response should be received");
+        assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{first:?}");
+        assert!(
+            first.contains("Content-Length: 0\r\n"),
+            "The empty body should be length-delimited. Response: {first:?}"
+        );
+
+        client
+            .write_all(b"GET /second HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut second = String::new();
+        reader
+            .read_to_string(&mut second)
+            .expect("The second request should be served on the same connection");
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"), "{second:?}");
+
+        connection
+            .join()
+            .expect("The connection thread should not panic")
+            .expect("Both requests should be served");
+        listener.request_queue.shutdown();
+    }
+
+    #[test]
+    fn pipelining_does_not_starve() {
+        use crate::request::Request;
+        use crate::server::handlers::{HandlerCallError, HandlerRegistryAddError, SyncableHandler};
+        use crate::server::response::Response;
+        use std::io::Write;
+
+        /// Takes a while to respond and records how many requests from the pipelining
+        /// connection were being handled at once
+        #[derive(Default)]
+        struct SlowDispatcher {
+            pipelined_in_flight: AtomicUsize,
+            max_pipelined_in_flight: AtomicUsize,
+        }
+
+        impl RequestDispatcher for SlowDispatcher {
+            type Error = HandlerCallError;
+
+            fn add(
+                &mut self,
+                _handler: Arc<SyncableHandler>,
+            ) -> Result<(), HandlerRegistryAddError> {
+                unimplemented!("Handlers aren't needed")
+            }
+
+            fn dispatch(&self, request: Request) -> Result<Response, Self::Error> {
+                let pipelined = request.head.path.to_string() == "/pipelined";
+                if pipelined {
+                    let in_flight = self.pipelined_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    self.max_pipelined_in_flight
+                        .fetch_max(in_flight, Ordering::SeqCst);
+                }
+                thread::sleep(Duration::from_millis(20));
+                if pipelined {
+                    self.pipelined_in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                Ok(ResponseBuilder::from(request)
+                    .ok()
+                    .text("done")
+                    .build()
+                    .expect("A valid 200 response should be produced"))
+            }
+        }
+
+        const PIPELINED: usize = 50;
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        let mut listener: HTTPListener<SlowDispatcher> = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            SlowDispatcher::default(),
+            ListenerConfig::default(),
+        );
+
+        let mut greedy = TcpStream::connect(addr).expect("Connecting should succeed");
+        let (mut greedy_stream, _) = server.accept().expect("Accepting should succeed");
+        let mut requests = "GET /pipelined HTTP/1.1\r\nHost: a.com\r\n\r\n".repeat(PIPELINED - 1);
+        requests += "GET /pipelined HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n\r\n";
+        greedy.write_all(requests.as_bytes()).unwrap();
+        let context = listener.connection_context();
+        let greedy_connection =
+            thread::spawn(move || context.handle_connection(&mut greedy_stream));
+        // Let the pipelining connection get ahead
+        thread::sleep(Duration::from_millis(50));
+
+        let mut polite = TcpStream::connect(addr).expect("Connecting should succeed");
+        let (mut polite_stream, _) = server.accept().expect("Accepting should succeed");
+        let sent = Instant::now();
+        polite
+            .write_all(b"GET /polite HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        listener
+            .connection_context()
+            .handle_connection(&mut polite_stream)
+            .expect("The polite connection should be served");
+        drop(polite_stream);
+        let mut response = String::new();
+        polite.read_to_string(&mut response).unwrap();
+        let waited = sent.elapsed();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response:?}");
+        assert!(
+            waited < Duration::from_millis(20 * PIPELINED as u64 / 2),
+            "The second connection shouldn't wait behind the pipelined requests. Waited {waited:?}"
+        );
+
+        greedy_connection
+            .join()
+            .expect("The connection thread should not panic")
+            .expect("The pipelined requests should be served");
+        let mut responses = String::new();
+        greedy.read_to_string(&mut responses).unwrap();
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), PIPELINED);
+        assert_eq!(
+            listener
+                .dispatcher()
+                .max_pipelined_in_flight
+                .load(Ordering::SeqCst),
+            1,
+            "A connection should never have more than one request in flight"
+        );
+        listener.request_queue.shutdown();
+    }
+
+    #[test]
+    fn custom_dispatcher() {
+        use crate::request::Request;
+        use crate::server::handlers::{HandlerCallError, HandlerRegistryAddError, SyncableHandler};
+        use crate::server::response::Response;
+        use std::io::Write;
+        use std::sync::atomic::AtomicUsize;
+
+        /// Responds to every request with its path, without any routing
+        #[derive(Default)]
+        struct EchoPathDispatcher {
+            served: AtomicUsize,
+        }
+
+        impl RequestDispatcher for EchoPathDispatcher {
+            type Error = HandlerCallError;
+
+            fn add(
+                &mut self,
+                _handler: Arc<SyncableHandler>,
+            ) -> Result<(), HandlerRegistryAddError> {
+                unimplemented!("Handlers aren't needed")
+            }
+
+            fn dispatch(&self, request: Request) -> Result<Response, Self::Error> {
+                self.served.fetch_add(1, Ordering::Relaxed);
+                let path = request.head.path.to_string();
+                Ok(ResponseBuilder::from(request)
+                    .ok()
+                    .text(&path)
+                    .build()
+                    .expect("A valid 200 response should be produced"))
+            }
+        }
+
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let addr = server.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).expect("Connecting should succeed");
+        client
+            .write_all(b"GET /any/path HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (mut stream, _) = server.accept().expect("Accepting should succeed");
+
+        let mut listener: HTTPListener<EchoPathDispatcher> = HTTPListener::new(
+            addr.ip(),
+            addr.port(),
+            EchoPathDispatcher::default(),
+            ListenerConfig::default(),
+        );
+        listener
+            .connection_context()
+            .handle_connection(&mut stream)
+            .expect("The request should be served");
+        listener.request_queue.shutdown();
+        drop(stream);
+        assert_eq!(listener.dispatcher().served.load(Ordering::Relaxed), 1);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 200 OK\r\n"),
+            "Response: {response:?}"
+        );
+        assert!(
+            response.ends_with("\r\n\r\n/any/path"),
+            "The custom dispatcher should have produced the response. Response: {response:?}"
+        );
+    }
+
+    #[test]
+    fn retry_after_within_bounds() {
+        let range = 5..=30;
+        let retry_afters: Vec<u64> = (0..50).map(|_| jittered_retry_after(&range)).collect();
+
+        assert!(
+            retry_afters
+                .iter()
+                .all(|retry_after| range.contains(retry_after)),
+            "Every Retry-After should be within {range:?}. Got {retry_afters:?}"
+        );
+        assert!(
+            retry_afters
+                .iter()
+                .any(|retry_after| *retry_after != retry_afters[0]),
+            "Retry-After should be jittered. Got {retry_afters:?}"
+        );
+        assert_eq!(jittered_retry_after(&(7..=7)), 7);
+        assert_eq!(
+            jittered_retry_after(&RangeInclusive::new(10, 5)),
+            10,
+            "A reversed range should not underflow"
+        );
+        // Only checking that the span doesn't overflow
+        jittered_retry_after(&(0..=u64::MAX));
+        assert!(
+            (u64::MAX - 1..=u64::MAX).contains(&jittered_retry_after(&(u64::MAX - 1..=u64::MAX)))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid Retry-After range")]
+    fn reversed_retry_after_rejected() {
+        let _ = ListenerConfig::default().overload_limit(10, RangeInclusive::new(10, 5));
     }
 }
@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::request::{Path, Request};
+
+use super::handlers::{
+    HandlerRegistry, HandlerRegistryAddError, RequestDispatcher, SyncableHandler,
+};
+use super::response::{Response, ResponseBuilder, ResponseStatus};
+
+/// The default Retry-After, in seconds, sent while the server is in maintenance mode
+pub const DEFAULT_MAINTENANCE_RETRY_AFTER: u64 = 120;
+
+/// Wraps a dispatcher so that an operator can flip the whole server into maintenance mode,
+/// E.G during a deployment. While the flag is set, every request except those for the allowed
+/// paths (E.G a health check) is answered with 503 Service Unavailable and a Retry-After.
+/// Use `flag` to get a handle that can be set from another thread
+pub struct Maintenance<D: RequestDispatcher = HandlerRegistry> {
+    inner: D,
+    enabled: Arc<AtomicBool>,
+    /// Paths that are still dispatched in maintenance mode. Matched exactly, ignoring the query
+    allowed: Vec<String>,
+    retry_after: u64,
+}
+
+impl<D: RequestDispatcher> Maintenance<D> {
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            enabled: Arc::new(AtomicBool::new(false)),
+            allowed: Vec::new(),
+            retry_after: DEFAULT_MAINTENANCE_RETRY_AFTER,
+        }
+    }
+
+    /// Keep serving requests for `path` in maintenance mode
+    pub fn allow(mut self, path: &str) -> Self {
+        self.allowed.push(path.to_string());
+        self
+    }
+
+    /// The Retry-After, in seconds, sent with maintenance responses
+    pub fn retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = seconds;
+        self
+    }
+
+    /// The flag that turns maintenance mode on when set to true
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.enabled)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        match path {
+            Path::OriginForm(target) => self.allowed.iter().any(|allowed| allowed == target.path()),
+            _ => false,
+        }
+    }
+}
+
+impl<D: RequestDispatcher> RequestDispatcher for Maintenance<D> {
+    type Error = D::Error;
+
+    fn add(&mut self, handler: Arc<SyncableHandler>) -> Result<(), HandlerRegistryAddError> {
+        self.inner.add(handler)
+    }
+
+    fn dispatch(&self, req: Request) -> Result<Response, D::Error> {
+        if !self.is_enabled() || self.is_allowed(&req.head.path) {
+            return self.inner.dispatch(req);
+        }
+
+        log::info!(target: "listener", "Rejecting request for {0:?}: the server is in maintenance mode", req.head.path);
+        Ok(ResponseBuilder::from(req)
+            .status(ResponseStatus::ServiceUnavailable)
+            .header("Retry-After", &self.retry_after.to_string())
+            .text("The server is down for maintenance")
+            .build()
+            .expect("A valid 503 response should be produced"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::_crud_example::{DogStore, DogStoreGetHandler};
+    use crate::request::HTTPMethod;
+    use crate::server::handlers::{Handler, HandlerGroup, HandlerPath, HandlerResult};
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    struct HealthHandler {
+        path: HandlerPath,
+        method: HTTPMethod,
+    }
+
+    impl Handler for HealthHandler {
+        fn get_path(&self) -> &HandlerPath {
+            &self.path
+        }
+
+        fn get_method(&self) -> &HTTPMethod {
+            &self.method
+        }
+
+        fn on_request(&self, req: Request) -> HandlerResult {
+            HandlerResult::Done(
+                ResponseBuilder::from(req)
+                    .ok()
+                    .text("OK")
+                    .build()
+                    .expect("A valid health check response should be produced"),
+            )
+        }
+    }
+
+    fn get(dispatcher: &Maintenance, path: &str) -> Response {
+        let (req, _) = mock_request(&format!("GET {path} HTTP/1.1\r\nHost: a.com\r\n"), b"");
+        dispatcher
+            .dispatch(req)
+            .expect("The request should be dispatched")
+    }
+
+    #[test]
+    fn maintenance_mode() {
+        let mut handlers = HandlerGroup::new(Mutex::new(DogStore::default()))
+            .handler(DogStoreGetHandler::new)
+            .build();
+        handlers.push(Arc::new(HealthHandler {
+            path: HandlerPath::new("/health"),
+            method: HTTPMethod::Get,
+        }));
+        let dispatcher = Maintenance::new(HandlerRegistry::new(handlers))
+            .allow("/health")
+            .retry_after(30);
+
+        assert_eq!(*get(&dispatcher, "/dogs").status(), ResponseStatus::OK);
+
+        dispatcher.flag().store(true, Ordering::Release);
+        let response = get(&dispatcher, "/dogs");
+        assert_eq!(*response.status(), ResponseStatus::ServiceUnavailable);
+        assert_eq!(
+            response.get_header("Retry-After".to_string()),
+            Some("30".to_string())
+        );
+        assert_eq!(
+            *get(&dispatcher, "/health?verbose=1").status(),
+            ResponseStatus::OK,
+            "Allowed paths should still be served"
+        );
+
+        dispatcher.flag().store(false, Ordering::Release);
+        assert_eq!(*get(&dispatcher, "/dogs").status(), ResponseStatus::OK);
+    }
+}
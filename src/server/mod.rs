@@ -1,4 +1,18 @@
+pub mod acme;
+pub mod auth;
+pub mod compression;
+pub mod connection;
+pub mod cookie;
+pub mod csrf;
+pub mod debug;
 pub mod handlers;
 pub mod listener;
+pub mod maintenance;
+pub mod negotiation;
 pub mod request_queue;
 pub mod response;
+pub mod sse;
+pub mod static_files;
+pub mod streams;
+pub mod vhost;
+pub mod websocket;
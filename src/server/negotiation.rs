@@ -0,0 +1,311 @@
+use crate::request::{
+    accept::negotiate_media_type, accept_language::negotiate_language, HTTPMethod, Request,
+};
+
+use super::{
+    handlers::{Handler, HandlerPath, HandlerResult},
+    response::{ResponseBuilder, ResponseStatus},
+};
+
+/// Renders one representation of a resource
+pub type RepresentationFn = dyn Fn(&Request) -> String + Send + Sync;
+
+/// A representation's media type, its language if it has one, how to render it, and the URL
+/// it can be fetched from directly if it has one
+type Representation = (
+    String,
+    Option<String>,
+    Box<RepresentationFn>,
+    Option<String>,
+);
+
+/// Serves one of several representations of the same resource (E.G HTML or JSON) depending
+/// on the request's Accept header. Representations are preferred in the order they were added,
+/// which also decides the representation sent to clients without an Accept header.
+/// Responds with 406 Not Acceptable when none of them are acceptable.
+/// Representations of a media type can be localised, in which case the language is chosen
+/// using Accept-Language and sent in Content-Language
+pub struct NegotiatingHandler {
+    path: HandlerPath,
+    method: HTTPMethod,
+    representations: Vec<Representation>,
+}
+
+impl NegotiatingHandler {
+    pub fn new(path: &str, method: HTTPMethod) -> Self {
+        Self {
+            path: HandlerPath::new(path),
+            method,
+            representations: Vec::new(),
+        }
+    }
+
+    /// Add a representation with the given media type, E.G `application/json`
+    pub fn representation<F>(mut self, media_type: &str, render: F) -> Self
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        self.representations
+            .push((media_type.to_string(), None, Box::new(render), None));
+        self
+    }
+
+    /// Add a representation with the given media type in one language, E.G `fr`.
+    /// The first language added for a media type is sent when none of them are acceptable
+    pub fn localised_representation<F>(
+        mut self,
+        media_type: &str,
+        language: &str,
+        render: F,
+    ) -> Self
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        self.representations.push((
+            media_type.to_string(),
+            Some(language.to_string()),
+            Box::new(render),
+            None,
+        ));
+        self
+    }
+
+    /// Send a Content-Location naming `url` whenever the representation added last is
+    /// selected, E.G `/dogs.json` for the JSON representation of `/dogs`.
+    /// Lets caches and clients find the representation without negotiating again
+    pub fn content_location(mut self, url: &str) -> Self {
+        let (.., location) = self
+            .representations
+            .last_mut()
+            .expect("A representation should be added before its Content-Location");
+        *location = Some(url.to_string());
+        self
+    }
+
+    /// The representation of `media_type` in the language preferred by `accept_language`
+    fn localise(&self, media_type: &str, accept_language: &str) -> Option<&Representation> {
+        let candidates: Vec<&Representation> = self
+            .representations
+            .iter()
+            .filter(|(candidate, ..)| candidate == media_type)
+            .collect();
+        let languages: Vec<&str> = candidates
+            .iter()
+            .filter_map(|(_, language, ..)| language.as_deref())
+            .collect();
+        let language = negotiate_language(accept_language, &languages);
+        candidates
+            .iter()
+            .find(|(_, candidate, ..)| candidate.as_deref() == language)
+            // Clients are better served by a language they didn't ask for than by a 406
+            .or(candidates.first())
+            .copied()
+    }
+}
+
+impl Handler for NegotiatingHandler {
+    fn get_path(&self) -> &HandlerPath {
+        &self.path
+    }
+
+    fn get_method(&self) -> &HTTPMethod {
+        &self.method
+    }
+
+    fn on_request(&self, req: Request) -> HandlerResult {
+        // Localised representations repeat their media type, not always next to each other
+        let mut available: Vec<&str> = Vec::new();
+        for (media_type, ..) in &self.representations {
+            if !available.contains(&media_type.as_str()) {
+                available.push(media_type);
+            }
+        }
+        // Missing headers mean that any media type or language is acceptable
+        let accept = req.head.headers.get("accept").map_or("*/*", String::as_str);
+        let accept_language = req
+            .head
+            .headers
+            .get("accept-language")
+            .map_or("*", String::as_str);
+        let selected = negotiate_media_type(accept, &available)
+            .and_then(|media_type| self.localise(media_type, accept_language));
+        let localised = self
+            .representations
+            .iter()
+            .any(|(_, language, ..)| language.is_some());
+
+        let response = match selected {
+            Some((media_type, language, render, location)) => {
+                let body = render(&req);
+                let builder = ResponseBuilder::from(req)
+                    .ok()
+                    .header("Content-Type", media_type)
+                    .body(body);
+                let builder = match language {
+                    Some(language) => builder.content_language(language),
+                    None => builder,
+                };
+                match location {
+                    Some(location) => builder.content_location(location),
+                    None => builder,
+                }
+            }
+            None => ResponseBuilder::from(req)
+                .status(ResponseStatus::NotAcceptable)
+                .text(&format!(
+                    "Available representations: {}",
+                    available.join(", ")
+                )),
+        };
+        HandlerResult::Done(
+            response
+                .header(
+                    "Vary",
+                    if localised {
+                        "Accept, Accept-Language"
+                    } else {
+                        "Accept"
+                    },
+                )
+                .build()
+                .expect("A valid negotiated response should be produced"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    fn homepage() -> NegotiatingHandler {
+        NegotiatingHandler::new("/", HTTPMethod::Get)
+            .representation("text/html", |_| "<h1>Dogs</h1>".to_string())
+            .representation("application/json", |_| r#"{"title":"Dogs"}"#.to_string())
+    }
+
+    fn negotiate(accept: &str) -> crate::server::response::Response {
+        let (req, _) = mock_request(
+            &format!("GET / HTTP/1.1\r\nHost: a.com\r\nAccept: {accept}\r\n"),
+            b"",
+        );
+        let HandlerResult::Done(response) = homepage().on_request(req) else {
+            panic!("The negotiating handler should produce a response");
+        };
+        response
+    }
+
+    #[test]
+    fn select_json() {
+        let response = negotiate("application/json");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(response.body(), br#"{"title":"Dogs"}"#);
+        assert_eq!(
+            response.get_header("Content-Type".to_string()),
+            Some("application/json; charset=UTF-8".to_string())
+        );
+        assert_eq!(
+            response.get_header("Vary".to_string()),
+            Some("Accept".to_string())
+        );
+    }
+
+    #[test]
+    fn select_html() {
+        let response = negotiate("text/html,application/xhtml+xml,*/*;q=0.8");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(response.body(), b"<h1>Dogs</h1>");
+    }
+
+    #[test]
+    fn select_language() {
+        let handler = NegotiatingHandler::new("/", HTTPMethod::Get)
+            .localised_representation("text/html", "en", |_| "<h1>Dogs</h1>".to_string())
+            .localised_representation("text/html", "fr", |_| "<h1>Chiens</h1>".to_string());
+        let (req, _) = mock_request(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nAccept-Language: fr-FR, fr;q=0.9, en;q=0.5\r\n",
+            b"",
+        );
+        let HandlerResult::Done(response) = handler.on_request(req) else {
+            panic!("The negotiating handler should produce a response");
+        };
+        assert_eq!(response.body(), b"<h1>Chiens</h1>");
+        assert_eq!(
+            response.get_header("Content-Language".to_string()),
+            Some("fr".to_string())
+        );
+        assert_eq!(
+            response.get_header("Vary".to_string()),
+            Some("Accept, Accept-Language".to_string())
+        );
+
+        let (req, _) = mock_request(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nAccept-Language: ja\r\n",
+            b"",
+        );
+        let HandlerResult::Done(response) = handler.on_request(req) else {
+            panic!("The negotiating handler should produce a response");
+        };
+        assert_eq!(
+            response.get_header("Content-Language".to_string()),
+            Some("en".to_string()),
+            "The first language should be the fallback"
+        );
+    }
+
+    #[test]
+    fn content_location() {
+        let handler = NegotiatingHandler::new("/dogs", HTTPMethod::Get)
+            .representation("text/html", |_| "<h1>Dogs</h1>".to_string())
+            .representation("application/json", |_| "[]".to_string())
+            .content_location("/dogs.json");
+        let negotiate = |accept: &str| {
+            let (req, _) = mock_request(
+                &format!("GET /dogs HTTP/1.1\r\nHost: a.com\r\nAccept: {accept}\r\n"),
+                b"",
+            );
+            let HandlerResult::Done(response) = handler.on_request(req) else {
+                panic!("The negotiating handler should produce a response");
+            };
+            response
+        };
+
+        assert_eq!(
+            negotiate("application/json").get_header("Content-Location".to_string()),
+            Some("/dogs.json".to_string())
+        );
+        assert_eq!(
+            negotiate("text/html").get_header("Content-Location".to_string()),
+            None,
+            "Only representations with a URL should send Content-Location"
+        );
+    }
+
+    #[test]
+    fn interleaved_languages() {
+        let handler = NegotiatingHandler::new("/", HTTPMethod::Get)
+            .localised_representation("text/html", "en", |_| "<h1>Dogs</h1>".to_string())
+            .localised_representation("application/json", "en", |_| "{}".to_string())
+            .localised_representation("text/html", "fr", |_| "<h1>Chiens</h1>".to_string());
+        let (req, _) = mock_request(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nAccept: image/png\r\n",
+            b"",
+        );
+        let HandlerResult::Done(response) = handler.on_request(req) else {
+            panic!("The negotiating handler should produce a response");
+        };
+        assert_eq!(*response.status(), ResponseStatus::NotAcceptable);
+        assert_eq!(
+            response.body(),
+            b"Available representations: text/html, application/json",
+            "Each media type should only be listed once"
+        );
+    }
+
+    #[test]
+    fn not_acceptable() {
+        let response = negotiate("image/png");
+        assert_eq!(*response.status(), ResponseStatus::NotAcceptable);
+    }
+}
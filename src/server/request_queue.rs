@@ -1,20 +1,56 @@
 use std::{
-    collections::VecDeque,
     io::Error as IoError,
-    sync::{Arc, Condvar, Mutex},
+    sync::{mpsc::Sender, Arc, RwLock},
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
-use log::{error, info};
+use crossbeam_channel::Receiver;
+use log::{error, info, warn};
 
 use crate::request::Request;
 
-use super::handlers::{DispatcherError, RequestDispatcher};
+use super::{
+    connection::ResponseOutcome,
+    handlers::{DispatcherError, RequestDispatcher},
+    response::{ResponseBuilder, ResponseStatus, DEFAULT_CHUNK_SIZE},
+};
 
 pub struct RequestQueueOptions {
     n_threads: usize,
+    // TODO: enforce a per-job timeout
+    #[allow(dead_code)]
     timeout: Duration,
+    nodelay_small_responses: bool,
+    on_response_sent: Option<ResponseSizeHook>,
+    chunk_size: usize,
+}
+
+/// How many bytes were sent in response to a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentResponse {
+    pub host: Option<String>,
+    pub path: String,
+    pub status: u16,
+    /// The size of the whole response, including the head
+    pub bytes: usize,
+}
+
+/// Called after each response is sent, E.G to enforce per-tenant egress quotas.
+/// Responses that fail to send are not reported
+#[derive(Clone)]
+pub struct ResponseSizeHook(Arc<dyn Fn(&SentResponse) + Send + Sync>);
+
+impl ResponseSizeHook {
+    pub fn new<F: Fn(&SentResponse) + Send + Sync + 'static>(hook: F) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for ResponseSizeHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResponseSizeHook")
+    }
 }
 
 /// Adapts to the number of cores available to the program
@@ -23,15 +59,30 @@ impl Default for RequestQueueOptions {
         Self {
             n_threads: thread::available_parallelism().map_or(4, |res| res.get().div_ceil(2)),
             timeout: Duration::new(10, 0),
+            nodelay_small_responses: false,
+            on_response_sent: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
         }
     }
 }
 
-enum ThreadPoolMessage<T> {
-    /// Work to pass to the `ThreadPool`'s callback
-    Work(T),
-    /// Shutdown signal
-    Die,
+impl RequestQueueOptions {
+    /// See `Response::send_nodelay_if_small`
+    pub fn nodelay_small_responses(mut self, enabled: bool) -> Self {
+        self.nodelay_small_responses = enabled;
+        self
+    }
+
+    pub fn on_response_sent(mut self, hook: Option<ResponseSizeHook>) -> Self {
+        self.on_response_sent = hook;
+        self
+    }
+
+    /// See `Response::with_chunk_size`
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
 }
 
 pub trait ThreadPool<I>
@@ -39,13 +90,15 @@ where
     I: Send + Sync + 'static,
 {
     fn enqueue(&mut self, to_process: I);
-    /// Send the signal to stop processing further jobs
+    /// Stop accepting jobs and wait for the workers to finish the ones already queued
     fn shutdown(&mut self);
 
+    /// Each worker runs `callback` on jobs from `work` until every sender has been dropped
+    /// and the queued jobs have run out
     fn spawn_all<F>(
         &mut self,
         callback: F,
-        work: Arc<SynchronisedQueue<ThreadPoolMessage<I>>>,
+        work: Receiver<I>,
         n_threads: usize,
     ) -> Result<Vec<thread::JoinHandle<()>>, IoError>
     where
@@ -55,29 +108,22 @@ where
 
         let mut threads = Vec::with_capacity(n_threads);
         for worker_num in 0..n_threads {
-            let work_ref = Arc::clone(&work);
+            let work_ref = work.clone();
             let cb = callback.clone();
-            let th = thread::Builder::new().spawn(move || loop {
-                let message = work_ref.pop();
-
-                match message {
-                    ThreadPoolMessage::Work(job) => {
-                        let start_time = SystemTime::now();
-                        cb(job);
-                        info!(
-                            "Job processed by worker {0} finished in {1} ms",
-                            worker_num,
-                            start_time
-                                .elapsed()
-                                .expect("The clock didn't change during the job")
-                                .as_millis()
-                        );
-                    }
-                    ThreadPoolMessage::Die => {
-                        info!("Shutting down worker {worker_num}");
-                        break;
-                    }
+            let th = thread::Builder::new().spawn(move || {
+                for job in work_ref.iter() {
+                    let start_time = SystemTime::now();
+                    cb(job);
+                    info!(
+                        "Job processed by worker {0} finished in {1} ms",
+                        worker_num,
+                        start_time
+                            .elapsed()
+                            .expect("The clock didn't change during the job")
+                            .as_millis()
+                    );
                 }
+                info!("Shutting down worker {worker_num}");
             });
 
             threads.push(th?);
@@ -87,30 +133,100 @@ where
     }
 }
 
+/// The sending half of the queue, shared with every `RequestQueueHandle`.
+/// Taking the sender on shutdown disconnects the channel even while handles are alive
+type SharedSender = Arc<RwLock<Option<crossbeam_channel::Sender<QueuedRequest>>>>;
+
 pub struct RequestQueue {
     threads: Option<Vec<thread::JoinHandle<()>>>,
-    // FIXME: using my own implementation of a synchronised queue
-    // will not be as performant as using a more mature abstraction.
-    // This should be swapped out for `crossbeam_channel::unbounded`.
-    // I chose to implement my own version to learn about synchronisation
-    // and borrow-checking in Rust
-    reqs: Arc<SynchronisedQueue<ThreadPoolMessage<Request>>>,
+    reqs: SharedSender,
+}
+
+/// A request waiting to be processed by the `RequestQueue`
+pub struct QueuedRequest {
+    request: Request,
+    return_stream: Option<Sender<ResponseOutcome>>,
+}
+
+impl QueuedRequest {
+    /// Report what happened to the connection through `sender`. If it is kept alive, the
+    /// connection is sent back once the response has been sent so that the next request can
+    /// be read from it
+    pub fn return_stream_to(mut self, sender: Sender<ResponseOutcome>) -> Self {
+        self.return_stream = Some(sender);
+        self
+    }
+}
+
+impl From<Request> for QueuedRequest {
+    fn from(request: Request) -> Self {
+        Self {
+            request,
+            return_stream: None,
+        }
+    }
+}
+
+/// Queue a request unless the queue has been shut down, in which case the request is dropped,
+/// closing its connection
+fn enqueue_on(reqs: &SharedSender, to_process: QueuedRequest) {
+    let sent = match &*reqs.read().unwrap() {
+        Some(sender) => sender.send(to_process).is_ok(),
+        None => false,
+    };
+    if !sent {
+        warn!("Dropped a request that was enqueued after shutdown");
+    }
+}
+
+fn pending_in(reqs: &SharedSender) -> usize {
+    reqs.read()
+        .unwrap()
+        .as_ref()
+        .map_or(0, |sender| sender.len())
+}
+
+impl RequestQueue {
+    /// The number of requests waiting for a worker
+    pub fn pending(&self) -> usize {
+        pending_in(&self.reqs)
+    }
+
+    /// A handle for enqueueing requests from other threads, E.G one per connection
+    pub fn handle(&self) -> RequestQueueHandle {
+        RequestQueueHandle {
+            reqs: Arc::clone(&self.reqs),
+        }
+    }
+}
+
+/// Enqueues requests on a `RequestQueue` without owning its workers
+#[derive(Clone)]
+pub struct RequestQueueHandle {
+    reqs: SharedSender,
+}
+
+impl RequestQueueHandle {
+    pub fn enqueue(&self, to_process: QueuedRequest) {
+        enqueue_on(&self.reqs, to_process)
+    }
+
+    /// The number of requests waiting for a worker
+    pub fn pending(&self) -> usize {
+        pending_in(&self.reqs)
+    }
 }
 
-impl ThreadPool<Request> for RequestQueue {
-    fn enqueue(&mut self, to_process: Request) {
-        self.reqs.push(ThreadPoolMessage::Work(to_process))
+impl ThreadPool<QueuedRequest> for RequestQueue {
+    fn enqueue(&mut self, to_process: QueuedRequest) {
+        enqueue_on(&self.reqs, to_process)
     }
 
     fn shutdown(&mut self) {
+        // Dropping the only sender disconnects the channel, so the workers exit once they
+        // have finished the requests that are already queued
+        self.reqs.write().unwrap().take();
         if let Some(threads) = self.threads.take() {
-            // This is a hack around the fact that there are no "close"
-            // semantics for my queue. Instead, I send a message to each worker
-            // to join
-            for _ in 0..threads.len() {
-                self.reqs.push(ThreadPoolMessage::Die);
-            }
-
             for th in threads {
                 th.join().expect("The thread should join");
             }
@@ -123,28 +239,81 @@ impl RequestQueue {
         dispatcher: Arc<D>,
         opts: RequestQueueOptions,
     ) -> Result<Self, IoError> {
-        let req_queue = Arc::new(SynchronisedQueue::with_capacity(opts.n_threads));
+        let (sender, receiver) = crossbeam_channel::unbounded();
         let mut instance = Self {
-            reqs: Arc::clone(&req_queue),
+            reqs: Arc::new(RwLock::new(Some(sender))),
             threads: None,
         };
 
         let dispatcher_ref = Arc::clone(&dispatcher);
+        let nodelay_small_responses = opts.nodelay_small_responses;
+        let on_response_sent = opts.on_response_sent;
+        let chunk_size = opts.chunk_size;
 
         let threads = ThreadPool::spawn_all(
             &mut instance,
-            move |req| {
-                let response = dispatcher_ref.dispatch(req).unwrap_or_else(|err| {
+            move |QueuedRequest {
+                      request,
+                      return_stream,
+                  }| {
+                let deadline = request.deadline();
+                let (method, host, path) = (
+                    request.head.method.clone(),
+                    request.head.headers.get("host").cloned(),
+                    request.head.path.to_string(),
+                );
+                let mut response = dispatcher_ref.dispatch(request).unwrap_or_else(|err| {
                     err.into_response()
                         .build()
                         .expect("A valid handler call error response should be produced")
                 });
+                if deadline.is_some_and(|deadline| Instant::now() > deadline) {
+                    error!("Request missed its deadline. Discarding response: {response}");
+                    response = ResponseBuilder::default()
+                        .version(response.version())
+                        .status(ResponseStatus::GatewayTimeout)
+                        .text("The request took too long to process")
+                        .header("Connection", "close")
+                        .stream(response.into_stream())
+                        .build()
+                        .expect("A valid 504 response should be produced");
+                }
+                let mut response = response.for_method(&method).with_chunk_size(chunk_size);
+                // The connection's reader may have given up, E.G on shutdown, so failing to
+                // report the outcome is fine
+                if let Some(sender) = return_stream {
+                    if response.is_hijacked() {
+                        let _ = sender.send(ResponseOutcome::Upgraded);
+                    } else if response.closes_connection() {
+                        let _ = sender.send(ResponseOutcome::Closed);
+                    } else {
+                        response = response.hijack(Box::new(move |stream| {
+                            let _ = sender.send(ResponseOutcome::KeepAlive(stream));
+                        }));
+                    }
+                }
                 info!("Produced response: {response}");
-                let _ = response
-                    .send()
-                    .inspect_err(|err| error!("Error occurred when sending response {err}"));
+                let status = response.status().to_code();
+                let sent = if nodelay_small_responses {
+                    response.send_nodelay_if_small()
+                } else {
+                    response.send()
+                };
+                match sent {
+                    Ok(bytes) => {
+                        if let Some(ResponseSizeHook(hook)) = &on_response_sent {
+                            hook(&SentResponse {
+                                host,
+                                path,
+                                status,
+                                bytes,
+                            });
+                        }
+                    }
+                    Err(err) => error!("Error occurred when sending response {err}"),
+                }
             },
-            req_queue,
+            receiver,
             opts.n_threads,
         );
 
@@ -161,49 +330,178 @@ impl Drop for RequestQueue {
     }
 }
 
-struct SynchronisedQueue<T: Send> {
-    signal: Condvar,
-    data: Mutex<VecDeque<T>>,
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
 
-impl<T: Send> SynchronisedQueue<T> {
-    pub fn new() -> Self {
-        Self {
-            signal: Condvar::new(),
-            data: Mutex::new(VecDeque::new()),
+    use crate::{
+        server::{
+            handlers::{HandlerCallError, HandlerRegistryAddError, SyncableHandler},
+            response::Response,
+        },
+        testing::mock_request,
+    };
+
+    use super::*;
+
+    /// Takes longer than any reasonable deadline to respond
+    struct SlowDispatcher;
+
+    impl RequestDispatcher for SlowDispatcher {
+        type Error = HandlerCallError;
+
+        fn add(&mut self, _handler: Arc<SyncableHandler>) -> Result<(), HandlerRegistryAddError> {
+            unimplemented!("Handlers aren't needed")
         }
-    }
 
-    pub fn with_capacity(size: usize) -> Self {
-        Self {
-            signal: Condvar::new(),
-            data: Mutex::new(VecDeque::with_capacity(size)),
+        fn dispatch(&self, request: Request) -> Result<Response, Self::Error> {
+            thread::sleep(Duration::from_millis(100));
+            Ok(ResponseBuilder::from(request)
+                .ok()
+                .text("Too late")
+                .build()
+                .expect("A valid 200 response should be produced"))
         }
     }
 
-    pub fn push(&self, x: T) {
-        let mut data = self.data.lock().unwrap();
-        data.push_back(x);
-        self.signal.notify_one();
+    #[test]
+    fn request_deadline_exceeded() {
+        let mut queue = RequestQueue::new(Arc::new(SlowDispatcher), RequestQueueOptions::default())
+            .expect("The threadpool should spawn");
+
+        let (mut request, output) = mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        request.set_deadline(Some(Instant::now() + Duration::from_millis(10)));
+        queue.enqueue(request.into());
+
+        let (mut request, on_time_output) = mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        request.set_deadline(Some(Instant::now() + Duration::from_secs(60)));
+        queue.enqueue(request.into());
+        queue.shutdown();
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+            "A request that exceeds its deadline should get a 504. Written: {written:?}"
+        );
+        assert!(!written.contains("Too late"));
+
+        let written = String::from_utf8(on_time_output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 200 OK\r\n"),
+            "A request within its deadline should be unaffected. Written: {written:?}"
+        );
+    }
+
+    #[test]
+    fn response_size_reported() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_ref = Arc::clone(&sent);
+        let options = RequestQueueOptions::default().on_response_sent(Some(ResponseSizeHook::new(
+            move |response| sent_ref.lock().unwrap().push(response.clone()),
+        )));
+        let mut queue = RequestQueue::new(Arc::new(SlowDispatcher), options)
+            .expect("The threadpool should spawn");
+
+        let (request, output) = mock_request("GET /quota HTTP/1.1\r\nHost: tenant.com\r\n", b"");
+        queue.enqueue(request.into());
+        queue.shutdown();
+
+        let written = output.lock().unwrap().len();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![SentResponse {
+                host: Some("tenant.com".to_string()),
+                path: "/quota".to_string(),
+                status: 200,
+                bytes: written,
+            }],
+            "The reported size should match the bytes written"
+        );
     }
 
-    pub fn pop(&self) -> T {
-        let mut data = self.data.lock().unwrap();
-        loop {
-            if let Some(x) = data.pop_front() {
-                return x;
-            }
-            data = self.signal.wait(data).unwrap();
-        }
+    #[test]
+    fn head_response_has_no_body() {
+        let mut queue = RequestQueue::new(Arc::new(SlowDispatcher), RequestQueueOptions::default())
+            .expect("The threadpool should spawn");
+
+        let (request, output) = mock_request("HEAD / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        queue.enqueue(request.into());
+        queue.shutdown();
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.starts_with("HTTP/1.1 200 OK\r\n") && written.ends_with("\r\n\r\n"),
+            "Only the head should be sent. Written: {written:?}"
+        );
+        assert!(
+            written.contains("Content-Length: 8\r\n"),
+            "Content-Length should match the body that would have been sent. Written: {written:?}"
+        );
+        assert!(!written.contains("Too late"));
     }
 
-    pub fn len(&self) -> usize {
-        let data = self.data.lock().unwrap();
-        data.len()
+    /// Responds immediately so that the queue itself is the bottleneck
+    struct FastDispatcher;
+
+    impl RequestDispatcher for FastDispatcher {
+        type Error = HandlerCallError;
+
+        fn add(&mut self, _handler: Arc<SyncableHandler>) -> Result<(), HandlerRegistryAddError> {
+            unimplemented!("Handlers aren't needed")
+        }
+
+        fn dispatch(&self, request: Request) -> Result<Response, Self::Error> {
+            Ok(ResponseBuilder::from(request)
+                .ok()
+                .build()
+                .expect("A valid 200 response should be produced"))
+        }
     }
 
-    pub fn is_empty(&self) -> bool {
-        let data = self.data.lock().unwrap();
-        data.is_empty()
+    #[test]
+    fn stress() {
+        const N_JOBS: usize = 5000;
+
+        let sent = Arc::new(Mutex::new(0));
+        let sent_ref = Arc::clone(&sent);
+        let options = RequestQueueOptions::default().on_response_sent(Some(ResponseSizeHook::new(
+            move |_| *sent_ref.lock().unwrap() += 1,
+        )));
+        let mut queue = RequestQueue::new(Arc::new(FastDispatcher), options)
+            .expect("The threadpool should spawn");
+
+        // Enqueue from several threads at once, as connections do
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = queue.handle();
+                thread::spawn(move || {
+                    for _ in 0..N_JOBS / 4 {
+                        let (request, _) = mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+                        handle.enqueue(request.into());
+                    }
+                    handle
+                })
+            })
+            .collect();
+        // The handles outlive the queue's workers, which mustn't keep them running
+        let handles: Vec<_> = producers
+            .into_iter()
+            .map(|producer| producer.join().expect("The producer should join"))
+            .collect();
+        queue.shutdown();
+
+        assert_eq!(
+            *sent.lock().unwrap(),
+            N_JOBS,
+            "Every queued job should be processed before shutdown finishes"
+        );
+        assert_eq!(queue.pending(), 0);
+
+        let (request, output) = mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        handles[0].enqueue(request.into());
+        assert!(
+            output.lock().unwrap().is_empty(),
+            "Requests enqueued after shutdown should be dropped"
+        );
     }
 }
@@ -1,10 +1,15 @@
 use regex::Regex;
-use std::char::ToUppercase;
 use std::fmt::Write as _;
-use std::io::{Error as IoError, Write};
+use std::io::{BufWriter, Error as IoError, ErrorKind, Read, Write};
 use std::{borrow::Cow, fmt::Display};
 
-use crate::request::{HTTPHeaders, HTTPVersion, Request, RequestHead, SyncableStream};
+use crate::request::range::{select_range, ByteRange, RangeSelection};
+use crate::request::{
+    contains_token, parse_weighted_list, HTTPHeaders, HTTPMethod, HTTPVersion, Request,
+    RequestHead, SyncableStream,
+};
+use crate::server::compression::CompressionConfig;
+use crate::server::cookie::Cookie;
 
 // See https://stackoverflow.com/a/36928678
 // Generated from en.wikipedia.org/wiki/List_of_HTTP_status_codes
@@ -189,13 +194,86 @@ impl ResponseStatus {
     }
 }
 
+impl From<ErrorKind> for ResponseStatus {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::PermissionDenied => Self::Forbidden,
+            ErrorKind::TimedOut => Self::GatewayTimeout,
+            _ => Self::InternalServerError,
+        }
+    }
+}
+
+impl From<IoError> for ResponseStatus {
+    fn from(err: IoError) -> Self {
+        Self::from(err.kind())
+    }
+}
+
+/// The range units a resource supports, sent in the Accept-Ranges header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceptRanges {
+    Bytes,
+    /// Explicitly tell the client that range requests aren't supported
+    None,
+}
+
+impl Display for AcceptRanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes => write!(f, "bytes"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Whether the connection stays open for another request after a response.
+/// Sent to the client in the Connection header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionMode {
+    KeepAlive,
+    Close,
+}
+
+impl ConnectionMode {
+    /// HTTP/1.1 connections are persistent unless either side closes them. Older versions
+    /// close them after every response
+    pub fn default_for(version: HTTPVersion) -> Self {
+        match version {
+            HTTPVersion::V1_1 => Self::KeepAlive,
+            _ => Self::Close,
+        }
+    }
+}
+
+impl Display for ConnectionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeepAlive => write!(f, "keep-alive"),
+            Self::Close => write!(f, "close"),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ResponseBuilder {
     version: Option<HTTPVersion>,
     status: Option<ResponseStatus>,
     headers: Option<HTTPHeaders>,
-    body: Option<String>,
+    body: Option<Vec<u8>>,
+    /// A streamed body and its length
+    body_reader: Option<(BodyReader, u64)>,
+    body_generator: Option<BodyGenerator>,
     stream: Option<Box<dyn SyncableStream>>,
+    trailers: HTTPHeaders,
+    /// Whether the client sent `TE: trailers`. Trailers are dropped otherwise
+    accepts_trailers: bool,
+    /// The client's Accept-Encoding header, for `auto_compress`
+    accept_encoding: Option<String>,
+    compression: Option<CompressionConfig>,
+    connection: Option<ConnectionMode>,
+    set_cookies: Vec<Cookie>,
 }
 
 impl std::fmt::Debug for ResponseBuilder {
@@ -204,8 +282,16 @@ impl std::fmt::Debug for ResponseBuilder {
             .field("version", &self.version)
             .field("status", &self.status)
             .field("headers", &self.headers)
-            .field("body", &self.body)
+            .field("body", &self.body.as_deref().map(String::from_utf8_lossy))
+            .field("body_reader", &self.body_reader.is_some())
+            .field("body_generator", &self.body_generator.is_some())
             .field("stream", &self.stream.as_ref().map(|s| s.get_type()))
+            .field("trailers", &self.trailers)
+            .field("accepts_trailers", &self.accepts_trailers)
+            .field("accept_encoding", &self.accept_encoding)
+            .field("compression", &self.compression)
+            .field("connection", &self.connection)
+            .field("set_cookies", &self.set_cookies)
             .finish()
     }
 }
@@ -231,8 +317,41 @@ impl ResponseBuilder {
         self
     }
 
-    pub fn body(mut self, body: String) -> Self {
-        self.body = Some(body);
+    pub fn body(self, body: String) -> Self {
+        self.body_bytes(body)
+    }
+
+    pub fn body_str(self, body: &str) -> Self {
+        self.body_bytes(body)
+    }
+
+    /// Send `body` unchanged, E.G an image. Set the Content-Type yourself because binary
+    /// bodies don't get a charset
+    pub fn body_bytes(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self.body_reader = None;
+        self.body_generator = None;
+        self
+    }
+
+    /// Stream the body from `reader` when the response is sent instead of holding it in
+    /// memory, E.G for a large file. Exactly `content_length` bytes are sent. Replaces any
+    /// body set with `body`
+    pub fn body_reader(mut self, reader: BodyReader, content_length: u64) -> Self {
+        self.body = None;
+        self.body_reader = Some((reader, content_length));
+        self.body_generator = None;
+        self
+    }
+
+    /// Produce the body while the response is sent by writing it to the connection, E.G for
+    /// an export that is too large to hold in memory. HTTP/1.1 bodies are sent with chunked
+    /// transfer coding. Older clients don't support it, so their connection is closed to end
+    /// the body instead. Replaces any other body
+    pub fn body_generator(mut self, generator: BodyGenerator) -> Self {
+        self.body = None;
+        self.body_reader = None;
+        self.body_generator = Some(generator);
         self
     }
 
@@ -242,16 +361,87 @@ impl ResponseBuilder {
     }
 
     pub fn build(self) -> Result<Response, &'static str> {
-        Ok(Response::new(
+        if !self.set_cookies.iter().all(Cookie::is_valid) {
+            return Err("Cookie names must be tokens and values must only contain cookie octets");
+        }
+        let mut headers = self.headers.unwrap_or_default();
+        if let Some((_, content_length)) = &self.body_reader {
+            headers.insert("content-length".to_string(), content_length.to_string());
+        }
+        if self.body_generator.is_some() && self.version == Some(HTTPVersion::V1_1) {
+            headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+        }
+        if let Some(mode) = self.connection {
+            // A Connection header set directly, E.G `Connection: Upgrade`, takes precedence
+            headers
+                .entry("connection".to_string())
+                .or_insert(mode.to_string());
+        }
+        let mut response = Response::new(
             self.version
                 .ok_or("Can't construct a Response without a version")?,
             self.status
                 .ok_or("Can't construct a Response without a status")?,
-            self.headers.unwrap_or_default(),
+            headers,
             self.body.unwrap_or_default(),
             self.stream
                 .ok_or("Can't construct a Response without a stream")?,
-        ))
+        );
+        if let Some(config) = &self.compression {
+            config.compress_response(&mut response, self.accept_encoding.as_deref());
+        }
+        if self.body_reader.is_some() && response.is_chunked() {
+            return Err("Streamed bodies have a Content-Length, so they can't be chunked");
+        }
+        response.body_reader = self.body_reader.map(|(reader, _)| reader);
+        response.body_generator = self.body_generator;
+        response.set_cookies = self.set_cookies;
+
+        if self.trailers.is_empty() {
+            Ok(response)
+        } else if !self.accepts_trailers {
+            log::debug!("Dropping trailers because the client didn't send TE: trailers");
+            Ok(response)
+        } else if !response.is_chunked() {
+            Err("Trailers can only be sent with chunked responses")
+        } else {
+            Ok(response.with_trailers(self.trailers))
+        }
+    }
+
+    /// Compress the body with gzip if the request that this responds to accepts it, E.G for
+    /// large JSON or HTML. Types that `config` doesn't consider compressible, such as images,
+    /// are sent unchanged. See `CompressionConfig::compress_response`
+    pub fn auto_compress(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Whether the connection should stay open after the response. Defaults to the
+    /// response's version's default (see `ConnectionMode::default_for`)
+    pub fn connection(mut self, mode: ConnectionMode) -> Self {
+        self.connection = Some(mode);
+        self
+    }
+
+    /// Send the body using chunked transfer coding instead of setting Content-Length.
+    /// Only supported by HTTP/1.1
+    pub fn chunked(self) -> Self {
+        self.header("Transfer-Encoding", "chunked")
+    }
+
+    /// Add a trailer field to send after the last chunk of a chunked response.
+    /// Trailers are only sent if the client sent `TE: trailers`
+    pub fn trailer(mut self, key: &str, value: &str) -> Self {
+        self.trailers.insert(key.to_lowercase(), value.to_string());
+        self
+    }
+
+    /// Set a cookie on the client. Each cookie is sent in its own Set-Cookie header, so this
+    /// can be called once per cookie
+    pub fn set_cookie(mut self, cookie: Cookie) -> Self {
+        self.set_cookies.push(cookie);
+        self
     }
 
     /// Helper method to set a header
@@ -262,12 +452,103 @@ impl ResponseBuilder {
         self
     }
 
+    /// A helper method to send a plaintext body
+    pub fn text(self, body: &str) -> Self {
+        self.header("Content-Type", "text/plain; charset=UTF-8")
+            .body_str(body)
+    }
+
+    /// A helper method to send an HTML body.
+    /// Use `escape_html` when interpolating untrusted data into `body`
+    pub fn html(self, body: String) -> Self {
+        self.header("Content-Type", "text/html; charset=UTF-8")
+            .body(body)
+    }
+
+    /// Advertise whether the resource supports range requests.
+    /// Only use `AcceptRanges::Bytes` if Range headers are honoured for this resource
+    pub fn accept_ranges(self, accept_ranges: AcceptRanges) -> Self {
+        self.header("Accept-Ranges", &accept_ranges.to_string())
+    }
+
+    /// Point to the URL of the specific representation being returned,
+    /// E.G `/dogs.json` when JSON was negotiated for `/dogs`
+    pub fn content_location(self, url: &str) -> Self {
+        self.header("Content-Location", url)
+    }
+
+    /// Name the language of the representation being returned, E.G `fr` when French was
+    /// negotiated with Accept-Language
+    pub fn content_language(self, tag: &str) -> Self {
+        self.header("Content-Language", tag)
+    }
+
+    /// Tell the client which of its Prefer header's preferences were honoured,
+    /// E.G `return=minimal`
+    pub fn preference_applied(self, applied: &str) -> Self {
+        self.header("Preference-Applied", applied)
+    }
+
+    /// Send the part of `body` selected by `ranges`, as returned by `conditional_ranges`.
+    /// See `select_range`
+    pub fn ranged_body(self, ranges: Option<Vec<ByteRange>>, body: impl Into<Vec<u8>>) -> Self {
+        let body = body.into();
+        let builder = self.accept_ranges(AcceptRanges::Bytes);
+        match select_range(ranges.as_deref(), &body) {
+            RangeSelection::Partial {
+                part,
+                content_range,
+            } => builder
+                .status(ResponseStatus::PartialContent)
+                .header("Content-Range", &content_range)
+                .body_bytes(part),
+            RangeSelection::Unsatisfiable { content_range } => builder
+                .status(ResponseStatus::RangeNotSatisfiable)
+                .header("Content-Range", &content_range),
+            RangeSelection::Full(_) => builder.ok().body_bytes(body),
+        }
+    }
+
     /// A helper method to set the status to 200 OK
     pub fn ok(mut self) -> Self {
         self.status = Some(ResponseStatus::OK);
         self
     }
 
+    /// A helper method to set the status to 201 Created and point the Location header
+    /// at the new resource
+    pub fn created_at(self, location: &str) -> Self {
+        self.status(ResponseStatus::Created)
+            .header("Location", location)
+    }
+
+    /// A helper method to set the status to 202 Accepted for work that will finish later.
+    /// Start the work (E.G on a thread or a job queue) before responding and point
+    /// `status_url` at a resource the client can poll for its progress. The URL is sent as
+    /// both Location and Content-Location because clients look for it in either
+    pub fn accepted(self, status_url: Option<&str>) -> Self {
+        let builder = self.status(ResponseStatus::Accepted);
+        match status_url {
+            Some(url) => builder.header("Location", url).content_location(url),
+            None => builder,
+        }
+    }
+
+    /// A helper method to reject an unconditional write to a resource that requires
+    /// If-Match or If-Unmodified-Since with 428 Precondition Required.
+    /// See `conditional::is_conditional`
+    pub fn precondition_required(self) -> Self {
+        self.status(ResponseStatus::PreconditionRequired)
+            .text("This request must be conditional. Send If-Match with the resource's ETag")
+    }
+
+    /// A helper method to reject a write whose precondition doesn't hold, E.G a stale
+    /// If-Match, with 412 Precondition Failed
+    pub fn precondition_failed(self) -> Self {
+        self.status(ResponseStatus::PreconditionFailed)
+            .text("The resource has changed since it was fetched")
+    }
+
     /// A helper method to set the status to 400 Bad Request
     pub fn bad_request(mut self) -> Self {
         self.status = Some(ResponseStatus::BadRequest);
@@ -291,16 +572,47 @@ impl ResponseBuilder {
         self.status = Some(ResponseStatus::InternalServerError);
         self
     }
+
+    /// A helper method to set the status based on the kind of IO error.
+    /// The error's message is logged rather than sent to avoid leaking server details
+    pub fn from_io_error(mut self, err: &IoError) -> Self {
+        log::error!("Responding to IO error: {err}");
+        self.status = Some(ResponseStatus::from(err.kind()));
+        self
+    }
 }
 
 impl From<Request> for ResponseBuilder {
-    fn from(value: Request) -> Self {
+    fn from(mut value: Request) -> Self {
         let Request {
-            head: RequestHead { version, .. },
+            head:
+                RequestHead {
+                    version,
+                    ref headers,
+                    ..
+                },
             ..
         } = value;
+        let accept_encoding = headers.get("accept-encoding").cloned();
+        let accepts_trailers = headers.get("te").is_some_and(|te| {
+            parse_weighted_list(te)
+                .iter()
+                .any(|(coding, weight)| coding.eq_ignore_ascii_case("trailers") && *weight > 0.0)
+        });
+        value.discard_unread_body();
+        let connection = match value.keep_alive() {
+            true => ConnectionMode::KeepAlive,
+            false => ConnectionMode::Close,
+        };
         let stream = value.into_stream();
-        ResponseBuilder::default().version(version).stream(stream)
+
+        let mut builder = ResponseBuilder::default()
+            .version(version)
+            .stream(stream)
+            .connection(connection);
+        builder.accepts_trailers = accepts_trailers;
+        builder.accept_encoding = accept_encoding;
+        builder
     }
 }
 
@@ -308,18 +620,51 @@ pub struct Response {
     pub version: HTTPVersion,
     pub status: ResponseStatus,
     pub headers: HTTPHeaders,
-    pub body: String,
+    pub body: Vec<u8>,
+    /// Sent after `body` without being read into memory. See `ResponseBuilder::body_reader`
+    body_reader: Option<BodyReader>,
+    /// Writes the body after `body`. See `ResponseBuilder::body_generator`
+    body_generator: Option<BodyGenerator>,
+    /// Headers sent after the body of a chunked response
+    pub trailers: HTTPHeaders,
+    /// Sent as separate Set-Cookie headers because, unlike other headers, they can't be
+    /// combined into one comma-separated header
+    set_cookies: Vec<Cookie>,
+    /// Sent in the Connection header unless the headers already have one, E.G
+    /// `Connection: Upgrade`
+    pub connection: ConnectionMode,
     stream: Box<dyn SyncableStream>,
+    /// Only the head is sent, E.G in reply to a HEAD request
+    omit_body: bool,
+    /// The largest chunk sent when using chunked transfer coding
+    chunk_size: usize,
+    /// Takes over the connection once the response has been sent
+    hijacker: Option<Hijacker>,
 }
 
+/// Where a streamed body is read from when the response is sent
+pub type BodyReader = Box<dyn Read + Send>;
+
+/// Writes a body to the connection while the response is sent
+pub type BodyGenerator = Box<dyn FnMut(&mut dyn Write) -> Result<(), IoError> + Send>;
+
+/// Takes full control of a connection, E.G to speak another protocol after a
+/// 101 Switching Protocols. It runs on the thread that sent the response
+pub type Hijacker = Box<dyn FnOnce(Box<dyn SyncableStream>) + Send>;
+
 impl std::fmt::Debug for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Response")
             .field("version", &self.version)
             .field("status", &self.status)
             .field("headers", &self.headers)
-            .field("body", &self.body)
+            .field("body", &String::from_utf8_lossy(&self.body))
+            .field("body_reader", &self.body_reader.is_some())
+            .field("body_generator", &self.body_generator.is_some())
+            .field("trailers", &self.trailers)
+            .field("connection", &self.connection)
             .field("stream", &self.stream.get_type())
+            .field("hijacked", &self.hijacker.is_some())
             .finish()
     }
 }
@@ -329,7 +674,7 @@ impl Response {
         version: HTTPVersion,
         status: ResponseStatus,
         headers: HTTPHeaders,
-        body: String,
+        body: Vec<u8>,
         stream: Box<dyn SyncableStream>,
     ) -> Self {
         let mut obj = Self {
@@ -337,12 +682,81 @@ impl Response {
             status,
             headers,
             body,
+            body_reader: None,
+            body_generator: None,
+            trailers: HTTPHeaders::new(),
+            set_cookies: Vec::new(),
+            connection: ConnectionMode::default_for(version),
             stream,
+            omit_body: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            hijacker: None,
         };
         ensure_headers(&mut obj);
         obj
     }
 
+    /// Set the trailers and advertise them in the Trailer header
+    pub fn with_trailers(mut self, trailers: HTTPHeaders) -> Self {
+        let mut names: Vec<String> = trailers.keys().map(|k| title_case_header(k)).collect();
+        names.sort();
+        self.set_header("Trailer".to_string(), names.join(", "));
+        self.trailers = trailers;
+        self
+    }
+
+    /// Send only the head if this is the response to a HEAD request, whatever body the handler
+    /// produced. The body is dropped but the headers, including Content-Length, are the same as
+    /// they would be for a GET
+    pub fn for_method(mut self, method: &HTTPMethod) -> Self {
+        self.omit_body = *method == HTTPMethod::Head;
+        if self.omit_body {
+            // Content-Length was set from the body when the response was created
+            self.body.clear();
+            self.body_reader = None;
+            self.body_generator = None;
+        }
+        self
+    }
+
+    /// Split chunked bodies into chunks of at most `chunk_size` bytes
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunks can't be empty");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Hand the connection to `hijacker` after sending this response instead of closing it.
+    /// The body of a chunked response is left for the hijacker to stream
+    pub fn hijack(mut self, hijacker: Hijacker) -> Self {
+        self.hijacker = Some(hijacker);
+        self
+    }
+
+    /// Whether the end of the body can only be signalled by closing the connection.
+    /// HTTP/1.0 responses without a Content-Length are delimited this way. No Connection header
+    /// is needed because HTTP/1.0 connections close by default
+    pub fn is_close_delimited(&self) -> bool {
+        self.version == HTTPVersion::V1_0
+            && !self.is_chunked()
+            && self.get_header("Content-Length".to_string()).is_none()
+    }
+
+    /// Whether the connection can't be reused for another request after this response.
+    /// Only HTTP/1.1 connections are kept alive
+    pub fn closes_connection(&self) -> bool {
+        self.version != HTTPVersion::V1_1 || self.connection == ConnectionMode::Close
+    }
+
+    pub fn is_hijacked(&self) -> bool {
+        self.hijacker.is_some()
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.get_header("Transfer-Encoding".to_string())
+            .is_some_and(|te| te.to_lowercase().contains("chunked"))
+    }
+
     pub fn version(&self) -> HTTPVersion {
         self.version
     }
@@ -355,7 +769,7 @@ impl Response {
         &self.headers
     }
 
-    pub fn body(&self) -> &str {
+    pub fn body(&self) -> &[u8] {
         &self.body
     }
 
@@ -367,57 +781,271 @@ impl Response {
         self.headers.get(&k.to_lowercase()).cloned()
     }
 
+    /// The cookies that will be sent in Set-Cookie headers. See `ResponseBuilder::set_cookie`
+    pub fn set_cookies(&self) -> &[Cookie] {
+        &self.set_cookies
+    }
+
     pub fn extend_headers(&mut self, headers: impl Iterator<Item = (String, String)>) {
         self.headers.extend(headers)
     }
 
+    /// Add `header` to the Vary header unless it is already listed
+    pub fn add_vary(&mut self, header: &str) {
+        match self.get_header("Vary".to_string()) {
+            Some(vary) if contains_token(&vary, header) || contains_token(&vary, "*") => {}
+            Some(vary) => {
+                self.set_header("Vary".to_string(), format!("{vary}, {header}"));
+            }
+            None => {
+                self.set_header("Vary".to_string(), header.to_string());
+            }
+        }
+    }
+
     pub fn insert_if_absent(&mut self, k: String, v: String) {
         self.headers.entry(k.to_lowercase()).or_insert(v);
     }
 
-    pub fn format(&self) -> String {
+    /// The status line and headers. HTTP/0.9 responses don't have them
+    fn format_head(&self) -> String {
         match self.version {
-            HTTPVersion::V0_9 => format_http0_9(self).to_owned(),
-            HTTPVersion::V1_0 | HTTPVersion::V1_1 => format_http1_x(self),
+            HTTPVersion::V0_9 => String::new(),
+            HTTPVersion::V1_0 | HTTPVersion::V1_1 => format_http1_x_head(self),
             HTTPVersion::V2 => todo!("Implement formatting HTTP 2 responses"),
             HTTPVersion::V3 => todo!("Implement formatting HTTP 3 responses"),
         }
     }
 
-    pub fn send(mut self) -> Result<(), IoError> {
-        write!(self.stream, "{0}", self.format())
+    /// `body` as it is sent after the head, E.G split into chunks
+    fn encode_body<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        let is_http1_x = matches!(self.version, HTTPVersion::V1_0 | HTTPVersion::V1_1);
+        // Hijackers and generators write their own chunks
+        let writes_own_chunks = self.hijacker.is_some() || self.body_generator.is_some();
+        if is_http1_x && (self.omit_body || (self.is_chunked() && writes_own_chunks)) {
+            Cow::Borrowed(&[])
+        } else if is_http1_x && self.is_chunked() {
+            Cow::Owned(format_chunked_body(body, &self.trailers, self.chunk_size))
+        } else {
+            Cow::Borrowed(body)
+        }
+    }
+
+    pub fn format(&self) -> Vec<u8> {
+        let mut formatted = self.format_head().into_bytes();
+        formatted.extend_from_slice(&self.encode_body(&self.body));
+        formatted
+    }
+
+    /// Discard this response and reuse its stream for a different one
+    pub fn into_stream(self) -> Box<dyn SyncableStream> {
+        self.stream
+    }
+
+    /// Write the response to its stream. Returns the number of bytes written, including the head
+    pub fn send(mut self) -> Result<usize, IoError> {
+        let n_written = self.write_head_and_body(false)?;
+        self.finish(n_written)
+    }
+
+    /// Write the head, then the body. See `send_nodelay_if_small` for `nodelay_if_small`
+    fn write_head_and_body(&mut self, nodelay_if_small: bool) -> Result<usize, IoError> {
+        let head = self.format_head();
+        let body = std::mem::take(&mut self.body);
+        let body = self.encode_body(&body);
+        let n_written = head.len() + body.len();
+        if nodelay_if_small {
+            self.stream.set_nodelay(n_written <= SMALL_RESPONSE_SIZE)?;
+        }
+
+        // Buffering the head avoids a small response being split across multiple packets.
+        // Large bodies are written straight through
+        let is_chunked = self.is_chunked();
+        let mut writer = BufWriter::new(&mut self.stream);
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&body)?;
+        let streamed = match (self.body_reader.take(), self.body_generator.take()) {
+            (Some(reader), _) => copy_body(reader, &mut writer, &self.headers)?,
+            (None, Some(mut generator)) if is_chunked => {
+                let mut chunked =
+                    ChunkedWriter::new(CountingWriter::new(writer.by_ref()), self.chunk_size);
+                generator(&mut chunked)?;
+                chunked.finish(&self.trailers)?.count
+            }
+            (None, Some(mut generator)) => {
+                let mut counting = CountingWriter::new(writer.by_ref());
+                generator(&mut counting)?;
+                counting.count
+            }
+            (None, None) => 0,
+        };
+        writer.flush()?;
+        Ok(n_written + streamed)
+    }
+
+    /// Flush the response, then either hand the connection to the hijacker or close it if
+    /// that's how the body is delimited
+    fn finish(mut self, n_written: usize) -> Result<usize, IoError> {
+        self.stream.flush()?;
+        if let Some(hijacker) = self.hijacker.take() {
+            hijacker(self.stream);
+        } else if self.is_close_delimited() {
+            self.stream.shutdown()?;
+        }
+        Ok(n_written)
+    }
+
+    /// Like `send`, but responses that fit in a single packet are sent without waiting for
+    /// Nagle's algorithm. Nagle's algorithm is left enabled for larger responses because
+    /// coalescing their segments improves throughput
+    pub fn send_nodelay_if_small(mut self) -> Result<usize, IoError> {
+        let n_written = self.write_head_and_body(true)?;
+        self.finish(n_written)
+    }
+}
+
+/// Copy a streamed body to `writer` through a fixed-size buffer. The reader must produce
+/// exactly Content-Length bytes because the client relies on it to find the end of the body
+fn copy_body(
+    reader: BodyReader,
+    writer: &mut impl Write,
+    headers: &HTTPHeaders,
+) -> Result<usize, IoError> {
+    let content_length: u64 = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or_default();
+    let copied = std::io::copy(&mut reader.take(content_length), writer)?;
+    if copied < content_length {
+        return Err(IoError::new(
+            ErrorKind::UnexpectedEof,
+            format!("The body ended after {copied} of {content_length} bytes"),
+        ));
+    }
+    Ok(copied as usize)
+}
+
+/// Counts the bytes written through it, E.G to report the size of a generated body
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self.version() {
-                HTTPVersion::V0_9 => format_http0_9(self).to_owned(),
-                HTTPVersion::V1_0 | HTTPVersion::V1_1 => format_http1_x(self),
-                other =>
-                    panic!("Formatting responses for HTTP version {other} is not yet supported"),
-            }
-        )
+        let formatted = match self.version() {
+            HTTPVersion::V0_9 => format_http0_9(self).to_vec(),
+            HTTPVersion::V1_0 | HTTPVersion::V1_1 => format_http1_x(self),
+            other => panic!("Formatting responses for HTTP version {other} is not yet supported"),
+        };
+        // Binary bodies are only displayed approximately
+        write!(f, "{}", String::from_utf8_lossy(&formatted))
     }
 }
 
 pub fn ensure_headers(res: &mut Response) {
-    if !res.body.is_empty() {
+    // A Connection header set by the handler takes precedence over the version's default
+    match res.get_header("Connection".to_string()) {
+        Some(connection) if contains_token(&connection, "close") => {
+            res.connection = ConnectionMode::Close
+        }
+        Some(connection) if contains_token(&connection, "keep-alive") => {
+            res.connection = ConnectionMode::KeepAlive
+        }
+        _ => {}
+    }
+    // The length of a chunked body is determined by the chunks. Empty HTTP/1.1 bodies need a
+    // Content-Length too, otherwise they're close-delimited and a client on a kept-alive
+    // connection waits for a close that never comes. HTTP/1.0 connections are closed anyway.
+    // See https://www.rfc-editor.org/rfc/rfc9112#section-6.3
+    let code = res.status.to_code();
+    let may_have_body = !(100..200).contains(&code) && code != 204 && code != 304;
+    let needs_length = !res.body.is_empty() || (res.version == HTTPVersion::V1_1 && may_have_body);
+    if needs_length && !res.is_chunked() {
         res.insert_if_absent("Content-Length".to_string(), res.body.len().to_string());
+    }
+    if let Some(ct) = res.get_header("Content-Type".to_string()) {
+        // Binary types, E.G images, have no charset
+        if !ct.contains("charset") && is_textual(&ct) {
+            res.set_header("Content-Type".to_string(), ct + "; charset=UTF-8");
+        }
+    }
 
-        if let Some(ct) = res.get_header("Content-Type".to_string()) {
-            if !ct.contains("charset") {
-                res.set_header("Content-Type".to_string(), ct + "; charset=UTF-8");
-            }
-        };
+    // Only HTTP/1.1 connections are kept open (see `Request::keep_alive`), so advertising
+    // keep-alive on older versions would be a lie. Otherwise the client can only tell where a
+    // close-delimited body ends when the connection closes
+    let can_keep_alive = res.version == HTTPVersion::V1_1 && !res.is_close_delimited();
+    if !can_keep_alive && res.connection == ConnectionMode::KeepAlive {
+        res.connection = ConnectionMode::Close;
+        res.set_header("Connection".to_string(), ConnectionMode::Close.to_string());
+    }
+}
+
+/// Whether a media type is text that needs a charset, E.G `text/html` or `application/json`
+fn is_textual(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// The default maximum size of each chunk in a chunked response
+pub const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Responses up to this many bytes fit in a single TCP segment on a typical 1500 byte MTU link
+pub const SMALL_RESPONSE_SIZE: usize = 1400;
+
+/// Escape the characters that are significant in HTML so that `s` can be safely interpolated
+/// into element content or quoted attribute values
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
     }
+    escaped
+}
+
+/// Format an informational (1xx) response, E.G 100 Continue. These never have a body
+pub fn format_interim(version: HTTPVersion, status: &ResponseStatus) -> String {
+    format!("{version} {0} {status}\r\n\r\n", status.to_code())
 }
 
 // Format for HTTP 1.1
-pub fn format_http0_9(res: &Response) -> &String {
+pub fn format_http0_9(res: &Response) -> &[u8] {
     &res.body
 }
 
@@ -439,30 +1067,136 @@ fn title_case_header(s: &str) -> String {
     new_s
 }
 
-pub fn format_http1_x(res: &Response) -> String {
-    let stringified_headers: String =
-        res.headers
-            .iter()
-            .fold(String::new(), |mut s, (key, value)| {
-                let _ = write!(s, "{0}: {value}\r\n", title_case_header(key));
-                s
-            });
+fn format_trailers(trailers: &HTTPHeaders) -> String {
+    let mut formatted = String::from("0\r\n");
+    for (key, value) in trailers {
+        let _ = write!(formatted, "{0}: {value}\r\n", title_case_header(key));
+    }
+    formatted.push_str("\r\n");
+    formatted
+}
+
+/// Encode the body as chunks of at most `chunk_size` bytes followed by the last chunk and
+/// trailers. See https://www.rfc-editor.org/rfc/rfc9112#section-7.1
+fn format_chunked_body(body: &[u8], trailers: &HTTPHeaders, chunk_size: usize) -> Vec<u8> {
+    let mut chunked = Vec::with_capacity(body.len() + format_trailers(trailers).len());
+    for chunk in body.chunks(chunk_size) {
+        chunked.extend_from_slice(format!("{0:X}\r\n", chunk.len()).as_bytes());
+        chunked.extend_from_slice(chunk);
+        chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(format_trailers(trailers).as_bytes());
+    chunked
+}
+
+/// Encodes everything written to it using chunked transfer coding, E.G when streaming a
+/// response body straight to the connection. Small writes are coalesced until `chunk_size`
+/// bytes are buffered so that the size prefixes don't waste bandwidth. `flush` sends whatever
+/// is buffered as a shorter chunk
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "Chunks can't be empty");
+        Self {
+            inner,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+        }
+    }
+
+    fn write_chunk(&mut self, length: usize) -> Result<(), IoError> {
+        if length == 0 {
+            return Ok(());
+        }
+        let mut chunk = format!("{length:X}\r\n").into_bytes();
+        chunk.extend(self.buffer.drain(..length));
+        chunk.extend(b"\r\n");
+        self.inner.write_all(&chunk)
+    }
+
+    /// Send the buffered bytes, the last chunk and `trailers`, then return the wrapped writer
+    pub fn finish(mut self, trailers: &HTTPHeaders) -> Result<W, IoError> {
+        self.write_chunk(self.buffer.len())?;
+        self.inner.write_all(format_trailers(trailers).as_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.chunk_size {
+            self.write_chunk(self.chunk_size)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_chunk(self.buffer.len())?;
+        self.inner.flush()
+    }
+}
+
+/// Format the status line and headers of an HTTP/1.x response, including the empty line
+/// that separates them from the body
+pub fn format_head(version: HTTPVersion, status: &ResponseStatus, headers: &HTTPHeaders) -> String {
+    let stringified_headers: String = headers.iter().fold(String::new(), |mut s, (key, value)| {
+        let _ = write!(s, "{0}: {value}\r\n", title_case_header(key));
+        s
+    });
 
     // There will be a trailing newline from the headers, so only 1 newline
     // here
     format!(
-        "{0} {1} {2}\r\n{3}\r\n{4}",
-        res.version,
-        res.status.to_code(),
-        res.status,
-        stringified_headers,
-        res.body
+        "{version} {0} {status}\r\n{stringified_headers}\r\n",
+        status.to_code()
     )
 }
 
+/// The head of an HTTP/1.x response, including the Connection header
+fn format_http1_x_head(res: &Response) -> String {
+    // HTTP/1.0 connections close by default, so `Connection: close` would be redundant
+    let is_default_close =
+        res.version == HTTPVersion::V1_0 && res.connection == ConnectionMode::Close;
+    let headers = if res.headers.contains_key("connection") || is_default_close {
+        Cow::Borrowed(&res.headers)
+    } else {
+        let mut headers = res.headers.clone();
+        headers.insert("connection".to_string(), res.connection.to_string());
+        Cow::Owned(headers)
+    };
+
+    let mut head = format_head(res.version, &res.status, &headers);
+    // Insert the cookies before the blank line that ends the head
+    let cookies: String = res
+        .set_cookies
+        .iter()
+        .map(|cookie| format!("Set-Cookie: {cookie}\r\n"))
+        .collect();
+    head.insert_str(head.len() - 2, &cookies);
+    head
+}
+
+/// The head followed by the raw bytes of the body
+pub fn format_http1_x(res: &Response) -> Vec<u8> {
+    let mut formatted = format_http1_x_head(res).into_bytes();
+    formatted.extend_from_slice(&res.encode_body(&res.body));
+    formatted
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, io::Cursor};
+    use std::{
+        collections::HashMap,
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
 
     use super::*;
 
@@ -470,8 +1204,12 @@ mod tests {
         Box::new(Cursor::new(Vec::new()))
     }
 
+    fn formatted(res: &Response) -> String {
+        String::from_utf8(format_http1_x(res)).expect("The response should be UTF-8")
+    }
+
     fn setup() {
-        let _ = env_logger::builder().is_test(true).try_init();
+        crate::testing::init_logger();
     }
 
     #[test]
@@ -487,7 +1225,7 @@ mod tests {
             .expect("An HTTP 0.9 response should be constructed");
 
         let result = format_http0_9(&res);
-        assert_eq!(result, "OK", "An HTTP 0.9 response is just the body");
+        assert_eq!(result, b"OK", "An HTTP 0.9 response is just the body");
     }
 
     #[test]
@@ -500,7 +1238,7 @@ mod tests {
             .stream(make_stream())
             .build()
             .expect("An HTTP 1.0 response should be constructed");
-        let result = format_http1_x(&res);
+        let result = formatted(&res);
         log::debug!("Result generated: {result}");
 
         let mut result_lines = result.lines();
@@ -517,6 +1255,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_http_1_0_close_delimited() {
+        use std::net::{TcpListener, TcpStream};
+        use std::time::Duration;
+
+        let server = TcpListener::bind("127.0.0.1:0").expect("Binding should succeed");
+        let mut client =
+            TcpStream::connect(server.local_addr().unwrap()).expect("Connecting should succeed");
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // Kept open so that only `send` can close the connection
+        let (accepted, _) = server.accept().expect("Accepting should succeed");
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_0)
+            .ok()
+            .stream(Box::new(accepted.try_clone().unwrap()))
+            .build()
+            .expect("An HTTP 1.0 response should be constructed");
+        assert!(res.is_close_delimited());
+        res.send().expect("Sending the response should succeed");
+
+        let mut received = String::new();
+        std::io::Read::read_to_string(&mut client, &mut received)
+            .expect("The connection should be closed after the response");
+        assert_eq!(received, "HTTP/1.0 200 OK\r\n\r\n");
+        drop(accepted);
+    }
+
+    #[test]
+    fn test_http_1_0_length_delimited() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_0)
+            .ok()
+            .body("Hello world".to_string())
+            .stream(make_stream())
+            .build()
+            .expect("An HTTP 1.0 response should be constructed");
+        assert!(
+            !res.is_close_delimited(),
+            "Responses with a Content-Length don't need the connection closed"
+        );
+    }
+
     #[test]
     fn test_format_http_1_0_with_body() {
         setup();
@@ -528,7 +1311,7 @@ mod tests {
             .stream(make_stream())
             .build()
             .expect("An HTTP 1.0 response should be constructed");
-        let result = format_http1_x(&res);
+        let result = formatted(&res);
         log::debug!("Result generated: {result}");
 
         let mut result_lines = result.lines();
@@ -565,7 +1348,7 @@ mod tests {
             .stream(make_stream())
             .build()
             .expect("An HTTP 1.0 response should be constructed");
-        let result = format_http1_x(&res);
+        let result = formatted(&res);
         log::debug!("Result generated: {result}");
 
         let mut result_lines = result.lines();
@@ -589,26 +1372,126 @@ mod tests {
     }
 
     #[test]
-    fn test_manage_headers() {
-        let mut res = ResponseBuilder::default()
+    fn connection_header_round_trip() {
+        let connection_header = |res: &Response| {
+            formatted(res)
+                .lines()
+                .find_map(|line| line.strip_prefix("Connection: "))
+                .map(str::to_string)
+        };
+
+        let res = ResponseBuilder::default()
             .version(HTTPVersion::V1_1)
             .ok()
             .stream(make_stream())
             .build()
-            .expect("An empty OK request should be constructed");
+            .expect("An HTTP 1.1 response should be constructed");
+        assert_eq!(res.connection, ConnectionMode::KeepAlive);
+        assert_eq!(connection_header(&res).as_deref(), Some("keep-alive"));
+        assert!(!res.closes_connection());
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .connection(ConnectionMode::Close)
+            .stream(make_stream())
+            .build()
+            .expect("An HTTP 1.1 response should be constructed");
+        assert_eq!(res.connection, ConnectionMode::Close);
+        assert_eq!(connection_header(&res).as_deref(), Some("close"));
+        assert!(res.closes_connection());
 
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_0)
+            .ok()
+            .body("Hello world".to_string())
+            .connection(ConnectionMode::KeepAlive)
+            .stream(make_stream())
+            .build()
+            .expect("An HTTP 1.0 response should be constructed");
         assert_eq!(
-            res.headers,
-            HashMap::new(),
-            "The headers should be empty initially"
+            connection_header(&res).as_deref(),
+            Some("close"),
+            "HTTP/1.0 connections are always closed, so keep-alive shouldn't be advertised"
         );
+        assert!(res.closes_connection());
 
-        res.set_header("Delta-Base".to_string(), "abc".to_string());
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_0)
+            .ok()
+            .connection(ConnectionMode::KeepAlive)
+            .stream(make_stream())
+            .build()
+            .expect("An HTTP 1.0 response should be constructed");
         assert_eq!(
-            res.get_header("Delta-Base".to_string()),
-            Some("abc".to_string()),
-            "Should get the newly-inserted header Delta-Base. Headers: {0:?}",
-            res.headers
+            res.connection,
+            ConnectionMode::Close,
+            "A close-delimited response can't keep the connection open"
+        );
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .status(ResponseStatus::SwitchingProtocols)
+            .header("Connection", "Upgrade")
+            .stream(make_stream())
+            .build()
+            .expect("A 101 response should be constructed");
+        assert_eq!(
+            connection_header(&res).as_deref(),
+            Some("Upgrade"),
+            "An existing Connection header should be kept"
+        );
+    }
+
+    #[test]
+    fn connection_from_request() {
+        let (request, _) = crate::testing::mock_request(
+            "GET / HTTP/1.1\r\nHost: a.com\r\nConnection: close\r\n",
+            b"",
+        );
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .headers(HashMap::from([(
+                "Content-Type".to_string(),
+                "text/plain".to_string(),
+            )]))
+            .build()
+            .expect("A valid response should be produced");
+        assert_eq!(
+            res.connection,
+            ConnectionMode::Close,
+            "The client's Connection: close should be honoured even if the headers are replaced"
+        );
+
+        let (request, _) = crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .build()
+            .expect("A valid response should be produced");
+        assert_eq!(res.connection, ConnectionMode::KeepAlive);
+    }
+
+    #[test]
+    fn test_manage_headers() {
+        let mut res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .stream(make_stream())
+            .build()
+            .expect("An empty OK request should be constructed");
+
+        assert_eq!(
+            res.headers,
+            HashMap::from([("content-length".to_string(), "0".to_string())]),
+            "Only the empty body's length should be set initially"
+        );
+
+        res.set_header("Delta-Base".to_string(), "abc".to_string());
+        assert_eq!(
+            res.get_header("Delta-Base".to_string()),
+            Some("abc".to_string()),
+            "Should get the newly-inserted header Delta-Base. Headers: {0:?}",
+            res.headers
         );
 
         assert_eq!(
@@ -633,4 +1516,642 @@ mod tests {
             res.headers
         );
     }
+
+    #[test]
+    fn test_text() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .text("Hello world")
+            .stream(make_stream())
+            .build()
+            .expect("A plaintext response should be constructed");
+
+        assert_eq!(res.body(), b"Hello world");
+        assert_eq!(
+            res.get_header("Content-Type".to_string()),
+            Some("text/plain; charset=UTF-8".to_string()),
+            "The content type should be plaintext with a single charset parameter"
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .html("<p>Hello world</p>".to_string())
+            .stream(make_stream())
+            .build()
+            .expect("An HTML response should be constructed");
+
+        assert_eq!(res.body(), b"<p>Hello world</p>");
+        assert_eq!(
+            res.get_header("Content-Type".to_string()),
+            Some("text/html; charset=UTF-8".to_string())
+        );
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("it's"), "it&#x27;s");
+        assert_eq!(
+            escape_html("Alfred"),
+            "Alfred",
+            "Safe text should be unchanged"
+        );
+    }
+
+    #[test]
+    fn test_accept_ranges() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .accept_ranges(AcceptRanges::Bytes)
+            .stream(make_stream())
+            .build()
+            .expect("A rangeable response should be constructed");
+        assert_eq!(
+            res.get_header("Accept-Ranges".to_string()),
+            Some("bytes".to_string())
+        );
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .stream(make_stream())
+            .build()
+            .expect("A response should be constructed");
+        assert_eq!(
+            res.get_header("Accept-Ranges".to_string()),
+            None,
+            "Range support should not be advertised by default"
+        );
+    }
+
+    #[test]
+    fn test_ranged_body_if_range() {
+        use crate::request::range::conditional_ranges;
+
+        let etag = Some("\"v1\"");
+        let ranged = |if_range: &str| {
+            let headers = HTTPHeaders::from([
+                ("range".to_string(), "bytes=0-4".to_string()),
+                ("if-range".to_string(), if_range.to_string()),
+            ]);
+            ResponseBuilder::default()
+                .version(HTTPVersion::V1_1)
+                .ranged_body(
+                    conditional_ranges(&headers, etag, None),
+                    "Hello, World!".to_string(),
+                )
+                .stream(make_stream())
+                .build()
+                .expect("A ranged response should be constructed")
+        };
+
+        let res = ranged("\"v1\"");
+        assert_eq!(res.status(), &ResponseStatus::PartialContent);
+        assert_eq!(res.body(), b"Hello");
+        assert_eq!(
+            res.get_header("Content-Range".to_string()),
+            Some("bytes 0-4/13".to_string())
+        );
+
+        let res = ranged("\"v0\"");
+        assert_eq!(
+            res.status(),
+            &ResponseStatus::OK,
+            "A stale If-Range should get the full representation"
+        );
+        assert_eq!(res.body(), b"Hello, World!");
+        assert_eq!(res.get_header("Content-Range".to_string()), None);
+    }
+
+    #[test]
+    fn test_ranged_body_unsatisfiable() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ranged_body(Some(vec![ByteRange::From(20)]), "Hello".to_string())
+            .stream(make_stream())
+            .build()
+            .expect("An unsatisfiable range response should be constructed");
+        assert_eq!(res.status(), &ResponseStatus::RangeNotSatisfiable);
+        assert_eq!(
+            res.get_header("Content-Range".to_string()),
+            Some("bytes */5".to_string())
+        );
+    }
+
+    #[test]
+    fn test_content_location() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .header("Content-Type", "application/json")
+            .content_location("/dogs.json")
+            .body("[]".to_string())
+            .stream(make_stream())
+            .build()
+            .expect("A negotiated JSON response should be constructed");
+
+        assert_eq!(
+            res.get_header("Content-Location".to_string()),
+            Some("/dogs.json".to_string()),
+            "Content-Location should point to the JSON variant"
+        );
+    }
+
+    #[test]
+    fn test_created_at() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .created_at("/dogs/Alfred")
+            .stream(make_stream())
+            .build()
+            .expect("A 201 response should be constructed");
+
+        assert_eq!(*res.status(), ResponseStatus::Created);
+        assert_eq!(
+            res.get_header("Location".to_string()),
+            Some("/dogs/Alfred".to_string()),
+            "The Location header should point to the new resource"
+        );
+    }
+
+    #[test]
+    fn test_accepted() {
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .accepted(Some("/jobs/42"))
+            .stream(make_stream())
+            .build()
+            .expect("A 202 response should be constructed");
+
+        assert_eq!(*res.status(), ResponseStatus::Accepted);
+        assert_eq!(
+            res.get_header("Location".to_string()),
+            Some("/jobs/42".to_string()),
+            "The Location header should point to the job's status"
+        );
+        assert_eq!(
+            res.get_header("Content-Location".to_string()),
+            Some("/jobs/42".to_string())
+        );
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .accepted(None)
+            .stream(make_stream())
+            .build()
+            .expect("A 202 response without a status URL should be constructed");
+        assert_eq!(*res.status(), ResponseStatus::Accepted);
+        assert_eq!(res.get_header("Location".to_string()), None);
+    }
+
+    #[test]
+    fn test_status_from_io_error() {
+        let cases = [
+            (ErrorKind::NotFound, ResponseStatus::NotFound),
+            (ErrorKind::PermissionDenied, ResponseStatus::Forbidden),
+            (ErrorKind::TimedOut, ResponseStatus::GatewayTimeout),
+            (ErrorKind::Other, ResponseStatus::InternalServerError),
+            (
+                ErrorKind::UnexpectedEof,
+                ResponseStatus::InternalServerError,
+            ),
+        ];
+
+        for (kind, expected) in cases {
+            assert_eq!(
+                ResponseStatus::from(IoError::from(kind)),
+                expected,
+                "{kind:?} should map to {expected}"
+            );
+        }
+
+        let res = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .from_io_error(&IoError::from(ErrorKind::PermissionDenied))
+            .stream(make_stream())
+            .build()
+            .expect("A response should be constructed from an IO error");
+        assert_eq!(res.status, ResponseStatus::Forbidden);
+    }
+
+    #[test]
+    fn test_chunked_trailers() {
+        let (request, output) =
+            crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\nTE: trailers\r\n", b"");
+        ResponseBuilder::from(request)
+            .ok()
+            .chunked()
+            .body("Hello world".to_string())
+            .trailer("X-Checksum", "abc123")
+            .build()
+            .expect("A chunked response with trailers should be constructed")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            written.contains("Trailer: X-Checksum\r\n"),
+            "The trailer should be declared. Written: {written:?}"
+        );
+        assert!(
+            !written.contains("Content-Length"),
+            "Chunked responses should not have a Content-Length. Written: {written:?}"
+        );
+        assert!(
+            written.ends_with("B\r\nHello world\r\n0\r\nX-Checksum: abc123\r\n\r\n"),
+            "The trailer should follow the last chunk. Written: {written:?}"
+        );
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let (request, _) = crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .chunked()
+            .body("Hello world".to_string())
+            .build()
+            .expect("A chunked response should be constructed")
+            .with_chunk_size(4);
+        assert!(
+            formatted(&res).ends_with("\r\n\r\n4\r\nHell\r\n4\r\no wo\r\n3\r\nrld\r\n0\r\n\r\n"),
+            "Chunks should be at most 4 bytes. Formatted: {0:?}",
+            formatted(&res)
+        );
+
+        assert_eq!(
+            format_chunked_body(b"\x89PNG", &HTTPHeaders::new(), 3),
+            b"3\r\n\x89PN\r\n1\r\nG\r\n0\r\n\r\n",
+            "Binary bodies should be chunked unchanged"
+        );
+    }
+
+    #[test]
+    fn test_set_cookies() {
+        use crate::server::cookie::SameSite;
+
+        let (request, _) = crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .set_cookie(
+                Cookie::new("session", "abc123")
+                    .same_site(SameSite::Lax)
+                    .http_only(true),
+            )
+            .set_cookie(Cookie::new("theme", "dark"))
+            .build()
+            .expect("A response with cookies should be constructed");
+
+        let formatted = formatted(&res);
+        let (head, _) = formatted
+            .split_once("\r\n\r\n")
+            .expect("The head should end with an empty line");
+        let set_cookies: Vec<&str> = head
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .collect();
+        assert_eq!(
+            set_cookies,
+            ["session=abc123; SameSite=Lax; HttpOnly", "theme=dark"],
+            "Each cookie should get its own header"
+        );
+
+        let (request, _) = crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        ResponseBuilder::from(request)
+            .ok()
+            .set_cookie(Cookie::new("session", "a; Domain=evil.com"))
+            .build()
+            .expect_err("Cookies that would inject attributes should be rejected");
+    }
+
+    #[test]
+    fn test_charset_from_media_type() {
+        let content_type = |media_type: &str| {
+            let (request, _) =
+                crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+            ResponseBuilder::from(request)
+                .ok()
+                .header("Content-Type", media_type)
+                .body_str("plain ASCII")
+                .build()
+                .expect("A valid response should be constructed")
+                .get_header("Content-Type".to_string())
+        };
+
+        assert_eq!(
+            content_type("application/octet-stream"),
+            Some("application/octet-stream".to_string()),
+            "Binary types shouldn't get a charset, even if the body happens to be UTF-8"
+        );
+        assert_eq!(
+            content_type("application/json"),
+            Some("application/json; charset=UTF-8".to_string())
+        );
+        assert_eq!(
+            content_type("application/problem+json"),
+            Some("application/problem+json; charset=UTF-8".to_string())
+        );
+        assert_eq!(
+            content_type("text/csv; charset=ISO-8859-1"),
+            Some("text/csv; charset=ISO-8859-1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_binary_body() {
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\xff";
+
+        let (request, output) =
+            crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .header("Content-Type", "image/png")
+            .body_bytes(PNG)
+            .build()
+            .expect("A binary response should be constructed");
+        assert_eq!(
+            res.get_header("Content-Type".to_string()),
+            Some("image/png".to_string()),
+            "Binary bodies shouldn't get a charset"
+        );
+        assert_eq!(
+            res.get_header("Content-Length".to_string()),
+            Some(PNG.len().to_string())
+        );
+        let n_sent = res.send().expect("Sending the response should succeed");
+
+        let written = output.lock().unwrap().clone();
+        assert_eq!(n_sent, written.len());
+        let body_start = written
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("The head should end with an empty line")
+            + 4;
+        assert_eq!(
+            &written[body_start..],
+            PNG,
+            "The body should be sent unchanged"
+        );
+    }
+
+    /// Generates `remaining` bytes of a repeating pattern without allocating them
+    struct PatternReader {
+        position: usize,
+        remaining: usize,
+    }
+
+    fn pattern_byte(position: usize) -> u8 {
+        (position % 251) as u8
+    }
+
+    impl Read for PatternReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.remaining);
+            for byte in &mut buf[..n] {
+                *byte = pattern_byte(self.position);
+                self.position += 1;
+            }
+            self.remaining -= n;
+            Ok(n)
+        }
+    }
+
+    /// Checks the body against the pattern as it is written instead of collecting it
+    #[derive(Default)]
+    struct PatternCheck {
+        head: Vec<u8>,
+        body_length: usize,
+        mismatches: usize,
+        largest_write: usize,
+    }
+
+    #[derive(Clone, Default)]
+    struct PatternCheckStream(Arc<Mutex<PatternCheck>>);
+
+    impl Write for PatternCheckStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut check = self.0.lock().unwrap();
+            check.largest_write = check.largest_write.max(buf.len());
+            for &byte in buf {
+                if !check.head.ends_with(b"\r\n\r\n") {
+                    check.head.push(byte);
+                    continue;
+                }
+                if byte != pattern_byte(check.body_length) {
+                    check.mismatches += 1;
+                }
+                check.body_length += 1;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for PatternCheckStream {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl SyncableStream for PatternCheckStream {
+        fn get_type(&self) -> crate::request::SyncableStreamType {
+            crate::request::SyncableStreamType::Tcp
+        }
+    }
+
+    #[test]
+    fn test_streamed_body() {
+        const LENGTH: usize = 1024 * 1024;
+
+        let stream = PatternCheckStream::default();
+        let n_sent = ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .header("Content-Type", "application/octet-stream")
+            .body_reader(
+                Box::new(PatternReader {
+                    position: 0,
+                    remaining: LENGTH,
+                }),
+                LENGTH as u64,
+            )
+            .stream(Box::new(stream.clone()))
+            .build()
+            .expect("A streamed response should be constructed")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let check = stream.0.lock().unwrap();
+        let head = String::from_utf8(check.head.clone()).unwrap();
+        assert!(
+            head.contains(&format!("Content-Length: {LENGTH}\r\n")),
+            "{head}"
+        );
+        assert_eq!(check.body_length, LENGTH);
+        assert_eq!(check.mismatches, 0, "The body should be sent unchanged");
+        assert_eq!(n_sent, check.head.len() + LENGTH);
+        assert!(
+            check.largest_write <= 64 * 1024,
+            "The body should be copied in small chunks. Largest write: {0}",
+            check.largest_write
+        );
+    }
+
+    #[test]
+    fn test_streamed_body_too_short() {
+        let stream = PatternCheckStream::default();
+        ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .body_reader(
+                Box::new(PatternReader {
+                    position: 0,
+                    remaining: 10,
+                }),
+                20,
+            )
+            .stream(Box::new(stream))
+            .build()
+            .expect("A streamed response should be constructed")
+            .send()
+            .expect_err("A body shorter than its Content-Length should fail to send");
+
+        ResponseBuilder::default()
+            .version(HTTPVersion::V1_1)
+            .ok()
+            .chunked()
+            .body_reader(Box::new(std::io::empty()), 0)
+            .stream(make_stream())
+            .build()
+            .expect_err("Streamed bodies can't be chunked");
+    }
+
+    /// The body of a chunked message, without its trailers
+    fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let line_end = chunked
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .expect("Chunk sizes should end with CRLF");
+            let size = std::str::from_utf8(&chunked[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).expect("Chunk sizes should be hex");
+            if size == 0 {
+                return body;
+            }
+            let chunk = &chunked[line_end + 2..];
+            body.extend_from_slice(&chunk[..size]);
+            assert_eq!(
+                &chunk[size..size + 2],
+                b"\r\n",
+                "Chunks should end with CRLF"
+            );
+            chunked = &chunk[size + 2..];
+        }
+    }
+
+    #[test]
+    fn test_body_generator() {
+        const LINES: usize = 1_000_000;
+
+        let (request, output) =
+            crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let n_sent = ResponseBuilder::from(request)
+            .ok()
+            .header("Content-Type", "text/csv")
+            .body_generator(Box::new(|out| {
+                for i in 0..LINES {
+                    writeln!(out, "{i},row")?;
+                }
+                Ok(())
+            }))
+            .build()
+            .expect("A generated response should be constructed")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let written = output.lock().unwrap().clone();
+        assert_eq!(n_sent, written.len());
+        let body_start = written
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("The head should end with an empty line")
+            + 4;
+        let head = String::from_utf8(written[..body_start].to_vec()).unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+
+        let body = String::from_utf8(dechunk(&written[body_start..])).unwrap();
+        let mut n_lines = 0;
+        for (i, line) in body.lines().enumerate() {
+            assert_eq!(line, format!("{i},row"));
+            n_lines += 1;
+        }
+        assert_eq!(n_lines, LINES, "Every line should be received");
+    }
+
+    #[test]
+    fn test_body_generator_http_1_0() {
+        let (request, output) = crate::testing::mock_request("GET / HTTP/1.0\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .body_generator(Box::new(|out| out.write_all(b"Hello world")))
+            .build()
+            .expect("A generated response should be constructed");
+        assert!(
+            res.is_close_delimited(),
+            "HTTP/1.0 doesn't support chunked transfer coding"
+        );
+        res.send().expect("Sending the response should succeed");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(written.ends_with("\r\n\r\nHello world"), "{written:?}");
+    }
+
+    #[test]
+    fn test_chunked_writer_coalesces() {
+        let mut writer = ChunkedWriter::new(Vec::new(), 8);
+        for word in ["ab", "cd", "ef", "gh", "ij"] {
+            writer
+                .write_all(word.as_bytes())
+                .expect("Writing to memory should succeed");
+        }
+        let written = writer
+            .finish(&HTTPHeaders::new())
+            .expect("Finishing should succeed");
+
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "8\r\nabcdefgh\r\n2\r\nij\r\n0\r\n\r\n",
+            "Small writes should be coalesced into chunks of the configured size"
+        );
+    }
+
+    #[test]
+    fn test_trailers_dropped_without_te() {
+        let (request, _) = crate::testing::mock_request("GET / HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let res = ResponseBuilder::from(request)
+            .ok()
+            .chunked()
+            .body("Hello world".to_string())
+            .trailer("X-Checksum", "abc123")
+            .build()
+            .expect("A chunked response should be constructed");
+
+        assert!(res.trailers.is_empty());
+        assert_eq!(res.get_header("Trailer".to_string()), None);
+        assert!(formatted(&res).ends_with("0\r\n\r\n"));
+    }
 }
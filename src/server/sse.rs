@@ -0,0 +1,236 @@
+use std::{
+    io::{Error as IoError, Write},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::request::{HTTPHeaders, HTTPVersion, Request, SyncableStream};
+
+use super::{
+    handlers::HandlerResult,
+    response::{ChunkedWriter, Response, ResponseBuilder, DEFAULT_CHUNK_SIZE},
+};
+
+/// An event sent to an `EventSource`.
+/// See https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SseEvent {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+}
+
+impl SseEvent {
+    /// Multi-line `data` is sent as one `data:` field per line
+    pub fn new(data: &str) -> Self {
+        Self {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The event type, dispatched to listeners registered with `addEventListener`.
+    /// Events without a type are dispatched as `message`
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// Sent back by the client in Last-Event-ID when it reconnects
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn format(&self) -> String {
+        // A newline would end the field early, so they're stripped from single-line fields
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        let mut formatted = String::new();
+        if let Some(event) = &self.event {
+            formatted += &format!("event: {}\n", single_line(event));
+        }
+        if let Some(id) = &self.id {
+            formatted += &format!("id: {}\n", single_line(id));
+        }
+        for line in self.data.split('\n') {
+            formatted += &format!("data: {}\n", line.trim_end_matches('\r'));
+        }
+        formatted + "\n"
+    }
+}
+
+enum SseMessage {
+    Event(SseEvent),
+    /// Ignored by clients. Used to stop idle connections from being closed
+    KeepAlive,
+}
+
+impl SseMessage {
+    fn format(&self) -> String {
+        match self {
+            Self::Event(event) => event.format(),
+            Self::KeepAlive => ": keep-alive\n\n".to_string(),
+        }
+    }
+}
+
+/// The client has disconnected, so no more events can be sent
+#[derive(Debug, PartialEq)]
+pub struct SseClosed;
+
+/// Pushes events to the client of an `SseResponse`. The stream ends once every sender has been
+/// dropped
+#[derive(Clone)]
+pub struct SseSender(Sender<SseMessage>);
+
+impl SseSender {
+    pub fn send(&self, event: SseEvent) -> Result<(), SseClosed> {
+        self.0.send(SseMessage::Event(event)).or(Err(SseClosed))
+    }
+
+    /// Send a comment to hold the connection open while there are no events
+    pub fn keep_alive(&self) -> Result<(), SseClosed> {
+        self.0.send(SseMessage::KeepAlive).or(Err(SseClosed))
+    }
+}
+
+/// A `text/event-stream` response whose events are pushed through an `SseSender`.
+/// Each event is flushed as soon as it is sent, and caching and transformations such as
+/// compression by proxies are disabled. The events are written by the worker thread that sent
+/// the response, so it is occupied until every `SseSender` has been dropped
+pub struct SseResponse {
+    response: Response,
+    receiver: Receiver<SseMessage>,
+}
+
+impl SseResponse {
+    pub fn new(req: Request) -> (Self, SseSender) {
+        let version = req.head.version;
+        let builder = ResponseBuilder::from(req)
+            .ok()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache, no-transform")
+            // Stops reverse proxies like nginx from buffering the events
+            .header("X-Accel-Buffering", "no");
+        // HTTP/1.0 doesn't support chunked transfer coding, so the stream ends by closing the
+        // connection instead
+        let builder = match version {
+            HTTPVersion::V1_1 => builder.chunked(),
+            _ => builder,
+        };
+        let (sender, receiver) = mpsc::channel();
+
+        (
+            Self {
+                response: builder
+                    .build()
+                    .expect("A valid event stream response should be produced"),
+                receiver,
+            },
+            SseSender(sender),
+        )
+    }
+}
+
+impl From<SseResponse> for HandlerResult {
+    fn from(SseResponse { response, receiver }: SseResponse) -> Self {
+        let chunked = response.is_chunked();
+        HandlerResult::Hijack(
+            response,
+            Box::new(move |stream| {
+                if let Err(err) = stream_events(stream, receiver, chunked) {
+                    log::info!("Event stream ended early: {err}");
+                }
+            }),
+        )
+    }
+}
+
+fn stream_events(
+    stream: Box<dyn SyncableStream>,
+    receiver: Receiver<SseMessage>,
+    chunked: bool,
+) -> Result<(), IoError> {
+    if chunked {
+        let mut writer = ChunkedWriter::new(stream, DEFAULT_CHUNK_SIZE);
+        write_messages(&mut writer, receiver)?;
+        writer.finish(&HTTPHeaders::new()).map(|_| ())
+    } else {
+        let mut stream = stream;
+        write_messages(&mut stream, receiver)?;
+        stream.shutdown()
+    }
+}
+
+fn write_messages(writer: &mut impl Write, receiver: Receiver<SseMessage>) -> Result<(), IoError> {
+    for message in receiver {
+        writer.write_all(message.format().as_bytes())?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    #[test]
+    fn format_event() {
+        assert_eq!(SseEvent::new("hello").format(), "data: hello\n\n");
+        assert_eq!(
+            SseEvent::new("line 1\nline 2")
+                .event("update")
+                .id("7")
+                .format(),
+            "event: update\nid: 7\ndata: line 1\ndata: line 2\n\n"
+        );
+    }
+
+    #[test]
+    fn events_received_in_order() {
+        let (request, output) = mock_request("GET /events HTTP/1.1\r\nHost: a.com\r\n", b"");
+        let (sse, sender) = SseResponse::new(request);
+
+        let publisher = thread::spawn(move || {
+            sender
+                .send(SseEvent::new("first").id("1"))
+                .expect("The client should be connected");
+            sender.keep_alive().expect("The client should be connected");
+            thread::sleep(Duration::from_millis(20));
+            sender
+                .send(SseEvent::new("second").id("2"))
+                .expect("The client should be connected");
+        });
+
+        let HandlerResult::Hijack(response, hijacker) = sse.into() else {
+            panic!("An event stream should hijack the connection");
+        };
+        response
+            .hijack(hijacker)
+            .send()
+            .expect("Sending the event stream should succeed");
+        publisher.join().expect("The publisher should finish");
+
+        let written = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let (head, body) = written
+            .split_once("\r\n\r\n")
+            .expect("The head should be sent");
+        assert!(head.contains("Content-Type: text/event-stream"), "{head}");
+        assert!(head.contains("Transfer-Encoding: chunked"), "{head}");
+
+        let first = body.find("id: 1\ndata: first\n\n");
+        let second = body.find("id: 2\ndata: second\n\n");
+        assert!(
+            first.is_some() && second.is_some() && first < second,
+            "Both events should be received in order. Body: {body:?}"
+        );
+        assert!(body.contains(": keep-alive\n\n"));
+        assert!(
+            body.ends_with("\r\n0\r\n\r\n"),
+            "The stream should end with the last chunk. Body: {body:?}"
+        );
+    }
+}
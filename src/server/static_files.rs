@@ -0,0 +1,263 @@
+use std::fs::File;
+use std::io::{Error as IoError, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::mime::{MimeType, SNIFF_LENGTH};
+use crate::request::{percent_decode, HTTPMethod, Path, Request};
+
+use super::handlers::{Handler, HandlerPath, HandlerResult};
+use super::response::{ResponseBuilder, ResponseStatus};
+
+/// Serves the files under a directory, E.G `/static/css/site.css` from `public/css/site.css`
+/// when mounted at `/static` with the root `public`. Directories are served by their
+/// `index.html`. Register it with `HandlerRegistry::mount` at `mount_path` so that the handler
+/// receives paths relative to the root.
+/// Paths that try to leave the root (E.G with `..`) are rejected with 403 Forbidden
+pub struct StaticFileHandler {
+    root: PathBuf,
+    mount: String,
+    path: HandlerPath,
+    method: HTTPMethod,
+}
+
+/// Why a request path couldn't be mapped to a file
+enum ResolveError {
+    /// The path escapes the root
+    Forbidden,
+    Io(IoError),
+}
+
+impl StaticFileHandler {
+    pub fn new(root: impl Into<PathBuf>, mount: &str) -> Self {
+        Self {
+            root: root.into(),
+            mount: mount.to_string(),
+            path: HandlerPath::new(mount),
+            method: HTTPMethod::Get,
+        }
+    }
+
+    /// The path prefix that files are served under
+    pub fn mount_path(&self) -> &str {
+        &self.mount
+    }
+
+    /// The file for a percent-encoded request path relative to the mount
+    fn resolve(&self, request_path: &str) -> Result<PathBuf, ResolveError> {
+        // `+` is only a space in queries
+        let decoded = percent_decode(&request_path.replace('+', "%2B"));
+        let mut path = self.root.clone();
+        // Segments are split after decoding so that `..%2F` can't hide a `..`.
+        // Backslashes are separators on Windows
+        for segment in decoded.split(['/', '\\']) {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(ResolveError::Forbidden),
+                _ if segment.contains('\0') => return Err(ResolveError::Forbidden),
+                _ => path.push(segment),
+            }
+        }
+        if path.is_dir() {
+            path.push("index.html");
+        }
+
+        // Symlinks could still point outside the root
+        let root = self.root.canonicalize().map_err(ResolveError::Io)?;
+        let path = path.canonicalize().map_err(ResolveError::Io)?;
+        if !path.starts_with(&root) {
+            return Err(ResolveError::Forbidden);
+        }
+        Ok(path)
+    }
+}
+
+/// The type of a file from its extension, falling back to sniffing its first few bytes.
+/// The file is left positioned at its start
+fn content_type(path: &std::path::Path, file: &mut File) -> Result<String, IoError> {
+    let from_extension = path
+        .extension()
+        .and_then(|extension| MimeType::from_extension(&extension.to_string_lossy()));
+    let mime = match from_extension {
+        Some(mime) => Some(mime),
+        None => {
+            let mut start = Vec::with_capacity(SNIFF_LENGTH);
+            file.by_ref()
+                .take(SNIFF_LENGTH as u64)
+                .read_to_end(&mut start)?;
+            file.seek(SeekFrom::Start(0))?;
+            MimeType::sniff(&start)
+        }
+    };
+    Ok(mime.map_or("application/octet-stream".to_string(), |mime| {
+        mime.to_string()
+    }))
+}
+
+impl Handler for StaticFileHandler {
+    fn get_path(&self) -> &HandlerPath {
+        &self.path
+    }
+
+    fn get_method(&self) -> &HTTPMethod {
+        &self.method
+    }
+
+    fn on_request(&self, req: Request) -> HandlerResult {
+        let resolved = match &req.head.path {
+            Path::OriginForm(target) => self.resolve(target.path()),
+            _ => Err(ResolveError::Forbidden),
+        };
+        // The file is streamed rather than read into memory, so large files are cheap to serve
+        let opened = resolved.and_then(|path| {
+            let mut file = File::open(&path).map_err(ResolveError::Io)?;
+            let length = file.metadata().map_err(ResolveError::Io)?.len();
+            let content_type = content_type(&path, &mut file).map_err(ResolveError::Io)?;
+            Ok((content_type, file, length))
+        });
+
+        let builder = ResponseBuilder::from(req);
+        let (content_type, file, length) = match opened {
+            Ok(file) => file,
+            Err(ResolveError::Forbidden) => {
+                return HandlerResult::Done(
+                    builder
+                        .status(ResponseStatus::Forbidden)
+                        .text("Forbidden")
+                        .build()
+                        .expect("A valid 403 response should be produced"),
+                );
+            }
+            Err(ResolveError::Io(e)) => {
+                return HandlerResult::Done(
+                    builder
+                        .from_io_error(&e)
+                        .build()
+                        .expect("A valid error response should be produced"),
+                );
+            }
+        };
+
+        HandlerResult::Done(
+            builder
+                .ok()
+                .header("Content-Type", &content_type)
+                .body_reader(Box::new(file), length)
+                .build()
+                .expect("A valid file response should be produced"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::server::handlers::{HandlerRegistry, RequestDispatcher};
+    use crate::server::response::Response;
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    /// A fresh directory of files to serve, unique to each test
+    fn site(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("http-server-static-{0}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("img")).expect("The test site should be created");
+        std::fs::write(root.join("index.html"), "<h1>Hello</h1>")
+            .expect("The index should be written");
+        std::fs::write(root.join("img/logo.png"), b"\x89PNG\r\n\x1a\n\xff\xfe")
+            .expect("The image should be written");
+        root
+    }
+
+    fn get(root: &std::path::Path, path: &str) -> (Response, Arc<Mutex<Vec<u8>>>) {
+        let handler = StaticFileHandler::new(root, "/static");
+        let mount = handler.mount_path().to_string();
+        let registry = HandlerRegistry::new(Vec::new()).mount(&mount, Arc::new(handler));
+        let (req, written) = mock_request(
+            &format!("GET {path} HTTP/1.1\r\nHost: example.com\r\n"),
+            b"",
+        );
+        let response = registry
+            .dispatch(req)
+            .expect("Paths under the mount should be handled");
+        (response, written)
+    }
+
+    /// Send the response and return the body that was streamed after the head
+    fn sent_body(response: Response, written: &Mutex<Vec<u8>>) -> Vec<u8> {
+        response
+            .send()
+            .expect("Sending the response should succeed");
+        let written = written.lock().expect("The stream should not be poisoned");
+        let head_end = written
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("The head should be terminated");
+        written[head_end + 4..].to_vec()
+    }
+
+    #[test]
+    fn serve_index() {
+        let root = site("index");
+        for path in ["/static/", "/static/index.html"] {
+            let (response, written) = get(&root, path);
+            assert_eq!(*response.status(), ResponseStatus::OK, "{path}");
+            assert_eq!(
+                response.get_header("Content-Type".to_string()),
+                Some("text/html; charset=UTF-8".to_string())
+            );
+            assert_eq!(sent_body(response, &written), b"<h1>Hello</h1>");
+        }
+
+        let (response, _) = get(&root, "/static/missing.html");
+        assert_eq!(*response.status(), ResponseStatus::NotFound);
+    }
+
+    #[test]
+    fn serve_binary() {
+        let root = site("binary");
+        let (response, written) = get(&root, "/static/img/logo.png");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+        assert_eq!(
+            response.get_header("Content-Type".to_string()),
+            Some("image/png".to_string())
+        );
+        assert_eq!(
+            response.get_header("Content-Length".to_string()),
+            Some("10".to_string())
+        );
+        assert_eq!(sent_body(response, &written), b"\x89PNG\r\n\x1a\n\xff\xfe");
+    }
+
+    #[test]
+    fn sniff_without_extension() {
+        let root = site("sniff");
+        std::fs::write(root.join("logo"), b"\x89PNG\r\n\x1a\n\xff\xfe")
+            .expect("The image should be written");
+        let (response, written) = get(&root, "/static/logo");
+        assert_eq!(
+            response.get_header("Content-Type".to_string()),
+            Some("image/png".to_string())
+        );
+        assert_eq!(
+            sent_body(response, &written),
+            b"\x89PNG\r\n\x1a\n\xff\xfe",
+            "Sniffing should not consume the start of the file"
+        );
+    }
+
+    #[test]
+    fn path_traversal_blocked() {
+        let root = site("traversal");
+        for path in [
+            "/static/..%2F..%2Fetc%2Fpasswd",
+            "/static/img/..%2F..%2F..%2Fsecret",
+            "/static/..%5Csecret",
+        ] {
+            let (response, _) = get(&root, path);
+            assert_eq!(*response.status(), ResponseStatus::Forbidden, "{path}");
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    io::{Error as IoError, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::request::{SyncableStream, SyncableStreamType};
+
+/// Limits how quickly bytes can be written to the wrapped stream, E.G to cap the bandwidth of
+/// a response or to simulate a slow connection in tests. Reads are not limited
+pub struct ThrottledStream {
+    inner: Box<dyn SyncableStream>,
+    bytes_per_sec: u64,
+    started: Option<Instant>,
+    written: u64,
+}
+
+impl ThrottledStream {
+    pub fn new(inner: Box<dyn SyncableStream>, bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "The write rate must be positive");
+        Self {
+            inner,
+            bytes_per_sec,
+            started: None,
+            written: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> Box<dyn SyncableStream> {
+        self.inner
+    }
+}
+
+impl Read for ThrottledStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for ThrottledStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+
+        // Write in small slices so that the rate is smooth rather than bursty
+        let max_slice = (self.bytes_per_sec / 10).max(1);
+        let slice_len = buf.len().min(max_slice.try_into().unwrap_or(usize::MAX));
+        let n_written = self.inner.write(&buf[..slice_len])?;
+        self.written += n_written as u64;
+
+        let due = Duration::from_secs_f64(self.written as f64 / self.bytes_per_sec as f64);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SyncableStream for ThrottledStream {
+    fn get_type(&self) -> SyncableStreamType {
+        self.inner.get_type()
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), IoError> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn shutdown(&self) -> Result<(), IoError> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// The bytes that passed through a `TeeStream`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Traffic {
+    /// Everything read from the stream, E.G requests
+    pub received: Vec<u8>,
+    /// Everything written to the stream, E.G responses
+    pub sent: Vec<u8>,
+}
+
+/// Shared so that the traffic can be inspected while the stream is in use
+pub type TrafficLog = Arc<Mutex<Traffic>>;
+
+/// Mirrors every byte read from and written to the wrapped stream into a `TrafficLog`, so that
+/// developers can see exactly what the server received and sent. Intended for debugging and
+/// tests: the log grows without bound
+pub struct TeeStream {
+    inner: Box<dyn SyncableStream>,
+    log: TrafficLog,
+}
+
+impl TeeStream {
+    pub fn new(inner: Box<dyn SyncableStream>, log: TrafficLog) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn into_inner(self) -> Box<dyn SyncableStream> {
+        self.inner
+    }
+
+    fn record(&self) -> std::sync::MutexGuard<'_, Traffic> {
+        self.log
+            .lock()
+            .expect("The traffic log lock should not be poisoned")
+    }
+}
+
+impl Read for TeeStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.record().received.extend_from_slice(&buf[..n_read]);
+        Ok(n_read)
+    }
+}
+
+impl Write for TeeStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n_written = self.inner.write(buf)?;
+        self.record().sent.extend_from_slice(&buf[..n_written]);
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SyncableStream for TeeStream {
+    fn get_type(&self) -> SyncableStreamType {
+        self.inner.get_type()
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), IoError> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn shutdown(&self) -> Result<(), IoError> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// Replays bytes that were read ahead from the wrapped stream before reading from it again,
+/// E.G the start of a pipelined request left in a `BufReader`'s buffer
+pub struct PrefixedStream {
+    prefix: Vec<u8>,
+    read: usize,
+    inner: Box<dyn SyncableStream>,
+}
+
+impl PrefixedStream {
+    pub fn new(prefix: Vec<u8>, inner: Box<dyn SyncableStream>) -> Self {
+        Self {
+            prefix,
+            read: 0,
+            inner,
+        }
+    }
+}
+
+impl Read for PrefixedStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let unread = &self.prefix[self.read..];
+        if unread.is_empty() {
+            return self.inner.read(buf);
+        }
+        let n_read = unread.len().min(buf.len());
+        buf[..n_read].copy_from_slice(&unread[..n_read]);
+        self.read += n_read;
+        Ok(n_read)
+    }
+}
+
+impl Write for PrefixedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SyncableStream for PrefixedStream {
+    fn get_type(&self) -> SyncableStreamType {
+        self.inner.get_type()
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> Result<(), IoError> {
+        self.inner.set_nodelay(nodelay)
+    }
+
+    fn shutdown(&self) -> Result<(), IoError> {
+        self.inner.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), IoError> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use crate::request::http1_1::read_head;
+    use crate::request::Request;
+    use crate::server::response::ResponseBuilder;
+    use crate::testing::MockStream;
+
+    use super::*;
+
+    #[test]
+    fn tee_records_request_and_response() {
+        let raw_request = b"GET /dogs HTTP/1.1\r\nHost: a.com\r\n\r\n";
+        let (inner, output) = MockStream::new(raw_request);
+        let log = TrafficLog::default();
+        let mut reader = BufReader::new(TeeStream::new(Box::new(inner), Arc::clone(&log)));
+
+        let (head, _) = read_head(&mut reader).expect("Reading the head should succeed");
+        let head = crate::request::http1_1::parse_req_head(&mut head.lines())
+            .expect("The request should be valid");
+        ResponseBuilder::from(Request::new(head, reader))
+            .ok()
+            .text("Woof")
+            .build()
+            .expect("A valid response should be produced")
+            .send()
+            .expect("Sending the response should succeed");
+
+        let traffic = log.lock().unwrap();
+        assert_eq!(traffic.received, raw_request);
+        assert_eq!(
+            traffic.sent,
+            *output.lock().unwrap(),
+            "The tee should capture exactly what was sent"
+        );
+        assert!(traffic.sent.ends_with(b"\r\n\r\nWoof"));
+    }
+
+    #[test]
+    fn throttled_write_duration() {
+        let (inner, output) = MockStream::new(b"");
+        let mut stream = ThrottledStream::new(Box::new(inner), 1000);
+
+        let start = Instant::now();
+        stream
+            .write_all(&[b'a'; 200])
+            .expect("Writing through the throttled stream should succeed");
+        let elapsed = start.elapsed();
+
+        assert!(
+            elapsed >= Duration::from_millis(190),
+            "Writing 200 bytes at 1000 B/s should take at least 200 ms. Took {elapsed:?}"
+        );
+        assert_eq!(
+            output.lock().unwrap().len(),
+            200,
+            "Every byte should be written"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::request::Request;
+
+use super::handlers::{
+    HandlerCallError, HandlerCallErrorReason, HandlerRegistry, HandlerRegistryAddError,
+    RequestDispatcher, SyncableHandler,
+};
+use super::response::Response;
+
+/// Dispatches requests to a different set of handlers depending on their Host header.
+/// Requests for an unknown host are sent to the default host, if there is one, and
+/// rejected with 421 Misdirected Request otherwise
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: HashMap<String, HandlerRegistry>,
+    default_host: Option<HandlerRegistry>,
+}
+
+/// Strip the port from a Host header value. IPv6 literals keep their brackets
+fn host_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+impl VirtualHosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve requests for `name` (with any port) using `registry`
+    pub fn host(mut self, name: &str, registry: HandlerRegistry) -> Self {
+        self.hosts
+            .insert(host_name(&name.to_ascii_lowercase()).to_string(), registry);
+        self
+    }
+
+    /// Serve requests for unknown hosts using `registry` instead of rejecting them
+    pub fn default_host(mut self, registry: HandlerRegistry) -> Self {
+        self.default_host = Some(registry);
+        self
+    }
+
+    fn registry_for(&self, host: Option<&str>) -> Option<&HandlerRegistry> {
+        host.and_then(|host| self.hosts.get(host_name(host)))
+            .or(self.default_host.as_ref())
+    }
+}
+
+impl RequestDispatcher for VirtualHosts {
+    type Error = HandlerCallError;
+
+    /// Adds the handler to the default host
+    fn add(&mut self, handler: Arc<SyncableHandler>) -> Result<(), HandlerRegistryAddError> {
+        self.default_host
+            .as_mut()
+            .ok_or(HandlerRegistryAddError::NoDefaultHost)?
+            .add(handler)
+    }
+
+    fn dispatch(&self, req: Request) -> Result<Response, HandlerCallError> {
+        let host = req.head.headers.get("host").cloned();
+        // Host names are case-insensitive, but only header names are lowercased when parsed
+        let lowercase_host = host.as_deref().map(str::to_ascii_lowercase);
+        match self.registry_for(lowercase_host.as_deref()) {
+            Some(registry) => registry.dispatch(req),
+            None => Err(HandlerCallError::new(
+                HandlerCallErrorReason::MisdirectedRequest(host),
+                req,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::_crud_example::{DogStore, DogStoreGetHandler};
+    use crate::server::handlers::{DispatcherError, HandlerGroup};
+    use crate::server::response::ResponseStatus;
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    fn dog_registry() -> HandlerRegistry {
+        HandlerRegistry::new(
+            HandlerGroup::new(Mutex::new(DogStore::default()))
+                .handler(DogStoreGetHandler::new)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn host_name_strips_port() {
+        assert_eq!(host_name("example.com:8080"), "example.com");
+        assert_eq!(host_name("example.com"), "example.com");
+        assert_eq!(host_name("[::1]:80"), "[::1]");
+        assert_eq!(host_name("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn dispatch_configured_host() {
+        let hosts = VirtualHosts::new().host("Example.com", dog_registry());
+
+        let (req, _) = mock_request("GET /dogs HTTP/1.1\r\nHost: example.com:8080\r\n", b"");
+        let response = hosts
+            .dispatch(req)
+            .expect("A request for a configured host should be dispatched");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+    }
+
+    #[test]
+    fn dispatch_mixed_case_host() {
+        let hosts = VirtualHosts::new().host("example.com", dog_registry());
+
+        let (req, _) = mock_request("GET /dogs HTTP/1.1\r\nHost: Example.COM\r\n", b"");
+        let response = hosts
+            .dispatch(req)
+            .expect("Host names should be matched ignoring case");
+        assert_eq!(*response.status(), ResponseStatus::OK);
+    }
+
+    #[test]
+    fn dispatch_unconfigured_host() {
+        let hosts = VirtualHosts::new().host("example.com", dog_registry());
+
+        let (req, _) = mock_request("GET /dogs HTTP/1.1\r\nHost: other.example\r\n", b"");
+        let err = hosts
+            .dispatch(req)
+            .expect_err("A request for an unconfigured host should be rejected");
+        assert_eq!(err.as_status_code(), ResponseStatus::MisdirectedRequest);
+        assert_eq!(err.as_status_code().to_code(), 421);
+
+        let hosts = hosts.default_host(dog_registry());
+        let (req, _) = mock_request("GET /dogs HTTP/1.1\r\nHost: other.example\r\n", b"");
+        hosts
+            .dispatch(req)
+            .expect("Unknown hosts should be sent to the default host");
+    }
+}
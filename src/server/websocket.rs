@@ -0,0 +1,168 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::request::{contains_token, HTTPHeaders, HTTPMethod, Request};
+
+use super::response::{Response, ResponseBuilder, ResponseStatus};
+
+/// Appended to the client's key before hashing. See https://www.rfc-editor.org/rfc/rfc6455#section-1.3
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The only WebSocket version supported by this server
+pub const WEBSOCKET_VERSION: &str = "13";
+
+/// The value of Sec-WebSocket-Accept for a client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{key}{HANDSHAKE_GUID}")).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+/// Pick the first subprotocol offered by the client (in the client's order of preference)
+/// that the server supports. Subprotocol names are case-sensitive
+pub fn negotiate_subprotocol(offered: &str, supported: &[&str]) -> Option<String> {
+    offered
+        .split(',')
+        .map(str::trim)
+        .find(|protocol| supported.contains(protocol))
+        .map(str::to_string)
+}
+
+/// Whether a comma-separated header contains `token`, ignoring case
+fn header_contains(headers: &HTTPHeaders, name: &str, token: &str) -> bool {
+    headers
+        .get(name)
+        .is_some_and(|value| contains_token(value, token))
+}
+
+/// Whether the request is asking to upgrade the connection to a WebSocket
+pub fn is_upgrade_request(headers: &HTTPHeaders) -> bool {
+    header_contains(headers, "upgrade", "websocket")
+        && header_contains(headers, "connection", "upgrade")
+}
+
+/// Respond to a WebSocket opening handshake.
+/// Returns 101 Switching Protocols with the first of the client's subprotocols that is in
+/// `subprotocols`, 426 Upgrade Required for unsupported WebSocket versions or 400 Bad Request
+/// for malformed handshakes. The connection should only be used as a WebSocket after a 101
+pub fn handshake(req: Request, subprotocols: &[&str]) -> Response {
+    let headers = &req.head.headers;
+    let key = headers.get("sec-websocket-key").cloned();
+    let is_valid =
+        req.head.method == HTTPMethod::Get && is_upgrade_request(headers) && key.is_some();
+    if !is_valid {
+        return ResponseBuilder::from(req)
+            .bad_request()
+            .text("Invalid WebSocket handshake")
+            .build()
+            .expect("A valid 400 response should be produced");
+    }
+
+    if headers
+        .get("sec-websocket-version")
+        .is_none_or(|version| version.trim() != WEBSOCKET_VERSION)
+    {
+        return ResponseBuilder::from(req)
+            .status(ResponseStatus::UpgradeRequired)
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", WEBSOCKET_VERSION)
+            .text("Unsupported WebSocket version")
+            .build()
+            .expect("A valid 426 response should be produced");
+    }
+
+    let subprotocol = headers
+        .get("sec-websocket-protocol")
+        .and_then(|offered| negotiate_subprotocol(offered, subprotocols));
+    let key = key.expect("The key was checked to be present");
+
+    let builder = ResponseBuilder::from(req)
+        .status(ResponseStatus::SwitchingProtocols)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(&key));
+    match subprotocol {
+        Some(subprotocol) => builder.header("Sec-WebSocket-Protocol", &subprotocol),
+        None => builder,
+    }
+    .build()
+    .expect("A valid 101 response should be produced")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::mock_request;
+
+    use super::*;
+
+    fn upgrade_request(extra_headers: &str) -> Request {
+        mock_request(
+            &format!(
+                "GET /chat HTTP/1.1\r\nHost: a.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{extra_headers}"
+            ),
+            b"",
+        )
+        .0
+    }
+
+    #[test]
+    fn accept_key_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn subprotocol_selected() {
+        let response = handshake(
+            upgrade_request(
+                "Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: chat.v2, chat.v1\r\n",
+            ),
+            &["chat.v1", "chat.v2"],
+        );
+
+        assert_eq!(*response.status(), ResponseStatus::SwitchingProtocols);
+        assert_eq!(
+            response.get_header("Sec-WebSocket-Protocol".to_string()),
+            Some("chat.v2".to_string()),
+            "The client's first supported preference should be chosen"
+        );
+        assert_eq!(
+            response.get_header("Sec-WebSocket-Accept".to_string()),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())
+        );
+    }
+
+    #[test]
+    fn subprotocol_omitted() {
+        let response = handshake(
+            upgrade_request("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n"),
+            &["chat.v1"],
+        );
+
+        assert_eq!(*response.status(), ResponseStatus::SwitchingProtocols);
+        assert_eq!(
+            response.get_header("Sec-WebSocket-Protocol".to_string()),
+            None,
+            "No subprotocol should be echoed when none match"
+        );
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let response = handshake(
+            upgrade_request("Sec-WebSocket-Version: 8\r\n"),
+            &["chat.v1"],
+        );
+
+        assert_eq!(*response.status(), ResponseStatus::UpgradeRequired);
+        assert_eq!(
+            response.get_header("Sec-WebSocket-Version".to_string()),
+            Some("13".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_handshake() {
+        let (req, _) = mock_request("GET /chat HTTP/1.1\r\nHost: a.com\r\n", b"");
+        assert_eq!(*handshake(req, &[]).status(), ResponseStatus::BadRequest);
+    }
+}
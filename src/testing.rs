@@ -0,0 +1,118 @@
+//! Helpers shared between unit tests
+use std::{
+    cell::RefCell,
+    io::{BufReader, Cursor, Read, Write},
+    sync::{Arc, Mutex, Once},
+};
+
+use log::{Level, Log, Metadata, Record};
+
+use crate::request::{http1_1, Request, SyncableStream, SyncableStreamType};
+
+/// An in-memory stream with separate buffers for the bytes read by the server
+/// and the bytes written by the server
+pub struct MockStream {
+    input: Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockStream {
+    /// Returns the stream and a handle to everything written to it
+    pub fn new(input: &[u8]) -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        (
+            Self {
+                input: Cursor::new(input.to_vec()),
+                output: Arc::clone(&output),
+            },
+            output,
+        )
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SyncableStream for MockStream {
+    fn get_type(&self) -> SyncableStreamType {
+        SyncableStreamType::Tcp
+    }
+}
+
+/// Parse `raw_head` and attach `body` as the rest of the stream.
+/// Returns the request and a handle to everything written to its stream
+pub fn mock_request(raw_head: &str, body: &[u8]) -> (Request, Arc<Mutex<Vec<u8>>>) {
+    let head = http1_1::parse_req_head(&mut raw_head.lines())
+        .expect("The test request head should be valid");
+    let (stream, output) = MockStream::new(body);
+    (Request::new(head, BufReader::new(stream)), output)
+}
+
+thread_local! {
+    /// The records logged on this thread while `capture_logs` is running
+    static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+}
+
+/// Logs like `env_logger` and also records what is logged on threads that are capturing.
+/// Only one logger can be installed per process, so unit tests must use this rather than
+/// installing `env_logger` themselves
+struct TestLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED.with_borrow_mut(|captured| {
+            if let Some(records) = captured {
+                records.push((record.level(), record.args().to_string()));
+            }
+        });
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the test logger. Safe to call from every test
+pub fn init_logger() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let inner = env_logger::builder().is_test(true).build();
+        log::set_logger(Box::leak(Box::new(TestLogger { inner })))
+            .expect("No other logger should be installed in unit tests");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Run `f` and return what it logged on the current thread with each record's level.
+/// Records logged on other threads, E.G by workers, aren't captured
+pub fn capture_logs<T>(f: impl FnOnce() -> T) -> (T, Vec<(Level, String)>) {
+    init_logger();
+    CAPTURED.set(Some(Vec::new()));
+    let result = f();
+    let records = CAPTURED
+        .take()
+        .expect("Records should be captured until `f` returns");
+    (result, records)
+}
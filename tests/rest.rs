@@ -1,8 +1,7 @@
-use std::io::Error as IoError;
-use std::net::{IpAddr, Ipv4Addr};
-use std::panic;
+use std::io::{BufRead, BufReader, Error as IoError, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
 use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use std::thread;
 use std::time::Duration;
 
 use http_server::_crud_example as rest_api;
@@ -99,6 +98,44 @@ fn test_get_endpoint() {
     );
 }
 
+#[test]
+fn test_get_plain_text() {
+    let TestDeps {
+        agent,
+        base_url,
+        port,
+    } = setup();
+    let mut dog_store = rest_api::DogStore::default();
+    dog_store.add("Rex");
+    dog_store.add("Fido");
+    let _ = run_listener(
+        port,
+        vec![Arc::new(rest_api::DogStoreGetHandler::new(Arc::new(
+            Mutex::new(dog_store),
+        )))],
+    );
+    thread::sleep(Duration::from_millis(50));
+
+    let mut response = agent
+        .get(qualify(&base_url, "dogs"))
+        .header("Accept", "application/json;q=0.5, text/plain")
+        .call()
+        .expect("Calling the /dogs endpoint should succeed");
+    assert_ok(&response);
+    assert_eq!(
+        response
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.to_str().ok()),
+        Some("text/plain; charset=UTF-8")
+    );
+    let raw_body = response
+        .body_mut()
+        .read_to_string()
+        .expect("Reading the body should succeed");
+    assert_eq!(raw_body, "Rex\nFido");
+}
+
 #[derive(Debug, Serialize)]
 struct NewDogName {
     name: String,
@@ -130,6 +167,14 @@ fn test_post_endpoint() {
         .send_json(&new_name)
         .expect("POSTing to the endpoint should succeed");
     assert_eq!(response.status(), http::StatusCode::CREATED);
+    assert_eq!(
+        response
+            .headers()
+            .get("Location")
+            .map(|location| location.to_str().unwrap()),
+        Some("/dogs/Alfred"),
+        "The Location header should point to the new dog"
+    );
 
     let dog_names = agent
         .get(qualify(&base_url, "dogs"))
@@ -174,3 +219,244 @@ fn test_post_endpoint() {
         "Alfred should still be in the store"
     );
 }
+
+#[test]
+fn test_post_prefer_minimal() {
+    let TestDeps {
+        agent,
+        base_url,
+        port,
+    } = setup();
+    let dog_store = Arc::new(Mutex::new(rest_api::DogStore::default()));
+    let _ = run_listener(
+        port,
+        vec![Arc::new(rest_api::DogStorePostHandler::new(dog_store))],
+    );
+    thread::sleep(Duration::from_millis(50));
+
+    let response = agent
+        .post(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .header("Prefer", "return=minimal")
+        .send_json(&NewDogName {
+            name: "Alfred".to_string(),
+        })
+        .expect("POSTing to the endpoint should succeed");
+    assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+    assert_eq!(
+        response
+            .headers()
+            .get("Preference-Applied")
+            .map(|applied| applied.to_str().unwrap()),
+        Some("return=minimal"),
+        "The honoured preference should be echoed"
+    );
+
+    let mut response = agent
+        .post(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .header("Prefer", "return=representation")
+        .send_json(&NewDogName {
+            name: "Bertie".to_string(),
+        })
+        .expect("POSTing to the endpoint should succeed");
+    assert_eq!(response.status(), http::StatusCode::CREATED);
+    assert_eq!(
+        response
+            .headers()
+            .get("Preference-Applied")
+            .map(|applied| applied.to_str().unwrap()),
+        Some("return=representation")
+    );
+    let body: serde_json::Value = response
+        .body_mut()
+        .read_json()
+        .expect("The representation should be valid JSON");
+    assert_eq!(body["name"], "Bertie");
+}
+
+#[derive(Debug, Serialize)]
+struct DogNames {
+    names: Vec<String>,
+}
+
+#[test]
+fn test_put_requires_precondition() {
+    let TestDeps {
+        agent,
+        base_url,
+        port,
+    } = setup();
+    let dog_store = Arc::new(Mutex::new(rest_api::DogStore::default()));
+    let _ = run_listener(
+        port,
+        vec![
+            Arc::new(rest_api::DogStoreGetHandler::new(dog_store.clone())),
+            Arc::new(rest_api::DogStorePutHandler::new(dog_store)),
+        ],
+    );
+    thread::sleep(Duration::from_millis(50));
+
+    let new_names = DogNames {
+        names: vec!["Alfred".to_string(), "Bertie".to_string()],
+    };
+    let response = agent
+        .put(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .send_json(&new_names)
+        .expect_err("An unconditional PUT should fail");
+    assert!(
+        matches!(response, ureq::Error::StatusCode(428)),
+        "The PUT request should fail with status 428 Precondition Required. Error: {response:?}"
+    );
+
+    let etag = agent
+        .get(qualify(&base_url, "dogs"))
+        .call()
+        .expect("GET /dogs should succeed")
+        .headers()
+        .get("ETag")
+        .expect("GET /dogs should send an ETag")
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let response = agent
+        .put(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .header("If-Match", "\"stale\"")
+        .send_json(&new_names)
+        .expect_err("A PUT with a stale ETag should fail");
+    assert!(
+        matches!(response, ureq::Error::StatusCode(412)),
+        "The PUT request should fail with status 412 Precondition Failed. Error: {response:?}"
+    );
+
+    let response = agent
+        .put(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .header("If-Match", &etag)
+        .send_json(&new_names)
+        .expect("A PUT with the current ETag should succeed");
+    assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+    assert_ne!(
+        response
+            .headers()
+            .get("ETag")
+            .map(|tag| tag.to_str().unwrap()),
+        Some(etag.as_str()),
+        "The ETag should change with the resource"
+    );
+
+    let dog_names = agent
+        .get(qualify(&base_url, "dogs"))
+        .call()
+        .expect("GET /dogs should succeed")
+        .body_mut()
+        .read_json::<rest_api::DogStore>()
+        .expect("GET /dogs should return valid JSON");
+    assert_eq!(dog_names.names, new_names.names);
+}
+
+/// Read one response with a Content-Length body, returning the head and the body
+fn read_response(reader: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .expect("Reading the response head should succeed");
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        head += &line;
+    }
+    let length: usize = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .expect("The response should have a Content-Length");
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .expect("Reading the response body should succeed");
+    (
+        head,
+        String::from_utf8(body).expect("The body should be UTF-8"),
+    )
+}
+
+#[test]
+fn test_keep_alive() {
+    let TestDeps {
+        agent,
+        base_url,
+        port,
+    } = setup();
+    let dog_store = Arc::new(Mutex::new(rest_api::DogStore::default()));
+    let _ = run_listener(
+        port,
+        vec![
+            Arc::new(rest_api::DogStoreGetHandler::new(dog_store.clone())),
+            Arc::new(rest_api::DogStorePostHandler::new(dog_store)),
+        ],
+    );
+    thread::sleep(Duration::from_millis(50));
+
+    // ureq pools connections, so the second request reuses the first one's connection
+    let response = agent
+        .post(qualify(&base_url, "dogs"))
+        .header("Content-Type", "application/json")
+        .send_json(&NewDogName {
+            name: "Alfred".to_string(),
+        })
+        .expect("POSTing to the endpoint should succeed");
+    assert_eq!(response.status(), http::StatusCode::CREATED);
+    let dog_names = agent
+        .get(qualify(&base_url, "dogs"))
+        .call()
+        .expect("A request on a reused connection should succeed")
+        .body_mut()
+        .read_json::<rest_api::DogStore>()
+        .expect("GET /dogs should return valid JSON");
+    assert_eq!(dog_names.names, vec!["Alfred"]);
+
+    // Check that both requests really are served on one connection
+    let stream = TcpStream::connect((IP, port)).expect("Connecting should succeed");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("Setting the timeout should succeed");
+    let mut writer = stream
+        .try_clone()
+        .expect("Cloning the stream should succeed");
+    let mut reader = BufReader::new(stream);
+    for _ in 0..2 {
+        writer
+            .write_all(format!("GET /dogs HTTP/1.1\r\nHost: {IP}:{port}\r\n\r\n").as_bytes())
+            .expect("Sending the request should succeed");
+        let (head, body) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+        assert!(
+            head.contains("Connection: keep-alive"),
+            "The connection should be kept open. Head: {head}"
+        );
+        assert!(body.contains("Alfred"), "{body}");
+    }
+
+    writer
+        .write_all(
+            format!("GET /dogs HTTP/1.1\r\nHost: {IP}:{port}\r\nConnection: close\r\n\r\n")
+                .as_bytes(),
+        )
+        .expect("Sending the request should succeed");
+    let (head, _) = read_response(&mut reader);
+    assert!(head.contains("Connection: close"), "{head}");
+    let mut rest = Vec::new();
+    reader
+        .read_to_end(&mut rest)
+        .expect("The server should close the connection");
+    assert!(rest.is_empty(), "Nothing should be sent after the response");
+}